@@ -0,0 +1,18 @@
+-- Conversations and their messages. `IF NOT EXISTS`, since databases from before migrations
+-- already have these tables.
+CREATE TABLE IF NOT EXISTS conversations (
+    key TEXT PRIMARY KEY,
+    system TEXT NOT NULL,
+    speakers TEXT NOT NULL DEFAULT '[]',
+    party TEXT NOT NULL DEFAULT '[]',
+    goal TEXT,
+    goal_reached INTEGER NOT NULL DEFAULT 0
+);
+CREATE TABLE IF NOT EXISTS messages (
+    id INTEGER PRIMARY KEY,
+    conversation TEXT NOT NULL REFERENCES conversations(key) ON DELETE CASCADE,
+    message_type TEXT NOT NULL,
+    content TEXT NOT NULL,
+    speaker TEXT
+);
+CREATE INDEX IF NOT EXISTS messages_by_conversation ON messages(conversation, id);
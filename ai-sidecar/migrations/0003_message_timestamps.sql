@@ -0,0 +1,2 @@
+-- When each message was added, in unix seconds. Left empty for messages stored before.
+ALTER TABLE messages ADD COLUMN created_at INTEGER;
@@ -0,0 +1,2 @@
+-- Moderation flags set on each conversation, as JSON.
+ALTER TABLE conversations ADD COLUMN flags TEXT NOT NULL DEFAULT '{}';
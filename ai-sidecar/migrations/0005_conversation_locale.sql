@@ -0,0 +1,2 @@
+-- The locale each session's NPC speaks in, as a BCP 47 tag.
+ALTER TABLE conversations ADD COLUMN locale TEXT;
@@ -0,0 +1,12 @@
+-- How generations fared per persona and prompt template. Replies without a persona are tallied
+-- under ''.
+CREATE TABLE generation_tallies (
+    persona TEXT NOT NULL,
+    template TEXT NOT NULL,
+    generations INTEGER NOT NULL,
+    characters INTEGER NOT NULL,
+    latency_ms INTEGER NOT NULL,
+    regenerations INTEGER NOT NULL,
+    blocked INTEGER NOT NULL,
+    PRIMARY KEY (persona, template)
+);
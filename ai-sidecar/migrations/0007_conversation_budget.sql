@@ -0,0 +1,2 @@
+-- The limits on what each session may spend generating an hour, as JSON.
+ALTER TABLE conversations ADD COLUMN budget TEXT;
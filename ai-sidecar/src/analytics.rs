@@ -0,0 +1,419 @@
+//! What players actually ask NPCs, and where the replies fall short.
+//!
+//! A background task periodically reads every idle conversation and builds a [`Report`]: prompts
+//! grouped by topic, a rough sentiment tally, and questions the NPC deflected or left unanswered.
+//! The analysis is lexical, so it stays cheap enough to run beside generation. Player ratings of
+//! recent replies are tallied alongside, and so is how generations fare per persona and template,
+//! see [`crate::tallies`].
+
+use std::{
+    collections::HashMap,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::Serialize;
+
+use crate::{
+    history::{History, MessageType},
+    server::{AppState, ServerError},
+    tallies::TallySummary,
+    traces::{Rating, Source, Trace},
+};
+
+const DEFAULT_INTERVAL_SECS: u64 = 600;
+const MAX_TOPICS: usize = 10;
+const MAX_EXAMPLES: usize = 3;
+const MAX_UNANSWERED: usize = 50;
+const MAX_COMMENTS: usize = 50;
+
+const STOPWORDS: &[&str] = &[
+    "about", "after", "again", "been", "could", "does", "doing", "from", "have", "here", "into",
+    "just", "know", "like", "more", "much", "some", "tell", "than", "that", "their", "them",
+    "then", "there", "these", "they", "this", "what", "when", "where", "which", "while", "whom",
+    "will", "with", "would", "your", "yours",
+];
+const POSITIVE: &[&str] = &[
+    "thanks",
+    "thank",
+    "great",
+    "good",
+    "love",
+    "nice",
+    "awesome",
+    "wonderful",
+    "happy",
+    "please",
+    "cool",
+    "amazing",
+    "helpful",
+];
+const NEGATIVE: &[&str] = &[
+    "hate", "bad", "stupid", "useless", "angry", "terrible", "awful", "boring", "wrong",
+    "annoying", "worst", "liar", "idiot",
+];
+/// Replies containing these dodge the question rather than answer it.
+const DEFLECTIONS: &[&str] = &[
+    "i don't know",
+    "i do not know",
+    "i'm not sure",
+    "i am not sure",
+    "i cannot",
+    "i can't",
+    "as an ai",
+    "i'm sorry",
+];
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Topic {
+    pub keyword: String,
+    pub count: usize,
+    pub examples: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Sentiment {
+    pub positive: usize,
+    pub neutral: usize,
+    pub negative: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Unanswered {
+    pub question: String,
+    /// Missing when the conversation ended without a reply.
+    pub reply: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Ratings {
+    pub up: usize,
+    pub down: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Comment {
+    pub generation_id: String,
+    pub rating: Rating,
+    pub prompt: String,
+    pub reply: String,
+    pub comment: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct FeedbackSummary {
+    pub generated: Ratings,
+    pub authored: Ratings,
+    pub fallback: Ratings,
+    /// The latest comments first.
+    pub comments: Vec<Comment>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Report {
+    pub generated_at: u64,
+    pub conversations: usize,
+    pub prompts: usize,
+    pub topics: Vec<Topic>,
+    pub sentiment: Sentiment,
+    pub unanswered: Vec<Unanswered>,
+    pub feedback: FeedbackSummary,
+    /// By persona and then template.
+    pub generations: Vec<TallySummary>,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+fn words(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+}
+
+fn keywords(text: &str) -> Vec<String> {
+    let mut keywords = words(text)
+        .filter(|word| word.len() >= 4 && !STOPWORDS.contains(&word.as_str()))
+        .collect::<Vec<_>>();
+    keywords.sort();
+    keywords.dedup();
+
+    keywords
+}
+
+fn sentiment(text: &str) -> i32 {
+    words(text)
+        .map(|word| {
+            i32::from(POSITIVE.contains(&word.as_str()))
+                - i32::from(NEGATIVE.contains(&word.as_str()))
+        })
+        .sum()
+}
+
+fn deflects(reply: &str) -> bool {
+    let reply = reply.to_lowercase();
+    reply.trim().is_empty() || DEFLECTIONS.iter().any(|phrase| reply.contains(phrase))
+}
+
+/// Groups prompts under their most common keyword, so each prompt lands in exactly one topic.
+fn topics(prompts: &[&str]) -> Vec<Topic> {
+    let keywords = prompts.iter().map(|p| keywords(p)).collect::<Vec<_>>();
+    let mut frequency = HashMap::<&str, usize>::new();
+    for keyword in keywords.iter().flatten() {
+        *frequency.entry(keyword).or_default() += 1;
+    }
+
+    let mut topics = HashMap::<&str, Topic>::new();
+    for (prompt, keywords) in prompts.iter().zip(&keywords) {
+        let Some(keyword) = keywords.iter().max_by(|a, b| {
+            frequency[a.as_str()]
+                .cmp(&frequency[b.as_str()])
+                .then(b.cmp(a))
+        }) else {
+            continue;
+        };
+
+        let topic = topics.entry(keyword).or_insert_with(|| Topic {
+            keyword: keyword.clone(),
+            count: 0,
+            examples: Vec::new(),
+        });
+        topic.count += 1;
+        if topic.examples.len() < MAX_EXAMPLES {
+            topic.examples.push(prompt.to_string());
+        }
+    }
+
+    let mut topics = topics.into_values().collect::<Vec<_>>();
+    topics.sort_by(|a, b| {
+        b.count
+            .cmp(&a.count)
+            .then_with(|| a.keyword.cmp(&b.keyword))
+    });
+    topics.truncate(MAX_TOPICS);
+
+    topics
+}
+
+pub fn analyze<'a>(histories: impl IntoIterator<Item = &'a History>) -> Report {
+    let mut report = Report {
+        generated_at: now(),
+        ..Default::default()
+    };
+    let mut prompts = Vec::new();
+
+    for history in histories {
+        report.conversations += 1;
+
+        let mut messages = history.history.iter().peekable();
+        while let Some(message) = messages.next() {
+            if message.message_type() != MessageType::User {
+                continue;
+            }
+            let prompt = message.content();
+            prompts.push(prompt);
+
+            match sentiment(prompt) {
+                score if score > 0 => report.sentiment.positive += 1,
+                score if score < 0 => report.sentiment.negative += 1,
+                _ => report.sentiment.neutral += 1,
+            }
+
+            let reply = messages
+                .peek()
+                .filter(|next| next.message_type() == MessageType::Assistant)
+                .map(|next| next.content());
+            if prompt.trim_end().ends_with('?')
+                && reply.is_none_or(deflects)
+                && report.unanswered.len() < MAX_UNANSWERED
+            {
+                report.unanswered.push(Unanswered {
+                    question: prompt.to_string(),
+                    reply: reply.map(str::to_string),
+                });
+            }
+        }
+    }
+
+    report.prompts = prompts.len();
+    report.topics = topics(&prompts);
+
+    report
+}
+
+/// Tallies ratings by where the rated reply came from.
+pub fn summarize_feedback<'a>(
+    traces: impl DoubleEndedIterator<Item = &'a Trace>,
+) -> FeedbackSummary {
+    let mut summary = FeedbackSummary::default();
+
+    for trace in traces.rev() {
+        let Some(feedback) = &trace.feedback else {
+            continue;
+        };
+
+        let ratings = match trace.source {
+            Source::Generated => &mut summary.generated,
+            Source::Authored => &mut summary.authored,
+            Source::Fallback => &mut summary.fallback,
+        };
+        match feedback.rating {
+            Rating::Up => ratings.up += 1,
+            Rating::Down => ratings.down += 1,
+        }
+
+        if let Some(comment) = &feedback.comment {
+            if summary.comments.len() < MAX_COMMENTS {
+                summary.comments.push(Comment {
+                    generation_id: trace.id.clone(),
+                    rating: feedback.rating,
+                    prompt: trace.prompt.clone(),
+                    reply: trace.reply.clone(),
+                    comment: comment.clone(),
+                });
+            }
+        }
+    }
+
+    summary
+}
+
+/// Snapshots every conversation that isn't busy generating, and analyzes them.
+pub async fn snapshot(state: &AppState) -> Report {
+    let mut conversations = vec![state.history.clone()];
+    conversations.extend(state.sessions.lock().await.histories().cloned());
+
+    let histories = conversations
+        .iter()
+        .filter_map(|history| history.try_lock().ok().map(|history| history.clone()))
+        .collect::<Vec<_>>();
+
+    let mut report = analyze(&histories);
+    report.feedback = summarize_feedback(state.traces.lock().await.iter());
+    report.generations = state.tallies.summaries();
+
+    report
+}
+
+/// Refreshes the report every `AI_SIDECAR_ANALYTICS_INTERVAL_SECS` seconds, or never if it is 0.
+pub fn spawn(state: AppState) -> Result<(), ServerError> {
+    let secs = match std::env::var("AI_SIDECAR_ANALYTICS_INTERVAL_SECS") {
+        Ok(v) => v
+            .parse()
+            .map_err(|_| ServerError::InvalidSetting("AI_SIDECAR_ANALYTICS_INTERVAL_SECS", v))?,
+        Err(_) => DEFAULT_INTERVAL_SECS,
+    };
+    if secs == 0 {
+        tracing::info!("conversation analytics disabled");
+        return Ok(());
+    }
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(secs));
+        loop {
+            interval.tick().await;
+            let report = snapshot(&state).await;
+            tracing::debug!("analyzed {} conversations", report.conversations);
+            *state.analytics.lock().await = Some(report);
+        }
+    });
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::traces::Traces;
+
+    fn history(exchanges: &[(&str, Option<&str>)]) -> History {
+        let mut history = History::new("System".into());
+        for (prompt, reply) in exchanges {
+            history.push(MessageType::User, prompt.to_string());
+            if let Some(reply) = reply {
+                history.push(MessageType::Assistant, reply.to_string());
+            }
+        }
+
+        history
+    }
+
+    #[test]
+    fn groups_prompts_by_topic() {
+        let report = analyze(&[
+            history(&[
+                ("Where can I buy a sword?", Some("At the smithy.")),
+                ("How much is a sword?", Some("Ten gold.")),
+            ]),
+            history(&[
+                ("Tell me about the dragon", Some("It sleeps in the hills.")),
+                (
+                    "How old is the dragon's hoard?",
+                    Some("Older than the village."),
+                ),
+            ]),
+        ]);
+
+        assert_eq!(report.conversations, 2);
+        assert_eq!(report.prompts, 4);
+        let topics = report
+            .topics
+            .iter()
+            .map(|t| (t.keyword.as_str(), t.count))
+            .collect::<Vec<_>>();
+        assert_eq!(topics, [("dragon", 2), ("sword", 2)]);
+    }
+
+    #[test]
+    fn finds_unanswered_questions_and_sentiment() {
+        let report = analyze(&[history(&[
+            ("Thanks, that was great!", Some("Happy to help.")),
+            ("Who stole the crown?", Some("I'm not sure, traveller.")),
+            ("This is stupid", Some("Hmph.")),
+            ("Where is the mill?", Some("North of the river.")),
+            ("Are you still there?", None),
+        ])]);
+
+        let unanswered = report
+            .unanswered
+            .iter()
+            .map(|u| (u.question.as_str(), u.reply.as_deref()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            unanswered,
+            [
+                ("Who stole the crown?", Some("I'm not sure, traveller.")),
+                ("Are you still there?", None)
+            ]
+        );
+        assert_eq!(report.sentiment.positive, 1);
+        assert_eq!(report.sentiment.negative, 1);
+        assert_eq!(report.sentiment.neutral, 3);
+    }
+
+    #[test]
+    fn tallies_feedback_by_source() {
+        let mut traces = Traces::default();
+        let generated = traces.record(None, None, "Hi".into(), "Hello".into(), Source::Generated);
+        let fallback = traces.record(None, None, "Hi".into(), "...".into(), Source::Fallback);
+        let authored = traces.record(None, None, "Hi".into(), "Well met".into(), Source::Authored);
+        traces.record(None, None, "Hi".into(), "Hey".into(), Source::Generated);
+        traces.feedback(&generated, Rating::Up, None);
+        traces.feedback(&fallback, Rating::Down, Some("Said nothing".into()));
+        traces.feedback(&authored, Rating::Up, Some("Loved it".into()));
+
+        let summary = summarize_feedback(traces.iter());
+        assert_eq!((summary.generated.up, summary.generated.down), (1, 0));
+        assert_eq!((summary.fallback.up, summary.fallback.down), (0, 1));
+        assert_eq!((summary.authored.up, summary.authored.down), (1, 0));
+        let comments = summary
+            .comments
+            .iter()
+            .map(|c| c.comment.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(comments, ["Loved it", "Said nothing"]);
+    }
+}
@@ -2,10 +2,11 @@ use axum::Router;
 
 use crate::server::AppState;
 
-mod v1;
+pub(crate) mod v1;
 
-pub fn route() -> Router<AppState> {
+/// The API, with `extensions` added to v1 alongside the core routes.
+pub fn route(state: AppState, extensions: Router<AppState>) -> Router<AppState> {
     tracing::info!("constructing api routes");
 
-    Router::new().nest("/v1", v1::route())
+    Router::new().nest("/v1", v1::route(state, extensions))
 }
@@ -1,26 +1,241 @@
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{atomic::Ordering, Arc},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
 use axum::{
-    extract::State,
-    http::{HeaderMap, StatusCode},
-    response::IntoResponse,
+    async_trait,
+    extract::{rejection::JsonRejection, ConnectInfo, FromRequest, Query, Request, State},
+    http::{header::RETRY_AFTER, HeaderMap, HeaderValue, StatusCode},
+    middleware::{self, Next},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
     routing::{delete, get, post},
-    Json, Router,
+    Extension, Json, Router,
+};
+use llama_cpp::{
+    grammar::{LlamaGrammar, LlamaGrammarFromStrError},
+    LlamaModel,
 };
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio::sync::{Mutex, OwnedMutexGuard};
+use tokio_stream::{wrappers::ReceiverStream, Stream, StreamExt};
+use tracing::Instrument;
 
-use crate::{history, llm, server::AppState};
+use crate::{
+    backend::{BackendRequest, LlmBackend},
+    bias::Bias,
+    cache::CacheKey,
+    canary::{Arm, CanaryConfig},
+    compaction,
+    degradation::{Level, Load},
+    failover::Link,
+    fallback::FallbackReason,
+    flags::Flag,
+    formatting::Formatting,
+    goals::{self, Goal},
+    group,
+    history::{Flags, History, Message, MessageType},
+    jobs::{self, Cancellable, JobError, Timings},
+    keys::{KeyStore, Scope},
+    llm,
+    locale::Locale,
+    memory::MemoryStore,
+    moderation::{Outcome, Verdict},
+    persist::HistoryDb,
+    replica::Replica,
+    server::AppState,
+    tallies::{Observation, Tally},
+    templates::Template,
+    tracecontext::{TraceContext, REQUEST_ID_HEADER},
+    traces::{Source, Traces},
+    turns::{PendingTurn, Turn},
+};
+
+mod admin;
+mod analytics;
+mod batch;
+mod chat;
+mod embeddings;
+mod feedback;
+mod game;
+mod history;
+mod jobs;
+mod lore;
+mod models;
+mod monitor;
+mod npc;
+mod quests;
+mod review;
+mod sessions;
+mod stats;
+mod system_prompt;
+mod tokens;
 
 const AUTH_HEADER_KEY: &str = "secret";
+/// Identifies the client to rate limit, instead of its IP address.
+const CLIENT_ID_HEADER_KEY: &str = "x-client-id";
 
-pub fn route() -> Router<AppState> {
+pub fn route(state: AppState, extensions: Router<AppState>) -> Router<AppState> {
     tracing::info!("constructing v1 route");
 
     Router::new()
         .route("/isbusy", get(handle_is_busy))
         .route("/clearhistory", delete(clear_history))
         .route("/generate", post(handle_generate))
+        .route("/generate/subscribe", get(subscribe_generation))
+        .route("/cancel", post(cancel_generation))
+        .nest("/admin", admin::route())
+        .nest("/analytics", analytics::route())
+        .nest("/embeddings", embeddings::route())
+        .nest("/feedback", feedback::route())
+        .nest("/game", game::route())
+        .nest("/history", history::route())
+        .nest("/jobs", jobs::route())
+        .nest("/lore", lore::route())
+        .nest("/models", models::route())
+        .nest("/monitor", monitor::route())
+        .nest("/npc", npc::route())
+        .nest("/quests", quests::route())
+        .nest("/review", review::route())
+        .nest("/sessions", sessions::route())
+        .nest("/stats", stats::route())
+        .merge(batch::route())
+        .merge(chat::route())
+        .merge(system_prompt::route())
+        .merge(tokens::route())
+        .merge(extensions)
+        .layer(middleware::from_fn_with_state(state.clone(), read_only))
+        .layer(middleware::from_fn_with_state(state, rate_limit))
+        .layer(middleware::from_fn(trace_request))
+}
+
+/// Runs the request in a span of its trace, and gives the response its request id.
+async fn trace_request(mut request: Request, next: Next) -> Response {
+    let context = TraceContext::from_headers(request.headers());
+    let span = tracing::info_span!(
+        "request",
+        request_id = %context.request_id,
+        trace_id = %context.trace_id,
+        method = %request.method(),
+        path = request.uri().path(),
+        backend_ms = tracing::field::Empty,
+    );
+    request.extensions_mut().insert(context.clone());
+
+    let started = Instant::now();
+    let mut response = next.run(request).instrument(span.clone()).await;
+    span.in_scope(|| {
+        tracing::debug!(
+            total_ms = started.elapsed().as_millis() as u64,
+            status = response.status().as_u16(),
+            "handled request"
+        )
+    });
+    if let Ok(request_id) = HeaderValue::from_str(&context.request_id) {
+        response.headers_mut().insert(REQUEST_ID_HEADER, request_id);
+    }
+    response
+}
+
+/// On a read-only replica, forwards requests other than reads to the primary, or declines them.
+/// WebSockets are always declined, since they would generate or write on the replica otherwise.
+async fn read_only(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    let Some(replica) = &state.replica else {
+        return next.run(request).await;
+    };
+    if Replica::serves(request.method(), request.uri().path()) {
+        return next.run(request).await;
+    }
+    if !replica.forwards() || Replica::upgrades(request.method(), request.uri().path()) {
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(RequestErrorResponse::ReadOnly),
+        )
+            .into_response();
+    }
+
+    match replica.forward(request).await {
+        Ok(response) => response,
+        Err(e) => {
+            tracing::error!("unable to forward to the primary: {e}");
+            (
+                StatusCode::BAD_GATEWAY,
+                Json(RequestErrorResponse::PrimaryUnavailable {
+                    message: e.to_string(),
+                }),
+            )
+                .into_response()
+        }
+    }
 }
 
-fn valid_header(headers: &HeaderMap, expected: &str) -> bool {
+/// The rate-limited client a request is made by: the client it names if its key may forward
+/// clients' requests, the key otherwise, or its IP address without one.
+fn client(headers: &HeaderMap, keys: &KeyStore, addr: Option<SocketAddr>) -> String {
+    let holder = headers
+        .get(AUTH_HEADER_KEY)
+        .and_then(|header| header.to_str().ok())
+        .and_then(|key| keys.holder(key));
+    let forwarded = headers
+        .get(CLIENT_ID_HEADER_KEY)
+        .and_then(|header| header.to_str().ok());
+
+    match (holder, forwarded) {
+        (Some((_, true)), Some(client)) => format!("client:{client}"),
+        (Some((name, _)), _) => format!("key:{name}"),
+        (None, _) => addr.map(|addr| addr.ip().to_string()).unwrap_or_default(),
+    }
+}
+
+/// Refuses requests from clients over their rate limit, and reports every client's limit in the
+/// response headers.
+async fn rate_limit(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    let Some(limiter) = &state.rate_limiter else {
+        return next.run(request).await;
+    };
+    let addr = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| *addr);
+    let client = client(request.headers(), &state.keys, addr);
+
+    let decision = limiter.check(&client, Instant::now());
+    let mut response = match decision.allowed {
+        true => next.run(request).await,
+        false => {
+            tracing::warn!("rate limiting client {client:?}");
+            let retry_after_secs = decision.retry_after.as_secs_f64().ceil() as u64;
+            let mut response = (
+                StatusCode::TOO_MANY_REQUESTS,
+                Json(RequestErrorResponse::RateLimited { retry_after_secs }),
+            )
+                .into_response();
+            response
+                .headers_mut()
+                .insert(RETRY_AFTER, HeaderValue::from(retry_after_secs));
+            response
+        }
+    };
+
+    let headers = response.headers_mut();
+    headers.insert("ratelimit-limit", HeaderValue::from(decision.limit));
+    headers.insert("ratelimit-remaining", HeaderValue::from(decision.remaining));
+    headers.insert(
+        "ratelimit-reset",
+        HeaderValue::from(decision.reset.as_secs_f64().ceil() as u64),
+    );
+    response
+}
+
+/// Whether the request's key is allowed `scope`.
+fn valid_header(headers: &HeaderMap, keys: &KeyStore, scope: Scope) -> bool {
     let Some(header) = headers.get(AUTH_HEADER_KEY) else {
         return false;
     };
@@ -29,7 +244,84 @@ fn valid_header(headers: &HeaderMap, expected: &str) -> bool {
         return false;
     };
 
-    value == expected
+    keys.allows(value, scope)
+}
+
+/// The tier the request's key is pinned to, if any.
+fn pinned_tier(headers: &HeaderMap, keys: &KeyStore) -> Option<String> {
+    let value = headers.get(AUTH_HEADER_KEY)?.to_str().ok()?;
+
+    keys.tier(value)
+}
+
+impl AppState {
+    /// Whether the request's key is allowed `scope`, for routes added through
+    /// [`crate::Sidecar::routes`] to check as the core ones do.
+    pub fn authorized(&self, headers: &HeaderMap, scope: Scope) -> bool {
+        valid_header(headers, &self.keys, scope)
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum RequestErrorResponse {
+    InvalidRequest {
+        message: String,
+    },
+    /// The client called too often, see the `retry-after` header.
+    RateLimited {
+        retry_after_secs: u64,
+    },
+    /// This instance is a read-only replica, and can't forward the request to a primary.
+    ReadOnly,
+    PrimaryUnavailable {
+        message: String,
+    },
+}
+
+/// A [`Json`] extractor that reports malformed bodies as a structured [`RequestErrorResponse`]
+/// instead of axum's plain-text rejection.
+struct JsonBody<T>(T);
+
+#[async_trait]
+impl<S, T> FromRequest<S> for JsonBody<T>
+where
+    S: Send + Sync,
+    T: DeserializeOwned,
+{
+    type Rejection = (StatusCode, Json<RequestErrorResponse>);
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        match Json::<T>::from_request(req, state).await {
+            Ok(Json(value)) => Ok(Self(value)),
+            Err(rejection) => {
+                tracing::warn!("rejected request body: {rejection}");
+                Err(rejection_response(rejection))
+            }
+        }
+    }
+}
+
+fn rejection_response(rejection: JsonRejection) -> (StatusCode, Json<RequestErrorResponse>) {
+    (
+        rejection.status(),
+        Json(RequestErrorResponse::InvalidRequest {
+            message: rejection.body_text(),
+        }),
+    )
+}
+
+#[derive(Debug, Deserialize)]
+struct SessionQuery {
+    session_id: Option<String>,
+}
+
+/// The conversation a request is for: its session's history, or the default one without a session.
+async fn conversation(state: &AppState, session_id: Option<&str>) -> Option<Arc<Mutex<History>>> {
+    match session_id {
+        Some(session_id) => state.sessions.lock().await.get(session_id),
+        None => Some(state.history.clone()),
+    }
 }
 
 #[derive(Debug, Serialize)]
@@ -37,19 +329,28 @@ fn valid_header(headers: &HeaderMap, expected: &str) -> bool {
 enum IsBusyResponse {
     Ready,
     Busy,
+    SessionNotFound,
 }
 
-async fn handle_is_busy(State(state): State<AppState>, headers: HeaderMap) -> impl IntoResponse {
+async fn handle_is_busy(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<SessionQuery>,
+) -> impl IntoResponse {
     tracing::debug!("checking if busy");
 
     let state = state.clone();
 
-    if !valid_header(&headers, &state.secret) {
+    if !valid_header(&headers, &state.keys, Scope::Generate) {
         tracing::warn!("invalid secret");
         return (StatusCode::UNAUTHORIZED, Json(IsBusyResponse::Busy));
     }
 
-    let lock_is_err = state.history.try_lock().is_err();
+    let Some(history) = conversation(&state, query.session_id.as_deref()).await else {
+        return (StatusCode::NOT_FOUND, Json(IsBusyResponse::SessionNotFound));
+    };
+    // Requests would queue behind the warmup until it is done.
+    let lock_is_err = !state.warm.load(Ordering::SeqCst) || history.try_lock().is_err();
 
     (
         StatusCode::OK,
@@ -65,93 +366,1860 @@ async fn handle_is_busy(State(state): State<AppState>, headers: HeaderMap) -> im
 enum ClearHistoryResponse {
     Success,
     Busy,
+    SessionNotFound,
+    /// The conversation is frozen or archived.
+    ReadOnly,
 }
 
-async fn clear_history(State(state): State<AppState>, headers: HeaderMap) -> impl IntoResponse {
+async fn clear_history(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<SessionQuery>,
+) -> impl IntoResponse {
     tracing::debug!("attempting to clear history");
 
     let state = state.clone();
 
-    if !valid_header(&headers, &state.secret) {
+    if !valid_header(&headers, &state.keys, Scope::Admin) {
         tracing::warn!("invalid secret");
         return (StatusCode::UNAUTHORIZED, Json(ClearHistoryResponse::Busy));
     }
 
-    let mut history = match state.history.try_lock() {
+    let Some(history) = conversation(&state, query.session_id.as_deref()).await else {
+        return (
+            StatusCode::NOT_FOUND,
+            Json(ClearHistoryResponse::SessionNotFound),
+        );
+    };
+    let mut history = match history.try_lock() {
         Ok(v) => v,
         Err(_) => {
             tracing::error!("tried to clear text while generating text");
             return (StatusCode::CONFLICT, Json(ClearHistoryResponse::Busy));
         }
     };
+    if history.flags.read_only() {
+        return (StatusCode::CONFLICT, Json(ClearHistoryResponse::ReadOnly));
+    }
     history.clear();
+    if let Some(db) = &state.history_db {
+        if let Err(e) = db.lock().await.save(query.session_id.as_deref(), &history) {
+            tracing::error!("unable to persist cleared history: {e}");
+        }
+    }
 
     (StatusCode::OK, Json(ClearHistoryResponse::Success))
 }
 
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum CancelResponse {
+    Success,
+    NotGenerating,
+    Unauthorized,
+}
+
+/// Cancels the conversation's generation, whether it is running or still queued.
+async fn cancel_generation(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<SessionQuery>,
+) -> impl IntoResponse {
+    if !valid_header(&headers, &state.keys, Scope::Generate) {
+        tracing::warn!("invalid secret");
+        return (StatusCode::UNAUTHORIZED, Json(CancelResponse::Unauthorized));
+    }
+
+    if !state.cancellations.cancel(query.session_id.as_deref()) {
+        return (StatusCode::NOT_FOUND, Json(CancelResponse::NotGenerating));
+    }
+    tracing::info!("cancelling generation");
+
+    (StatusCode::OK, Json(CancelResponse::Success))
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum SubscribeResponse {
+    NotStreaming,
+    Unauthorized,
+}
+
+/// Follows the conversation's streaming generation alongside whoever requested it, from its first
+/// event.
+async fn subscribe_generation(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<SessionQuery>,
+) -> Response {
+    if !valid_header(&headers, &state.keys, Scope::HistoryRead) {
+        tracing::warn!("invalid secret");
+        return (
+            StatusCode::UNAUTHORIZED,
+            Json(SubscribeResponse::Unauthorized),
+        )
+            .into_response();
+    }
+
+    let Some(events) = state.streams.subscribe(query.session_id.as_deref()) else {
+        return (StatusCode::NOT_FOUND, Json(SubscribeResponse::NotStreaming)).into_response();
+    };
+    tracing::debug!("subscribed to a generation stream");
+
+    Reply::Stream(Box::pin(ReceiverStream::new(events))).into_response()
+}
+
 #[derive(Debug, Deserialize)]
 struct GenerateRequest {
     setup: Option<String>,
     prompt: String,
     max_tokens: Option<usize>,
+    /// Continues this session's conversation instead of the default one.
+    session_id: Option<String>,
+    /// Surfaces what the sidecar remembers about this player in the NPC's setup.
+    player_id: Option<String>,
+    /// The player's subscription tier, unless the key is pinned to one.
+    tier: Option<String>,
+    /// Describes this location from the registry in the NPC's setup.
+    location_id: Option<String>,
+    /// Replies as this persona from the registry: its system prompt stands in for `setup`, and
+    /// its sampler settings for those the request leaves unset.
+    persona: Option<String>,
+    /// Which NPC replies, in a group conversation. Defaults to whoever the prompt addresses, or
+    /// else whoever has gone longest without speaking.
+    speaker: Option<String>,
+    /// Which of the party's players the prompt is from, in a party conversation.
+    player: Option<String>,
+    /// A GBNF grammar the reply must follow, starting from its `root` rule.
+    grammar: Option<String>,
+    /// `json` constrains the reply to a single JSON object.
+    #[serde(default)]
+    response_format: llm::ResponseFormat,
+    /// Marks up the prompt for a model with another chat format than the configured one.
+    template: Option<Template>,
+    /// Which of the config's extra models replies, instead of the default one.
+    model: Option<String>,
+    /// Streams the reply as server-sent events instead of responding once it is complete.
+    #[serde(default)]
+    stream: bool,
+    /// Responds at once with a job id to poll for the reply, instead of waiting for it.
+    #[serde(default)]
+    detach: bool,
+    /// Which of the fallback pack's lines to use if the model can't answer.
+    task: Option<String>,
+    /// Fills `{placeholders}` in fallback lines.
+    #[serde(default)]
+    vars: HashMap<String, String>,
+    /// Ends the reply at the first of these, e.g. `<|user|>` if the model starts writing the
+    /// player's next turn.
+    #[serde(default)]
+    stop: Vec<String>,
+    /// `keep` writes special tokens such as `<|im_end|>` into the reply, and `mark` lists them
+    /// alongside it, with where they fell. They are left out by default.
+    #[serde(default)]
+    special_tokens: llm::SpecialTokens,
+    /// Converts the reply's Markdown, instead of the configured formatting for `/generate`.
+    formatting: Option<Formatting>,
+    /// Makes the reply reproducible: the same prompt and seed give the same reply, as long as the
+    /// model and its settings stay the same.
+    seed: Option<u32>,
+    /// Added to token logits, by token id, on top of the configured bias. -100 bans a token.
+    #[serde(default)]
+    logit_bias: HashMap<i32, f32>,
+    /// Words the reply may not contain, besides the configured ones.
+    #[serde(default)]
+    banned_words: Vec<String>,
+    /// Generates the reply even if an identical request's is cached.
+    #[serde(default)]
+    bypass_cache: bool,
+    /// When the game stops waiting for the reply, in unix milliseconds. A request still queued by
+    /// then is dropped instead of generated.
+    deadline: Option<u64>,
+    /// Sampler settings such as `temperature`, to tune an NPC's personality per request.
+    #[serde(flatten)]
+    sampler: llm::SamplerOptions,
 }
 
-impl Into<llm::Options> for GenerateRequest {
-    fn into(self) -> llm::Options {
-        llm::Options {
-            setup: self.setup,
-            prompt: self.prompt,
-            max_tokens: self.max_tokens,
+impl From<GenerateRequest> for llm::Options {
+    fn from(req: GenerateRequest) -> Self {
+        Self {
+            setup: req.setup,
+            prompt: req.prompt,
+            max_tokens: req.max_tokens,
+            context: None,
+            session: llm::SessionSettings::default(),
+            sampler: req.sampler,
+            cancel: None,
+            speaker: req.speaker,
+            player: req.player,
+            grammar: None,
+            template: req.template.unwrap_or_default(),
+            stop: req.stop,
+            bias: Bias {
+                logit_bias: req.logit_bias,
+                banned_words: req.banned_words,
+            },
+            special_tokens: req.special_tokens,
+            seed: req.seed,
+            pipeline: llm::Pipeline::default(),
+            kept_session: None,
+            prefixes: None,
         }
     }
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize)]
 #[serde(tag = "type", rename_all = "snake_case")]
-enum GenerateResponse {
-    Success { message: String },
+pub enum GenerateResponse {
+    /// Every reply carries a `generation_id` that feedback on it refers to.
+    Success {
+        message: String,
+        generation_id: String,
+        /// Who replied, in a group conversation.
+        speaker: Option<String>,
+        /// Whether this reply reached the session's goal.
+        goal_reached: bool,
+        /// The special tokens in the reply, if they were asked to be marked.
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        special_tokens: Vec<llm::SpecialToken>,
+        /// How long the generation waited in the queue, and then ran. Both 0 for cached replies.
+        #[serde(flatten)]
+        timings: Timings,
+        /// Whether the default model or the canary replied, if the request took part in a rollout.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        served_by: Option<Arm>,
+    },
+    /// An authored reply to a closely matching prompt, served instead of generating. Streaming
+    /// requests get it as a single token.
+    Authored {
+        message: String,
+        generation_id: String,
+    },
+    /// An authored line from the fallback pack, served because the model couldn't answer.
+    Fallback {
+        message: String,
+        reason: FallbackReason,
+        generation_id: String,
+    },
+    /// A detached request, or one that arrived while another generation was active, whose reply
+    /// is polled for at `/jobs/{job_id}`. `position` jobs are ahead of it.
+    Queued {
+        job_id: String,
+        position: u64,
+    },
+    /// Stopped through `/cancel` before the reply was complete. The prompt isn't kept.
+    Cancelled,
+    /// The request's `deadline` passed before its generation started. The prompt isn't kept.
+    DeadlineExceeded,
+    /// The reply broke a moderation rule, and there was no fallback line to serve instead. The
+    /// prompt isn't kept.
+    Moderated,
     Busy,
-    GenerateError { message: String },
+    SessionNotFound,
+    TierNotFound,
+    /// The player has sent too many messages to the party conversation lately, or made more
+    /// requests than their tier's quota allows.
+    RateLimited {
+        retry_after_secs: u64,
+    },
+    /// The session has spent its budget for the hour.
+    BudgetExhausted {
+        retry_after_secs: u64,
+    },
+    LocationNotFound,
+    PersonaNotFound,
+    ModelNotFound,
+    /// The speaker isn't one of the group conversation's NPCs.
+    SpeakerNotFound,
+    /// The player isn't one of the party conversation's players, or wasn't given.
+    PlayerNotFound,
+    InvalidGrammar {
+        message: String,
+    },
+    /// Folded into the player's turn that is already waiting, whose reply answers both.
+    Merged,
+    /// Generation in the conversation has been paused, e.g. by a game master taking over.
+    Paused,
+    /// The conversation is frozen or archived, so nothing can be added to it.
+    ReadOnly,
+    GenerateError {
+        message: String,
+    },
+}
+
+/// Events emitted by a streaming generation: server-sent events named after their `type`, or JSON
+/// messages over `/ws`.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum StreamEvent {
+    Token {
+        text: String,
+    },
+    /// A special token, if they were asked to be marked.
+    Special {
+        token: String,
+    },
+    Done {
+        usage: llm::Usage,
+        /// How long the generation waited in the queue before `elapsed_ms` started.
+        queued_ms: u128,
+        elapsed_ms: u128,
+        generation_id: String,
+        speaker: Option<String>,
+        /// The whole reply in the requested formatting, unless it is raw and no hook or redaction
+        /// changed it, since tokens are sent as the model wrote them.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        message: Option<String>,
+        /// Whether the default model or the canary replied, if the request took part in a rollout.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        served_by: Option<Arm>,
+    },
+    /// Sent instead of `done` when the whole reply broke a moderation rule. The streamed tokens
+    /// should be discarded, and the prompt isn't kept.
+    Moderated,
+    /// Sent before `done` when the reply reached the session's goal.
+    GoalReached {
+        goal: String,
+    },
+    Cancelled,
+    /// Sent instead of any tokens when the request's `deadline` passed while it was queued.
+    DeadlineExceeded,
+    GenerateError {
+        message: String,
+    },
+}
+
+impl StreamEvent {
+    fn into_event(self) -> Event {
+        let name = match &self {
+            Self::Token { .. } => "token",
+            Self::Special { .. } => "special",
+            Self::Done { .. } => "done",
+            Self::GoalReached { .. } => "goal_reached",
+            Self::Moderated => "moderated",
+            Self::Cancelled => "cancelled",
+            Self::DeadlineExceeded => "deadline_exceeded",
+            Self::GenerateError { .. } => "generate_error",
+        };
+
+        Event::default()
+            .event(name)
+            .json_data(&self)
+            .expect("stream events always serialize")
+    }
+}
+
+/// The parts of a request still needed once it has become [`llm::Options`].
+#[derive(Debug)]
+struct Exchange {
+    session_id: Option<String>,
+    /// The persona replying, which the generation is tallied under.
+    persona: Option<String>,
+    player_id: Option<String>,
+    location_id: Option<String>,
+    speaker: Option<String>,
+    player: Option<String>,
+    prompt: String,
+    task: Option<String>,
+    vars: HashMap<String, String>,
+    formatting: Option<Formatting>,
+    /// Game context rendered for the NPC's background.
+    background: Option<String>,
+    /// When the request arrived, to measure its latency. `None` if it was detached, since the
+    /// player isn't waiting on it.
+    received: Option<Instant>,
+    /// When the game stops waiting for the reply.
+    deadline: Option<SystemTime>,
+    /// The request's trace, carried on to a hosted backend.
+    trace: Option<TraceContext>,
+    /// Which model the canary rollout gave the session, if it took part in one.
+    served_by: Option<Arm>,
+    /// Cancels the generation once it has started, if chaos testing picked it to be.
+    #[cfg(feature = "chaos")]
+    injected_cancel: Option<tokio_util::sync::CancellationToken>,
+}
+
+impl Exchange {
+    fn take(req: &mut GenerateRequest) -> Self {
+        Self {
+            session_id: req.session_id.clone(),
+            persona: req.persona.clone(),
+            player_id: req.player_id.clone(),
+            location_id: req.location_id.clone(),
+            speaker: req.speaker.clone(),
+            player: req.player.clone(),
+            prompt: req.prompt.clone(),
+            task: req.task.take(),
+            vars: std::mem::take(&mut req.vars),
+            formatting: req.formatting,
+            background: None,
+            received: (!req.detach).then(Instant::now),
+            deadline: req
+                .deadline
+                .map(|deadline| UNIX_EPOCH + Duration::from_millis(deadline)),
+            trace: None,
+            served_by: None,
+            #[cfg(feature = "chaos")]
+            injected_cancel: None,
+        }
+    }
+
+    fn formatting(&self, state: &AppState) -> Formatting {
+        self.formatting
+            .unwrap_or_else(|| state.config.formatting("/generate"))
+    }
+
+    /// Measures how long the player waited for the reply.
+    fn replied(&self, state: &AppState) {
+        if let Some(received) = self.received {
+            state.latency.record(received.elapsed());
+        }
+    }
+
+    /// Records the reply to this exchange, returning its generation id.
+    fn trace(&self, traces: &mut Traces, reply: &str, source: Source) -> String {
+        traces.record(
+            self.session_id.clone(),
+            self.player_id.clone(),
+            self.prompt.clone(),
+            reply.to_string(),
+            source,
+        )
+    }
+}
+
+/// Cancels a generation chaos testing picked, now that it has started, as its player would.
+#[cfg(feature = "chaos")]
+fn inject_cancel(token: Option<&tokio_util::sync::CancellationToken>) {
+    if let Some(token) = token {
+        tracing::warn!("chaos: cancelling a started generation");
+        token.cancel();
+    }
+}
+
+/// Answers with a fallback line for the exchange's task if a pack is loaded and has one, or with
+/// `status` and `response` otherwise.
+async fn fallback_or(
+    state: &AppState,
+    exchange: &Exchange,
+    reason: FallbackReason,
+    status: StatusCode,
+    response: GenerateResponse,
+) -> Reply {
+    let locale = match &exchange.session_id {
+        Some(session_id) => state.sessions.lock().await.locale(session_id).cloned(),
+        None => None,
+    };
+    let message = state
+        .fallback
+        .as_ref()
+        .and_then(|pack| pack.line(exchange.task.as_deref(), &exchange.vars, locale.as_ref()));
+    let Some(message) = message else {
+        state.chain.failed(Link::Canned);
+        return Reply::Complete(status, response);
+    };
+    tracing::info!("serving a fallback line: {reason:?}");
+    state.chain.served(Link::Canned);
+    let generation_id = exchange.trace(&mut *state.traces.lock().await, &message, Source::Fallback);
+
+    Reply::Complete(
+        StatusCode::OK,
+        GenerateResponse::Fallback {
+            message,
+            reason,
+            generation_id,
+        },
+    )
+}
+
+/// Answers a generation that couldn't run, with a fallback line if there is one.
+async fn job_failed(state: &AppState, exchange: &Exchange, e: JobError) -> Reply {
+    tracing::warn!("unable to generate: {e}");
+    match e {
+        JobError::Full => {
+            fallback_or(
+                state,
+                exchange,
+                FallbackReason::Busy,
+                StatusCode::CONFLICT,
+                GenerateResponse::Busy,
+            )
+            .await
+        }
+        // Nobody is waiting for a fallback line either.
+        JobError::Expired => Reply::Complete(
+            StatusCode::GATEWAY_TIMEOUT,
+            GenerateResponse::DeadlineExceeded,
+        ),
+        JobError::Stopped | JobError::Panicked => {
+            fallback_or(
+                state,
+                exchange,
+                FallbackReason::GenerateError,
+                StatusCode::INTERNAL_SERVER_ERROR,
+                GenerateResponse::GenerateError {
+                    message: e.to_string(),
+                },
+            )
+            .await
+        }
+    }
+}
+
+/// Background for the system message: the language to reply in, the world time, where the NPC
+/// is, the game's context, who else is in the conversation, what the NPC remembers about the
+/// player, and any game master's whisper.
+async fn prompt_context(
+    state: &AppState,
+    exchange: &Exchange,
+    history: &History,
+) -> Option<String> {
+    let locale = history.locale.as_ref().map(Locale::instruction);
+    let time = state.world_time.lock().await.context();
+    let location = exchange
+        .location_id
+        .as_deref()
+        .and_then(|id| state.locations.get(id))
+        .map(|location| location.context());
+    let cast = exchange
+        .speaker
+        .as_deref()
+        .filter(|_| !history.speakers.is_empty())
+        .map(|speaker| group::context(&history.speakers, speaker));
+    let party = (!history.party.is_empty()).then(|| group::party_context(&history.party));
+    let goal = history.goal.as_ref().and_then(Goal::context);
+    let memories = match &exchange.player_id {
+        Some(player_id) => state.memory.lock().await.context_for(player_id),
+        None => None,
+    };
+
+    let whisper = history
+        .whisper
+        .as_ref()
+        .map(|whisper| format!("Direction from the game master, never to be mentioned: {whisper}"));
+
+    let background = exchange.background.clone();
+
+    let context = [
+        locale, time, location, background, cast, party, goal, memories, whisper,
+    ]
+    .into_iter()
+    .flatten()
+    .collect::<Vec<_>>();
+    (!context.is_empty()).then(|| context.join("\n\n"))
+}
+
+/// Drops the space a named speaker's reply starts with, after the `Name:` the prompt ends with.
+fn tidy_reply(exchange: &Exchange, generated: &mut llm::Generated) {
+    if exchange.speaker.is_some() {
+        generated.trim_start();
+    }
+}
+
+fn note_conversation(memory: &mut MemoryStore, player_id: &str, prompt: &str) {
+    memory.note(player_id, format!("told an NPC \"{prompt}\""));
+}
+
+/// Counts a generation against the conversation's budget, if it has one.
+fn charge(history: &mut History, tokens: usize, generating: Duration) {
+    if let Some(budget) = &mut history.budget {
+        budget.charge(tokens, generating, Instant::now());
+    }
+}
+
+/// Persists and broadcasts the messages appended since `start`, remembers the exchange for the
+/// player, and traces the reply, returning its generation id.
+async fn record_exchange(
+    state: &AppState,
+    history: &History,
+    start: usize,
+    exchange: &Exchange,
+    source: Source,
+) -> String {
+    if let Some(db) = &state.history_db {
+        persist_messages(
+            &mut *db.lock().await,
+            exchange.session_id.as_deref(),
+            &history.history[start..],
+        );
+    }
+    state
+        .monitor
+        .observe(exchange.session_id.as_deref(), &history.history[start..]);
+    if let Some(player_id) = &exchange.player_id {
+        note_conversation(&mut *state.memory.lock().await, player_id, &exchange.prompt);
+    }
+
+    let reply = history
+        .history
+        .last()
+        .map(|m| m.content())
+        .unwrap_or_default();
+    exchange.trace(&mut *state.traces.lock().await, reply, source)
+}
+
+/// Stores messages appended to a conversation, logging rather than failing the request on error.
+fn persist_messages(db: &mut HistoryDb, session_id: Option<&str>, messages: &[Message]) {
+    if let Err(e) = db.append(session_id, messages) {
+        tracing::error!("unable to persist messages: {e}");
+    }
+}
+
+/// Stores a persona and template's tally, logging rather than failing the request on error.
+fn persist_tally(db: &mut HistoryDb, persona: Option<&str>, template: Template, tally: &Tally) {
+    if let Err(e) = db.save_tally(persona, template, tally) {
+        tracing::error!("unable to persist the tally for {persona:?}: {e}");
+    }
+}
+
+/// Stores a whole conversation, logging rather than failing the request on error.
+fn persist_conversation(db: &mut HistoryDb, session_id: Option<&str>, history: &History) {
+    if let Err(e) = db.save(session_id, history) {
+        tracing::error!("unable to persist {session_id:?}: {e}");
+    }
+}
+
+/// Summarizes the conversation's older messages if it has grown too long, on the inference
+/// thread.
+fn compact(
+    state: &AppState,
+    model: &LlamaModel,
+    template: Template,
+    history: &mut History,
+    session_id: Option<&str>,
+) {
+    let Some(threshold) = state.config.summarize_above_tokens else {
+        return;
+    };
+    if compaction::compact(model, template, history, threshold, state.config.session()) {
+        if let Some(db) = &state.history_db {
+            persist_conversation(&mut db.blocking_lock(), session_id, history);
+        }
+    }
+}
+
+/// Queues a streaming generation, holding the history lock until the reply is complete. Its
+/// events go to the requester and are published to the conversation's subscribers.
+fn stream_generate(
+    state: AppState,
+    ai_model: Arc<LlamaModel>,
+    mut history: OwnedMutexGuard<History>,
+    opts: llm::Options,
+    exchange: Arc<Exchange>,
+    priority: bool,
+    cancel: Cancellable,
+) -> Result<ReceiverStream<StreamEvent>, JobError> {
+    let jobs = state.jobs.clone();
+    let template = opts.template;
+    let session = opts.session;
+    let (publisher, rx) = state.streams.start(exchange.session_id.clone());
+    let submitted = Instant::now();
+
+    jobs.submit_with(priority, move || {
+        let started = Instant::now();
+        if jobs::expired(exchange.deadline) {
+            tracing::debug!("dropping a streaming request whose deadline passed while queued");
+            publisher.publish(&StreamEvent::DeadlineExceeded);
+            return;
+        }
+        #[cfg(feature = "chaos")]
+        inject_cancel(exchange.injected_cancel.as_ref());
+        compact(
+            &state,
+            &ai_model,
+            template,
+            &mut history,
+            exchange.session_id.as_deref(),
+        );
+        let start = history.history.len();
+        // Keeps generating as long as anyone, the requester or a spectator, is following along.
+        let send = |event: StreamEvent| publisher.publish(&event);
+
+        let keep_special = opts.special_tokens == llm::SpecialTokens::Keep;
+        let mut streamed = String::new();
+        let result = llm::generate_text_streaming(&ai_model, &mut history, opts, |chunk| {
+            send(match chunk {
+                llm::Chunk::Text(text) => {
+                    streamed.push_str(text);
+                    StreamEvent::Token { text: text.into() }
+                }
+                llm::Chunk::Special(token) => StreamEvent::Special {
+                    token: token.into(),
+                },
+            })
+        });
+        let tokens = result
+            .as_ref()
+            .map_or(0, |generated| generated.usage.completion_tokens);
+        charge(&mut history, tokens, started.elapsed());
+        match result {
+            Ok(mut generated) => {
+                let verdict = state.moderator.as_ref().map_or(Verdict::Pass, |moderator| {
+                    moderator.review(&ai_model, template, session, &mut generated)
+                });
+                let blocked = verdict != Verdict::Pass;
+                let characters = match blocked {
+                    true => 0,
+                    false => generated.text.chars().count(),
+                };
+                let tally = state.tallies.record(
+                    exchange.persona.as_deref(),
+                    template,
+                    Observation {
+                        characters,
+                        latency_ms: started.elapsed().as_millis().try_into().unwrap_or(u64::MAX),
+                        regenerations: 0,
+                        blocked,
+                    },
+                );
+                if let Some(db) = &state.history_db {
+                    persist_tally(
+                        &mut db.blocking_lock(),
+                        exchange.persona.as_deref(),
+                        template,
+                        &tally,
+                    );
+                }
+                if blocked {
+                    // Already streamed, so there's no regenerating it.
+                    tracing::warn!("rejected a streamed reply by moderation: {verdict:?}");
+                    history.history.truncate(start);
+                    send(StreamEvent::Moderated);
+                    return;
+                }
+                // Hooks and redaction change the reply after its tokens were sent.
+                let sent = if keep_special {
+                    generated.with_special_tokens()
+                } else {
+                    generated.text.clone()
+                };
+                let rewritten = streamed != sent;
+                tidy_reply(&exchange, &mut generated);
+                let (output, usage) = (generated.text, generated.usage);
+                history.push_reply(exchange.speaker.clone(), output.clone());
+                if let Some(db) = &state.history_db {
+                    persist_messages(
+                        &mut db.blocking_lock(),
+                        exchange.session_id.as_deref(),
+                        &history.history[start..],
+                    );
+                }
+                state
+                    .monitor
+                    .observe(exchange.session_id.as_deref(), &history.history[start..]);
+                if let Some(player_id) = &exchange.player_id {
+                    note_conversation(
+                        &mut state.memory.blocking_lock(),
+                        player_id,
+                        &exchange.prompt,
+                    );
+                }
+                let generation_id = exchange.trace(
+                    &mut state.traces.blocking_lock(),
+                    &output,
+                    Source::Generated,
+                );
+                let reached = match goals::classifier_prompt(&history) {
+                    Some(prompt)
+                        if goals::judge(&ai_model, template, &prompt, state.config.session()) =>
+                    {
+                        goals::reach(&mut history)
+                    }
+                    _ => None,
+                };
+                if let Some(goal) = reached {
+                    if let Some(db) = &state.history_db {
+                        persist_conversation(
+                            &mut db.blocking_lock(),
+                            exchange.session_id.as_deref(),
+                            &history,
+                        );
+                    }
+                    send(StreamEvent::GoalReached { goal });
+                }
+
+                exchange.replied(&state);
+                state.chain.served(Link::Local);
+                let formatting = exchange.formatting(&state);
+                send(StreamEvent::Done {
+                    usage,
+                    queued_ms: started.duration_since(submitted).as_millis(),
+                    elapsed_ms: started.elapsed().as_millis(),
+                    generation_id,
+                    speaker: exchange.speaker.clone(),
+                    message: (formatting != Formatting::Raw || rewritten)
+                        .then(|| formatting.apply(&output)),
+                    served_by: exchange.served_by,
+                });
+            }
+            Err(_) if cancel.is_cancelled() => {
+                send(StreamEvent::Cancelled);
+            }
+            Err(e) => {
+                tracing::error!("unable to stream generation: {e}");
+                state.chain.failed(Link::Local);
+                // The prompt isn't kept, since it was never answered.
+                history.history.truncate(start);
+                send(StreamEvent::GenerateError {
+                    message: "unable to create token generation stream".into(),
+                });
+            }
+        }
+    })?;
+
+    Ok(ReceiverStream::new(rx))
+}
+
+/// Generates the reply with the hosted backend instead of the local model. Streamed replies
+/// arrive as a single token event, since the whole reply is generated first.
+async fn generate_remotely(
+    state: &AppState,
+    backend: &dyn LlmBackend,
+    history: &mut History,
+    opts: llm::Options,
+    stream: bool,
+    exchange: &Exchange,
+) -> Reply {
+    let cancelled = opts.cancel.clone().unwrap_or_default();
+    let setup = opts
+        .setup
+        .unwrap_or_else(|| history.system.content().to_string());
+    let start = history.history.len();
+    history.push_prompt(opts.player, opts.pipeline.prompt(opts.prompt));
+    let request = BackendRequest {
+        system: match opts.context {
+            Some(context) => format!("{setup}\n\n{context}"),
+            None => setup,
+        },
+        messages: history.history.clone(),
+        speaker: opts.speaker,
+        max_tokens: opts.max_tokens.unwrap_or(llm::DEFAULT_MAX_TOKENS),
+        sampler: opts.sampler,
+        stop: opts.stop,
+        trace: exchange.trace.clone(),
+    };
+
+    let started = Instant::now();
+    #[cfg(feature = "chaos")]
+    inject_cancel(exchange.injected_cancel.as_ref());
+    let result = tokio::select! {
+        result = backend.generate(request) => result,
+        () = cancelled.cancelled() => {
+            history.history.truncate(start);
+            return Reply::Complete(StatusCode::OK, GenerateResponse::Cancelled);
+        }
+    };
+    let tokens = result
+        .as_ref()
+        .map_or(0, |generated| generated.usage.completion_tokens);
+    charge(history, tokens, started.elapsed());
+    match result {
+        Ok(mut generated) => {
+            state.chain.served(Link::Remote);
+            opts.pipeline.completion(&mut generated);
+            let verdict = state
+                .moderator
+                .as_ref()
+                .map_or(Verdict::Pass, |moderator| moderator.screen(&mut generated));
+            if verdict != Verdict::Pass {
+                tracing::warn!("rejected a reply by moderation: {verdict:?}");
+                history.history.truncate(start);
+                return fallback_or(
+                    state,
+                    exchange,
+                    FallbackReason::Moderated,
+                    StatusCode::OK,
+                    GenerateResponse::Moderated,
+                )
+                .await;
+            }
+            tidy_reply(exchange, &mut generated);
+            let (output, usage) = (generated.text, generated.usage);
+            history.push_reply(exchange.speaker.clone(), output.clone());
+            let generation_id =
+                record_exchange(state, history, start, exchange, Source::Generated).await;
+            exchange.replied(state);
+            let generating_ms = started.elapsed().as_millis();
+            let formatting = exchange.formatting(state);
+            if stream {
+                let message = (formatting != Formatting::Raw).then(|| formatting.apply(&output));
+                let events = [
+                    StreamEvent::Token { text: output },
+                    StreamEvent::Done {
+                        usage,
+                        // The backend is called outside of the job queue.
+                        queued_ms: 0,
+                        elapsed_ms: generating_ms,
+                        generation_id,
+                        speaker: exchange.speaker.clone(),
+                        message,
+                        served_by: exchange.served_by,
+                    },
+                ];
+                return Reply::Stream(Box::pin(tokio_stream::iter(events)));
+            }
+
+            Reply::Complete(
+                StatusCode::OK,
+                GenerateResponse::Success {
+                    message: formatting.apply(&output),
+                    generation_id,
+                    speaker: exchange.speaker.clone(),
+                    goal_reached: false,
+                    special_tokens: Vec::new(),
+                    timings: Timings {
+                        queued_ms: 0,
+                        generating_ms: generating_ms.try_into().unwrap_or(u64::MAX),
+                    },
+                    served_by: exchange.served_by,
+                },
+            )
+        }
+        Err(e) => {
+            tracing::error!("unable to generate text with {}: {e}", backend.name());
+            state.chain.failed(Link::Remote);
+            history.history.truncate(start);
+            fallback_or(
+                state,
+                exchange,
+                FallbackReason::GenerateError,
+                StatusCode::BAD_GATEWAY,
+                GenerateResponse::GenerateError {
+                    message: "the backend couldn't generate a reply".into(),
+                },
+            )
+            .await
+        }
+    }
+}
+
+/// Hands a request the local model couldn't reply to over to the hosted backend.
+async fn fail_over(
+    state: &AppState,
+    (backend, opts): &(Arc<dyn LlmBackend>, llm::Options),
+    history: &mut History,
+    stream: bool,
+    exchange: &Exchange,
+    reason: &str,
+) -> Reply {
+    tracing::warn!("failing over to {}: {reason}", backend.name());
+    generate_remotely(
+        state,
+        backend.as_ref(),
+        history,
+        opts.clone(),
+        stream,
+        exchange,
+    )
+    .await
+}
+
+/// A `/generate` reply, either complete or streamed as it is generated.
+enum Reply {
+    Complete(StatusCode, GenerateResponse),
+    /// Sent as server-sent events over HTTP.
+    Stream(Pin<Box<dyn Stream<Item = StreamEvent> + Send>>),
+}
+
+impl IntoResponse for Reply {
+    fn into_response(self) -> Response {
+        match self {
+            Self::Complete(status, response) => (status, Json(response)).into_response(),
+            Self::Stream(events) => {
+                Sse::new(events.map(|event| Ok::<_, Infallible>(event.into_event())))
+                    .keep_alive(KeepAlive::default())
+                    .into_response()
+            }
+        }
+    }
 }
 
 async fn handle_generate(
     State(state): State<AppState>,
     headers: HeaderMap,
-    Json(req): Json<GenerateRequest>,
-) -> impl IntoResponse {
+    trace: Option<Extension<TraceContext>>,
+    JsonBody(mut req): JsonBody<GenerateRequest>,
+) -> Response {
     tracing::debug!("maybe generating text");
 
     let state = state.clone();
 
-    if !valid_header(&headers, &state.secret) {
+    if !valid_header(&headers, &state.keys, Scope::Generate) {
         tracing::warn!("invalid secret");
         return (StatusCode::UNAUTHORIZED, Json(GenerateResponse::Busy)).into_response();
     }
 
-    let ai_model = state.ai_model.clone();
-    let mut history = match state.history.try_lock() {
-        Ok(v) => v,
-        Err(_) => {
-            tracing::warn!("already generating text");
-            return (StatusCode::CONFLICT, Json(GenerateResponse::Busy)).into_response();
-        }
-    };
+    req.tier = pinned_tier(&headers, &state.keys).or(req.tier.take());
+    let mut exchange = Exchange::take(&mut req);
+    exchange.trace = trace.map(|Extension(context)| context);
+    dispatch(state, req, exchange).await
+}
 
-    let Ok(output) = llm::generate_text(&ai_model, &mut history, req) else {
+/// Generates the reply, or detaches the request to be polled for.
+async fn dispatch(state: AppState, req: GenerateRequest, exchange: Exchange) -> Response {
+    // Rather than holding the request open behind another generation, it gets a ticket to poll.
+    let queue = !req.detach && !req.stream && state.jobs.active();
+    if !req.detach && !queue {
+        return generate(state, req, exchange).await.into_response();
+    }
+    if req.stream {
         return (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            Json(GenerateResponse::GenerateError {
-                message: "unable to create token generation stream".into(),
+            StatusCode::BAD_REQUEST,
+            Json(RequestErrorResponse::InvalidRequest {
+                message: "detached requests can't stream".into(),
             }),
         )
             .into_response();
+    }
+
+    let detached = state.jobs.clone().detach(async move {
+        let reply = match generate(state, req, exchange).await {
+            Reply::Complete(_, response) => response,
+            Reply::Stream(_) => unreachable!("detached requests never stream"),
+        };
+        serde_json::to_value(reply).expect("generate responses always serialize")
+    });
+
+    match detached {
+        Ok((job_id, position)) => (
+            StatusCode::ACCEPTED,
+            Json(GenerateResponse::Queued { job_id, position }),
+        )
+            .into_response(),
+        Err(e) => {
+            tracing::warn!("unable to detach the request: {e}");
+            (StatusCode::CONFLICT, Json(GenerateResponse::Busy)).into_response()
+        }
+    }
+}
+
+/// The grammar the reply must follow: the request's own, or the JSON grammar for JSON replies.
+fn grammar(req: &GenerateRequest) -> Result<Option<LlamaGrammar>, String> {
+    let grammar = match (&req.grammar, req.response_format) {
+        (Some(_), llm::ResponseFormat::Json) => {
+            return Err("grammar can't be combined with the json response_format".into())
+        }
+        (Some(grammar), llm::ResponseFormat::Text) => grammar.as_str(),
+        (None, llm::ResponseFormat::Json) => llm::JSON_GRAMMAR,
+        (None, llm::ResponseFormat::Text) => return Ok(None),
     };
 
-    history.push(history::MessageType::Assistant, output.clone());
+    grammar
+        .parse()
+        .map(Some)
+        .map_err(|e: LlamaGrammarFromStrError| e.to_string())
+}
 
-    (
+/// Why a conversation with `flags` takes no prompts, if it doesn't.
+fn refusal(flags: &Flags) -> Option<GenerateResponse> {
+    match flags {
+        flags if flags.read_only() => Some(GenerateResponse::ReadOnly),
+        Flags { paused: true, .. } => Some(GenerateResponse::Paused),
+        _ => None,
+    }
+}
+
+/// Checks that the conversation takes `exchange` now, dropping its player if it isn't a party's.
+fn admit(history: &History, exchange: &mut Exchange) -> Result<(), (StatusCode, GenerateResponse)> {
+    if let Some(refusal) = refusal(&history.flags) {
+        return Err((StatusCode::CONFLICT, refusal));
+    }
+    if let Some(speaker) = &exchange.speaker {
+        if !history.speakers.is_empty() && !history.speakers.contains(speaker) {
+            return Err((StatusCode::NOT_FOUND, GenerateResponse::SpeakerNotFound));
+        }
+    }
+    let exhausted = history
+        .budget
+        .as_ref()
+        .and_then(|budget| budget.exhausted(Instant::now()));
+    if let Some(retry_after) = exhausted {
+        return Err((
+            StatusCode::TOO_MANY_REQUESTS,
+            GenerateResponse::BudgetExhausted {
+                retry_after_secs: retry_after.as_secs_f64().ceil() as u64,
+            },
+        ));
+    }
+    match &exchange.player {
+        _ if history.party.is_empty() => exchange.player = None,
+        Some(player) if history.party.contains(player) => {}
+        _ => return Err((StatusCode::NOT_FOUND, GenerateResponse::PlayerNotFound)),
+    }
+
+    Ok(())
+}
+
+/// Generates a reply once the conversation is free, queueing behind any other generation.
+async fn generate(state: AppState, mut req: GenerateRequest, mut exchange: Exchange) -> Reply {
+    if jobs::expired(exchange.deadline) {
+        return Reply::Complete(
+            StatusCode::GATEWAY_TIMEOUT,
+            GenerateResponse::DeadlineExceeded,
+        );
+    }
+    state.exploits.inspect(
+        &exchange.prompt,
+        exchange.session_id.as_deref(),
+        exchange.player_id.as_deref(),
+    );
+    if let Some(location_id) = &exchange.location_id {
+        if state.locations.get(location_id).is_none() {
+            return Reply::Complete(StatusCode::NOT_FOUND, GenerateResponse::LocationNotFound);
+        }
+    }
+    if let Some(id) = &req.persona {
+        let Some(persona) = state.personas.get(id) else {
+            return Reply::Complete(StatusCode::NOT_FOUND, GenerateResponse::PersonaNotFound);
+        };
+        req.setup.get_or_insert(persona.system_prompt);
+        req.sampler = std::mem::take(&mut req.sampler).or(&persona.sampler);
+    }
+    let grammar = match grammar(&req) {
+        Ok(grammar) => grammar,
+        Err(message) => {
+            return Reply::Complete(
+                StatusCode::BAD_REQUEST,
+                GenerateResponse::InvalidGrammar { message },
+            );
+        }
+    };
+    let Some(shared) = conversation(&state, req.session_id.as_deref()).await else {
+        return Reply::Complete(StatusCode::NOT_FOUND, GenerateResponse::SessionNotFound);
+    };
+    let party = match &exchange.session_id {
+        Some(session_id) => state.sessions.lock().await.party(session_id).to_vec(),
+        None => Vec::new(),
+    };
+    let turn = match (&exchange.session_id, &exchange.player) {
+        (Some(session_id), Some(player)) if party.contains(player) => {
+            match state
+                .turns
+                .offer(session_id, player, &exchange.prompt, Instant::now())
+            {
+                Turn::Queued(turn) => Some(turn),
+                Turn::Merged(merged) => {
+                    let (status, response) = merged
+                        .refusal()
+                        .await
+                        .unwrap_or((StatusCode::OK, GenerateResponse::Merged));
+                    return Reply::Complete(status, response);
+                }
+                Turn::RateLimited { retry_after } => {
+                    return Reply::Complete(
+                        StatusCode::TOO_MANY_REQUESTS,
+                        GenerateResponse::RateLimited {
+                            retry_after_secs: retry_after.as_secs_f64().ceil() as u64,
+                        },
+                    );
+                }
+            }
+        }
+        _ => None,
+    };
+    let mut history = shared.clone().lock_owned().await;
+    if let Err((status, response)) = admit(&history, &mut exchange) {
+        // Messages folded into the turn are dropped with it, so they get the same answer.
+        if let Some(turn) = turn {
+            turn.refuse((status, response.clone()));
+        }
+        return Reply::Complete(status, response);
+    }
+    if let Some(prompt) = turn.and_then(PendingTurn::take) {
+        exchange.prompt = prompt;
+    }
+    if exchange.speaker.is_none() {
+        exchange.speaker = group::next_speaker(&history, &exchange.prompt).map(str::to_string);
+    }
+
+    // Injected cancellations wait for the generation to start, to take the path a player's would.
+    #[cfg(feature = "chaos")]
+    let mut cancel_injected = false;
+    #[cfg(feature = "chaos")]
+    if let Some(chaos) = &state.chaos {
+        match chaos.inject().await {
+            Ok(()) => {}
+            Err(crate::chaos::ChaosError::InjectedCancel) => cancel_injected = true,
+            Err(e) => {
+                return fallback_or(
+                    &state,
+                    &exchange,
+                    FallbackReason::GenerateError,
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    GenerateResponse::GenerateError {
+                        message: e.to_string(),
+                    },
+                )
+                .await;
+            }
+        }
+    }
+
+    let tier = match &req.tier {
+        Some(name) => match state.tiers.get(name) {
+            Some(tier) => Some(tier),
+            None => return Reply::Complete(StatusCode::NOT_FOUND, GenerateResponse::TierNotFound),
+        },
+        None => None,
+    };
+    if let Some(tier) = tier {
+        if req.model.is_none() {
+            req.model = tier.config.model.clone();
+        }
+        if let Some(cap) = tier.config.max_tokens {
+            let max_tokens = req.max_tokens.unwrap_or(state.config.max_tokens);
+            req.max_tokens = Some(max_tokens.min(cap));
+        }
+    }
+    let level = state.ladder.observe(
+        Load {
+            p95_ms: state.latency.snapshot().p95_ms,
+            queued: state.jobs.queued(),
+        },
+        Instant::now(),
+    );
+    // A share of sessions is replied to by the canary instead of the default model.
+    if let Some(canary) = state.config.canary.as_ref() {
+        let arm = match &exchange.session_id {
+            _ if req.model.is_some() || level != Level::Full => None,
+            // Grammars need a local model.
+            Some(_) if canary.hosted() && grammar.is_some() => Some(Arm::Primary),
+            Some(session_id) => Some(canary.arm(session_id)),
+            None => Some(Arm::Primary),
+        };
+        if arm == Some(Arm::Canary) && !canary.hosted() {
+            req.model = Some(canary.model.clone());
+        }
+        exchange.served_by = arm;
+    }
+    let picked = match &req.model {
+        Some(name) => match state.models.get(name) {
+            Some(model) => Some((model.model.clone(), model.template)),
+            None => return Reply::Complete(StatusCode::NOT_FOUND, GenerateResponse::ModelNotFound),
+        },
+        None if level == Level::Reduced => state
+            .ladder
+            .reduced_model()
+            .and_then(|name| state.models.get(name))
+            .map(|model| (model.model.clone(), model.template)),
+        None => None,
+    };
+    // Only requests that would be served count against the quota.
+    if let Some(tier) = tier {
+        let player = req
+            .player_id
+            .as_deref()
+            .or(req.session_id.as_deref())
+            .unwrap_or_default();
+        if let Some(retry_after_secs) = tier.admit(player, Instant::now()) {
+            return Reply::Complete(
+                StatusCode::TOO_MANY_REQUESTS,
+                GenerateResponse::RateLimited { retry_after_secs },
+            );
+        }
+    }
+    let priority = tier.is_some_and(|tier| tier.config.priority);
+    let default_model = picked.is_none();
+    // Which model replies, as far as the cache is concerned.
+    let model_name = match picked {
+        Some(_) => req
+            .model
+            .clone()
+            .or_else(|| state.ladder.reduced_model().map(str::to_string)),
+        None => None,
+    };
+    let (ai_model, model_template) = match picked {
+        Some((model, template)) => (Some(model), template),
+        None => (state.ai_model.load_full(), None),
+    };
+
+    let stream = req.stream;
+
+    // Authored lines are written for a single NPC, not a group, don't steer toward goals and
+    // follow no grammar. They are matched with the default model's embeddings.
+    let authored = state
+        .dialogue
+        .load()
+        .as_ref()
+        .filter(|_| {
+            state.flags.enabled(Flag::Retrieval)
+                && history.speakers.is_empty()
+                && goals::classifier_prompt(&history).is_none()
+                && grammar.is_none()
+                && default_model
+        })
+        .zip(state.ai_model.load_full())
+        .and_then(|(dialogue, ai_model)| dialogue.reply(&ai_model, &exchange.prompt))
+        .map(str::to_string);
+    if let Some(message) = authored {
+        tracing::debug!("serving an authored reply");
+        let start = history.history.len();
+        history.push_prompt(exchange.player.clone(), exchange.prompt.clone());
+        history.push(MessageType::Assistant, message.clone());
+        let generation_id =
+            record_exchange(&state, &history, start, &exchange, Source::Authored).await;
+        exchange.replied(&state);
+
+        if stream {
+            let events = [
+                StreamEvent::Token { text: message },
+                StreamEvent::Done {
+                    usage: llm::Usage::default(),
+                    queued_ms: 0,
+                    elapsed_ms: 0,
+                    generation_id,
+                    speaker: None,
+                    message: None,
+                    served_by: None,
+                },
+            ];
+            return Reply::Stream(Box::pin(tokio_stream::iter(events)));
+        }
+        return Reply::Complete(
+            StatusCode::OK,
+            GenerateResponse::Authored {
+                message,
+                generation_id,
+            },
+        );
+    }
+
+    if level == Level::Canned {
+        return fallback_or(
+            &state,
+            &exchange,
+            FallbackReason::Degraded,
+            StatusCode::SERVICE_UNAVAILABLE,
+            GenerateResponse::Busy,
+        )
+        .await;
+    }
+
+    let cancel = state.cancellations.register(exchange.session_id.clone());
+    #[cfg(feature = "chaos")]
+    exchange.injected_cancel = cancel_injected.then(|| cancel.token());
+    let context = prompt_context(&state, &exchange, &history).await;
+    let use_cache = state.cache.enabled() && !req.bypass_cache;
+    let mut opts = llm::Options {
+        prompt: exchange.prompt.clone(),
+        max_tokens: Some(req.max_tokens.unwrap_or(state.config.max_tokens)),
+        context,
+        session: state.config.session(),
+        cancel: Some(cancel.token()),
+        speaker: exchange.speaker.clone(),
+        player: exchange.player.clone(),
+        grammar,
+        template: req
+            .template
+            .or(model_template)
+            .unwrap_or(state.config.prompt_template),
+        pipeline: state.config.pipeline("/generate"),
+        kept_session: req
+            .seed
+            .is_none()
+            .then(|| {
+                state
+                    .kept_sessions
+                    .get(exchange.session_id.as_deref(), model_name.as_deref())
+            })
+            .flatten(),
+        prefixes: Some(state.prefixes.clone()),
+        ..req.into()
+    };
+    opts.bias = state.config.bias.with(opts.bias);
+
+    let backend = state.backend.as_ref().filter(|_| default_model);
+    let failover = state
+        .config
+        .anthropic
+        .as_ref()
+        .filter(|anthropic| anthropic.failover);
+    let hosted_canary = exchange.served_by == Some(Arm::Canary)
+        && state
+            .config
+            .canary
+            .as_ref()
+            .is_some_and(CanaryConfig::hosted);
+    if let Some(backend) = backend.filter(|_| failover.is_none() || hosted_canary) {
+        if opts.grammar.is_some() {
+            return Reply::Complete(
+                StatusCode::BAD_REQUEST,
+                GenerateResponse::InvalidGrammar {
+                    message: "grammars need a local model".into(),
+                },
+            );
+        }
+        return generate_remotely(
+            &state,
+            backend.as_ref(),
+            &mut history,
+            opts,
+            stream,
+            &exchange,
+        )
+        .await;
+    }
+
+    // What the local model can't reply to goes to the hosted backend, if it's configured to take
+    // over, and then to the fallback lines.
+    let failover_after = failover
+        .and_then(|anthropic| anthropic.failover_after_secs)
+        .map(Duration::from_secs);
+    let failover = backend
+        .filter(|_| opts.grammar.is_none())
+        .zip(failover)
+        .map(|(backend, _)| {
+            let opts = llm::Options {
+                kept_session: None,
+                prefixes: None,
+                ..opts.clone()
+            };
+            (backend.clone(), opts)
+        });
+
+    let Some(ai_model) = ai_model else {
+        state.chain.failed(Link::Local);
+        if let Some(failover) = &failover {
+            let reason = "no model is loaded";
+            return fail_over(&state, failover, &mut history, stream, &exchange, reason).await;
+        }
+        return fallback_or(
+            &state,
+            &exchange,
+            FallbackReason::Unavailable,
+            StatusCode::SERVICE_UNAVAILABLE,
+            GenerateResponse::GenerateError {
+                message: "no model is loaded".into(),
+            },
+        )
+        .await;
+    };
+
+    if stream {
+        let exchange = Arc::new(exchange);
+        let events = stream_generate(
+            state.clone(),
+            ai_model,
+            history,
+            opts,
+            exchange.clone(),
+            priority,
+            cancel,
+        );
+        return match events {
+            Ok(events) => Reply::Stream(Box::pin(events)),
+            Err(e) => {
+                state.chain.failed(Link::Local);
+                match &failover {
+                    // The history went with the job that never started.
+                    Some(failover) if matches!(e, JobError::Full | JobError::Stopped) => {
+                        let mut history = shared.lock().await;
+                        let reason = &e.to_string();
+                        fail_over(&state, failover, &mut history, stream, &exchange, reason).await
+                    }
+                    _ => job_failed(&state, &exchange, e).await,
+                }
+            }
+        };
+    }
+
+    let model = ai_model.clone();
+    let template = opts.template;
+    let special_tokens = opts.special_tokens;
+    let cache_key = use_cache
+        .then(|| CacheKey::new(model_name.as_deref(), &history, &opts))
+        .flatten();
+    let cached = cache_key.and_then(|key| state.cache.get(key));
+    let hit = cached.is_some();
+    let ((mut history, start, output), timings) = match cached {
+        Some(cached) => {
+            tracing::debug!("serving a cached reply");
+            let start = history.history.len();
+            history.push_prompt(opts.player.clone(), opts.pipeline.prompt(opts.prompt));
+            (
+                (history, start, Ok(Outcome::Passed(cached))),
+                Timings::default(),
+            )
+        }
+        None => {
+            let session_id = exchange.session_id.clone();
+            let job_state = state.clone();
+            // Taken back to fail over with if the job doesn't start in time.
+            let queued = Arc::new(std::sync::Mutex::new(Some(history)));
+            let job_history = queued.clone();
+            #[cfg(feature = "chaos")]
+            let injected_cancel = exchange.injected_cancel.clone();
+            let job = state.jobs.run_timed(exchange.deadline, priority, move || {
+                let mut history = job_history.lock().unwrap().take()?;
+                let started = Instant::now();
+                #[cfg(feature = "chaos")]
+                inject_cancel(injected_cancel.as_ref());
+                compact(
+                    &job_state,
+                    &ai_model,
+                    template,
+                    &mut history,
+                    session_id.as_deref(),
+                );
+                let start = history.history.len();
+                let result = match &job_state.moderator {
+                    Some(moderator) => moderator.generate(&ai_model, &mut history, opts),
+                    None => llm::generate_text_streaming(&ai_model, &mut history, opts, |_| true)
+                        .map(Outcome::Passed),
+                };
+                let tokens = match &result {
+                    Ok(Outcome::Passed(generated)) => generated.usage.completion_tokens,
+                    _ => 0,
+                };
+                charge(&mut history, tokens, started.elapsed());
+                let output = result.map_err(|e| e.to_string());
+                Some((history, start, output))
+            });
+            tokio::pin!(job);
+            let generated = match failover.as_ref().zip(failover_after) {
+                Some((failover, after)) => match tokio::time::timeout(after, &mut job).await {
+                    Ok(generated) => generated,
+                    Err(_) => {
+                        let waiting = queued.lock().unwrap().take();
+                        match waiting {
+                            Some(mut history) => {
+                                state.chain.failed(Link::Local);
+                                let reason = &format!("not started after {after:?}");
+                                return fail_over(
+                                    &state,
+                                    failover,
+                                    &mut history,
+                                    stream,
+                                    &exchange,
+                                    reason,
+                                )
+                                .await;
+                            }
+                            // Already generating, so it gets to finish.
+                            None => job.await,
+                        }
+                    }
+                },
+                None => job.await,
+            };
+            match generated {
+                Ok(Some(generated)) => generated,
+                Ok(None) => unreachable!("only a failover takes the history back"),
+                Err(e) => {
+                    state.chain.failed(Link::Local);
+                    // Panics aren't retried, and expired requests have no one waiting on them.
+                    let retry = matches!(e, JobError::Full | JobError::Stopped);
+                    let waiting = queued.lock().unwrap().take();
+                    match (waiting, &failover) {
+                        (Some(mut history), Some(failover)) if retry => {
+                            let reason = &e.to_string();
+                            return fail_over(
+                                &state,
+                                failover,
+                                &mut history,
+                                stream,
+                                &exchange,
+                                reason,
+                            )
+                            .await;
+                        }
+                        _ => return job_failed(&state, &exchange, e).await,
+                    }
+                }
+            }
+        }
+    };
+    let observation = match &output {
+        Ok(Outcome::Passed(generated)) => Some(Observation {
+            characters: generated.text.chars().count(),
+            latency_ms: timings.generating_ms,
+            regenerations: generated.regenerations,
+            blocked: false,
+        }),
+        Ok(Outcome::Rejected) => Some(Observation {
+            latency_ms: timings.generating_ms,
+            blocked: true,
+            ..Observation::default()
+        }),
+        Err(_) => None,
+    };
+    if let Some(observation) = observation.filter(|_| !hit) {
+        let persona = exchange.persona.as_deref();
+        let tally = state.tallies.record(persona, template, observation);
+        if let Some(db) = &state.history_db {
+            persist_tally(&mut *db.lock().await, persona, template, &tally);
+        }
+    }
+    let mut generated = match output {
+        Ok(Outcome::Passed(generated)) => generated,
+        Ok(Outcome::Rejected) => {
+            return fallback_or(
+                &state,
+                &exchange,
+                FallbackReason::Moderated,
+                StatusCode::OK,
+                GenerateResponse::Moderated,
+            )
+            .await;
+        }
+        Err(_) if cancel.is_cancelled() => {
+            return Reply::Complete(StatusCode::OK, GenerateResponse::Cancelled);
+        }
+        Err(e) => {
+            tracing::error!("unable to generate text: {e}");
+            history.history.truncate(start);
+            state.chain.failed(Link::Local);
+            if let Some(failover) = &failover {
+                let reason = "the local model couldn't generate a reply";
+                return fail_over(&state, failover, &mut history, stream, &exchange, reason).await;
+            }
+            return fallback_or(
+                &state,
+                &exchange,
+                FallbackReason::GenerateError,
+                StatusCode::INTERNAL_SERVER_ERROR,
+                GenerateResponse::GenerateError {
+                    message: "unable to create token generation stream".into(),
+                },
+            )
+            .await;
+        }
+    };
+
+    if let Some(key) = cache_key.filter(|_| !hit) {
+        state.cache.insert(key, generated.clone());
+    }
+
+    tidy_reply(&exchange, &mut generated);
+    let output = generated.text.clone();
+    history.push_reply(exchange.speaker.clone(), output.clone());
+    let generation_id =
+        record_exchange(&state, &history, start, &exchange, Source::Generated).await;
+
+    let goal_reached = match goals::classifier_prompt(&history) {
+        Some(prompt) => {
+            let session = state.config.session();
+            let judged = state
+                .jobs
+                .run(move || goals::judge(&model, template, &prompt, session))
+                .await;
+            judged.unwrap_or_else(|e| {
+                tracing::warn!("unable to judge whether the goal was reached: {e}");
+                false
+            })
+        }
+        None => false,
+    };
+    if goal_reached {
+        goals::reach(&mut history);
+        if let Some(db) = &state.history_db {
+            persist_conversation(
+                &mut *db.lock().await,
+                exchange.session_id.as_deref(),
+                &history,
+            );
+        }
+    }
+
+    exchange.replied(&state);
+    if !hit {
+        state.chain.served(Link::Local);
+    }
+    generated.format(exchange.formatting(&state));
+    Reply::Complete(
         StatusCode::OK,
-        Json(GenerateResponse::Success { message: output }),
+        GenerateResponse::Success {
+            message: match special_tokens {
+                llm::SpecialTokens::Keep => generated.with_special_tokens(),
+                _ => generated.text,
+            },
+            generation_id,
+            speaker: exchange.speaker,
+            goal_reached,
+            special_tokens: match special_tokens {
+                llm::SpecialTokens::Mark => generated.special_tokens,
+                _ => Vec::new(),
+            },
+            timings,
+            served_by: exchange.served_by,
+        },
     )
-        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::{to_bytes, Body};
+    use proptest::prelude::*;
+    use tower::ServiceExt;
+
+    use super::*;
+
+    async fn accept(JsonBody(_): JsonBody<GenerateRequest>) -> StatusCode {
+        StatusCode::OK
+    }
+
+    fn post_generate(content_type: Option<&str>, body: Vec<u8>) -> (StatusCode, Vec<u8>) {
+        let router = Router::new().route("/generate", post(accept));
+        let mut request = Request::post("/generate");
+        if let Some(content_type) = content_type {
+            request = request.header("content-type", content_type);
+        }
+        let request = request.body(Body::from(body)).unwrap();
+
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(async {
+                let response = router.oneshot(request).await.unwrap();
+                let status = response.status();
+                let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+                (status, body.to_vec())
+            })
+    }
+
+    fn assert_structured_error(status: StatusCode, body: &[u8]) -> Result<(), TestCaseError> {
+        prop_assert!(status.is_client_error(), "unexpected status {status}");
+        let value: serde_json::Value = serde_json::from_slice(body)
+            .map_err(|e| TestCaseError::fail(format!("error body is not JSON: {e}")))?;
+        prop_assert_eq!(&value["type"], "invalid_request");
+        prop_assert!(value["message"].is_string());
+        Ok(())
+    }
+
+    fn json_like() -> impl Strategy<Value = String> {
+        prop_oneof![
+            any::<String>(),
+            r#"\{("(setup|prompt|max_tokens|session_id|player_id|tier|location_id|persona|speaker|player|grammar|response_format|template|model|stream|detach|task|vars|temperature|top_p|top_k|min_p|repeat_penalty|mirostat|mirostat_tau|mirostat_eta)"|[0-9]+|-1|null|true|\[\]|[:,"{}]|\PC){0,12}\}?"#,
+        ]
+    }
+
+    #[test]
+    fn missing_content_type_is_structured() {
+        let (status, body) = post_generate(None, br#"{"prompt":"hi"}"#.to_vec());
+        assert_eq!(status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        assert_structured_error(status, &body).unwrap();
+    }
+
+    #[test]
+    fn sampler_settings_are_validated() {
+        let (status, _) = post_generate(
+            Some("application/json"),
+            br#"{"prompt":"hi","temperature":1.2,"top_k":20,"mirostat":2}"#.to_vec(),
+        );
+        assert_eq!(status, StatusCode::OK);
+
+        let (status, body) = post_generate(
+            Some("application/json"),
+            br#"{"prompt":"hi","mirostat":3}"#.to_vec(),
+        );
+        assert_structured_error(status, &body).unwrap();
+    }
+
+    #[test]
+    fn grammars_are_parsed_up_front() {
+        let request = |body: serde_json::Value| serde_json::from_value(body).unwrap();
+
+        assert!(grammar(&request(serde_json::json!({ "prompt": "hi" })))
+            .unwrap()
+            .is_none());
+        assert!(grammar(&request(
+            serde_json::json!({ "prompt": "hi", "response_format": "json" })
+        ))
+        .unwrap()
+        .is_some());
+        assert!(grammar(&request(
+            serde_json::json!({ "prompt": "hi", "grammar": "root ::= \"yes\" | \"no\"" })
+        ))
+        .unwrap()
+        .is_some());
+        assert!(grammar(&request(
+            serde_json::json!({ "prompt": "hi", "grammar": "yes | no" })
+        ))
+        .is_err());
+        assert!(grammar(&request(serde_json::json!({
+            "prompt": "hi",
+            "grammar": "root ::= \"yes\"",
+            "response_format": "json",
+        })))
+        .is_err());
+    }
+
+    #[test]
+    fn frozen_conversations_refuse_prompts() {
+        let flags = |paused, frozen, archived| Flags {
+            paused,
+            frozen,
+            archived,
+        };
+
+        assert!(refusal(&Flags::default()).is_none());
+        assert!(matches!(
+            refusal(&flags(false, true, false)),
+            Some(GenerateResponse::ReadOnly)
+        ));
+        assert!(matches!(
+            refusal(&flags(false, false, true)),
+            Some(GenerateResponse::ReadOnly)
+        ));
+        assert!(matches!(
+            refusal(&flags(true, true, false)),
+            Some(GenerateResponse::ReadOnly)
+        ));
+        assert!(matches!(
+            refusal(&flags(true, false, false)),
+            Some(GenerateResponse::Paused)
+        ));
+    }
+
+    #[test]
+    fn rate_limits_by_key_unless_trusted_with_client_ids() {
+        let keys = KeyStore::new("root".into());
+        let key = keys
+            .create("tests".into(), [Scope::Generate].into(), None)
+            .unwrap();
+        let addr = Some(SocketAddr::from(([10, 0, 0, 1], 80)));
+        let headers = |key: &str, client: Option<&str>| {
+            let mut headers = HeaderMap::new();
+            headers.insert(AUTH_HEADER_KEY, key.parse().unwrap());
+            if let Some(client) = client {
+                headers.insert(CLIENT_ID_HEADER_KEY, client.parse().unwrap());
+            }
+            headers
+        };
+
+        assert_eq!(
+            client(&headers("root", Some("ayla")), &keys, addr),
+            "client:ayla"
+        );
+        assert_eq!(client(&headers("root", None), &keys, addr), "key:root");
+        assert_eq!(
+            client(&headers(&key, Some("ayla")), &keys, addr),
+            "key:tests"
+        );
+        assert_eq!(
+            client(&headers("wrong", Some("ayla")), &keys, addr),
+            "10.0.0.1"
+        );
+        assert_eq!(client(&HeaderMap::new(), &keys, None), "");
+    }
+
+    proptest! {
+        #[test]
+        fn arbitrary_bytes_never_escape_unstructured(body in any::<Vec<u8>>()) {
+            let (status, response) = post_generate(Some("application/json"), body);
+            if status != StatusCode::OK {
+                assert_structured_error(status, &response)?;
+            }
+        }
+
+        #[test]
+        fn malformed_json_returns_structured_errors(body in json_like()) {
+            let parsed = serde_json::from_str::<GenerateRequest>(&body);
+            let (status, response) = post_generate(Some("application/json"), body.into_bytes());
+            match parsed {
+                Ok(_) => prop_assert_eq!(status, StatusCode::OK),
+                Err(_) => assert_structured_error(status, &response)?,
+            }
+        }
+    }
 }
@@ -0,0 +1,473 @@
+//! Loads, swaps and unloads the model without restarting the sidecar.
+//!
+//! Swaps are refused while any conversation is generating, so no reply is cut off part-way. The
+//! degradation ladder's level can also be pinned here, e.g. ahead of a known traffic spike,
+//! scoped API keys are created and revoked, NPC personas are defined, and experimental
+//! subsystems are toggled until the next restart. The effective configuration is served as
+//! logged at startup, with models and features as they are now.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::{atomic::Ordering, Arc},
+    time::Instant,
+};
+
+use axum::{
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+    routing::{delete, get, post, put},
+    Json, Router,
+};
+use llama_cpp::LlamaModel;
+use serde::{Deserialize, Serialize};
+use tokio::sync::OwnedMutexGuard;
+
+use super::{valid_header, JsonBody};
+use crate::{
+    banner::Banner,
+    degradation::{DegradationStatus, Level},
+    flags::Flag,
+    history::History,
+    keys::{KeyInfo, KeysError, Scope},
+    kv,
+    personas::Persona,
+    retrieval::DialogueCorpus,
+    server::{warm_up, AppState, ServerError},
+};
+
+pub fn route() -> Router<AppState> {
+    Router::new()
+        .route("/model/load", post(load_model))
+        .route("/model", delete(unload_model))
+        .route("/config", get(get_config))
+        .route(
+            "/degradation",
+            get(get_degradation)
+                .put(pin_degradation)
+                .delete(release_degradation),
+        )
+        .route("/flags", get(list_flags))
+        .route("/flags/:flag", put(toggle_flag))
+        .route("/keys", get(list_keys).post(create_key))
+        .route("/keys/:name", delete(revoke_key))
+        .route("/personas", get(list_personas))
+        .route("/personas/:id", put(define_persona).delete(remove_persona))
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum AdminResponse {
+    Success,
+    Unauthorized,
+    Busy,
+    LoadError {
+        message: String,
+    },
+    Config {
+        banner: Banner,
+    },
+    ConfigError {
+        message: String,
+    },
+    Degradation {
+        status: DegradationStatus,
+    },
+    Flags {
+        flags: BTreeMap<Flag, bool>,
+    },
+    Keys {
+        keys: Vec<KeyInfo>,
+    },
+    /// The new key, which is only ever shown here.
+    KeyCreated {
+        name: String,
+        key: String,
+    },
+    KeyExists,
+    KeyNotFound,
+    /// The key was to be pinned to a tier that isn't configured.
+    TierNotFound {
+        tier: String,
+    },
+    KeyError {
+        message: String,
+    },
+    /// By id.
+    Personas {
+        personas: BTreeMap<String, Persona>,
+    },
+    PersonaNotFound,
+    PersonaError {
+        message: String,
+    },
+}
+
+#[derive(Debug, Deserialize)]
+struct LoadModelRequest {
+    /// Path to a GGUF file on the sidecar's machine.
+    path: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct CreateKeyRequest {
+    name: String,
+    scopes: BTreeSet<Scope>,
+    /// One of the configured tiers, which every request made with the key gets.
+    tier: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct PinRequest {
+    level: Level,
+}
+
+#[derive(Debug, Deserialize)]
+struct ToggleRequest {
+    enabled: bool,
+}
+
+/// Locks every conversation, or returns `None` if any of them is generating.
+async fn lock_all(state: &AppState) -> Option<Vec<OwnedMutexGuard<History>>> {
+    let mut histories = vec![state.history.clone()];
+    histories.extend(state.sessions.lock().await.histories().cloned());
+
+    histories
+        .into_iter()
+        .map(|history| history.try_lock_owned().ok())
+        .collect()
+}
+
+/// Swaps in `model` along with the authored dialogue embedded for it.
+async fn swap(
+    state: &AppState,
+    model: Option<LlamaModel>,
+    dialogue: Option<DialogueCorpus>,
+) -> (StatusCode, AdminResponse) {
+    let Some(_idle) = lock_all(state).await else {
+        tracing::warn!("refusing to swap the model while generating text");
+        return (StatusCode::CONFLICT, AdminResponse::Busy);
+    };
+    // `/isbusy` reports the new model as ready only once it has warmed up too.
+    state.warm.store(false, Ordering::SeqCst);
+    state.ai_model.store(model.map(Arc::new));
+    state.dialogue.store(dialogue.map(Arc::new));
+    // Cached replies and kept sessions came from the old model.
+    state.cache.clear();
+    state.kept_sessions.clear();
+    kv::prepare(state);
+    warm_up(state);
+
+    (StatusCode::OK, AdminResponse::Success)
+}
+
+async fn load_model(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    JsonBody(req): JsonBody<LoadModelRequest>,
+) -> impl IntoResponse {
+    if !valid_header(&headers, &state.keys, Scope::Admin) {
+        tracing::warn!("invalid secret");
+        return (StatusCode::UNAUTHORIZED, Json(AdminResponse::Unauthorized));
+    }
+
+    tracing::info!("loading model from {}", req.path);
+    let params = state.config.model_params();
+    let loaded = tokio::task::spawn_blocking(move || -> Result<_, ServerError> {
+        let model = crate::server::load_model(&req.path, params)?;
+        let dialogue = DialogueCorpus::from_env(&model)?;
+        Ok((model, dialogue))
+    })
+    .await;
+    let (model, dialogue) = match loaded {
+        Ok(Ok(loaded)) => loaded,
+        Ok(Err(e)) => {
+            tracing::error!("unable to load model: {e}");
+            return (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                Json(AdminResponse::LoadError {
+                    message: e.to_string(),
+                }),
+            );
+        }
+        Err(e) => {
+            tracing::error!("model loading task failed: {e}");
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(AdminResponse::LoadError {
+                    message: e.to_string(),
+                }),
+            );
+        }
+    };
+
+    let (status, response) = swap(&state, Some(model), dialogue).await;
+    (status, Json(response))
+}
+
+/// Unloads the model, leaving only fallback lines to answer with.
+async fn unload_model(State(state): State<AppState>, headers: HeaderMap) -> impl IntoResponse {
+    if !valid_header(&headers, &state.keys, Scope::Admin) {
+        tracing::warn!("invalid secret");
+        return (StatusCode::UNAUTHORIZED, Json(AdminResponse::Unauthorized));
+    }
+
+    tracing::info!("unloading model");
+    let (status, response) = swap(&state, None, None).await;
+    (status, Json(response))
+}
+
+async fn get_config(State(state): State<AppState>, headers: HeaderMap) -> impl IntoResponse {
+    if !valid_header(&headers, &state.keys, Scope::Admin) {
+        tracing::warn!("invalid secret");
+        return (StatusCode::UNAUTHORIZED, Json(AdminResponse::Unauthorized));
+    }
+
+    match Banner::new(&state) {
+        Ok(banner) => (StatusCode::OK, Json(AdminResponse::Config { banner })),
+        Err(e) => {
+            tracing::error!("unable to summarize the configuration: {e}");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(AdminResponse::ConfigError {
+                    message: e.to_string(),
+                }),
+            )
+        }
+    }
+}
+
+async fn get_degradation(State(state): State<AppState>, headers: HeaderMap) -> impl IntoResponse {
+    if !valid_header(&headers, &state.keys, Scope::Admin) {
+        tracing::warn!("invalid secret");
+        return (StatusCode::UNAUTHORIZED, Json(AdminResponse::Unauthorized));
+    }
+
+    (
+        StatusCode::OK,
+        Json(AdminResponse::Degradation {
+            status: state.ladder.status(),
+        }),
+    )
+}
+
+/// Holds the service level at the requested one until it is released.
+async fn pin_degradation(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    JsonBody(req): JsonBody<PinRequest>,
+) -> impl IntoResponse {
+    if !valid_header(&headers, &state.keys, Scope::Admin) {
+        tracing::warn!("invalid secret");
+        return (StatusCode::UNAUTHORIZED, Json(AdminResponse::Unauthorized));
+    }
+
+    state.ladder.pin(req.level);
+    (
+        StatusCode::OK,
+        Json(AdminResponse::Degradation {
+            status: state.ladder.status(),
+        }),
+    )
+}
+
+/// Lets the service level follow the load again.
+async fn release_degradation(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    if !valid_header(&headers, &state.keys, Scope::Admin) {
+        tracing::warn!("invalid secret");
+        return (StatusCode::UNAUTHORIZED, Json(AdminResponse::Unauthorized));
+    }
+
+    state.ladder.release(Instant::now());
+    (
+        StatusCode::OK,
+        Json(AdminResponse::Degradation {
+            status: state.ladder.status(),
+        }),
+    )
+}
+
+async fn list_flags(State(state): State<AppState>, headers: HeaderMap) -> impl IntoResponse {
+    if !valid_header(&headers, &state.keys, Scope::Admin) {
+        tracing::warn!("invalid secret");
+        return (StatusCode::UNAUTHORIZED, Json(AdminResponse::Unauthorized));
+    }
+
+    (
+        StatusCode::OK,
+        Json(AdminResponse::Flags {
+            flags: state.flags.list(),
+        }),
+    )
+}
+
+async fn toggle_flag(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(flag): Path<Flag>,
+    JsonBody(req): JsonBody<ToggleRequest>,
+) -> impl IntoResponse {
+    if !valid_header(&headers, &state.keys, Scope::Admin) {
+        tracing::warn!("invalid secret");
+        return (StatusCode::UNAUTHORIZED, Json(AdminResponse::Unauthorized));
+    }
+
+    state.flags.set(flag, req.enabled);
+    (
+        StatusCode::OK,
+        Json(AdminResponse::Flags {
+            flags: state.flags.list(),
+        }),
+    )
+}
+
+async fn list_keys(State(state): State<AppState>, headers: HeaderMap) -> impl IntoResponse {
+    if !valid_header(&headers, &state.keys, Scope::Admin) {
+        tracing::warn!("invalid secret");
+        return (StatusCode::UNAUTHORIZED, Json(AdminResponse::Unauthorized));
+    }
+
+    (
+        StatusCode::OK,
+        Json(AdminResponse::Keys {
+            keys: state.keys.list(),
+        }),
+    )
+}
+
+async fn create_key(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    JsonBody(req): JsonBody<CreateKeyRequest>,
+) -> impl IntoResponse {
+    if !valid_header(&headers, &state.keys, Scope::Admin) {
+        tracing::warn!("invalid secret");
+        return (StatusCode::UNAUTHORIZED, Json(AdminResponse::Unauthorized));
+    }
+
+    if let Some(tier) = req
+        .tier
+        .as_ref()
+        .filter(|tier| state.tiers.get(tier).is_none())
+    {
+        return (
+            StatusCode::NOT_FOUND,
+            Json(AdminResponse::TierNotFound { tier: tier.clone() }),
+        );
+    }
+
+    match state.keys.create(req.name.clone(), req.scopes, req.tier) {
+        Ok(key) => (
+            StatusCode::CREATED,
+            Json(AdminResponse::KeyCreated {
+                name: req.name,
+                key,
+            }),
+        ),
+        Err(KeysError::Exists(_)) => (StatusCode::CONFLICT, Json(AdminResponse::KeyExists)),
+        Err(e) => {
+            tracing::error!("unable to create API key: {e}");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(AdminResponse::KeyError {
+                    message: e.to_string(),
+                }),
+            )
+        }
+    }
+}
+
+async fn revoke_key(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(name): Path<String>,
+) -> impl IntoResponse {
+    if !valid_header(&headers, &state.keys, Scope::Admin) {
+        tracing::warn!("invalid secret");
+        return (StatusCode::UNAUTHORIZED, Json(AdminResponse::Unauthorized));
+    }
+
+    match state.keys.revoke(&name) {
+        Ok(true) => (StatusCode::OK, Json(AdminResponse::Success)),
+        Ok(false) => (StatusCode::NOT_FOUND, Json(AdminResponse::KeyNotFound)),
+        Err(e) => {
+            tracing::error!("unable to revoke API key: {e}");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(AdminResponse::KeyError {
+                    message: e.to_string(),
+                }),
+            )
+        }
+    }
+}
+
+async fn list_personas(State(state): State<AppState>, headers: HeaderMap) -> impl IntoResponse {
+    if !valid_header(&headers, &state.keys, Scope::Admin) {
+        tracing::warn!("invalid secret");
+        return (StatusCode::UNAUTHORIZED, Json(AdminResponse::Unauthorized));
+    }
+
+    (
+        StatusCode::OK,
+        Json(AdminResponse::Personas {
+            personas: state.personas.list(),
+        }),
+    )
+}
+
+/// Defines a persona, or replaces the one with the same id. Conversations already started as it
+/// keep their system message.
+async fn define_persona(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+    JsonBody(persona): JsonBody<Persona>,
+) -> impl IntoResponse {
+    if !valid_header(&headers, &state.keys, Scope::Admin) {
+        tracing::warn!("invalid secret");
+        return (StatusCode::UNAUTHORIZED, Json(AdminResponse::Unauthorized));
+    }
+
+    match state.personas.define(id, persona) {
+        Ok(true) => (StatusCode::CREATED, Json(AdminResponse::Success)),
+        Ok(false) => (StatusCode::OK, Json(AdminResponse::Success)),
+        Err(e) => {
+            tracing::error!("unable to define persona: {e}");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(AdminResponse::PersonaError {
+                    message: e.to_string(),
+                }),
+            )
+        }
+    }
+}
+
+async fn remove_persona(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    if !valid_header(&headers, &state.keys, Scope::Admin) {
+        tracing::warn!("invalid secret");
+        return (StatusCode::UNAUTHORIZED, Json(AdminResponse::Unauthorized));
+    }
+
+    match state.personas.remove(&id) {
+        Ok(true) => (StatusCode::OK, Json(AdminResponse::Success)),
+        Ok(false) => (StatusCode::NOT_FOUND, Json(AdminResponse::PersonaNotFound)),
+        Err(e) => {
+            tracing::error!("unable to remove persona: {e}");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(AdminResponse::PersonaError {
+                    message: e.to_string(),
+                }),
+            )
+        }
+    }
+}
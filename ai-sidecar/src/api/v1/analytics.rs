@@ -0,0 +1,45 @@
+use axum::{
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+    routing::get,
+    Json, Router,
+};
+use serde::Serialize;
+
+use super::valid_header;
+use crate::{analytics::Report, keys::Scope, server::AppState};
+
+pub fn route() -> Router<AppState> {
+    Router::new().route("/", get(get_report))
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum AnalyticsResponse {
+    Success { report: Report },
+    Unauthorized,
+}
+
+/// The latest report, or a fresh one if the background task hasn't produced one yet.
+async fn get_report(State(state): State<AppState>, headers: HeaderMap) -> impl IntoResponse {
+    if !valid_header(&headers, &state.keys, Scope::Admin) {
+        tracing::warn!("invalid secret");
+        return (
+            StatusCode::UNAUTHORIZED,
+            Json(AnalyticsResponse::Unauthorized),
+        );
+    }
+
+    let report = state.analytics.lock().await.clone();
+    let report = match report {
+        Some(mut report) => {
+            // Tallies are cheap to summarize, so they are never stale.
+            report.generations = state.tallies.summaries();
+            report
+        }
+        None => crate::analytics::snapshot(&state).await,
+    };
+
+    (StatusCode::OK, Json(AnalyticsResponse::Success { report }))
+}
@@ -0,0 +1,281 @@
+//! Generates replies to many independent prompts in one request, e.g. flavor text for every item
+//! in a shop, instead of a round trip and a busy check for each.
+//!
+//! The prompts are one-offs, outside of any conversation, and are generated one after another in
+//! a single job, so a batch takes one place in the queue.
+//!
+//! A player's tier applies as it does to `/generate`: its model, its `max_tokens` cap and its
+//! priority. Each prompt counts against its quota, and those over it are answered `rate_limited`
+//! without being generated.
+
+use axum::{
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::post,
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+
+use std::time::Instant;
+
+use super::{pinned_tier, valid_header, JsonBody};
+use crate::{
+    cache::CacheKey, formatting::Formatting, history::History, jobs::JobError, keys::Scope, llm,
+    moderation::Outcome, server::AppState,
+};
+
+const MAX_PROMPTS: usize = 64;
+
+pub fn route() -> Router<AppState> {
+    Router::new().route("/generate_batch", post(generate_batch))
+}
+
+#[derive(Debug, Deserialize)]
+struct BatchPrompt {
+    prompt: String,
+    /// Replaces the batch's `setup` for this prompt.
+    setup: Option<String>,
+    max_tokens: Option<usize>,
+    #[serde(default)]
+    stop: Vec<String>,
+    seed: Option<u32>,
+    #[serde(flatten)]
+    sampler: llm::SamplerOptions,
+}
+
+#[derive(Debug, Deserialize)]
+struct BatchRequest {
+    prompts: Vec<BatchPrompt>,
+    /// The system message for every prompt. Defaults to the current one.
+    setup: Option<String>,
+    /// Converts the replies' Markdown, instead of the configured formatting for this route.
+    formatting: Option<Formatting>,
+    /// Generates every reply, even those whose prompt's reply is cached.
+    #[serde(default)]
+    bypass_cache: bool,
+    /// The player's subscription tier, unless the key is pinned to one.
+    tier: Option<String>,
+    /// Whose quota in the tier the prompts count against.
+    player_id: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum BatchResult {
+    Success {
+        message: String,
+        usage: llm::Usage,
+    },
+    /// The reply broke a moderation rule.
+    Moderated,
+    /// The prompt was over the tier's quota, so it wasn't generated.
+    RateLimited {
+        retry_after_secs: u64,
+    },
+    GenerateError {
+        message: String,
+    },
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum BatchResponse {
+    /// One result per prompt, in the order they were sent.
+    Results {
+        results: Vec<BatchResult>,
+    },
+    Busy,
+    Unauthorized,
+    InvalidRequest {
+        message: String,
+    },
+    TierNotFound,
+    GenerateError {
+        message: String,
+    },
+}
+
+impl IntoResponse for BatchResponse {
+    fn into_response(self) -> Response {
+        let status = match &self {
+            Self::Results { .. } => StatusCode::OK,
+            Self::Busy => StatusCode::CONFLICT,
+            Self::Unauthorized => StatusCode::UNAUTHORIZED,
+            Self::InvalidRequest { .. } => StatusCode::BAD_REQUEST,
+            Self::TierNotFound => StatusCode::NOT_FOUND,
+            Self::GenerateError { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+
+        (status, Json(self)).into_response()
+    }
+}
+
+fn validate(req: &BatchRequest) -> Result<(), String> {
+    match req.prompts.len() {
+        0 => Err("prompts must not be empty".into()),
+        n if n > MAX_PROMPTS => Err(format!("at most {MAX_PROMPTS} prompts, not {n}")),
+        _ => Ok(()),
+    }
+}
+
+async fn generate_batch(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    JsonBody(mut req): JsonBody<BatchRequest>,
+) -> BatchResponse {
+    if !valid_header(&headers, &state.keys, Scope::Generate) {
+        tracing::warn!("invalid secret");
+        return BatchResponse::Unauthorized;
+    }
+    if let Err(message) = validate(&req) {
+        return BatchResponse::InvalidRequest { message };
+    }
+    let tier = match pinned_tier(&headers, &state.keys).or(req.tier.take()) {
+        Some(name) => match state.tiers.get(&name) {
+            Some(tier) => Some(tier),
+            None => return BatchResponse::TierNotFound,
+        },
+        None => None,
+    };
+    let model_name = tier.and_then(|tier| tier.config.model.clone());
+    let picked = model_name
+        .as_deref()
+        .and_then(|name| state.models.get(name));
+    // Which model replies, as far as the cache is concerned.
+    let model_name = model_name.filter(|_| picked.is_some());
+    let (model, template) = match picked {
+        Some(model) => (
+            Some(model.model.clone()),
+            model.template.unwrap_or(state.config.prompt_template),
+        ),
+        None => (state.ai_model.load_full(), state.config.prompt_template),
+    };
+    let Some(model) = model else {
+        return BatchResponse::GenerateError {
+            message: "no model is loaded".into(),
+        };
+    };
+    let player = req.player_id.as_deref().unwrap_or_default();
+    let now = Instant::now();
+    let over_quota: Vec<_> = req
+        .prompts
+        .iter()
+        .map(|_| tier.and_then(|tier| tier.admit(player, now)))
+        .collect();
+    let cap = tier.and_then(|tier| tier.config.max_tokens);
+    let priority = tier.is_some_and(|tier| tier.config.priority);
+    let setup = match req.setup {
+        Some(setup) => setup,
+        None => state.history.lock().await.system.content().to_string(),
+    };
+    let session = state.config.session();
+    let max_tokens = state.config.max_tokens;
+    let bias = state.config.bias.clone();
+    let formatting = req
+        .formatting
+        .unwrap_or_else(|| state.config.formatting("/generate_batch"));
+    let pipeline = state.config.pipeline("/generate_batch");
+    let moderator = state.moderator.clone();
+    let cache = state.cache.clone();
+    let prefixes = state.prefixes.clone();
+    let use_cache = cache.enabled() && !req.bypass_cache;
+    tracing::debug!("generating a batch of {} replies", req.prompts.len());
+
+    let results = state
+        .jobs
+        .run_with(priority, move || {
+            req.prompts
+                .into_iter()
+                .zip(over_quota)
+                .map(|(prompt, over_quota)| {
+                    if let Some(retry_after_secs) = over_quota {
+                        return BatchResult::RateLimited { retry_after_secs };
+                    }
+                    let max_tokens = prompt.max_tokens.unwrap_or(max_tokens);
+                    let mut history = History::new(prompt.setup.unwrap_or_else(|| setup.clone()));
+                    history.clear();
+                    let opts = llm::Options {
+                        setup: None,
+                        prompt: prompt.prompt,
+                        max_tokens: Some(cap.map_or(max_tokens, |cap| max_tokens.min(cap))),
+                        context: None,
+                        session,
+                        sampler: prompt.sampler,
+                        cancel: None,
+                        speaker: None,
+                        player: None,
+                        grammar: None,
+                        template,
+                        stop: prompt.stop,
+                        bias: bias.clone(),
+                        special_tokens: llm::SpecialTokens::Strip,
+                        seed: prompt.seed,
+                        pipeline: pipeline.clone(),
+                        kept_session: None,
+                        prefixes: Some(prefixes.clone()),
+                    };
+                    let key = use_cache
+                        .then(|| CacheKey::new(model_name.as_deref(), &history, &opts))
+                        .flatten();
+                    let cached = key.and_then(|key| cache.get(key));
+                    let hit = cached.is_some();
+                    let output = match (cached, &moderator) {
+                        (Some(cached), _) => Ok(Outcome::Passed(cached)),
+                        (None, Some(moderator)) => moderator.generate(&model, &mut history, opts),
+                        (None, None) => {
+                            llm::generate_text_streaming(&model, &mut history, opts, |_| true)
+                                .map(Outcome::Passed)
+                        }
+                    };
+                    if let (Some(key), false, Ok(Outcome::Passed(generated))) = (key, hit, &output)
+                    {
+                        cache.insert(key, generated.clone());
+                    }
+                    match output {
+                        Ok(Outcome::Passed(generated)) => BatchResult::Success {
+                            message: formatting.apply(&generated.text),
+                            usage: generated.usage,
+                        },
+                        Ok(Outcome::Rejected) => BatchResult::Moderated,
+                        Err(e) => {
+                            tracing::warn!("unable to generate a batched reply: {e}");
+                            BatchResult::GenerateError {
+                                message: e.to_string(),
+                            }
+                        }
+                    }
+                })
+                .collect()
+        })
+        .await;
+
+    match results {
+        Ok(results) => BatchResponse::Results { results },
+        Err(JobError::Full) => {
+            tracing::warn!("generation queue is full");
+            BatchResponse::Busy
+        }
+        Err(e) => BatchResponse::GenerateError {
+            message: e.to_string(),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn batches_are_bounded() {
+        let request = |prompts: usize| -> BatchRequest {
+            let prompt = serde_json::json!({ "prompt": "Describe the item.", "temperature": 1.1 });
+            serde_json::from_value(serde_json::json!({ "prompts": vec![prompt; prompts] })).unwrap()
+        };
+
+        assert!(validate(&request(20)).is_ok());
+        assert!(validate(&request(0)).is_err());
+        assert!(validate(&request(MAX_PROMPTS + 1)).is_err());
+        assert_eq!(request(1).prompts[0].sampler.temperature, Some(1.1));
+    }
+}
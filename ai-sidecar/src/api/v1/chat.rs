@@ -0,0 +1,192 @@
+//! Interactive chat over a WebSocket, so a game client can show an NPC's reply as it is typed
+//! without a request, a stream and busy checks for every prompt.
+//!
+//! Each text message either way is JSON with a `type`. `generate` takes the same fields as
+//! `/generate` and is always streamed: it is answered with the reply's events, shaped like their
+//! server-sent counterparts, or with the single `/generate` response that stands in for them,
+//! e.g. `session_not_found`. `cancel` stops the reply being streamed, which then ends with a
+//! `cancelled` event. A connection streams one reply at a time, so a prompt sent during one is
+//! answered `busy`.
+//!
+//! The key is checked once for the connection rather than per prompt. A key pinned to a tier puts
+//! every prompt in it.
+
+use std::pin::Pin;
+
+use axum::{
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        State,
+    },
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+use tokio_stream::{Stream, StreamExt};
+
+use super::{
+    generate, pinned_tier, valid_header, Exchange, GenerateRequest, GenerateResponse, Reply,
+    StreamEvent,
+};
+use crate::{keys::Scope, server::AppState};
+
+pub fn route() -> Router<AppState> {
+    Router::new().route("/ws", get(chat))
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ChatRequest {
+    Generate(Box<GenerateRequest>),
+    /// Stops the reply being streamed, whether it is generating or still queued.
+    Cancel,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ChatResponse {
+    Unauthorized,
+    InvalidRequest {
+        message: String,
+    },
+    /// A `cancel` arrived while no reply was being streamed.
+    NotGenerating,
+}
+
+/// The reply being streamed, with the session it is in.
+struct Streaming {
+    session_id: Option<String>,
+    events: Pin<Box<dyn Stream<Item = StreamEvent> + Send>>,
+}
+
+async fn chat(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    upgrade: WebSocketUpgrade,
+) -> Response {
+    if !valid_header(&headers, &state.keys, Scope::Generate) {
+        tracing::warn!("invalid secret");
+        return (StatusCode::UNAUTHORIZED, Json(ChatResponse::Unauthorized)).into_response();
+    }
+
+    let tier = pinned_tier(&headers, &state.keys);
+    upgrade.on_upgrade(move |socket| converse(state, tier, socket))
+}
+
+/// `message` as a WebSocket text message.
+pub(super) fn text(message: &impl Serialize) -> Message {
+    Message::Text(serde_json::to_string(message).expect("chat messages always serialize"))
+}
+
+/// The next event of the reply being streamed, waiting forever if there is none.
+async fn next_event(streaming: &mut Option<Streaming>) -> Option<StreamEvent> {
+    match streaming {
+        Some(streaming) => streaming.events.next().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Chats over `socket`, with every request in `tier` if the key is pinned to one.
+async fn converse(state: AppState, tier: Option<String>, mut socket: WebSocket) {
+    tracing::debug!("chat connected");
+    let mut streaming = None;
+
+    loop {
+        let message = tokio::select! {
+            received = socket.recv() => match received {
+                Some(Ok(Message::Text(request))) => {
+                    receive(&state, tier.as_deref(), &request, &mut streaming).await
+                }
+                Some(Ok(Message::Close(_)) | Err(_)) | None => break,
+                // Pings are answered by axum.
+                Some(Ok(_)) => None,
+            },
+            event = next_event(&mut streaming) => match event {
+                Some(event) => Some(text(&event)),
+                None => {
+                    streaming = None;
+                    None
+                }
+            },
+        };
+
+        if let Some(message) = message {
+            if socket.send(message).await.is_err() {
+                break;
+            }
+        }
+    }
+    // Dropping the stream stops a reply no one else is following.
+    tracing::debug!("chat disconnected");
+}
+
+/// Handles a client's message, starting or cancelling a reply, and returns any answer to it.
+async fn receive(
+    state: &AppState,
+    tier: Option<&str>,
+    request: &str,
+    streaming: &mut Option<Streaming>,
+) -> Option<Message> {
+    let request = match serde_json::from_str(request) {
+        Ok(request) => request,
+        Err(e) => {
+            return Some(text(&ChatResponse::InvalidRequest {
+                message: e.to_string(),
+            }))
+        }
+    };
+
+    match request {
+        ChatRequest::Generate(_) if streaming.is_some() => Some(text(&GenerateResponse::Busy)),
+        ChatRequest::Generate(mut req) => {
+            req.stream = true;
+            req.detach = false;
+            if let Some(tier) = tier {
+                req.tier = Some(tier.to_string());
+            }
+            let exchange = Exchange::take(&mut req);
+            let session_id = req.session_id.clone();
+            match generate(state.clone(), *req, exchange).await {
+                Reply::Stream(events) => {
+                    *streaming = Some(Streaming { session_id, events });
+                    None
+                }
+                Reply::Complete(_, response) => Some(text(&response)),
+            }
+        }
+        ChatRequest::Cancel => match streaming {
+            Some(streaming) => {
+                tracing::info!("cancelling generation");
+                state.cancellations.cancel(streaming.session_id.as_deref());
+                None
+            }
+            None => Some(text(&ChatResponse::NotGenerating)),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_chat_requests() {
+        let request = serde_json::from_str::<ChatRequest>(
+            r#"{"type": "generate", "prompt": "Hail!", "session_id": "a", "max_tokens": 32}"#,
+        );
+        let Ok(ChatRequest::Generate(req)) = request else {
+            panic!("expected a generate request");
+        };
+        assert_eq!(req.prompt, "Hail!");
+        assert_eq!(req.session_id.as_deref(), Some("a"));
+        assert_eq!(req.max_tokens, Some(32));
+
+        assert!(matches!(
+            serde_json::from_str::<ChatRequest>(r#"{"type": "cancel"}"#),
+            Ok(ChatRequest::Cancel)
+        ));
+        assert!(serde_json::from_str::<ChatRequest>(r#"{"type": "generate"}"#).is_err());
+    }
+}
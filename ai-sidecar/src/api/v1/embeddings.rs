@@ -0,0 +1,112 @@
+//! Embeds text for the game's own semantic search, e.g. retrieving an NPC's relevant memories.
+//!
+//! Uses the model at `AI_SIDECAR_EMBEDDING_MODEL_PATH` if one is set, or else the loaded model.
+//! Embedding runs on the inference thread, queued like any generation.
+
+use axum::{
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+    routing::post,
+    Json, Router,
+};
+use llama_cpp::EmbeddingsParams;
+use serde::{Deserialize, Serialize};
+
+use super::{valid_header, JsonBody};
+use crate::{jobs::JobError, keys::Scope, server::AppState};
+
+/// How many texts a single request may embed.
+const MAX_INPUTS: usize = 64;
+
+pub fn route() -> Router<AppState> {
+    Router::new().route("/", post(embed))
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum EmbeddingsResponse {
+    /// One vector per input, in the same order.
+    Success {
+        embeddings: Vec<Vec<f32>>,
+        dimensions: usize,
+    },
+    Unauthorized,
+    TooManyInputs {
+        max: usize,
+    },
+    Busy,
+    EmbeddingsError {
+        message: String,
+    },
+}
+
+#[derive(Debug, Deserialize)]
+struct EmbeddingsRequest {
+    input: Vec<String>,
+}
+
+async fn embed(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    JsonBody(req): JsonBody<EmbeddingsRequest>,
+) -> impl IntoResponse {
+    if !valid_header(&headers, &state.keys, Scope::Generate) {
+        tracing::warn!("invalid secret");
+        return (
+            StatusCode::UNAUTHORIZED,
+            Json(EmbeddingsResponse::Unauthorized),
+        );
+    }
+    if req.input.len() > MAX_INPUTS {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(EmbeddingsResponse::TooManyInputs { max: MAX_INPUTS }),
+        );
+    }
+    let Some(model) = state
+        .embedding_model
+        .clone()
+        .or_else(|| state.ai_model.load_full())
+    else {
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(EmbeddingsResponse::EmbeddingsError {
+                message: "no model is loaded".into(),
+            }),
+        );
+    };
+
+    let dimensions = model.embed_len();
+    let embedded = state
+        .jobs
+        .run(move || {
+            model
+                .embeddings(&req.input, EmbeddingsParams::default())
+                .map_err(|e| e.to_string())
+        })
+        .await;
+    match embedded {
+        Ok(Ok(embeddings)) => (
+            StatusCode::OK,
+            Json(EmbeddingsResponse::Success {
+                embeddings,
+                dimensions,
+            }),
+        ),
+        Ok(Err(message)) => {
+            tracing::error!("unable to embed: {message}");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(EmbeddingsResponse::EmbeddingsError { message }),
+            )
+        }
+        Err(JobError::Full) => (StatusCode::CONFLICT, Json(EmbeddingsResponse::Busy)),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(EmbeddingsResponse::EmbeddingsError {
+                message: e.to_string(),
+            }),
+        ),
+    }
+}
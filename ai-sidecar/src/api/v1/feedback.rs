@@ -0,0 +1,61 @@
+//! Player ratings of the replies `/generate` served, by generation id.
+
+use axum::{
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+    routing::post,
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+
+use super::{valid_header, JsonBody};
+use crate::{keys::Scope, server::AppState, traces::Rating};
+
+pub fn route() -> Router<AppState> {
+    Router::new().route("/", post(give_feedback))
+}
+
+#[derive(Debug, Deserialize)]
+struct FeedbackRequest {
+    generation_id: String,
+    rating: Rating,
+    comment: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum FeedbackResponse {
+    Success,
+    Unauthorized,
+    /// The reply is unknown, or too old to still be traced.
+    GenerationNotFound,
+}
+
+async fn give_feedback(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    JsonBody(req): JsonBody<FeedbackRequest>,
+) -> impl IntoResponse {
+    if !valid_header(&headers, &state.keys, Scope::Generate) {
+        tracing::warn!("invalid secret");
+        return (
+            StatusCode::UNAUTHORIZED,
+            Json(FeedbackResponse::Unauthorized),
+        );
+    }
+
+    let found = state
+        .traces
+        .lock()
+        .await
+        .feedback(&req.generation_id, req.rating, req.comment);
+    if !found {
+        return (
+            StatusCode::NOT_FOUND,
+            Json(FeedbackResponse::GenerationNotFound),
+        );
+    }
+
+    (StatusCode::OK, Json(FeedbackResponse::Success))
+}
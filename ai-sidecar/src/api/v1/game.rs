@@ -0,0 +1,83 @@
+use axum::{
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+    routing::{get, post},
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+
+use super::{valid_header, JsonBody};
+use crate::{keys::Scope, memory::GameEvent, server::AppState, temporal::WorldTime};
+
+pub fn route() -> Router<AppState> {
+    Router::new()
+        .route("/events", post(handle_events))
+        .route("/time", get(get_time).put(set_time))
+}
+
+#[derive(Debug, Deserialize)]
+struct EventsRequest {
+    events: Vec<GameEvent>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum EventsResponse {
+    Success { accepted: usize },
+    Unauthorized,
+}
+
+async fn handle_events(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    JsonBody(req): JsonBody<EventsRequest>,
+) -> impl IntoResponse {
+    if !valid_header(&headers, &state.keys, Scope::Generate) {
+        tracing::warn!("invalid secret");
+        return (StatusCode::UNAUTHORIZED, Json(EventsResponse::Unauthorized));
+    }
+
+    let accepted = req.events.len();
+    tracing::debug!("ingesting {accepted} game events");
+
+    let mut memory = state.memory.lock().await;
+    for event in req.events {
+        memory.ingest(event);
+    }
+
+    (StatusCode::OK, Json(EventsResponse::Success { accepted }))
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum TimeResponse {
+    Success { time: WorldTime },
+    Unauthorized,
+}
+
+async fn get_time(State(state): State<AppState>, headers: HeaderMap) -> impl IntoResponse {
+    if !valid_header(&headers, &state.keys, Scope::Generate) {
+        tracing::warn!("invalid secret");
+        return (StatusCode::UNAUTHORIZED, Json(TimeResponse::Unauthorized));
+    }
+
+    let time = state.world_time.lock().await.clone();
+    (StatusCode::OK, Json(TimeResponse::Success { time }))
+}
+
+/// Replaces the world time, so fields left out are forgotten.
+async fn set_time(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    JsonBody(time): JsonBody<WorldTime>,
+) -> impl IntoResponse {
+    if !valid_header(&headers, &state.keys, Scope::Generate) {
+        tracing::warn!("invalid secret");
+        return (StatusCode::UNAUTHORIZED, Json(TimeResponse::Unauthorized));
+    }
+
+    tracing::debug!("world time is now {time:?}");
+    *state.world_time.lock().await = time.clone();
+    (StatusCode::OK, Json(TimeResponse::Success { time }))
+}
@@ -0,0 +1,168 @@
+//! The messages of a conversation as the sidecar sees them, a page at a time, for inspecting what
+//! the model is being prompted with.
+
+use std::ops::Range;
+
+use axum::{
+    extract::{Query, State},
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+    routing::get,
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+
+use super::{conversation, valid_header};
+use crate::{history::Message, keys::Scope, persist, server::AppState};
+
+const DEFAULT_LIMIT: usize = 50;
+const MAX_LIMIT: usize = 200;
+
+pub fn route() -> Router<AppState> {
+    Router::new().route("/", get(get_history))
+}
+
+#[derive(Debug, Deserialize)]
+struct HistoryQuery {
+    session_id: Option<String>,
+    #[serde(default)]
+    offset: usize,
+    limit: Option<usize>,
+}
+
+/// Which messages to return, oldest first.
+#[derive(Debug, Deserialize)]
+pub(super) struct Page {
+    #[serde(default)]
+    offset: usize,
+    /// Defaults to 50, and is between 1 and 200.
+    limit: Option<usize>,
+}
+
+impl Page {
+    /// Which of `total` messages the page holds, and the offset of the next page if there is one.
+    fn bounds(&self, total: usize) -> (Range<usize>, Option<usize>) {
+        let limit = self.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+        let start = self.offset.min(total);
+        let end = start.saturating_add(limit).min(total);
+
+        (start..end, (end < total).then_some(end))
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub(super) struct HistoryMessage {
+    role: &'static str,
+    content: String,
+    speaker: Option<String>,
+    /// In unix seconds, if it is known.
+    created_at: Option<u64>,
+}
+
+impl From<&Message> for HistoryMessage {
+    fn from(message: &Message) -> Self {
+        Self {
+            role: persist::message_type_name(message.message_type()),
+            content: message.content().to_string(),
+            speaker: message.speaker().map(str::to_string),
+            created_at: message.created_at(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub(super) enum HistoryResponse {
+    Messages {
+        system: String,
+        messages: Vec<HistoryMessage>,
+        /// How many messages the conversation has in all.
+        total: usize,
+        offset: usize,
+        /// The offset of the next page, if there is one.
+        next_offset: Option<usize>,
+    },
+    Unauthorized,
+    SessionNotFound,
+    Busy,
+}
+
+async fn get_history(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<HistoryQuery>,
+) -> impl IntoResponse {
+    let page = Page {
+        offset: query.offset,
+        limit: query.limit,
+    };
+    history_page(&state, &headers, query.session_id.as_deref(), page).await
+}
+
+/// A page of the conversation's messages, or of the default one without a session.
+pub(super) async fn history_page(
+    state: &AppState,
+    headers: &HeaderMap,
+    session_id: Option<&str>,
+    page: Page,
+) -> (StatusCode, Json<HistoryResponse>) {
+    if !valid_header(headers, &state.keys, Scope::HistoryRead) {
+        tracing::warn!("invalid secret");
+        return (
+            StatusCode::UNAUTHORIZED,
+            Json(HistoryResponse::Unauthorized),
+        );
+    }
+
+    let Some(history) = conversation(state, session_id).await else {
+        return (
+            StatusCode::NOT_FOUND,
+            Json(HistoryResponse::SessionNotFound),
+        );
+    };
+    let Ok(history) = history.try_lock() else {
+        return (StatusCode::CONFLICT, Json(HistoryResponse::Busy));
+    };
+
+    let total = history.history.len();
+    let (bounds, next_offset) = page.bounds(total);
+    let messages = history.history[bounds]
+        .iter()
+        .map(HistoryMessage::from)
+        .collect::<Vec<_>>();
+
+    (
+        StatusCode::OK,
+        Json(HistoryResponse::Messages {
+            system: history.system.content().to_string(),
+            messages,
+            total,
+            offset: page.offset,
+            next_offset,
+        }),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn page(offset: usize, limit: Option<usize>) -> Page {
+        Page { offset, limit }
+    }
+
+    #[test]
+    fn bounds_pages() {
+        assert_eq!(page(0, None).bounds(120), (0..50, Some(50)));
+        assert_eq!(page(100, None).bounds(120), (100..120, None));
+        assert_eq!(page(0, Some(1000)).bounds(500), (0..200, Some(200)));
+        assert_eq!(page(5, Some(0)).bounds(10), (5..6, Some(6)));
+        assert_eq!(page(usize::MAX, Some(10)).bounds(10), (10..10, None));
+    }
+
+    #[test]
+    fn bounds_empty_pages() {
+        assert_eq!(page(0, None).bounds(0), (0..0, None));
+        assert_eq!(page(20, Some(10)).bounds(10), (10..10, None));
+    }
+}
@@ -0,0 +1,53 @@
+//! Results of detached and queued `/generate` requests.
+
+use axum::{
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+    routing::get,
+    Json, Router,
+};
+use serde::Serialize;
+
+use super::valid_header;
+use crate::{jobs::JobStatus, keys::Scope, server::AppState};
+
+pub fn route() -> Router<AppState> {
+    Router::new().route("/:id", get(poll_job))
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum JobResponse {
+    /// `position` jobs are ahead of this one.
+    Pending {
+        position: u64,
+    },
+    /// The job's `/generate` response. Only returned once, after which the job is forgotten.
+    Done {
+        result: serde_json::Value,
+    },
+    Unauthorized,
+    /// The job doesn't exist, its result was already collected, or it went uncollected for over
+    /// ten minutes.
+    JobNotFound,
+}
+
+async fn poll_job(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    if !valid_header(&headers, &state.keys, Scope::Generate) {
+        tracing::warn!("invalid secret");
+        return (StatusCode::UNAUTHORIZED, Json(JobResponse::Unauthorized));
+    }
+
+    match state.jobs.poll(&id) {
+        Some(JobStatus::Pending { position }) => {
+            (StatusCode::OK, Json(JobResponse::Pending { position }))
+        }
+        Some(JobStatus::Done { result }) => (StatusCode::OK, Json(JobResponse::Done { result })),
+        None => (StatusCode::NOT_FOUND, Json(JobResponse::JobNotFound)),
+    }
+}
@@ -0,0 +1,108 @@
+//! Generates lore for items and locations, regenerating near-duplicates, see [`crate::lore`].
+
+use axum::{
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+    routing::post,
+    Json, Router,
+};
+use serde::Serialize;
+
+use super::{valid_header, JsonBody};
+use crate::{
+    jobs::JobError,
+    keys::Scope,
+    lore::{self, Subject},
+    server::AppState,
+};
+
+pub fn route() -> Router<AppState> {
+    Router::new().route("/generate", post(generate_lore))
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum LoreResponse {
+    Success {
+        description: String,
+    },
+    /// Every attempt was close to a description generated before. The last one is given in case
+    /// it will do.
+    Duplicate {
+        description: String,
+    },
+    Unauthorized,
+    InvalidRequest {
+        message: String,
+    },
+    Busy,
+    GenerateError {
+        message: String,
+    },
+}
+
+async fn generate_lore(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    JsonBody(subject): JsonBody<Subject>,
+) -> impl IntoResponse {
+    if !valid_header(&headers, &state.keys, Scope::Generate) {
+        tracing::warn!("invalid secret");
+        return (StatusCode::UNAUTHORIZED, Json(LoreResponse::Unauthorized));
+    }
+    if subject.name.trim().is_empty() {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(LoreResponse::InvalidRequest {
+                message: "name must not be empty".into(),
+            }),
+        );
+    }
+    let Some(model) = state.ai_model.load_full() else {
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(LoreResponse::GenerateError {
+                message: "no model is loaded".into(),
+            }),
+        );
+    };
+    let session = state.config.session();
+    let template = state.config.prompt_template;
+    let index = state.lore.clone();
+
+    let lore = state
+        .jobs
+        .run(move || {
+            lore::generate(&model, template, session, &subject, &index).map_err(|e| e.to_string())
+        })
+        .await;
+    match lore {
+        Ok(Ok(lore)) if lore.duplicate => (
+            StatusCode::CONFLICT,
+            Json(LoreResponse::Duplicate {
+                description: lore.description,
+            }),
+        ),
+        Ok(Ok(lore)) => (
+            StatusCode::OK,
+            Json(LoreResponse::Success {
+                description: lore.description,
+            }),
+        ),
+        Ok(Err(message)) => {
+            tracing::error!("unable to generate lore: {message}");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(LoreResponse::GenerateError { message }),
+            )
+        }
+        Err(JobError::Full) => (StatusCode::CONFLICT, Json(LoreResponse::Busy)),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(LoreResponse::GenerateError {
+                message: e.to_string(),
+            }),
+        ),
+    }
+}
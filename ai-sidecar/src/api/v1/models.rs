@@ -0,0 +1,56 @@
+//! Lists the models requests can pick from.
+
+use axum::{
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+    routing::get,
+    Json, Router,
+};
+use serde::Serialize;
+
+use super::valid_header;
+use crate::{keys::Scope, server::AppState, templates::Template};
+
+pub fn route() -> Router<AppState> {
+    Router::new().route("/", get(list_models))
+}
+
+#[derive(Debug, Serialize)]
+struct ModelInfo {
+    /// What to send as a request's `model`, or `None` for the default model.
+    name: Option<String>,
+    path: String,
+    prompt_template: Template,
+    /// Whether the model is loaded. Only the default model can be unloaded.
+    loaded: bool,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ModelsResponse {
+    Models { models: Vec<ModelInfo> },
+    Unauthorized,
+}
+
+async fn list_models(State(state): State<AppState>, headers: HeaderMap) -> impl IntoResponse {
+    if !valid_header(&headers, &state.keys, Scope::Generate) {
+        tracing::warn!("invalid secret");
+        return (StatusCode::UNAUTHORIZED, Json(ModelsResponse::Unauthorized));
+    }
+
+    let mut models = vec![ModelInfo {
+        name: None,
+        path: state.config.model_path.display().to_string(),
+        prompt_template: state.config.prompt_template,
+        loaded: state.ai_model.load().is_some(),
+    }];
+    models.extend(state.models.iter().map(|(name, model)| ModelInfo {
+        name: Some(name.to_string()),
+        path: model.path.display().to_string(),
+        prompt_template: model.template.unwrap_or(state.config.prompt_template),
+        loaded: true,
+    }));
+
+    (StatusCode::OK, Json(ModelsResponse::Models { models }))
+}
@@ -0,0 +1,332 @@
+//! Live monitoring of a conversation for game masters: its messages over a WebSocket as they are
+//! added, whispers that steer the NPC without the players seeing them, and replies written by hand
+//! when a human takes over.
+//!
+//! Each text message on the socket is JSON with a `type`. A watcher first gets the conversation so
+//! far as a `transcript`, then each new message as a `message`. One that falls behind gets a
+//! `lagged` message saying how many messages it missed. It can send a `whisper` at any time, which
+//! is answered `whispered` once it is set. A whisper is added to the system message's background
+//! for every reply until it is cleared, and isn't persisted.
+//!
+//! A hand-written reply is added to the conversation as the NPC's, and can pause generation in
+//! it, so `/generate` answers `paused` until it is resumed and the game master has the NPC to
+//! themselves.
+
+use std::sync::Arc;
+
+use axum::{
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        Query, State,
+    },
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{get, post, put},
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{broadcast::error::RecvError, Mutex};
+
+use super::{
+    chat::text, conversation, history::HistoryMessage, persist_conversation, persist_messages,
+    valid_header, JsonBody, SessionQuery,
+};
+use crate::{history::History, keys::Scope, server::AppState};
+
+pub fn route() -> Router<AppState> {
+    Router::new()
+        .route("/", get(watch_conversation))
+        .route("/reply", post(reply))
+        .route("/pause", put(pause))
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum MonitorResponse {
+    Success,
+    Unauthorized,
+    SessionNotFound,
+    PersonaNotFound,
+    /// The speaker isn't one of the group conversation's NPCs.
+    SpeakerNotFound,
+    /// The conversation is frozen or archived.
+    ReadOnly,
+    InvalidRequest {
+        message: String,
+    },
+}
+
+impl IntoResponse for MonitorResponse {
+    fn into_response(self) -> Response {
+        let status = match &self {
+            Self::Success => StatusCode::OK,
+            Self::Unauthorized => StatusCode::UNAUTHORIZED,
+            Self::SessionNotFound | Self::PersonaNotFound | Self::SpeakerNotFound => {
+                StatusCode::NOT_FOUND
+            }
+            Self::ReadOnly => StatusCode::CONFLICT,
+            Self::InvalidRequest { .. } => StatusCode::BAD_REQUEST,
+        };
+
+        (status, Json(self)).into_response()
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum WatchRequest {
+    /// Replaces the conversation's whisper, or clears it if `None`.
+    Whisper { whisper: Option<String> },
+}
+
+/// Messages sent while watching a conversation.
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum WatchEvent {
+    Transcript {
+        system: String,
+        messages: Vec<HistoryMessage>,
+    },
+    Message {
+        message: HistoryMessage,
+    },
+    Lagged {
+        missed: u64,
+    },
+    /// The whisper was set, or cleared if it is `None`.
+    Whispered {
+        whisper: Option<String>,
+    },
+    InvalidRequest {
+        message: String,
+    },
+}
+
+async fn watch_conversation(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<SessionQuery>,
+    upgrade: WebSocketUpgrade,
+) -> Response {
+    if !valid_header(&headers, &state.keys, Scope::Admin) {
+        tracing::warn!("invalid secret");
+        return MonitorResponse::Unauthorized.into_response();
+    }
+    let Some(history) = conversation(&state, query.session_id.as_deref()).await else {
+        return MonitorResponse::SessionNotFound.into_response();
+    };
+
+    upgrade.on_upgrade(move |socket| watch(state, query.session_id, history, socket))
+}
+
+async fn watch(
+    state: AppState,
+    session_id: Option<String>,
+    history: Arc<Mutex<History>>,
+    mut socket: WebSocket,
+) {
+    // Watching before reading the transcript means no message falls between the two, though one
+    // being added meanwhile can arrive twice.
+    let mut observed = state.monitor.watch();
+    let transcript = {
+        let history = history.lock().await;
+        WatchEvent::Transcript {
+            system: history.system.content().to_string(),
+            messages: history.history.iter().map(HistoryMessage::from).collect(),
+        }
+    };
+    tracing::info!("watching {session_id:?}");
+
+    let mut next = Some(transcript);
+    loop {
+        if let Some(event) = next.take() {
+            if socket.send(text(&event)).await.is_err() {
+                break;
+            }
+        }
+        next = tokio::select! {
+            received = socket.recv() => match received {
+                Some(Ok(Message::Text(request))) => {
+                    Some(whisper(&history, session_id.as_deref(), &request).await)
+                }
+                Some(Ok(Message::Close(_)) | Err(_)) | None => break,
+                // Pings are answered by axum.
+                Some(Ok(_)) => None,
+            },
+            received = observed.recv() => match received {
+                Ok(observed) if observed.session_id == session_id => Some(WatchEvent::Message {
+                    message: HistoryMessage::from(&observed.message),
+                }),
+                Ok(_) => None,
+                Err(RecvError::Lagged(missed)) => Some(WatchEvent::Lagged { missed }),
+                Err(RecvError::Closed) => break,
+            },
+        };
+    }
+    tracing::info!("stopped watching {session_id:?}");
+}
+
+/// Sets a whisper for the conversation's NPC, once any generation in it has finished.
+async fn whisper(history: &Mutex<History>, session_id: Option<&str>, request: &str) -> WatchEvent {
+    let whisper = match serde_json::from_str(request) {
+        Ok(WatchRequest::Whisper { whisper }) => {
+            whisper.filter(|whisper| !whisper.trim().is_empty())
+        }
+        Err(e) => {
+            return WatchEvent::InvalidRequest {
+                message: e.to_string(),
+            }
+        }
+    };
+
+    history.lock().await.whisper = whisper.clone();
+    tracing::info!("updated the whisper for {session_id:?}");
+
+    WatchEvent::Whispered { whisper }
+}
+
+#[derive(Debug, Deserialize)]
+struct ReplyRequest {
+    message: String,
+    /// Replies as this persona from the registry, under its name in a group conversation.
+    persona: Option<String>,
+    /// Which NPC replies, in a group conversation. Defaults to the persona's name.
+    speaker: Option<String>,
+    /// Pauses generation in the conversation, or resumes it if `false`.
+    pause: Option<bool>,
+}
+
+/// The NPC a hand-written reply is from: none in a conversation with one NPC, or else one of the
+/// group's.
+fn reply_speaker(history: &History, speaker: Option<String>) -> Result<Option<String>, ()> {
+    match speaker {
+        _ if history.speakers.is_empty() => Ok(None),
+        Some(speaker) if !history.speakers.contains(&speaker) => Err(()),
+        speaker => Ok(speaker),
+    }
+}
+
+/// Adds a hand-written reply to the conversation as the NPC's, once any generation in it has
+/// finished.
+async fn reply(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<SessionQuery>,
+    JsonBody(req): JsonBody<ReplyRequest>,
+) -> MonitorResponse {
+    if !valid_header(&headers, &state.keys, Scope::Admin) {
+        tracing::warn!("invalid secret");
+        return MonitorResponse::Unauthorized;
+    }
+    if req.message.trim().is_empty() {
+        return MonitorResponse::InvalidRequest {
+            message: "message must not be empty".into(),
+        };
+    }
+    let persona = match &req.persona {
+        Some(id) => match state.personas.get(id) {
+            Some(persona) => Some(persona),
+            None => return MonitorResponse::PersonaNotFound,
+        },
+        None => None,
+    };
+    let Some(history) = conversation(&state, query.session_id.as_deref()).await else {
+        return MonitorResponse::SessionNotFound;
+    };
+
+    let mut history = history.lock().await;
+    if history.flags.read_only() {
+        return MonitorResponse::ReadOnly;
+    }
+    let speaker = req.speaker.or(persona.map(|persona| persona.name));
+    let Ok(speaker) = reply_speaker(&history, speaker) else {
+        return MonitorResponse::SpeakerNotFound;
+    };
+    if let Some(pause) = req.pause {
+        history.flags.paused = pause;
+    }
+    let start = history.history.len();
+    history.push_reply(speaker, req.message);
+    tracing::info!("game master replied in {:?}", query.session_id);
+
+    let session_id = query.session_id.as_deref();
+    if let Some(db) = &state.history_db {
+        let mut db = db.lock().await;
+        persist_messages(&mut db, session_id, &history.history[start..]);
+        if req.pause.is_some() {
+            persist_conversation(&mut db, session_id, &history);
+        }
+    }
+    state.monitor.observe(session_id, &history.history[start..]);
+
+    MonitorResponse::Success
+}
+
+#[derive(Debug, Deserialize)]
+struct PauseRequest {
+    paused: bool,
+}
+
+/// Pauses or resumes generation in the conversation, once any generation in it has finished.
+async fn pause(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<SessionQuery>,
+    JsonBody(req): JsonBody<PauseRequest>,
+) -> MonitorResponse {
+    if !valid_header(&headers, &state.keys, Scope::Admin) {
+        tracing::warn!("invalid secret");
+        return MonitorResponse::Unauthorized;
+    }
+    let Some(history) = conversation(&state, query.session_id.as_deref()).await else {
+        return MonitorResponse::SessionNotFound;
+    };
+
+    let mut history = history.lock().await;
+    history.flags.paused = req.paused;
+    if let Some(db) = &state.history_db {
+        persist_conversation(&mut *db.lock().await, query.session_id.as_deref(), &history);
+    }
+    tracing::info!(
+        paused = req.paused,
+        "updated pause for {:?}",
+        query.session_id
+    );
+
+    MonitorResponse::Success
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_whispers() {
+        let request = serde_json::from_str::<WatchRequest>(
+            r#"{"type": "whisper", "whisper": "The stranger is a spy."}"#,
+        );
+        assert!(matches!(
+            request,
+            Ok(WatchRequest::Whisper { whisper: Some(whisper) }) if whisper.starts_with("The")
+        ));
+        assert!(matches!(
+            serde_json::from_str::<WatchRequest>(r#"{"type": "whisper", "whisper": null}"#),
+            Ok(WatchRequest::Whisper { whisper: None })
+        ));
+        assert!(serde_json::from_str::<WatchRequest>(r#"{"type": "reply"}"#).is_err());
+    }
+
+    #[test]
+    fn replies_come_from_the_group() {
+        let mut history = History::new("Setup".into());
+        assert_eq!(reply_speaker(&history, Some("Brunhild".into())), Ok(None));
+
+        history.speakers = vec!["Brunhild".into(), "Marta".into()];
+        assert_eq!(
+            reply_speaker(&history, Some("Marta".into())),
+            Ok(Some("Marta".into()))
+        );
+        assert_eq!(reply_speaker(&history, None), Ok(None));
+        assert!(reply_speaker(&history, Some("Tom".into())).is_err());
+    }
+}
@@ -0,0 +1,85 @@
+//! NPC dialogue from structured game context, such as the player's name and quest progress, which
+//! is rendered into the NPC's background with the configured template. Otherwise the request is
+//! a `/generate` request, and gets the same replies.
+
+use axum::{
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::post,
+    Extension, Json, Router,
+};
+use serde::Deserialize;
+
+use super::{
+    dispatch, pinned_tier, valid_header, Exchange, GenerateRequest, GenerateResponse, JsonBody,
+};
+use crate::{game_context::GameContext, keys::Scope, server::AppState, tracecontext::TraceContext};
+
+pub fn route() -> Router<AppState> {
+    Router::new().route("/dialogue", post(npc_dialogue))
+}
+
+#[derive(Debug, Deserialize)]
+struct DialogueRequest {
+    #[serde(default)]
+    context: GameContext,
+    #[serde(flatten)]
+    generate: GenerateRequest,
+}
+
+async fn npc_dialogue(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    trace: Option<Extension<TraceContext>>,
+    JsonBody(mut req): JsonBody<DialogueRequest>,
+) -> Response {
+    if !valid_header(&headers, &state.keys, Scope::Generate) {
+        tracing::warn!("invalid secret");
+        return (StatusCode::UNAUTHORIZED, Json(GenerateResponse::Busy)).into_response();
+    }
+
+    req.generate.tier = pinned_tier(&headers, &state.keys).or(req.generate.tier.take());
+    let mut exchange = Exchange::take(&mut req.generate);
+    exchange.trace = trace.map(|Extension(context)| context);
+    let locale = match &exchange.session_id {
+        Some(session_id) => state.sessions.lock().await.locale(session_id).cloned(),
+        None => None,
+    };
+    exchange.background = req
+        .context
+        .render(state.config.npc_context_template(locale.as_ref()));
+    dispatch(state, req.generate, exchange).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn context_sits_alongside_the_request() {
+        let req: DialogueRequest = serde_json::from_value(serde_json::json!({
+            "prompt": "Any work for me?",
+            "persona": "blacksmith",
+            "temperature": 0.7,
+            "context": {
+                "player_name": "Ada",
+                "relationship": 40,
+                "quest_flags": { "found_the_map": true },
+            },
+        }))
+        .unwrap();
+
+        assert_eq!(req.generate.prompt, "Any work for me?");
+        assert_eq!(req.generate.persona.as_deref(), Some("blacksmith"));
+        assert_eq!(req.generate.sampler.temperature, Some(0.7));
+        assert_eq!(req.context.player_name.as_deref(), Some("Ada"));
+        assert!(
+            serde_json::from_value::<DialogueRequest>(serde_json::json!({
+                "prompt": "hi",
+                "context": { "gold": 3 },
+            }))
+            .is_err()
+        );
+    }
+}
@@ -0,0 +1,107 @@
+//! Generates quests as typed JSON, see [`crate::quests`].
+//!
+//! Generation runs on the inference thread, queued like any other, and every retry happens within
+//! the same job.
+
+use axum::{
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+    routing::post,
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+
+use super::{valid_header, JsonBody};
+use crate::{
+    jobs::JobError,
+    keys::Scope,
+    quests::{self, Difficulty, Quest},
+    server::AppState,
+};
+
+pub fn route() -> Router<AppState> {
+    Router::new().route("/generate", post(generate_quest))
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum QuestResponse {
+    Success {
+        quest: Quest,
+    },
+    Unauthorized,
+    InvalidRequest {
+        message: String,
+    },
+    Busy,
+    /// The model couldn't be run, or gave no valid quest in any attempt.
+    GenerateError {
+        message: String,
+    },
+}
+
+#[derive(Debug, Deserialize)]
+struct QuestRequest {
+    /// What the quest is about, e.g. "the miller's daughter went missing in the woods".
+    brief: String,
+    /// Asks for a quest of this difficulty, instead of leaving it to the model.
+    difficulty: Option<Difficulty>,
+}
+
+async fn generate_quest(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    JsonBody(req): JsonBody<QuestRequest>,
+) -> impl IntoResponse {
+    if !valid_header(&headers, &state.keys, Scope::Generate) {
+        tracing::warn!("invalid secret");
+        return (StatusCode::UNAUTHORIZED, Json(QuestResponse::Unauthorized));
+    }
+    if req.brief.trim().is_empty() {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(QuestResponse::InvalidRequest {
+                message: "brief must not be empty".into(),
+            }),
+        );
+    }
+    let Some(model) = state.ai_model.load_full() else {
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(QuestResponse::GenerateError {
+                message: "no model is loaded".into(),
+            }),
+        );
+    };
+    let session = state.config.session();
+    let template = state.config.prompt_template;
+
+    let quest = state
+        .jobs
+        .run(move || {
+            quests::generate(&model, template, session, &req.brief, req.difficulty)
+                .map_err(|e| e.to_string())
+        })
+        .await;
+    match quest {
+        Ok(Ok(quest)) => {
+            tracing::info!("generated quest {:?}", quest.title);
+            (StatusCode::OK, Json(QuestResponse::Success { quest }))
+        }
+        Ok(Err(message)) => {
+            tracing::error!("unable to generate a quest: {message}");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(QuestResponse::GenerateError { message }),
+            )
+        }
+        Err(JobError::Full) => (StatusCode::CONFLICT, Json(QuestResponse::Busy)),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(QuestResponse::GenerateError {
+                message: e.to_string(),
+            }),
+        ),
+    }
+}
@@ -0,0 +1,327 @@
+use axum::{
+    extract::{Path, Query, State},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+
+use super::{valid_header, JsonBody};
+use crate::{
+    diff::{self, Change},
+    jobs::JobError,
+    keys::Scope,
+    llm,
+    review::{self, Entry, ReviewError, Status},
+    server::AppState,
+};
+
+const DEFAULT_MAX_TOKENS: usize = 256;
+
+pub fn route() -> Router<AppState> {
+    Router::new()
+        .route("/entries", get(list_entries).post(submit_entry))
+        .route("/entries/:id", get(get_entry).put(edit_entry))
+        .route("/entries/:id/approve", post(approve_entry))
+        .route("/entries/:id/reject", post(reject_entry))
+        .route("/entries/:id/regenerate", post(regenerate_entry))
+        .route("/approved", get(list_approved))
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ReviewResponse {
+    Success {
+        entry: Entry,
+    },
+    List {
+        entries: Vec<Entry>,
+    },
+    /// The regenerated entry, with what changed since the previous version.
+    Regenerated {
+        entry: Entry,
+        diff: Vec<Change>,
+    },
+    Busy,
+    Unauthorized,
+    NotFound,
+    InvalidRequest {
+        message: String,
+    },
+    GenerateError {
+        message: String,
+    },
+    StorageError {
+        message: String,
+    },
+}
+
+impl IntoResponse for ReviewResponse {
+    fn into_response(self) -> Response {
+        let status = match &self {
+            Self::Success { .. } | Self::List { .. } | Self::Regenerated { .. } => StatusCode::OK,
+            Self::Busy => StatusCode::CONFLICT,
+            Self::Unauthorized => StatusCode::UNAUTHORIZED,
+            Self::NotFound => StatusCode::NOT_FOUND,
+            Self::InvalidRequest { .. } => StatusCode::BAD_REQUEST,
+            Self::GenerateError { .. } | Self::StorageError { .. } => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        };
+
+        (status, Json(self)).into_response()
+    }
+}
+
+impl From<Result<Entry, ReviewError>> for ReviewResponse {
+    fn from(result: Result<Entry, ReviewError>) -> Self {
+        match result {
+            Ok(entry) => Self::Success { entry },
+            Err(ReviewError::NotFound(_)) => Self::NotFound,
+            Err(e) => {
+                tracing::error!("unable to update review queue: {e}");
+                Self::StorageError {
+                    message: e.to_string(),
+                }
+            }
+        }
+    }
+}
+
+/// Generates content with the shared model without touching the conversation history.
+async fn generate(
+    state: &AppState,
+    setup: Option<String>,
+    prompt: String,
+    max_tokens: Option<usize>,
+) -> Result<String, ReviewResponse> {
+    let Some(model) = state.ai_model.load_full() else {
+        return Err(ReviewResponse::GenerateError {
+            message: "no model is loaded".into(),
+        });
+    };
+    let system = match setup {
+        Some(setup) => setup,
+        None => state.history.lock().await.system.content().to_string(),
+    };
+    let session = state.config.session();
+    let template = state.config.prompt_template;
+
+    let output = state
+        .jobs
+        .run(move || {
+            llm::complete(
+                &model,
+                template,
+                system,
+                prompt,
+                max_tokens.unwrap_or(DEFAULT_MAX_TOKENS),
+                session,
+            )
+            .map_err(|e| e.to_string())
+        })
+        .await;
+    match output {
+        Ok(output) => output.map_err(|message| ReviewResponse::GenerateError { message }),
+        Err(JobError::Full) => {
+            tracing::warn!("generation queue is full");
+            Err(ReviewResponse::Busy)
+        }
+        Err(e) => Err(ReviewResponse::GenerateError {
+            message: e.to_string(),
+        }),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ListQuery {
+    status: Option<Status>,
+    kind: Option<String>,
+}
+
+async fn list_entries(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<ListQuery>,
+) -> ReviewResponse {
+    if !valid_header(&headers, &state.keys, Scope::Admin) {
+        tracing::warn!("invalid secret");
+        return ReviewResponse::Unauthorized;
+    }
+
+    let review = state.review.lock().await;
+    ReviewResponse::List {
+        entries: review
+            .list(query.status, query.kind.as_deref())
+            .into_iter()
+            .cloned()
+            .collect(),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct KindQuery {
+    kind: Option<String>,
+}
+
+/// The only way the game server should fetch reviewed content.
+async fn list_approved(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<KindQuery>,
+) -> ReviewResponse {
+    list_entries(
+        State(state),
+        headers,
+        Query(ListQuery {
+            status: Some(Status::Approved),
+            kind: query.kind,
+        }),
+    )
+    .await
+}
+
+async fn get_entry(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<u64>,
+) -> ReviewResponse {
+    if !valid_header(&headers, &state.keys, Scope::Admin) {
+        tracing::warn!("invalid secret");
+        return ReviewResponse::Unauthorized;
+    }
+
+    state.review.lock().await.get(id).cloned().into()
+}
+
+#[derive(Debug, Deserialize)]
+struct SubmitRequest {
+    kind: String,
+    setup: Option<String>,
+    /// Generates the content from this prompt if `content` is not given.
+    prompt: Option<String>,
+    content: Option<serde_json::Value>,
+    max_tokens: Option<usize>,
+}
+
+async fn submit_entry(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    JsonBody(req): JsonBody<SubmitRequest>,
+) -> ReviewResponse {
+    if !valid_header(&headers, &state.keys, Scope::Admin) {
+        tracing::warn!("invalid secret");
+        return ReviewResponse::Unauthorized;
+    }
+
+    let content = match (req.content, &req.prompt) {
+        (Some(content), _) => content,
+        (None, Some(prompt)) => {
+            match generate(&state, req.setup.clone(), prompt.clone(), req.max_tokens).await {
+                Ok(output) => output.into(),
+                Err(response) => return response,
+            }
+        }
+        (None, None) => {
+            return ReviewResponse::InvalidRequest {
+                message: "either content or a prompt is required".into(),
+            }
+        }
+    };
+
+    state
+        .review
+        .lock()
+        .await
+        .submit(req.kind, req.setup, req.prompt, content)
+        .into()
+}
+
+#[derive(Debug, Deserialize)]
+struct EditRequest {
+    content: serde_json::Value,
+}
+
+async fn edit_entry(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<u64>,
+    JsonBody(req): JsonBody<EditRequest>,
+) -> ReviewResponse {
+    if !valid_header(&headers, &state.keys, Scope::Admin) {
+        tracing::warn!("invalid secret");
+        return ReviewResponse::Unauthorized;
+    }
+
+    state.review.lock().await.replace(id, req.content).into()
+}
+
+async fn approve_entry(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<u64>,
+) -> ReviewResponse {
+    if !valid_header(&headers, &state.keys, Scope::Admin) {
+        tracing::warn!("invalid secret");
+        return ReviewResponse::Unauthorized;
+    }
+
+    state.review.lock().await.approve(id).into()
+}
+
+#[derive(Debug, Deserialize)]
+struct RejectRequest {
+    note: Option<String>,
+}
+
+async fn reject_entry(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<u64>,
+    JsonBody(req): JsonBody<RejectRequest>,
+) -> ReviewResponse {
+    if !valid_header(&headers, &state.keys, Scope::Admin) {
+        tracing::warn!("invalid secret");
+        return ReviewResponse::Unauthorized;
+    }
+
+    state.review.lock().await.reject(id, req.note).into()
+}
+
+async fn regenerate_entry(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<u64>,
+) -> ReviewResponse {
+    if !valid_header(&headers, &state.keys, Scope::Admin) {
+        tracing::warn!("invalid secret");
+        return ReviewResponse::Unauthorized;
+    }
+
+    let (setup, prompt) = match state.review.lock().await.get(id) {
+        Ok(entry) => (entry.setup.clone(), entry.regeneration_prompt()),
+        Err(e) => return Err::<Entry, _>(e).into(),
+    };
+    let Some(prompt) = prompt else {
+        return ReviewResponse::InvalidRequest {
+            message: "entry was submitted without a prompt and can only be edited".into(),
+        };
+    };
+
+    let output = match generate(&state, setup, prompt, None).await {
+        Ok(output) => output,
+        Err(response) => return response,
+    };
+
+    match state.review.lock().await.replace(id, output.into()) {
+        Ok(entry) => {
+            let previous = entry.revisions.last().map(|r| review::text(&r.content));
+            ReviewResponse::Regenerated {
+                diff: diff::diff(&previous.unwrap_or_default(), &review::text(&entry.content)),
+                entry,
+            }
+        }
+        Err(e) => Err::<Entry, _>(e).into(),
+    }
+}
@@ -0,0 +1,641 @@
+use axum::{
+    extract::{Path, Query, State},
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+    routing::{get, post, put},
+    Json, Router,
+};
+use llama_cpp::grammar::LlamaGrammar;
+use serde::{Deserialize, Serialize};
+
+use super::{
+    history::{history_page, Page},
+    valid_header, JsonBody,
+};
+use crate::{
+    bounds::Violation,
+    budgets::{Budget, Limits},
+    goals::Goal,
+    handoff,
+    history::Flags,
+    jobs::JobError,
+    keys::Scope,
+    llm,
+    locale::Locale,
+    outcomes::{self, Field},
+    server::AppState,
+    sessions::SessionError,
+};
+
+pub fn route() -> Router<AppState> {
+    Router::new()
+        .route("/", post(create_session))
+        .route("/:session_id", get(get_session).delete(delete_session))
+        .route("/:session_id/budget", put(set_budget))
+        .route("/:session_id/history", get(get_session_history))
+        .route("/:session_id/handoff", post(hand_over_session))
+        .route("/:session_id/goal", put(set_goal))
+        .route("/:session_id/flags", put(set_flags))
+        .route("/:session_id/locale", put(set_locale))
+        .route("/:session_id/outcomes", post(extract_outcomes))
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum SessionResponse {
+    Success {
+        session_id: String,
+    },
+    Info {
+        session_id: String,
+        messages: usize,
+        speakers: Vec<String>,
+        party: Vec<String>,
+        goal: Option<Goal>,
+        flags: Flags,
+        locale: Option<Locale>,
+        budget: Option<Limits>,
+        busy: bool,
+    },
+    Unauthorized,
+    NotFound,
+    AlreadyExists,
+    TooManySessions,
+    PersonaNotFound,
+    /// The session is frozen or archived.
+    ReadOnly,
+    /// What the conversation settled, keyed by field name.
+    Outcomes {
+        outcomes: serde_json::Map<String, serde_json::Value>,
+        /// Values the model proposed outside their configured bounds, and what was done to them.
+        adjusted: Vec<Violation>,
+    },
+    InvalidOutcomes {
+        message: String,
+    },
+    Busy,
+    GenerateError {
+        message: String,
+    },
+}
+
+#[derive(Debug, Deserialize)]
+struct CreateSessionRequest {
+    /// Defaults to a generated id.
+    session_id: Option<String>,
+    /// The session's system message. Defaults to the sidecar's default one.
+    setup: Option<String>,
+    /// Starts the session as this persona from the registry, with its system prompt unless
+    /// `setup` is given, and its greeting.
+    persona: Option<String>,
+    /// What the session may spend generating an hour, after which `/generate` answers
+    /// `budget_exhausted`.
+    budget: Option<Limits>,
+    /// NPCs taking turns to reply, making it a group conversation.
+    #[serde(default)]
+    speakers: Vec<String>,
+    /// Players sharing the session, naming which of them each prompt is from.
+    #[serde(default)]
+    party: Vec<String>,
+    /// What the NPC steers the conversation toward, e.g. "get the player to accept quest 12".
+    goal: Option<String>,
+    /// The NPC's language, and how it writes numbers and dates, e.g. `de` or `pt-BR`.
+    locale: Option<Locale>,
+}
+
+async fn get_session_history(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(session_id): Path<String>,
+    Query(page): Query<Page>,
+) -> impl IntoResponse {
+    history_page(&state, &headers, Some(&session_id), page).await
+}
+
+async fn create_session(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    JsonBody(req): JsonBody<CreateSessionRequest>,
+) -> impl IntoResponse {
+    if !valid_header(&headers, &state.keys, Scope::Generate) {
+        tracing::warn!("invalid secret");
+        return (
+            StatusCode::UNAUTHORIZED,
+            Json(SessionResponse::Unauthorized),
+        );
+    }
+
+    let persona = match &req.persona {
+        Some(id) => match state.personas.get(id) {
+            Some(persona) => Some(persona),
+            None => {
+                return (
+                    StatusCode::NOT_FOUND,
+                    Json(SessionResponse::PersonaNotFound),
+                )
+            }
+        },
+        None => None,
+    };
+    let (setup, greeting) = match persona {
+        Some(persona) => (req.setup.or(Some(persona.system_prompt)), persona.greeting),
+        None => (req.setup, None),
+    };
+
+    let mut sessions = state.sessions.lock().await;
+    match sessions.create(
+        req.session_id,
+        setup,
+        req.speakers,
+        req.party,
+        req.goal,
+        req.locale,
+    ) {
+        Ok(session_id) => {
+            if let Some(history) = sessions.get(&session_id) {
+                let mut history = history.lock().await;
+                history.budget = req.budget.map(Budget::new);
+                if let Some(greeting) = greeting {
+                    history.set_greeting(greeting);
+                }
+                if let Some(db) = &state.history_db {
+                    if let Err(e) = db.lock().await.save(Some(&session_id), &history) {
+                        tracing::error!("unable to persist session {session_id:?}: {e}");
+                    }
+                }
+            }
+
+            (
+                StatusCode::OK,
+                Json(SessionResponse::Success { session_id }),
+            )
+        }
+        Err(SessionError::AlreadyExists(_)) => {
+            (StatusCode::CONFLICT, Json(SessionResponse::AlreadyExists))
+        }
+        Err(SessionError::TooMany) => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(SessionResponse::TooManySessions),
+        ),
+    }
+}
+
+async fn get_session(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(session_id): Path<String>,
+) -> impl IntoResponse {
+    if !valid_header(&headers, &state.keys, Scope::HistoryRead) {
+        tracing::warn!("invalid secret");
+        return (
+            StatusCode::UNAUTHORIZED,
+            Json(SessionResponse::Unauthorized),
+        );
+    }
+
+    let Some(history) = state.sessions.lock().await.get(&session_id) else {
+        return (StatusCode::NOT_FOUND, Json(SessionResponse::NotFound));
+    };
+
+    let locale = state.sessions.lock().await.locale(&session_id).cloned();
+    let (messages, speakers, party, goal, flags, budget, busy) = match history.try_lock() {
+        Ok(history) => (
+            history.history.len(),
+            history.speakers.clone(),
+            history.party.clone(),
+            history.goal.clone(),
+            history.flags,
+            history.budget.as_ref().map(Budget::limits),
+            false,
+        ),
+        Err(_) => (
+            0,
+            Vec::new(),
+            Vec::new(),
+            None,
+            Flags::default(),
+            None,
+            true,
+        ),
+    };
+
+    (
+        StatusCode::OK,
+        Json(SessionResponse::Info {
+            session_id,
+            messages,
+            speakers,
+            party,
+            goal,
+            flags,
+            locale,
+            budget,
+            busy,
+        }),
+    )
+}
+
+#[derive(Debug, Deserialize)]
+struct BudgetRequest {
+    /// Replaces the session's limits, or lifts them if `None`. What it already spent counts
+    /// against new limits.
+    budget: Option<Limits>,
+}
+
+/// Sets what the session may spend generating an hour, once any generation in it has finished.
+async fn set_budget(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(session_id): Path<String>,
+    JsonBody(req): JsonBody<BudgetRequest>,
+) -> impl IntoResponse {
+    if !valid_header(&headers, &state.keys, Scope::Generate) {
+        tracing::warn!("invalid secret");
+        return (
+            StatusCode::UNAUTHORIZED,
+            Json(SessionResponse::Unauthorized),
+        );
+    }
+
+    let Some(history) = state.sessions.lock().await.get(&session_id) else {
+        return (StatusCode::NOT_FOUND, Json(SessionResponse::NotFound));
+    };
+    let mut history = history.lock().await;
+    match (&mut history.budget, req.budget) {
+        (Some(budget), Some(limits)) => budget.set_limits(limits),
+        (budget, limits) => *budget = limits.map(Budget::new),
+    }
+    tracing::info!(budget = ?req.budget, "updated the budget of session {session_id:?}");
+
+    if let Some(db) = &state.history_db {
+        if let Err(e) = db.lock().await.save(Some(&session_id), &history) {
+            tracing::error!("unable to persist session {session_id:?}: {e}");
+        }
+    }
+
+    (
+        StatusCode::OK,
+        Json(SessionResponse::Success { session_id }),
+    )
+}
+
+async fn delete_session(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(session_id): Path<String>,
+) -> impl IntoResponse {
+    if !valid_header(&headers, &state.keys, Scope::Generate) {
+        tracing::warn!("invalid secret");
+        return (
+            StatusCode::UNAUTHORIZED,
+            Json(SessionResponse::Unauthorized),
+        );
+    }
+
+    match state.sessions.lock().await.remove(&session_id) {
+        true => {
+            if let Some(db) = &state.history_db {
+                if let Err(e) = db.lock().await.remove(Some(&session_id)) {
+                    tracing::error!("unable to remove persisted session {session_id:?}: {e}");
+                }
+            }
+
+            (
+                StatusCode::OK,
+                Json(SessionResponse::Success { session_id }),
+            )
+        }
+        false => (StatusCode::NOT_FOUND, Json(SessionResponse::NotFound)),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct HandoffRequest {
+    /// The new NPC's system message.
+    setup: String,
+    /// Replaces the conversation with a summary of it in the new system message, instead of
+    /// keeping it whole.
+    #[serde(default)]
+    summarize: bool,
+}
+
+/// Generates a one-off completion on the inference thread.
+async fn complete(
+    state: &AppState,
+    system: &str,
+    prompt: String,
+    max_tokens: usize,
+    grammar: Option<LlamaGrammar>,
+) -> Result<String, (StatusCode, SessionResponse)> {
+    let Some(model) = state.ai_model.load_full() else {
+        return Err((
+            StatusCode::SERVICE_UNAVAILABLE,
+            SessionResponse::GenerateError {
+                message: "no model is loaded".into(),
+            },
+        ));
+    };
+    let system = system.to_string();
+    let session = state.config.session();
+    let template = state.config.prompt_template;
+
+    let output = state
+        .jobs
+        .run(move || {
+            llm::complete_constrained(
+                &model, template, system, prompt, max_tokens, session, grammar,
+            )
+            .map_err(|e| e.to_string())
+        })
+        .await;
+    match output {
+        Ok(output) => output.map_err(|message| {
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                SessionResponse::GenerateError { message },
+            )
+        }),
+        Err(JobError::Full) => Err((StatusCode::CONFLICT, SessionResponse::Busy)),
+        Err(e) => Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            SessionResponse::GenerateError {
+                message: e.to_string(),
+            },
+        )),
+    }
+}
+
+/// Hands the session over to another NPC, once any generation in it has finished.
+async fn hand_over_session(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(session_id): Path<String>,
+    JsonBody(req): JsonBody<HandoffRequest>,
+) -> impl IntoResponse {
+    if !valid_header(&headers, &state.keys, Scope::Generate) {
+        tracing::warn!("invalid secret");
+        return (
+            StatusCode::UNAUTHORIZED,
+            Json(SessionResponse::Unauthorized),
+        );
+    }
+
+    let Some(history) = state.sessions.lock().await.get(&session_id) else {
+        return (StatusCode::NOT_FOUND, Json(SessionResponse::NotFound));
+    };
+    let mut history = history.lock().await;
+    if history.flags.read_only() {
+        return (StatusCode::CONFLICT, Json(SessionResponse::ReadOnly));
+    }
+
+    let summary = match req.summarize {
+        true => match complete(
+            &state,
+            handoff::SYSTEM_MESSAGE,
+            handoff::transcript(&history),
+            handoff::SUMMARY_MAX_TOKENS,
+            None,
+        )
+        .await
+        {
+            Ok(summary) => Some(summary),
+            Err((status, response)) => return (status, Json(response)),
+        },
+        false => None,
+    };
+    handoff::hand_over(&mut history, req.setup, summary.as_deref());
+    tracing::debug!("handed session {session_id:?} over");
+
+    if let Some(db) = &state.history_db {
+        if let Err(e) = db.lock().await.save(Some(&session_id), &history) {
+            tracing::error!("unable to persist session {session_id:?}: {e}");
+        }
+    }
+
+    (
+        StatusCode::OK,
+        Json(SessionResponse::Success { session_id }),
+    )
+}
+
+#[derive(Debug, Deserialize)]
+struct GoalRequest {
+    /// Replaces the session's goal, or clears it if `None`.
+    goal: Option<String>,
+}
+
+/// Sets a new goal for the session's NPC, once any generation in it has finished.
+async fn set_goal(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(session_id): Path<String>,
+    JsonBody(req): JsonBody<GoalRequest>,
+) -> impl IntoResponse {
+    if !valid_header(&headers, &state.keys, Scope::Generate) {
+        tracing::warn!("invalid secret");
+        return (
+            StatusCode::UNAUTHORIZED,
+            Json(SessionResponse::Unauthorized),
+        );
+    }
+
+    let Some(history) = state.sessions.lock().await.get(&session_id) else {
+        return (StatusCode::NOT_FOUND, Json(SessionResponse::NotFound));
+    };
+    let mut history = history.lock().await;
+    if history.flags.read_only() {
+        return (StatusCode::CONFLICT, Json(SessionResponse::ReadOnly));
+    }
+    history.goal = req.goal.map(Goal::new);
+
+    if let Some(db) = &state.history_db {
+        if let Err(e) = db.lock().await.save(Some(&session_id), &history) {
+            tracing::error!("unable to persist session {session_id:?}: {e}");
+        }
+    }
+
+    (
+        StatusCode::OK,
+        Json(SessionResponse::Success { session_id }),
+    )
+}
+
+/// Changes to a session's flags, leaving out those that stay as they are.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FlagsRequest {
+    /// Whether `/generate` refuses with `paused`, e.g. while a game master replies by hand.
+    paused: Option<bool>,
+    /// Whether the session's history is read-only.
+    frozen: Option<bool>,
+    /// Whether the session is archived, which also makes it read-only.
+    archived: Option<bool>,
+}
+
+/// Updates the session's flags, once any generation in it has finished.
+async fn set_flags(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(session_id): Path<String>,
+    JsonBody(req): JsonBody<FlagsRequest>,
+) -> impl IntoResponse {
+    if !valid_header(&headers, &state.keys, Scope::Generate) {
+        tracing::warn!("invalid secret");
+        return (
+            StatusCode::UNAUTHORIZED,
+            Json(SessionResponse::Unauthorized),
+        );
+    }
+
+    let Some(history) = state.sessions.lock().await.get(&session_id) else {
+        return (StatusCode::NOT_FOUND, Json(SessionResponse::NotFound));
+    };
+    let mut history = history.lock().await;
+    let flags = &mut history.flags;
+    flags.paused = req.paused.unwrap_or(flags.paused);
+    flags.frozen = req.frozen.unwrap_or(flags.frozen);
+    flags.archived = req.archived.unwrap_or(flags.archived);
+    tracing::info!(flags = ?history.flags, "updated the flags of session {session_id:?}");
+
+    if let Some(db) = &state.history_db {
+        if let Err(e) = db.lock().await.save(Some(&session_id), &history) {
+            tracing::error!("unable to persist session {session_id:?}: {e}");
+        }
+    }
+
+    (
+        StatusCode::OK,
+        Json(SessionResponse::Success { session_id }),
+    )
+}
+
+#[derive(Debug, Deserialize)]
+struct LocaleRequest {
+    /// Replaces the session's locale, or clears it if `None`.
+    locale: Option<Locale>,
+}
+
+/// Sets the session's locale, once any generation in it has finished.
+async fn set_locale(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(session_id): Path<String>,
+    JsonBody(req): JsonBody<LocaleRequest>,
+) -> impl IntoResponse {
+    if !valid_header(&headers, &state.keys, Scope::Generate) {
+        tracing::warn!("invalid secret");
+        return (
+            StatusCode::UNAUTHORIZED,
+            Json(SessionResponse::Unauthorized),
+        );
+    }
+
+    let Some(history) = state.sessions.lock().await.get(&session_id) else {
+        return (StatusCode::NOT_FOUND, Json(SessionResponse::NotFound));
+    };
+    let mut history = history.lock().await;
+    if history.flags.read_only() {
+        return (StatusCode::CONFLICT, Json(SessionResponse::ReadOnly));
+    }
+    history.locale = req.locale;
+    state
+        .sessions
+        .lock()
+        .await
+        .set_locale(&session_id, history.locale.clone());
+
+    if let Some(db) = &state.history_db {
+        if let Err(e) = db.lock().await.save(Some(&session_id), &history) {
+            tracing::error!("unable to persist session {session_id:?}: {e}");
+        }
+    }
+
+    (
+        StatusCode::OK,
+        Json(SessionResponse::Success { session_id }),
+    )
+}
+
+#[derive(Debug, Deserialize)]
+struct OutcomesRequest {
+    /// The outcomes to extract, e.g. `{"name": "quest_accepted", "type": "boolean"}`.
+    fields: Vec<Field>,
+}
+
+/// Extracts structured outcomes from the session's conversation, once any generation in it has
+/// finished.
+async fn extract_outcomes(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(session_id): Path<String>,
+    JsonBody(req): JsonBody<OutcomesRequest>,
+) -> impl IntoResponse {
+    if !valid_header(&headers, &state.keys, Scope::Generate) {
+        tracing::warn!("invalid secret");
+        return (
+            StatusCode::UNAUTHORIZED,
+            Json(SessionResponse::Unauthorized),
+        );
+    }
+
+    let grammar = outcomes::validate(&req.fields).and_then(|()| {
+        outcomes::grammar(&req.fields)
+            .parse::<LlamaGrammar>()
+            .map_err(|e| outcomes::OutcomeError::Grammar(e.to_string()))
+    });
+    let grammar = match grammar {
+        Ok(grammar) => grammar,
+        Err(e) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(SessionResponse::InvalidOutcomes {
+                    message: e.to_string(),
+                }),
+            );
+        }
+    };
+
+    let Some(history) = state.sessions.lock().await.get(&session_id) else {
+        return (StatusCode::NOT_FOUND, Json(SessionResponse::NotFound));
+    };
+    let prompt = outcomes::prompt(&req.fields, &*history.lock().await);
+
+    let output = match complete(
+        &state,
+        outcomes::SYSTEM_MESSAGE,
+        prompt,
+        outcomes::MAX_TOKENS,
+        Some(grammar),
+    )
+    .await
+    {
+        Ok(output) => output,
+        Err((status, response)) => return (status, Json(response)),
+    };
+    match serde_json::from_str(&output) {
+        Ok(mut outcomes) => {
+            let adjusted = state.bounds.check(&mut outcomes);
+            for violation in &adjusted {
+                tracing::warn!(
+                    "outcome {:?} of {} in session {session_id:?} is out of bounds, {:?}",
+                    violation.field,
+                    violation.proposed,
+                    violation.action
+                );
+            }
+            (
+                StatusCode::OK,
+                Json(SessionResponse::Outcomes { outcomes, adjusted }),
+            )
+        }
+        Err(e) => {
+            tracing::error!("unable to parse extracted outcomes {output:?}: {e}");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(SessionResponse::GenerateError {
+                    message: "the extracted outcomes were incomplete".into(),
+                }),
+            )
+        }
+    }
+}
@@ -0,0 +1,67 @@
+//! Serving statistics, such as how long players wait for replies.
+
+use axum::{
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+    routing::get,
+    Json, Router,
+};
+use serde::Serialize;
+
+use super::valid_header;
+use crate::{
+    cache::CacheStats, failover::ChainStats, governor::GovernorStats, keys::Scope, kv::PrefixStats,
+    server::AppState, slo::LatencyStats,
+};
+
+pub fn route() -> Router<AppState> {
+    Router::new().route("/", get(get_stats))
+}
+
+#[derive(Debug, Serialize)]
+struct Stats {
+    latency: LatencyStats,
+    /// How long the primary takes to respond, on a read-only replica that forwards to one, or else
+    /// the hosted model backend.
+    backend_latency: Option<LatencyStats>,
+    /// How often the local model, hosted backend and fallback lines each replied or failed to.
+    backends: ChainStats,
+    /// How often requests were answered from the response cache.
+    cache: CacheStats,
+    /// How much prompt evaluation the system prompts fed ahead of time saved.
+    prefix_cache: PrefixStats,
+    /// How much of the model's time background generation may take, and how often it waited.
+    background: GovernorStats,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum StatsResponse {
+    Stats(Box<Stats>),
+    Unauthorized,
+}
+
+async fn get_stats(State(state): State<AppState>, headers: HeaderMap) -> impl IntoResponse {
+    if !valid_header(&headers, &state.keys, Scope::Admin) {
+        tracing::warn!("invalid secret");
+        return (StatusCode::UNAUTHORIZED, Json(StatsResponse::Unauthorized));
+    }
+
+    (
+        StatusCode::OK,
+        Json(StatsResponse::Stats(Box::new(Stats {
+            latency: state.latency.snapshot(),
+            backend_latency: state
+                .replica
+                .as_ref()
+                .filter(|replica| replica.forwards())
+                .map(|replica| replica.backend_latency())
+                .or_else(|| state.backend.as_ref().map(|backend| backend.latency())),
+            backends: state.chain.stats(),
+            cache: state.cache.stats(),
+            prefix_cache: state.prefixes.stats(),
+            background: state.governor.stats(),
+        }))),
+    )
+}
@@ -0,0 +1,125 @@
+//! Reads and replaces the system message at runtime, so an NPC's ground rules can change without
+//! rebuilding or restarting the sidecar.
+//!
+//! Without a session, this is the default conversation's system message, which sessions started
+//! without a setup of their own also get. With one, it is only that session's. A session's
+//! replacement is persisted with it, but the default one lasts until a restart, after which the
+//! configured system message applies again.
+
+use axum::{
+    extract::{Query, State},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+
+use super::{conversation, valid_header, JsonBody, SessionQuery};
+use crate::{keys::Scope, server::AppState};
+
+pub fn route() -> Router<AppState> {
+    Router::new().route(
+        "/system_prompt",
+        get(get_system_prompt).put(set_system_prompt),
+    )
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum SystemPromptResponse {
+    Success {
+        system_prompt: String,
+    },
+    Unauthorized,
+    SessionNotFound,
+    /// The conversation is frozen or archived.
+    ReadOnly,
+    InvalidRequest {
+        message: String,
+    },
+}
+
+impl IntoResponse for SystemPromptResponse {
+    fn into_response(self) -> Response {
+        let status = match &self {
+            Self::Success { .. } => StatusCode::OK,
+            Self::Unauthorized => StatusCode::UNAUTHORIZED,
+            Self::SessionNotFound => StatusCode::NOT_FOUND,
+            Self::ReadOnly => StatusCode::CONFLICT,
+            Self::InvalidRequest { .. } => StatusCode::BAD_REQUEST,
+        };
+
+        (status, Json(self)).into_response()
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct SystemPromptRequest {
+    system_prompt: String,
+}
+
+async fn get_system_prompt(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<SessionQuery>,
+) -> SystemPromptResponse {
+    if !valid_header(&headers, &state.keys, Scope::HistoryRead) {
+        tracing::warn!("invalid secret");
+        return SystemPromptResponse::Unauthorized;
+    }
+    let Some(history) = conversation(&state, query.session_id.as_deref()).await else {
+        return SystemPromptResponse::SessionNotFound;
+    };
+    let system_prompt = history.lock().await.system.content().to_string();
+
+    SystemPromptResponse::Success { system_prompt }
+}
+
+/// Replaces the system message once any generation in the conversation has finished. Replies
+/// already in the conversation are kept.
+async fn set_system_prompt(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<SessionQuery>,
+    JsonBody(req): JsonBody<SystemPromptRequest>,
+) -> SystemPromptResponse {
+    if !valid_header(&headers, &state.keys, Scope::Admin) {
+        tracing::warn!("invalid secret");
+        return SystemPromptResponse::Unauthorized;
+    }
+    if req.system_prompt.trim().is_empty() {
+        return SystemPromptResponse::InvalidRequest {
+            message: "system_prompt must not be empty".into(),
+        };
+    }
+    let session_id = query.session_id.as_deref();
+    let Some(history) = conversation(&state, session_id).await else {
+        return SystemPromptResponse::SessionNotFound;
+    };
+
+    {
+        let mut history = history.lock().await;
+        if history.flags.read_only() {
+            return SystemPromptResponse::ReadOnly;
+        }
+        history.set_system(req.system_prompt.clone());
+        tracing::info!("replaced the system message of {session_id:?}");
+        if let Some(db) = &state.history_db {
+            if let Err(e) = db.lock().await.save(session_id, &history) {
+                tracing::error!("unable to persist the system message: {e}");
+            }
+        }
+    }
+    if session_id.is_none() {
+        state
+            .sessions
+            .lock()
+            .await
+            .set_default_system(req.system_prompt.clone());
+    }
+
+    SystemPromptResponse::Success {
+        system_prompt: req.system_prompt,
+    }
+}
@@ -0,0 +1,147 @@
+//! Exposes the tokenizer, so clients can count a prompt's tokens before sending it and budget
+//! `max_tokens` to fit. This is the one at `AI_SIDECAR_TOKENIZER_PATH` if it is set, or else the
+//! loaded model's.
+
+use std::sync::Arc;
+
+use axum::{
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+    routing::post,
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+
+use super::{valid_header, JsonBody};
+use crate::{
+    keys::Scope,
+    server::AppState,
+    tokenizer::{Tokenizer, TokenizerError},
+};
+
+pub fn route() -> Router<AppState> {
+    Router::new()
+        .route("/tokenize", post(tokenize))
+        .route("/detokenize", post(detokenize))
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum TokensResponse {
+    Tokens {
+        tokens: Vec<i32>,
+        count: usize,
+    },
+    Text {
+        text: String,
+        count: usize,
+    },
+    Unauthorized,
+    /// A token id outside the tokenizer's vocabulary.
+    InvalidToken {
+        token: i32,
+    },
+    TokenizeError {
+        message: String,
+    },
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenizeRequest {
+    text: String,
+    /// Counts the beginning-of-sequence token every prompt starts with.
+    #[serde(default)]
+    add_bos: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct DetokenizeRequest {
+    tokens: Vec<i32>,
+}
+
+fn tokenizer(state: &AppState) -> Option<Arc<dyn Tokenizer>> {
+    match &state.tokenizer {
+        Some(tokenizer) => Some(tokenizer.clone()),
+        None => state
+            .ai_model
+            .load_full()
+            .map(|model| model as Arc<dyn Tokenizer>),
+    }
+}
+
+fn unavailable() -> (StatusCode, Json<TokensResponse>) {
+    (
+        StatusCode::SERVICE_UNAVAILABLE,
+        Json(TokensResponse::TokenizeError {
+            message: "no model is loaded".into(),
+        }),
+    )
+}
+
+async fn tokenize(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    JsonBody(req): JsonBody<TokenizeRequest>,
+) -> impl IntoResponse {
+    if !valid_header(&headers, &state.keys, Scope::Generate) {
+        tracing::warn!("invalid secret");
+        return (StatusCode::UNAUTHORIZED, Json(TokensResponse::Unauthorized));
+    }
+    let Some(tokenizer) = tokenizer(&state) else {
+        return unavailable();
+    };
+
+    match tokenizer.encode(&req.text, req.add_bos) {
+        Ok(tokens) => (
+            StatusCode::OK,
+            Json(TokensResponse::Tokens {
+                count: tokens.len(),
+                tokens,
+            }),
+        ),
+        Err(e) => {
+            tracing::warn!("unable to tokenize: {e}");
+            (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                Json(TokensResponse::TokenizeError {
+                    message: e.to_string(),
+                }),
+            )
+        }
+    }
+}
+
+async fn detokenize(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    JsonBody(req): JsonBody<DetokenizeRequest>,
+) -> impl IntoResponse {
+    if !valid_header(&headers, &state.keys, Scope::Generate) {
+        tracing::warn!("invalid secret");
+        return (StatusCode::UNAUTHORIZED, Json(TokensResponse::Unauthorized));
+    }
+    let Some(tokenizer) = tokenizer(&state) else {
+        return unavailable();
+    };
+
+    match tokenizer.decode(&req.tokens) {
+        Ok(text) => (
+            StatusCode::OK,
+            Json(TokensResponse::Text {
+                text,
+                count: req.tokens.len(),
+            }),
+        ),
+        Err(TokenizerError::InvalidToken(token)) => (
+            StatusCode::BAD_REQUEST,
+            Json(TokensResponse::InvalidToken { token }),
+        ),
+        Err(e) => (
+            StatusCode::UNPROCESSABLE_ENTITY,
+            Json(TokensResponse::TokenizeError {
+                message: e.to_string(),
+            }),
+        ),
+    }
+}
@@ -0,0 +1,306 @@
+//! Hosted models `/generate` can reply with instead of the local llama.cpp one, for operators who
+//! would rather not run inference themselves.
+//!
+//! A backend is configured in the config file's `anthropic` section. The sidecar then loads no
+//! local model, and requests that don't name one of the extra `models` are answered by the
+//! backend. It is sent the conversation as chat messages rather than through the prompt format,
+//! with the same system message and sampler settings. Those the API lacks, such as `min_p` and
+//! Mirostat, are ignored. Grammars, kept sessions and the moderation classifier need a local model,
+//! so they don't apply, though moderation rules do.
+//!
+//! Each call carries the request's trace on, and how long the backend takes is tracked apart from
+//! the total.
+
+use std::time::Instant;
+
+use axum::{async_trait, http::HeaderMap};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    history::{Message, MessageType},
+    llm,
+    slo::{self, Latency, LatencyStats},
+    tracecontext::TraceContext,
+};
+
+const DEFAULT_ANTHROPIC_URL: &str = "https://api.anthropic.com";
+const ANTHROPIC_VERSION: &str = "2023-06-01";
+
+#[derive(Debug, thiserror::Error)]
+pub enum BackendError {
+    #[error(transparent)]
+    Http(#[from] reqwest::Error),
+    #[error("the backend refused the request: {0}")]
+    Refused(String),
+}
+
+/// What a backend is asked to reply to.
+#[derive(Debug, Clone)]
+pub struct BackendRequest {
+    pub system: String,
+    /// The conversation so far, ending with the player's prompt.
+    pub messages: Vec<Message>,
+    /// Who replies, in a group conversation.
+    pub speaker: Option<String>,
+    pub max_tokens: usize,
+    pub sampler: llm::SamplerOptions,
+    pub stop: Vec<String>,
+    /// The trace of the request being answered, if it has one.
+    pub trace: Option<TraceContext>,
+}
+
+/// Generates replies somewhere other than the local model.
+#[async_trait]
+pub trait LlmBackend: Send + Sync {
+    /// Names the backend and its model, for logs.
+    fn name(&self) -> String;
+
+    async fn generate(&self, request: BackendRequest) -> Result<llm::Generated, BackendError>;
+
+    /// How long the backend takes to respond.
+    fn latency(&self) -> LatencyStats;
+}
+
+/// The config file's `anthropic` section.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct AnthropicConfig {
+    /// E.g. `claude-3-5-haiku-latest`.
+    pub model: String,
+    /// Overridden by `AI_SIDECAR_ANTHROPIC_API_KEY`, so it can be left out of the file.
+    pub api_key: Option<String>,
+    /// Caps every request's `max_tokens`, if set.
+    pub max_tokens: Option<usize>,
+    /// Where the Messages API is served, e.g. through a gateway. Anthropic's own, if unset.
+    pub base_url: Option<String>,
+    /// Keeps the local model loaded and replying first, handing requests over to this backend
+    /// only when it errors, is too busy or isn't loaded.
+    #[serde(default)]
+    pub failover: bool,
+    /// With `failover`, also hands over requests that have waited this long for the local model
+    /// without it starting on them.
+    pub failover_after_secs: Option<u64>,
+}
+
+/// The Anthropic Messages API.
+#[derive(Debug)]
+pub struct Anthropic {
+    http: reqwest::Client,
+    config: AnthropicConfig,
+    /// Until the response headers arrive.
+    latency: Latency,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+struct ChatMessage {
+    role: &'static str,
+    content: String,
+}
+
+#[derive(Debug, Serialize)]
+struct MessagesRequest<'a> {
+    model: &'a str,
+    max_tokens: usize,
+    system: &'a str,
+    messages: Vec<ChatMessage>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_k: Option<i32>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    stop_sequences: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ContentBlock {
+    #[serde(default)]
+    text: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct MessagesUsage {
+    input_tokens: usize,
+    output_tokens: usize,
+}
+
+#[derive(Debug, Deserialize)]
+struct MessagesResponse {
+    content: Vec<ContentBlock>,
+    usage: MessagesUsage,
+}
+
+#[derive(Debug, Deserialize)]
+struct ErrorDetail {
+    message: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct ErrorResponse {
+    error: ErrorDetail,
+}
+
+/// The conversation as alternating user and assistant messages, starting with the user's as the
+/// API requires. Speakers are named like in a prompt template, and consecutive messages from the
+/// same side, such as a party's players, are joined.
+fn chat_messages(messages: &[Message]) -> Vec<ChatMessage> {
+    let mut chat: Vec<ChatMessage> = Vec::new();
+    for message in messages {
+        let role = match message.message_type() {
+            MessageType::User => "user",
+            MessageType::Assistant if chat.is_empty() => continue,
+            MessageType::Assistant => "assistant",
+            MessageType::System => continue,
+        };
+        let content = match message.speaker() {
+            Some(speaker) => format!("{speaker}: {}", message.content()),
+            None => message.content().to_string(),
+        };
+        match chat.last_mut() {
+            Some(last) if last.role == role => {
+                last.content.push_str("\n\n");
+                last.content.push_str(&content);
+            }
+            _ => chat.push(ChatMessage { role, content }),
+        }
+    }
+
+    chat
+}
+
+impl Anthropic {
+    pub fn new(http: reqwest::Client, config: AnthropicConfig) -> Self {
+        Self {
+            http,
+            config,
+            latency: Latency::new(None, slo::DEFAULT_WINDOW),
+        }
+    }
+}
+
+#[async_trait]
+impl LlmBackend for Anthropic {
+    fn name(&self) -> String {
+        format!("anthropic/{}", self.config.model)
+    }
+
+    async fn generate(&self, request: BackendRequest) -> Result<llm::Generated, BackendError> {
+        let system = match &request.speaker {
+            Some(speaker) => format!("{}\n\nReply as {speaker}.", request.system),
+            None => request.system,
+        };
+        let max_tokens = match self.config.max_tokens {
+            Some(max) => request.max_tokens.min(max),
+            None => request.max_tokens,
+        };
+        let body = MessagesRequest {
+            model: &self.config.model,
+            max_tokens,
+            system: &system,
+            messages: chat_messages(&request.messages),
+            temperature: request.sampler.temperature,
+            top_p: request.sampler.top_p,
+            top_k: request.sampler.top_k,
+            stop_sequences: request.stop,
+        };
+        let url = self
+            .config
+            .base_url
+            .as_deref()
+            .unwrap_or(DEFAULT_ANTHROPIC_URL)
+            .trim_end_matches('/');
+        let mut headers = HeaderMap::new();
+        if let Some(context) = &request.trace {
+            context.inject(&mut headers);
+        }
+
+        let started = Instant::now();
+        let response = self
+            .http
+            .post(format!("{url}/v1/messages"))
+            .headers(headers)
+            .header(
+                "x-api-key",
+                self.config.api_key.as_deref().unwrap_or_default(),
+            )
+            .header("anthropic-version", ANTHROPIC_VERSION)
+            .json(&body)
+            .send()
+            .await?;
+        let backend = started.elapsed();
+        self.latency.record(backend);
+        tracing::Span::current().record("backend_ms", backend.as_millis() as u64);
+        if !response.status().is_success() {
+            let status = response.status();
+            let message = match response.json::<ErrorResponse>().await {
+                Ok(error) => error.error.message,
+                Err(_) => status.to_string(),
+            };
+            return Err(BackendError::Refused(message));
+        }
+        let response: MessagesResponse = response.json().await?;
+
+        let text = response
+            .content
+            .into_iter()
+            .map(|block| block.text)
+            .collect::<String>();
+        // Models tend to name the speaker the way the conversation does.
+        let text = match &request.speaker {
+            Some(speaker) => text
+                .strip_prefix(&format!("{speaker}:"))
+                .map(str::to_string)
+                .unwrap_or(text),
+            None => text,
+        };
+
+        Ok(llm::Generated {
+            text,
+            usage: llm::Usage {
+                prompt_tokens: response.usage.input_tokens,
+                completion_tokens: response.usage.output_tokens,
+            },
+            ..llm::Generated::default()
+        })
+    }
+
+    fn latency(&self) -> LatencyStats {
+        self.latency.snapshot()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::history::History;
+
+    use super::*;
+
+    #[test]
+    fn alternates_chat_messages() {
+        let mut history = History::new("You are a bard.".into());
+        history.push(MessageType::User, "Sing!".into());
+        history.push(MessageType::User, "Louder!".into());
+        history.push(MessageType::Assistant, "La la la.".into());
+        history.push_reply(Some("Wren".into()), "Encore?".into());
+        history.push(MessageType::User, "Thanks.".into());
+
+        assert_eq!(
+            chat_messages(&history.history),
+            [
+                ChatMessage {
+                    role: "user",
+                    content: "Sing!\n\nLouder!".into(),
+                },
+                ChatMessage {
+                    role: "assistant",
+                    content: "La la la.\n\nWren: Encore?".into(),
+                },
+                ChatMessage {
+                    role: "user",
+                    content: "Thanks.".into(),
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,102 @@
+//! What the sidecar is running with, logged once it is listening and served at `/admin/config`,
+//! since the settings come from a config file, env vars and defaults alike.
+
+use std::{collections::BTreeMap, path::PathBuf};
+
+use serde::Serialize;
+
+use crate::{flags::Flag, server::AppState};
+
+/// Shown in place of every secret.
+const MASK: &str = "********";
+
+#[derive(Debug, Serialize)]
+pub struct Sources {
+    /// The config file, if one is set.
+    file: Option<PathBuf>,
+    /// Env vars that overrode it.
+    env: Vec<&'static str>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ModelSummary {
+    name: String,
+    path: PathBuf,
+    loaded: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Banner {
+    sources: Sources,
+    /// The effective settings, in the config file's format.
+    settings: serde_json::Value,
+    models: Vec<ModelSummary>,
+    /// Which optional parts of the sidecar are turned on.
+    features: BTreeMap<&'static str, bool>,
+    /// Which experimental subsystems are turned on, as toggled now.
+    flags: BTreeMap<Flag, bool>,
+    endpoints: Vec<String>,
+}
+
+impl Banner {
+    pub fn new(state: &AppState) -> Result<Self, serde_json::Error> {
+        let config = &state.config;
+        let mut settings = config.shareable()?;
+        if let (Some(settings), Ok(_)) = (settings.as_object_mut(), config.secret()) {
+            settings.insert("secret".into(), MASK.into());
+        }
+
+        let mut models = vec![ModelSummary {
+            name: "default".into(),
+            path: config.model_path.clone(),
+            loaded: state.ai_model.load().is_some(),
+        }];
+        models.extend(state.models.iter().map(|(name, model)| ModelSummary {
+            name: name.to_string(),
+            path: model.path.clone(),
+            loaded: true,
+        }));
+
+        let features = BTreeMap::from([
+            ("anthropic", state.backend.is_some()),
+            ("chaos", cfg!(feature = "chaos")),
+            ("degradation", config.degradation.is_some()),
+            ("dialogue_corpus", state.dialogue.load().is_some()),
+            ("embedding_model", state.embedding_model.is_some()),
+            ("fallback", state.fallback.is_some()),
+            ("kept_sessions", state.kept_sessions.enabled()),
+            ("moderation", state.moderator.is_some()),
+            (
+                "prefix_cache",
+                config.prefix_cache && config.prefix_cache_size > 0,
+            ),
+            ("persistence", state.history_db.is_some()),
+            ("rate_limit", state.rate_limiter.is_some()),
+            ("replica", state.replica.is_some()),
+            ("response_cache", state.cache.enabled()),
+            ("summarization", config.summarize_above_tokens.is_some()),
+            ("tokenizer", state.tokenizer.is_some()),
+            ("warmup", config.warmup),
+        ]);
+
+        Ok(Self {
+            sources: Sources {
+                file: config.file.clone(),
+                env: config.overrides.clone(),
+            },
+            settings,
+            models,
+            features,
+            flags: state.flags.list(),
+            endpoints: vec![format!("http://{}/api/v1", state.address)],
+        })
+    }
+
+    /// Logs the banner as a single structured event.
+    pub fn log(&self) {
+        match serde_json::to_string(self) {
+            Ok(banner) => tracing::info!(banner = %banner, "listening on {}", self.endpoints[0]),
+            Err(e) => tracing::error!("unable to summarize the configuration: {e}"),
+        }
+    }
+}
@@ -0,0 +1,191 @@
+//! Logit bias and banned words, so some tokens can be made more or less likely and some words
+//! kept out of NPC dialogue altogether.
+//!
+//! The config's bias applies to every generation, and a request's adds to it. A bias of -100 or
+//! less bans a token, and nothing added to it lifts the ban. Banned words are tokenized the ways
+//! they could appear in a reply, lowercase or capitalized and with or without a space before
+//! them, and each of those token sequences is kept from being completed.
+
+use std::collections::HashMap;
+
+use llama_cpp::{Sampler, Token};
+use llama_cpp_sys::{llama_context, llama_token_data_array};
+use serde::{Deserialize, Serialize};
+
+use crate::tokenizer::Tokenizer;
+
+/// The bias at or below which a token is banned.
+pub const BAN: f32 = -100.0;
+const MAX_BIAS: f32 = 100.0;
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub struct Bias {
+    /// Added to each token's logit, by token id.
+    #[serde(default)]
+    pub logit_bias: HashMap<i32, f32>,
+    /// Words replies may never contain.
+    #[serde(default)]
+    pub banned_words: Vec<String>,
+}
+
+fn combine(a: f32, b: f32) -> f32 {
+    match a <= BAN || b <= BAN {
+        true => BAN,
+        false => (a + b).clamp(BAN, MAX_BIAS),
+    }
+}
+
+impl Bias {
+    /// This bias with `other` added to it.
+    pub fn with(&self, other: Bias) -> Bias {
+        let mut bias = self.clone();
+        for (token, value) in other.logit_bias {
+            let combined = match bias.logit_bias.get(&token) {
+                Some(existing) => combine(*existing, value),
+                None => combine(0.0, value),
+            };
+            bias.logit_bias.insert(token, combined);
+        }
+        bias.banned_words.extend(other.banned_words);
+
+        bias
+    }
+
+    /// The token sequences that spell the banned words.
+    fn banned_sequences(&self, tokenizer: &dyn Tokenizer) -> Vec<Vec<Token>> {
+        let mut sequences = Vec::new();
+        for word in self.banned_words.iter().map(|word| word.trim()) {
+            let mut chars = word.chars();
+            let Some(first) = chars.next() else {
+                continue;
+            };
+            let capitalized = first.to_uppercase().chain(chars).collect::<String>();
+            for spelling in [word, &capitalized] {
+                for text in [spelling.to_string(), format!(" {spelling}")] {
+                    match tokenizer.encode(&text, false) {
+                        Ok(tokens) if !tokens.is_empty() => {
+                            let tokens = tokens.into_iter().map(Token).collect();
+                            if !sequences.contains(&tokens) {
+                                sequences.push(tokens);
+                            }
+                        }
+                        Ok(_) => {}
+                        Err(e) => tracing::warn!("unable to ban {text:?}: {e}"),
+                    }
+                }
+            }
+        }
+
+        sequences
+    }
+}
+
+/// Applies a [`Bias`] to the candidates before another sampler picks among them.
+pub struct BiasedSampler<S> {
+    sampler: S,
+    logit_bias: HashMap<i32, f32>,
+    banned: Vec<Vec<Token>>,
+}
+
+impl<S> BiasedSampler<S> {
+    pub fn new(sampler: S, bias: &Bias, tokenizer: &dyn Tokenizer) -> Self {
+        let (bans, logit_bias) = bias
+            .logit_bias
+            .iter()
+            .map(|(token, value)| (*token, value.clamp(BAN, MAX_BIAS)))
+            .partition::<HashMap<_, _>, _>(|(_, value)| *value <= BAN);
+        let mut banned = bias.banned_sequences(tokenizer);
+        banned.extend(bans.into_keys().map(|token| vec![Token(token)]));
+
+        Self {
+            sampler,
+            logit_bias,
+            banned,
+        }
+    }
+
+    /// The tokens that would complete a banned sequence after `tokens`.
+    fn banned_next(&self, tokens: &[Token]) -> Vec<i32> {
+        self.banned
+            .iter()
+            .filter_map(|sequence| {
+                let (last, start) = sequence.split_last()?;
+                tokens.ends_with(start).then_some(last.0)
+            })
+            .collect()
+    }
+}
+
+impl<S: Sampler> Sampler for BiasedSampler<S> {
+    fn sample(
+        &mut self,
+        context: *mut llama_context,
+        tokens: &[Token],
+        candidates_p: llama_token_data_array,
+    ) -> Token {
+        let banned = self.banned_next(tokens);
+        if !self.logit_bias.is_empty() || !banned.is_empty() {
+            // SAFETY: llama.cpp hands over `size` candidates, which are ours until we return.
+            let candidates =
+                unsafe { std::slice::from_raw_parts_mut(candidates_p.data, candidates_p.size) };
+            for candidate in candidates {
+                if banned.contains(&candidate.id) {
+                    candidate.logit = f32::NEG_INFINITY;
+                } else if let Some(bias) = self.logit_bias.get(&candidate.id) {
+                    candidate.logit += bias;
+                }
+            }
+        }
+
+        self.sampler.sample(context, tokens, candidates_p)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Chars;
+
+    impl Tokenizer for Chars {
+        fn encode(
+            &self,
+            text: &str,
+            _add_bos: bool,
+        ) -> Result<Vec<i32>, crate::tokenizer::TokenizerError> {
+            Ok(text.chars().map(|c| c as i32).collect())
+        }
+
+        fn decode(&self, tokens: &[i32]) -> Result<String, crate::tokenizer::TokenizerError> {
+            Ok(tokens
+                .iter()
+                .filter_map(|token| char::from_u32(*token as u32))
+                .collect())
+        }
+    }
+
+    #[test]
+    fn bans_words_and_keeps_configured_bans() {
+        let config = Bias {
+            logit_bias: HashMap::from([(1, BAN), (2, 5.0)]),
+            banned_words: vec!["ok".into()],
+        };
+        let bias = config.with(Bias {
+            logit_bias: HashMap::from([(1, 100.0), (2, -10.0), (3, -500.0)]),
+            banned_words: Vec::new(),
+        });
+        assert_eq!(bias.logit_bias[&1], BAN);
+        assert_eq!(bias.logit_bias[&2], -5.0);
+        assert_eq!(bias.logit_bias[&3], BAN);
+
+        let sampler = BiasedSampler::new((), &bias, &Chars);
+        let tokens = |text: &str| text.chars().map(|c| Token(c as i32)).collect::<Vec<_>>();
+        let mut next = sampler.banned_next(&tokens("well o"));
+        next.sort();
+        next.dedup();
+        assert_eq!(next, [1, 3, 'k' as i32]);
+        assert!(sampler.banned_next(&tokens("\"O")).contains(&('k' as i32)));
+        assert!(!sampler.banned_next(&tokens("hi")).contains(&('k' as i32)));
+        assert_eq!(sampler.logit_bias, HashMap::from([(2, -5.0)]));
+    }
+}
@@ -0,0 +1,306 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Display,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use clap::Parser;
+use reqwest::StatusCode;
+use serde_json::json;
+use tokio::sync::Mutex;
+
+/// Drives a running sidecar with a mix of game-like requests and reports latency and throughput.
+#[derive(Debug, Parser)]
+struct Args {
+    /// Base URL of the sidecar. Defaults to localhost on `AI_SIDECAR_PORT`.
+    #[arg(long)]
+    url: Option<String>,
+    /// Shared secret. Defaults to `AI_SIDECAR_SECRET`.
+    #[arg(long, env = "AI_SIDECAR_SECRET", hide_env_values = true)]
+    secret: String,
+    /// Number of requests in flight at once.
+    #[arg(long, default_value_t = 1)]
+    concurrency: usize,
+    /// Total number of requests to send.
+    #[arg(long, default_value_t = 20)]
+    requests: usize,
+    /// Relative weights of each request kind, e.g. `barks=5,chats=3,quests=1`.
+    #[arg(long, default_value = "barks=5,chats=3,quests=1", value_parser = parse_mix)]
+    mix: Mix,
+    /// Clear the conversation history before starting.
+    #[arg(long)]
+    clear_history: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Kind {
+    Bark,
+    Chat,
+    Quest,
+}
+
+impl Kind {
+    fn body(&self) -> serde_json::Value {
+        match self {
+            Self::Bark => json!({
+                "prompt": "Say one short line as the player walks past your stall.",
+                "max_tokens": 32,
+            }),
+            Self::Chat => json!({
+                "prompt": "I'm new in town. What should I know about this place?",
+                "max_tokens": 128,
+            }),
+            Self::Quest => json!({
+                "setup": "You are a quest giver in a fantasy village. Describe quests with a title, an objective and a reward.",
+                "prompt": "Do you have any work for me?",
+                "max_tokens": 256,
+            }),
+        }
+    }
+}
+
+impl Display for Kind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Self::Bark => "barks",
+                Self::Chat => "chats",
+                Self::Quest => "quests",
+            }
+        )
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Mix(Vec<(Kind, usize)>);
+
+/// Each kind may only be weighted once, since the report has a line per entry.
+fn parse_mix(value: &str) -> Result<Mix, String> {
+    let mix = value
+        .split(',')
+        .map(|entry| {
+            let (kind, weight) = entry
+                .split_once('=')
+                .ok_or_else(|| format!("expected kind=weight, got {entry:?}"))?;
+            let kind = match kind.trim() {
+                "barks" => Kind::Bark,
+                "chats" => Kind::Chat,
+                "quests" => Kind::Quest,
+                other => return Err(format!("unknown request kind {other:?}")),
+            };
+            let weight = weight
+                .trim()
+                .parse()
+                .map_err(|e| format!("invalid weight for {kind}: {e}"))?;
+            Ok((kind, weight))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    for (i, (kind, _)) in mix.iter().enumerate() {
+        if mix[..i].iter().any(|(earlier, _)| earlier == kind) {
+            return Err(format!("{kind} is weighted more than once"));
+        }
+    }
+
+    Ok(Mix(mix))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Outcome {
+    Success,
+    Busy,
+    Failed,
+}
+
+#[derive(Debug, Default)]
+struct Samples {
+    latencies: BTreeMap<Kind, Vec<Duration>>,
+    outcomes: BTreeMap<(Kind, Outcome), usize>,
+}
+
+/// Polls a queued request's job until it is done, for the outcome of its reply.
+async fn wait_for_job(
+    client: &reqwest::Client,
+    url: &str,
+    secret: &str,
+    res: reqwest::Response,
+) -> Result<Outcome, reqwest::Error> {
+    let queued: serde_json::Value = res.json().await?;
+    let job_id = queued["job_id"].as_str().unwrap_or_default();
+    loop {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let job: serde_json::Value = client
+            .get(format!("{url}/api/v1/jobs/{job_id}"))
+            .header("secret", secret)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        if job["type"] == "done" {
+            return Ok(match job["result"]["type"].as_str() {
+                Some("busy") => Outcome::Busy,
+                Some("generate_error") => Outcome::Failed,
+                _ => Outcome::Success,
+            });
+        }
+    }
+}
+
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    let rank = ((p / 100.0) * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = Args::parse();
+    let url = match args.url {
+        Some(url) => url,
+        None => format!("http://127.0.0.1:{}", std::env::var("AI_SIDECAR_PORT")?),
+    };
+    let client = reqwest::Client::new();
+
+    if args.clear_history {
+        client
+            .delete(format!("{url}/api/v1/clearhistory"))
+            .header("secret", &args.secret)
+            .send()
+            .await?
+            .error_for_status()?;
+    }
+
+    let schedule: Arc<Vec<Kind>> = Arc::new(
+        args.mix
+            .0
+            .iter()
+            .flat_map(|(kind, weight)| std::iter::repeat_n(*kind, *weight))
+            .collect(),
+    );
+    if schedule.is_empty() {
+        return Err("request mix has no weight".into());
+    }
+
+    let next = Arc::new(AtomicUsize::new(0));
+    let samples = Arc::new(Mutex::new(Samples::default()));
+    let started = Instant::now();
+
+    let workers = (0..args.concurrency.max(1))
+        .map(|_| {
+            let client = client.clone();
+            let base = url.clone();
+            let url = format!("{url}/api/v1/generate");
+            let secret = args.secret.clone();
+            let schedule = schedule.clone();
+            let next = next.clone();
+            let samples = samples.clone();
+            let total = args.requests;
+
+            tokio::spawn(async move {
+                loop {
+                    let i = next.fetch_add(1, Ordering::SeqCst);
+                    if i >= total {
+                        break;
+                    }
+                    let kind = schedule[i % schedule.len()];
+
+                    let request_started = Instant::now();
+                    let outcome = match client
+                        .post(&url)
+                        .header("secret", &secret)
+                        .json(&kind.body())
+                        .send()
+                        .await
+                    {
+                        Ok(res) if res.status() == StatusCode::ACCEPTED => {
+                            wait_for_job(&client, &base, &secret, res)
+                                .await
+                                .unwrap_or(Outcome::Failed)
+                        }
+                        Ok(res) if res.status().is_success() => Outcome::Success,
+                        Ok(res) if res.status() == StatusCode::CONFLICT => Outcome::Busy,
+                        Ok(_) | Err(_) => Outcome::Failed,
+                    };
+                    let elapsed = request_started.elapsed();
+
+                    let mut samples = samples.lock().await;
+                    *samples.outcomes.entry((kind, outcome)).or_default() += 1;
+                    if outcome == Outcome::Success {
+                        samples.latencies.entry(kind).or_default().push(elapsed);
+                    }
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+
+    for worker in workers {
+        worker.await?;
+    }
+
+    let wall_time = started.elapsed();
+    let mut samples = samples.lock().await;
+
+    println!(
+        "{} requests, concurrency {}, {:.2?} wall time",
+        args.requests, args.concurrency, wall_time
+    );
+    println!(
+        "{:<8} {:>6} {:>6} {:>6} {:>10} {:>10} {:>10} {:>10}",
+        "kind", "ok", "busy", "failed", "p50", "p90", "p99", "max"
+    );
+
+    let mut total_success = 0;
+    for (kind, _) in args.mix.0.iter() {
+        let mut latencies = samples.latencies.remove(kind).unwrap_or_default();
+        latencies.sort();
+        let count = |outcome| {
+            samples
+                .outcomes
+                .get(&(*kind, outcome))
+                .copied()
+                .unwrap_or(0)
+        };
+        total_success += count(Outcome::Success);
+
+        println!(
+            "{:<8} {:>6} {:>6} {:>6} {:>10.2?} {:>10.2?} {:>10.2?} {:>10.2?}",
+            kind.to_string(),
+            count(Outcome::Success),
+            count(Outcome::Busy),
+            count(Outcome::Failed),
+            percentile(&latencies, 50.0),
+            percentile(&latencies, 90.0),
+            percentile(&latencies, 99.0),
+            latencies.last().copied().unwrap_or_default(),
+        );
+    }
+
+    println!(
+        "throughput: {:.2} successful requests/s",
+        total_success as f64 / wall_time.as_secs_f64()
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn weighs_each_kind_once() {
+        let mix = parse_mix("barks=5, quests=1").unwrap();
+        assert_eq!(mix.0, [(Kind::Bark, 5), (Kind::Quest, 1)]);
+
+        assert!(parse_mix("barks=5,barks=2").is_err());
+        assert!(parse_mix("duels=1").is_err());
+    }
+}
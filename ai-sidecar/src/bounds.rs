@@ -0,0 +1,174 @@
+//! Limits on the game-mechanical numbers the model proposes, e.g. prices and rewards, so a
+//! silver-tongued player can't talk an NPC into a reward of a million gold.
+//!
+//! The limits are a YAML map of field names at `AI_SIDECAR_BOUNDS_PATH`, see
+//! `bounds.example.yaml`. A value outside its field's range is either clamped into it or
+//! rejected, leaving the field unsettled.
+
+use std::{collections::HashMap, path::Path};
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Number, Value};
+
+#[derive(Debug, thiserror::Error)]
+pub enum BoundsError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Yaml(#[from] serde_yaml::Error),
+    #[error("the bounds of {0:?} have a min above their max")]
+    Empty(String),
+}
+
+/// What happens to a value outside its field's range.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    /// Moves the value to the nearest end of the range.
+    #[default]
+    Clamp,
+    /// Drops the value, as if the conversation hadn't settled it.
+    Reject,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Bound {
+    pub min: Option<i64>,
+    pub max: Option<i64>,
+    #[serde(default)]
+    pub action: Action,
+}
+
+impl Bound {
+    /// `value` if it is in range, otherwise what the action makes of it.
+    fn apply(&self, value: i64) -> Option<i64> {
+        let min = self.min.unwrap_or(i64::MIN);
+        let max = self.max.unwrap_or(i64::MAX);
+        match self.action {
+            _ if (min..=max).contains(&value) => Some(value),
+            Action::Clamp => Some(value.clamp(min, max)),
+            Action::Reject => None,
+        }
+    }
+}
+
+/// An out-of-range value, and what [`Bounds::check`] did to it.
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct Violation {
+    pub field: String,
+    /// What the model proposed.
+    pub proposed: Number,
+    pub action: Action,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(transparent)]
+pub struct Bounds {
+    fields: HashMap<String, Bound>,
+}
+
+impl Bounds {
+    /// Loads the limits at `AI_SIDECAR_BOUNDS_PATH`, or none if it isn't set.
+    pub fn from_env() -> Result<Self, BoundsError> {
+        match std::env::var("AI_SIDECAR_BOUNDS_PATH") {
+            Ok(path) => Self::load(path),
+            Err(_) => Ok(Self::default()),
+        }
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, BoundsError> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+
+    fn parse(yaml: &str) -> Result<Self, BoundsError> {
+        let bounds: Self = serde_yaml::from_str(yaml)?;
+        for (field, bound) in &bounds.fields {
+            if let (Some(min), Some(max)) = (bound.min, bound.max) {
+                if min > max {
+                    return Err(BoundsError::Empty(field.clone()));
+                }
+            }
+        }
+
+        Ok(bounds)
+    }
+
+    /// Brings every bounded number in `values` into range, nulling the ones that are rejected.
+    /// Numbers that aren't integers can't be checked, so they are rejected from bounded fields.
+    pub fn check(&self, values: &mut Map<String, Value>) -> Vec<Violation> {
+        let mut violations = Vec::new();
+        for (field, value) in values.iter_mut() {
+            let (Some(bound), Value::Number(number)) = (self.fields.get(field), &*value) else {
+                continue;
+            };
+
+            let checked = number
+                .as_i64()
+                .and_then(|n| bound.apply(n).map(|to| (n, to)));
+            let (checked, action) = match checked {
+                Some((from, to)) if from == to => continue,
+                Some((_, to)) => (Value::from(to), Action::Clamp),
+                None => (Value::Null, Action::Reject),
+            };
+            violations.push(Violation {
+                field: field.clone(),
+                proposed: number.clone(),
+                action,
+            });
+            *value = checked;
+        }
+
+        violations
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clamps_and_rejects_out_of_range_values() {
+        let bounds = Bounds::parse(include_str!(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/bounds.example.yaml"
+        )))
+        .unwrap();
+
+        let mut values = serde_json::json!({
+            "price": 1_000_000,
+            "reward_gold": 50,
+            "damage": 9000,
+            "quest_accepted": true,
+            "unbounded": -3,
+        });
+        let violations = bounds.check(values.as_object_mut().unwrap());
+
+        assert_eq!(
+            values,
+            serde_json::json!({
+                "price": 500,
+                "reward_gold": 50,
+                "damage": null,
+                "quest_accepted": true,
+                "unbounded": -3,
+            })
+        );
+        assert_eq!(violations.len(), 2);
+        assert!(violations.contains(&Violation {
+            field: "price".into(),
+            proposed: 1_000_000.into(),
+            action: Action::Clamp,
+        }));
+        assert!(violations.contains(&Violation {
+            field: "damage".into(),
+            proposed: 9000.into(),
+            action: Action::Reject,
+        }));
+
+        assert!(matches!(
+            Bounds::parse("price:\n  min: 10\n  max: 1\n"),
+            Err(BoundsError::Empty(_))
+        ));
+    }
+}
@@ -0,0 +1,153 @@
+//! Per-session budgets, so a free-tier player can't drive unlimited inference cost: at most so many
+//! generated tokens, or so much generation time, in any hour.
+//!
+//! What a session spent counts against its budget for an hour, so the budget frees up gradually
+//! instead of all at once. A reply that starts within budget is finished even if it goes over.
+//! The limits are saved with the conversation, but not what it spent, so a restart forgives it.
+
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+const WINDOW: Duration = Duration::from_secs(60 * 60);
+
+/// What a session may spend an hour. A limit that isn't set doesn't apply.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Limits {
+    /// Tokens generated for the session's replies.
+    pub max_tokens: Option<u64>,
+    /// Milliseconds spent generating the session's replies, queueing aside.
+    pub max_generating_ms: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Spend {
+    at: Instant,
+    tokens: u64,
+    generating_ms: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct Budget {
+    limits: Limits,
+    /// Spending within the last hour, oldest first.
+    spent: VecDeque<Spend>,
+}
+
+impl Budget {
+    pub fn new(limits: Limits) -> Self {
+        Self {
+            limits,
+            spent: VecDeque::new(),
+        }
+    }
+
+    pub fn limits(&self) -> Limits {
+        self.limits
+    }
+
+    /// Replaces the limits, keeping what was already spent.
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    fn within(&self, tokens: u64, generating_ms: u64) -> bool {
+        self.limits.max_tokens.is_none_or(|max| tokens < max)
+            && self
+                .limits
+                .max_generating_ms
+                .is_none_or(|max| generating_ms < max)
+    }
+
+    /// How long until the session may generate again at `now`, or `None` if it may right away.
+    pub fn exhausted(&self, now: Instant) -> Option<Duration> {
+        let recent = self
+            .spent
+            .iter()
+            .filter(|spend| now.duration_since(spend.at) < WINDOW);
+        let (mut tokens, mut generating_ms) = recent.clone().fold((0, 0), |(tokens, ms), spend| {
+            (tokens + spend.tokens, ms + spend.generating_ms)
+        });
+        if self.within(tokens, generating_ms) {
+            return None;
+        }
+
+        // Spending ages out oldest first, until enough of it has for the session to be within its
+        // limits again.
+        for spend in recent {
+            tokens -= spend.tokens;
+            generating_ms -= spend.generating_ms;
+            if self.within(tokens, generating_ms) {
+                return Some((spend.at + WINDOW).saturating_duration_since(now));
+            }
+        }
+        // Only a limit of 0 isn't met with nothing spent.
+        Some(WINDOW)
+    }
+
+    /// Counts a reply's generation against the budget.
+    pub fn charge(&mut self, tokens: usize, generating: Duration, now: Instant) {
+        while self
+            .spent
+            .front()
+            .is_some_and(|spend| now.duration_since(spend.at) >= WINDOW)
+        {
+            self.spent.pop_front();
+        }
+        self.spent.push_back(Spend {
+            at: now,
+            tokens: tokens as u64,
+            generating_ms: generating.as_millis().try_into().unwrap_or(u64::MAX),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frees_up_as_spending_ages_out() {
+        let start = Instant::now();
+        let mut budget = Budget::new(Limits {
+            max_tokens: Some(100),
+            max_generating_ms: None,
+        });
+        assert_eq!(budget.exhausted(start), None);
+
+        budget.charge(60, Duration::from_secs(1), start);
+        assert_eq!(budget.exhausted(start), None);
+        let later = start + Duration::from_secs(600);
+        budget.charge(60, Duration::from_secs(1), later);
+        // Going over only stops the next reply, until the first one's tokens age out.
+        assert_eq!(
+            budget.exhausted(later),
+            Some(WINDOW - Duration::from_secs(600))
+        );
+        assert_eq!(budget.exhausted(start + WINDOW), None);
+
+        budget.set_limits(Limits {
+            max_tokens: None,
+            max_generating_ms: Some(1500),
+        });
+        assert_eq!(
+            budget.exhausted(later),
+            Some(WINDOW - Duration::from_secs(600))
+        );
+        budget.set_limits(Limits::default());
+        assert_eq!(budget.exhausted(later), None);
+    }
+
+    #[test]
+    fn a_limit_of_zero_never_frees_up() {
+        let budget = Budget::new(Limits {
+            max_tokens: Some(0),
+            max_generating_ms: None,
+        });
+        assert_eq!(budget.exhausted(Instant::now()), Some(WINDOW));
+    }
+}
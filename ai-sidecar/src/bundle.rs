@@ -0,0 +1,272 @@
+//! Packs the sidecar's state into a single JSON bundle and unpacks it again, so an operator can
+//! move to another host or share a configured world with others.
+//!
+//! A bundle holds the resolved config without its secret, the system message, the data files the
+//! sidecar is pointed at through `AI_SIDECAR_*` env vars, such as locations and memory rules, and
+//! every conversation in the history database. Importing writes these out to a fresh directory,
+//! along with a new history database, and logs the env vars to serve them with.
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::{Config, ConfigError},
+    goals::Goal,
+    history::{History, MessageType},
+    persist::{self, HistoryDb, PersistError},
+};
+
+/// Bumped whenever the bundle format changes incompatibly.
+const BUNDLE_VERSION: u32 = 1;
+
+/// Data files that are part of a bundle, by the env var pointing at them and the name they are
+/// imported as.
+const DATA_FILES: &[(&str, &str)] = &[
+    ("AI_SIDECAR_BOUNDS_PATH", "bounds.yaml"),
+    ("AI_SIDECAR_DIALOGUE_PATH", "dialogue.yaml"),
+    ("AI_SIDECAR_FALLBACK_PATH", "fallback.yaml"),
+    ("AI_SIDECAR_LOCATIONS_PATH", "locations.yaml"),
+    ("AI_SIDECAR_MEMORY_RULES", "memory_rules.yaml"),
+    ("AI_SIDECAR_REVIEW_PATH", "review.json"),
+];
+
+const CONFIG_FILE: &str = "config.yaml";
+const SYSTEM_PROMPT_FILE: &str = "system_prompt.txt";
+const HISTORY_DB_FILE: &str = "history.db";
+
+#[derive(Debug, thiserror::Error)]
+pub enum BundleError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[error(transparent)]
+    Yaml(#[from] serde_yaml::Error),
+    #[error(transparent)]
+    Config(#[from] ConfigError),
+    #[error(transparent)]
+    Persist(#[from] PersistError),
+    #[error("bundle version {0} is newer than this sidecar supports")]
+    Unsupported(u32),
+    #[error("{0} already exists, import into an empty directory")]
+    Exists(PathBuf),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct StoredMessage {
+    message_type: String,
+    content: String,
+    speaker: Option<String>,
+    #[serde(default)]
+    created_at: Option<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Conversation {
+    /// `None` for the default conversation.
+    session_id: Option<String>,
+    system: String,
+    speakers: Vec<String>,
+    party: Vec<String>,
+    goal: Option<String>,
+    goal_reached: bool,
+    messages: Vec<StoredMessage>,
+}
+
+impl Conversation {
+    fn new(session_id: Option<String>, history: &History) -> Self {
+        Self {
+            session_id,
+            system: history.system.content().to_string(),
+            speakers: history.speakers.clone(),
+            party: history.party.clone(),
+            goal: history.goal.as_ref().map(|goal| goal.description.clone()),
+            goal_reached: history.goal.as_ref().is_some_and(|goal| goal.reached),
+            messages: history
+                .history
+                .iter()
+                .map(|message| StoredMessage {
+                    message_type: persist::message_type_name(message.message_type()).into(),
+                    content: message.content().to_string(),
+                    speaker: message.speaker().map(str::to_string),
+                    created_at: message.created_at(),
+                })
+                .collect(),
+        }
+    }
+
+    fn history(self) -> Result<History, PersistError> {
+        let mut history = History::new(self.system);
+        history.clear();
+        history.speakers = self.speakers;
+        history.party = self.party;
+        history.goal = self.goal.map(|description| Goal {
+            description,
+            reached: self.goal_reached,
+        });
+        for message in self.messages {
+            match persist::parse_message_type(&message.message_type)? {
+                MessageType::User => history.push_prompt(message.speaker, message.content),
+                MessageType::Assistant => history.push_reply(message.speaker, message.content),
+                message_type => history.push(message_type, message.content),
+            }
+            if let Some(restored) = history.history.last_mut() {
+                restored.set_created_at(message.created_at);
+            }
+        }
+
+        Ok(history)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Bundle {
+    version: u32,
+    exported_at: u64,
+    /// The resolved settings, in the config file's format, without the secret.
+    config: serde_json::Value,
+    system_prompt: String,
+    /// The contents of each data file, by the env var that pointed at it.
+    files: BTreeMap<String, String>,
+    conversations: Vec<Conversation>,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// Writes the state of the sidecar configured by the environment to `out`.
+pub fn export_state(out: &Path) -> Result<(), BundleError> {
+    let config = Config::load()?;
+    let mut files = BTreeMap::new();
+    for (key, _) in DATA_FILES {
+        if let Ok(path) = std::env::var(key) {
+            files.insert(key.to_string(), std::fs::read_to_string(path)?);
+        }
+    }
+    let conversations = match HistoryDb::from_env()? {
+        Some(db) => db
+            .load()?
+            .into_iter()
+            .map(|stored| Conversation::new(stored.session_id, &stored.history))
+            .collect(),
+        None => {
+            tracing::warn!("AI_SIDECAR_HISTORY_DB isn't set, so no conversations are exported");
+            Vec::new()
+        }
+    };
+
+    let bundle = Bundle {
+        version: BUNDLE_VERSION,
+        exported_at: now(),
+        config: config.shareable()?,
+        system_prompt: config.system_prompt.clone(),
+        files,
+        conversations,
+    };
+    std::fs::write(out, serde_json::to_string_pretty(&bundle)?)?;
+    tracing::info!(
+        "exported {} data files and {} conversations to {}",
+        bundle.files.len(),
+        bundle.conversations.len(),
+        out.display()
+    );
+
+    Ok(())
+}
+
+/// Unpacks the bundle at `bundle_path` into `dir`, logging the env vars to serve it with.
+pub fn import_state(bundle_path: &Path, dir: &Path) -> Result<(), BundleError> {
+    let bundle: Bundle = serde_json::from_str(&std::fs::read_to_string(bundle_path)?)?;
+    if bundle.version > BUNDLE_VERSION {
+        return Err(BundleError::Unsupported(bundle.version));
+    }
+    // Checked up front, so importing never overwrites anything or stops half-way.
+    let names = [CONFIG_FILE, SYSTEM_PROMPT_FILE, HISTORY_DB_FILE]
+        .into_iter()
+        .chain(DATA_FILES.iter().map(|(_, name)| *name));
+    for name in names {
+        let path = dir.join(name);
+        if path.exists() {
+            return Err(BundleError::Exists(path));
+        }
+    }
+    std::fs::create_dir_all(dir)?;
+    let mut env = Vec::new();
+
+    let system_prompt_path = dir.join(SYSTEM_PROMPT_FILE);
+    std::fs::write(&system_prompt_path, bundle.system_prompt)?;
+    let mut config = bundle.config;
+    if let Some(config) = config.as_object_mut() {
+        config.insert(
+            "system_prompt_path".into(),
+            serde_json::to_value(system_prompt_path)?,
+        );
+    }
+    let config_path = dir.join(CONFIG_FILE);
+    std::fs::write(&config_path, serde_yaml::to_string(&config)?)?;
+    env.push(("AI_SIDECAR_CONFIG".to_string(), config_path));
+
+    for (key, contents) in bundle.files {
+        let Some((_, name)) = DATA_FILES.iter().find(|(known, _)| *known == key) else {
+            tracing::warn!("skipping unknown data file {key}");
+            continue;
+        };
+        let path = dir.join(name);
+        std::fs::write(&path, contents)?;
+        env.push((key, path));
+    }
+
+    let db_path = dir.join(HISTORY_DB_FILE);
+    let mut db = HistoryDb::open(&db_path)?;
+    let conversations = bundle.conversations.len();
+    for conversation in bundle.conversations {
+        let session_id = conversation.session_id.clone();
+        db.save(session_id.as_deref(), &conversation.history()?)?;
+    }
+    env.push(("AI_SIDECAR_HISTORY_DB".to_string(), db_path));
+
+    tracing::info!(
+        "imported {conversations} conversations into {}",
+        dir.display()
+    );
+    for (key, path) in &env {
+        tracing::info!("serve with {key}={}", path.display());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn conversations_round_trip() {
+        let mut history = History::new("You are a bard.".into());
+        history.party = vec!["Ayla".into()];
+        history.goal = Some(Goal::new("Sing a song.".into()));
+        history.push_prompt(Some("Ayla".into()), "Play something.".into());
+        history.push_reply(None, "Gladly!".into());
+
+        let json = serde_json::to_string(&Conversation::new(Some("a".into()), &history)).unwrap();
+        let restored = serde_json::from_str::<Conversation>(&json)
+            .unwrap()
+            .history()
+            .unwrap();
+        assert_eq!(restored.system.content(), "You are a bard.");
+        assert_eq!(restored.party, history.party);
+        assert_eq!(restored.goal, history.goal);
+        assert_eq!(restored.history.len(), 3);
+        assert_eq!(restored.history[1].speaker(), Some("Ayla"));
+        assert_eq!(restored.history[2].content(), "Gladly!");
+    }
+}
@@ -0,0 +1,199 @@
+//! Replies to requests identical to earlier ones, returned without touching the model. Flavor
+//! text is often asked for with the same prompt over and over.
+//!
+//! Requests are keyed by a hash of everything that shapes the reply: the model, the rendered
+//! conversation with its system message, and the sampler settings. The cache holds the
+//! `response_cache_size` most recently used replies, and is off unless that is set, since a cached
+//! prompt always gets the same reply. Requests can bypass it with `bypass_cache`. Streamed replies
+//! and grammar-constrained ones aren't cached.
+
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap, VecDeque},
+    hash::{Hash, Hasher},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use serde::Serialize;
+
+use crate::{history::History, llm};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CacheKey(u64);
+
+impl CacheKey {
+    /// The key of generating from `opts` with `history`, on the model picked by name, if any.
+    /// `None` if the reply can't be cached.
+    pub fn new(model: Option<&str>, history: &History, opts: &llm::Options) -> Option<Self> {
+        // Grammars are compiled, so there's nothing left to tell them apart by.
+        if opts.grammar.is_some() {
+            return None;
+        }
+
+        let mut next = history.clone();
+        next.push_prompt(
+            opts.player.clone(),
+            opts.pipeline.prompt(opts.prompt.clone()),
+        );
+        let system = llm::system_message(history, opts.setup.clone(), opts.context.clone());
+        let rendered = next.prompt(opts.template, system, opts.speaker.as_deref());
+        let mut logit_bias = opts
+            .bias
+            .logit_bias
+            .iter()
+            .map(|(token, bias)| (*token, bias.to_bits()))
+            .collect::<Vec<_>>();
+        logit_bias.sort_unstable();
+
+        let mut hasher = DefaultHasher::new();
+        model.hash(&mut hasher);
+        rendered.hash(&mut hasher);
+        serde_json::to_string(&opts.sampler).ok()?.hash(&mut hasher);
+        opts.max_tokens.hash(&mut hasher);
+        opts.stop.hash(&mut hasher);
+        logit_bias.hash(&mut hasher);
+        opts.bias.banned_words.hash(&mut hasher);
+        opts.special_tokens.hash(&mut hasher);
+        opts.seed.hash(&mut hasher);
+        format!("{:?}", opts.pipeline.post_completion).hash(&mut hasher);
+
+        Some(Self(hasher.finish()))
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct CacheStats {
+    pub capacity: usize,
+    pub entries: usize,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+#[derive(Debug, Default)]
+struct Entries {
+    replies: HashMap<CacheKey, llm::Generated>,
+    /// Least recently used first.
+    order: VecDeque<CacheKey>,
+}
+
+#[derive(Debug, Default)]
+pub struct ResponseCache {
+    capacity: usize,
+    entries: Mutex<Entries>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl ResponseCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            ..Self::default()
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    pub fn get(&self, key: CacheKey) -> Option<llm::Generated> {
+        let mut entries = self.entries.lock().unwrap();
+        let Some(reply) = entries.replies.get(&key).cloned() else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            return None;
+        };
+        entries.order.retain(|used| *used != key);
+        entries.order.push_back(key);
+        self.hits.fetch_add(1, Ordering::Relaxed);
+
+        Some(reply)
+    }
+
+    pub fn insert(&self, key: CacheKey, reply: llm::Generated) {
+        if !self.enabled() {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        if entries.replies.insert(key, reply).is_some() {
+            entries.order.retain(|used| *used != key);
+        } else if entries.order.len() == self.capacity {
+            if let Some(evicted) = entries.order.pop_front() {
+                entries.replies.remove(&evicted);
+            }
+        }
+        entries.order.push_back(key);
+    }
+
+    pub fn clear(&self) {
+        *self.entries.lock().unwrap() = Entries::default();
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            capacity: self.capacity,
+            entries: self.entries.lock().unwrap().replies.len(),
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reply(text: &str) -> llm::Generated {
+        llm::Generated {
+            text: text.into(),
+            ..llm::Generated::default()
+        }
+    }
+
+    #[test]
+    fn evicts_the_least_recently_used() {
+        let history = History::new("You are a bard.".into());
+        let opts = |prompt: &str| llm::Options {
+            setup: None,
+            prompt: prompt.into(),
+            max_tokens: None,
+            context: None,
+            session: llm::SessionSettings::default(),
+            sampler: llm::SamplerOptions::default(),
+            cancel: None,
+            speaker: None,
+            player: None,
+            grammar: None,
+            template: Default::default(),
+            stop: Vec::new(),
+            bias: Default::default(),
+            special_tokens: llm::SpecialTokens::Strip,
+            seed: None,
+            pipeline: llm::Pipeline::default(),
+            kept_session: None,
+            prefixes: None,
+        };
+        let (song, tale, joke) = (
+            CacheKey::new(None, &history, &opts("Sing.")).unwrap(),
+            CacheKey::new(None, &history, &opts("Tell a tale.")).unwrap(),
+            CacheKey::new(None, &history, &opts("Tell a joke.")).unwrap(),
+        );
+        assert_eq!(Some(song), CacheKey::new(None, &history, &opts("Sing.")));
+        assert_ne!(
+            Some(song),
+            CacheKey::new(Some("flavor"), &history, &opts("Sing."))
+        );
+
+        let cache = ResponseCache::new(2);
+        cache.insert(song, reply("La la la."));
+        cache.insert(tale, reply("Once upon a time..."));
+        assert_eq!(cache.get(song).unwrap().text, "La la la.");
+        cache.insert(joke, reply("A goblin walks into a tavern..."));
+        assert!(cache.get(tale).is_none());
+        assert!(cache.get(song).is_some());
+
+        let stats = cache.stats();
+        assert_eq!((stats.entries, stats.hits, stats.misses), (2, 2, 1));
+    }
+}
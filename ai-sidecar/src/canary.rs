@@ -0,0 +1,87 @@
+//! Rolls a model upgrade out to a share of the live game first: the `canary` replies to that
+//! percentage of sessions instead of the default model, and each reply says which one served it.
+//!
+//! Sessions are picked by a hash of their id, so a session stays with the same model across
+//! restarts and replicas. Requests without a session, naming a model or on a degraded ladder
+//! always get the usual one.
+
+use serde::{Deserialize, Serialize};
+
+use crate::lore::fnv1a;
+
+/// Names the hosted backend as the canary, rather than one of the extra `models`.
+pub const HOSTED: &str = "anthropic";
+
+/// The config file's `canary` section.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct CanaryConfig {
+    /// One of the extra `models`, or `anthropic` for the hosted backend when it's a failover.
+    pub model: String,
+    /// The share of sessions it replies to, from 0 to 100.
+    pub percent: u8,
+}
+
+impl CanaryConfig {
+    /// Which model replies in the session.
+    pub fn arm(&self, session_id: &str) -> Arm {
+        match fnv1a(session_id.as_bytes()) % 100 < u64::from(self.percent) {
+            true => Arm::Canary,
+            false => Arm::Primary,
+        }
+    }
+
+    pub fn hosted(&self) -> bool {
+        self.model == HOSTED
+    }
+}
+
+/// Which model served a reply, while a canary is configured.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Arm {
+    /// The default model, or hosted backend if it's the default.
+    Primary,
+    Canary,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn canary(percent: u8) -> CanaryConfig {
+        CanaryConfig {
+            model: "quests".into(),
+            percent,
+        }
+    }
+
+    #[test]
+    fn routes_about_the_share_of_sessions() {
+        let sessions: Vec<String> = (0..10_000).map(|i| format!("session-{i}")).collect();
+        let routed = |percent| {
+            sessions
+                .iter()
+                .filter(|id| canary(percent).arm(id) == Arm::Canary)
+                .count()
+        };
+
+        assert_eq!(routed(0), 0);
+        assert_eq!(routed(100), sessions.len());
+        assert!((800..1200).contains(&routed(10)), "{}", routed(10));
+    }
+
+    #[test]
+    fn keeps_each_session_on_one_arm() {
+        let half = canary(50);
+        for id in ["a", "b", "c", "d"] {
+            assert_eq!(half.arm(id), half.arm(id));
+        }
+        // A session on the canary stays there as the rollout widens.
+        let id = (0..)
+            .map(|i| format!("session-{i}"))
+            .find(|id| half.arm(id) == Arm::Canary)
+            .unwrap();
+        assert_eq!(canary(80).arm(&id), Arm::Canary);
+    }
+}
@@ -0,0 +1,148 @@
+//! Failure injection for resilience testing, compiled only with the `chaos` feature.
+//!
+//! Configured through `AI_SIDECAR_CHAOS`, e.g. `latency_ms=250,error_rate=0.1,cancel_rate=0.05,seed=7`.
+//! Faults are drawn from a seeded generator so a given seed always produces the same sequence.
+
+use std::{sync::Mutex, time::Duration};
+
+#[derive(Debug, thiserror::Error)]
+pub enum ChaosError {
+    #[error("invalid chaos setting {0:?}")]
+    InvalidSetting(String),
+    #[error("injected backend error")]
+    InjectedError,
+    #[error("injected cancellation")]
+    InjectedCancel,
+}
+
+/// A tiny deterministic generator (SplitMix64), good enough for picking faults.
+#[derive(Debug)]
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next_f64(&mut self) -> f64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^= z >> 31;
+
+        (z >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+#[derive(Debug)]
+pub struct Chaos {
+    latency: Duration,
+    error_rate: f64,
+    cancel_rate: f64,
+    rng: Mutex<SplitMix64>,
+}
+
+impl Chaos {
+    pub fn from_env() -> Result<Option<Self>, ChaosError> {
+        match std::env::var("AI_SIDECAR_CHAOS") {
+            Ok(spec) => Self::parse(&spec).map(Some),
+            Err(_) => Ok(None),
+        }
+    }
+
+    pub fn parse(spec: &str) -> Result<Self, ChaosError> {
+        let mut chaos = Self {
+            latency: Duration::ZERO,
+            error_rate: 0.0,
+            cancel_rate: 0.0,
+            rng: Mutex::new(SplitMix64(0)),
+        };
+
+        for setting in spec.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            let invalid = || ChaosError::InvalidSetting(setting.to_string());
+            let (key, value) = setting.split_once('=').ok_or_else(invalid)?;
+
+            match key.trim() {
+                "latency_ms" => {
+                    chaos.latency = Duration::from_millis(value.parse().map_err(|_| invalid())?)
+                }
+                "error_rate" => chaos.error_rate = parse_rate(value).ok_or_else(invalid)?,
+                "cancel_rate" => chaos.cancel_rate = parse_rate(value).ok_or_else(invalid)?,
+                "seed" => chaos.rng = Mutex::new(SplitMix64(value.parse().map_err(|_| invalid())?)),
+                _ => return Err(invalid()),
+            }
+        }
+
+        Ok(chaos)
+    }
+
+    /// Sleeps for the configured latency, then either lets the request through or fails it. An
+    /// `InjectedCancel` is the caller's to carry out, by cancelling the generation once it starts.
+    pub async fn inject(&self) -> Result<(), ChaosError> {
+        if !self.latency.is_zero() {
+            tokio::time::sleep(self.latency).await;
+        }
+
+        self.roll()
+    }
+
+    fn roll(&self) -> Result<(), ChaosError> {
+        let roll = self.rng.lock().expect("chaos rng poisoned").next_f64();
+
+        if roll < self.error_rate {
+            tracing::warn!("chaos: injecting backend error");
+            Err(ChaosError::InjectedError)
+        } else if roll < self.error_rate + self.cancel_rate {
+            tracing::warn!("chaos: injecting cancellation");
+            Err(ChaosError::InjectedCancel)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+fn parse_rate(value: &str) -> Option<f64> {
+    value
+        .trim()
+        .parse::<f64>()
+        .ok()
+        .filter(|rate| (0.0..=1.0).contains(rate))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn faults(chaos: &Chaos, n: usize) -> Vec<Option<bool>> {
+        (0..n)
+            .map(|_| match chaos.roll() {
+                Ok(()) => None,
+                Err(ChaosError::InjectedError) => Some(true),
+                Err(_) => Some(false),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn same_seed_same_faults() {
+        let spec = "error_rate=0.3,cancel_rate=0.3,seed=42";
+        let a = Chaos::parse(spec).unwrap();
+        let b = Chaos::parse(spec).unwrap();
+
+        assert_eq!(faults(&a, 64), faults(&b, 64));
+    }
+
+    #[test]
+    fn rates_bound_faults() {
+        let never = Chaos::parse("error_rate=0,cancel_rate=0").unwrap();
+        assert!(faults(&never, 64).iter().all(Option::is_none));
+
+        let always = Chaos::parse("error_rate=1").unwrap();
+        assert!(faults(&always, 64).iter().all(|f| *f == Some(true)));
+    }
+
+    #[test]
+    fn rejects_bad_settings() {
+        assert!(Chaos::parse("error_rate=2").is_err());
+        assert!(Chaos::parse("latency_ms=soon").is_err());
+        assert!(Chaos::parse("volume=11").is_err());
+        assert!(Chaos::parse("latency_ms=10, seed=3").is_ok());
+    }
+}
@@ -0,0 +1,136 @@
+//! Validates a config file without starting the server, for deployment pipelines and anyone
+//! self-hosting: `ai-sidecar check-config config.yaml`.
+//!
+//! Settings are resolved as `serve` would resolve them, env var overrides included. Beyond parsing
+//! them, every model must be a readable GGUF file, the port must be free, and the data files set
+//! through env vars must load. Every problem found is logged, rather than only the first.
+
+use std::{
+    io::Read,
+    net::TcpListener,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    bounds::Bounds,
+    config::{Config, ConfigError},
+    fallback::FallbackPack,
+    jobs::Jobs,
+    locations::Locations,
+    memory::MemoryRules,
+    moderation::Moderator,
+    personas::Personas,
+    ratelimit::RateLimiter,
+    tokenizer::HfTokenizer,
+    turns::Turns,
+};
+
+/// Every GGUF file starts with these bytes.
+const GGUF_MAGIC: &[u8; 4] = b"GGUF";
+
+#[derive(Debug, thiserror::Error)]
+pub enum CheckError {
+    #[error(transparent)]
+    Config(#[from] ConfigError),
+    #[error("the config has {0} problem(s)")]
+    Failed(usize),
+}
+
+/// Why the model at `path` couldn't be loaded, if it is bound to fail.
+fn model_problem(path: &Path) -> Option<String> {
+    let mut magic = [0; 4];
+    let read = std::fs::File::open(path).and_then(|mut file| file.read_exact(&mut magic));
+    match read {
+        Err(e) => Some(format!("unable to read model {}: {e}", path.display())),
+        Ok(()) if &magic != GGUF_MAGIC => Some(format!("{} isn't a GGUF model", path.display())),
+        Ok(()) => None,
+    }
+}
+
+/// The error of a data file that didn't load, if any.
+fn load<T, E: std::fmt::Display>(what: &str, loaded: Result<T, E>) -> Option<String> {
+    loaded
+        .err()
+        .map(|e| format!("unable to load the {what}: {e}"))
+}
+
+/// Every problem with the config, besides those that stop it from being resolved at all.
+fn problems(config: &Config) -> Vec<String> {
+    let mut problems = Vec::new();
+    if let Err(e) = config.secret() {
+        problems.push(e.to_string());
+    }
+    match config.port() {
+        Ok(port) => {
+            if let Err(e) = TcpListener::bind((config.bind_address, port)) {
+                problems.push(format!(
+                    "unable to listen on {}:{port}: {e}",
+                    config.bind_address
+                ));
+            }
+        }
+        Err(e) => problems.push(e.to_string()),
+    }
+
+    let mut models = vec![config.model_path.clone()];
+    models.extend(config.models.values().map(|model| model.path.clone()));
+    models.extend(std::env::var_os("AI_SIDECAR_EMBEDDING_MODEL_PATH").map(PathBuf::from));
+    problems.extend(models.iter().filter_map(|path| model_problem(path)));
+
+    problems.extend(
+        [
+            load("fallback lines", FallbackPack::from_env()),
+            load("moderation rules", Moderator::from_env()),
+            load("locations", Locations::from_env()),
+            load("personas", Personas::from_env()),
+            load("bounds", Bounds::from_env()),
+            load("memory rules", MemoryRules::from_env()),
+            load("tokenizer", HfTokenizer::from_env()),
+            load("job queue settings", Jobs::from_env()),
+            load("rate limit", RateLimiter::from_env()),
+            load("party turn limit", Turns::<()>::from_env()),
+        ]
+        .into_iter()
+        .flatten(),
+    );
+
+    problems
+}
+
+/// Checks the config at `path`, logging every problem found.
+pub fn check_config(path: &Path) -> Result<(), CheckError> {
+    let config = Config::load_from(Some(path.to_path_buf()))?;
+    let problems = problems(&config);
+    for problem in &problems {
+        tracing::error!("{problem}");
+    }
+    if !problems.is_empty() {
+        return Err(CheckError::Failed(problems.len()));
+    }
+
+    tracing::info!("{} is valid", path.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn models_must_be_gguf() {
+        let path =
+            std::env::temp_dir().join(format!("ai-sidecar-check-{}.gguf", std::process::id()));
+        std::fs::write(&path, b"GGUF\x03\x00\x00\x00").unwrap();
+        assert_eq!(model_problem(&path), None);
+
+        std::fs::write(&path, b"<html>404 Not Found</html>").unwrap();
+        assert!(model_problem(&path)
+            .unwrap()
+            .ends_with("isn't a GGUF model"));
+
+        std::fs::remove_file(&path).unwrap();
+        assert!(model_problem(&path)
+            .unwrap()
+            .starts_with("unable to read model"));
+    }
+}
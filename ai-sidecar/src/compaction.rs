@@ -0,0 +1,127 @@
+//! Rolling summaries, so long-running conversations stay coherent without their context growing
+//! without bound.
+//!
+//! Once a conversation's prompt takes up more than `summarize_above_tokens`, the model summarizes
+//! all but its newest messages, and the summary replaces them as a single system message. The
+//! next summary folds in the previous one, so the conversation keeps its thread however long it
+//! runs.
+
+use llama_cpp::LlamaModel;
+
+use crate::{
+    handoff,
+    history::{History, MessageType},
+    llm,
+    templates::Template,
+    tokenizer,
+};
+
+/// How many of the newest messages are always kept verbatim.
+const KEEP_RECENT: usize = 4;
+const SUMMARY_MAX_TOKENS: usize = 160;
+
+const SYSTEM_MESSAGE: &str = "You summarize conversations between a player and an NPC in a \
+fantasy world, so the NPC can carry on without the full transcript. Keep names, promises, prices \
+and anything else the NPC must remember. Reply with the summary only, in at most four sentences, \
+in the third person.";
+
+const SUMMARY_PREFIX: &str = "Earlier in this conversation: ";
+
+/// Where the messages to summarize end and the prompt asking for their summary, or `None` if
+/// there aren't enough messages to summarize.
+fn summary_prompt(history: &History) -> Option<(usize, String)> {
+    let cut = history.history.len().checked_sub(KEEP_RECENT)?;
+    let older = &history.history[..cut];
+    let previous = older.first().and_then(|message| {
+        (message.message_type() == MessageType::System)
+            .then(|| message.content().strip_prefix(SUMMARY_PREFIX))
+            .flatten()
+    });
+    // A previous summary alone has nothing new to fold in.
+    if older.len() <= usize::from(previous.is_some()) {
+        return None;
+    }
+
+    let mut prompt = String::new();
+    if let Some(previous) = previous {
+        prompt += &format!("Summary so far: {previous}\n\n");
+    }
+    prompt += &format!("Conversation:\n{}", handoff::script(older));
+
+    Some((cut, prompt))
+}
+
+/// Summarizes the older messages of `history` if its prompt takes up more than `threshold`
+/// tokens, on the calling thread. Returns whether it did.
+pub fn compact(
+    model: &LlamaModel,
+    template: Template,
+    history: &mut History,
+    threshold: usize,
+    session: llm::SessionSettings,
+) -> bool {
+    let tokens = tokenizer::count(model, &history.prompt(template, None, None));
+    if tokens <= threshold {
+        return false;
+    }
+    let Some((cut, prompt)) = summary_prompt(history) else {
+        return false;
+    };
+
+    let summary = llm::complete(
+        model,
+        template,
+        SYSTEM_MESSAGE.into(),
+        prompt,
+        SUMMARY_MAX_TOKENS,
+        session,
+    );
+    match summary {
+        Ok(summary) => {
+            history.fold(cut, format!("{SUMMARY_PREFIX}{}", summary.trim()));
+            tracing::debug!("summarized {cut} messages of a {tokens}-token conversation");
+            true
+        }
+        Err(e) => {
+            tracing::warn!("unable to summarize the conversation: {e}");
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn folds_older_messages_into_the_summary() {
+        let mut history = History::new("You are a blacksmith.".into());
+        history.push(MessageType::User, "I need a sword.".into());
+        history.push(MessageType::Assistant, "Ten gold.".into());
+        history.push(MessageType::User, "Deal.".into());
+        assert_eq!(summary_prompt(&history), None);
+
+        history.push(MessageType::Assistant, "Come back tomorrow.".into());
+        history.push(MessageType::User, "Will do.".into());
+        let (cut, prompt) = summary_prompt(&history).unwrap();
+        assert_eq!(cut, 2);
+        assert_eq!(
+            prompt,
+            "Conversation:\nNPC: Hello, how may I help you today?\nPlayer: I need a sword.\n"
+        );
+
+        history.fold(
+            cut,
+            format!("{SUMMARY_PREFIX}The player asked for a sword."),
+        );
+        assert_eq!(history.history.len(), 5);
+        assert_eq!(history.history[0].message_type(), MessageType::System);
+        history.push(MessageType::Assistant, "See you.".into());
+        let (cut, prompt) = summary_prompt(&history).unwrap();
+        assert_eq!(cut, 2);
+        assert_eq!(
+            prompt,
+            "Summary so far: The player asked for a sword.\n\nConversation:\nNPC: Ten gold.\n"
+        );
+    }
+}
@@ -0,0 +1,662 @@
+//! Core sidecar settings, from an optional YAML file at `AI_SIDECAR_CONFIG`.
+//!
+//! Each setting can be overridden by its own `AI_SIDECAR_*` env var, so deployments that only use
+//! env vars keep working. See `config.example.yaml` for every setting and its default.
+
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, HashMap},
+    net::{IpAddr, Ipv4Addr},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use llama_cpp::LlamaParams;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    backend::AnthropicConfig, bias::Bias, canary::CanaryConfig, degradation::DegradationConfig,
+    flags::Flag, formatting::Formatting, game_context, governor, hooks::PipelineConfig, kv, llm,
+    locale::Locale, templates::Template, tiers::TierConfig,
+};
+
+const DEFAULT_MODEL_PATH: &str = "assets/tinyllama-1.1b-chat-v1.0.Q5_K_M.gguf";
+const DEFAULT_SYSTEM_MESSAGE: &str = include_str!(concat!(
+    env!("CARGO_MANIFEST_DIR"),
+    "/src/default_system_message.txt"
+));
+
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Yaml(#[from] serde_yaml::Error),
+    #[error("{0} must be set to serve, either in the config file or as {1}")]
+    Missing(&'static str, &'static str),
+    #[error("invalid value {1:?} for {0}")]
+    Invalid(&'static str, String),
+}
+
+/// An extra model, picked per request by name.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ModelConfig {
+    pub path: PathBuf,
+    /// Overrides the default `prompt_template` for this model.
+    pub prompt_template: Option<Template>,
+}
+
+/// The config file, where every setting is optional.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    model_path: Option<PathBuf>,
+    prompt_template: Option<Template>,
+    bind_address: Option<IpAddr>,
+    port: Option<u16>,
+    secret: Option<String>,
+    max_tokens: Option<usize>,
+    summarize_above_tokens: Option<usize>,
+    response_cache_size: Option<usize>,
+    kept_sessions: Option<usize>,
+    prefix_cache: Option<bool>,
+    prefix_cache_size: Option<usize>,
+    background_share: Option<f32>,
+    threads: Option<u32>,
+    gpu_layers: Option<u32>,
+    context_size: Option<u32>,
+    batch_size: Option<u32>,
+    mmap: Option<bool>,
+    mlock: Option<bool>,
+    warmup: Option<bool>,
+    system_prompt_path: Option<PathBuf>,
+    #[serde(default)]
+    models: BTreeMap<String, ModelConfig>,
+    degradation: Option<DegradationConfig>,
+    anthropic: Option<AnthropicConfig>,
+    canary: Option<CanaryConfig>,
+    #[serde(default)]
+    tiers: BTreeMap<String, TierConfig>,
+    logit_bias: Option<HashMap<i32, f32>>,
+    banned_words: Option<Vec<String>>,
+    #[serde(default)]
+    formatting: BTreeMap<String, Formatting>,
+    #[serde(default)]
+    pipelines: BTreeMap<String, PipelineConfig>,
+    #[serde(default)]
+    flags: BTreeMap<Flag, bool>,
+    npc_context_template: Option<String>,
+    #[serde(default)]
+    localized_npc_context_templates: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub model_path: PathBuf,
+    /// How prompts are marked up for the model, unless a request picks another template.
+    pub prompt_template: Template,
+    pub bind_address: IpAddr,
+    /// Only needed to serve, see [`Config::port`].
+    port: Option<u16>,
+    /// Only needed to serve, see [`Config::secret`].
+    secret: Option<String>,
+    /// Used when a request doesn't ask for a particular number of tokens.
+    pub max_tokens: usize,
+    /// Conversations whose prompt grows past this many tokens have their older messages
+    /// summarized. Never, if unset.
+    pub summarize_above_tokens: Option<usize>,
+    /// Replies kept for requests identical to earlier ones. None, by default.
+    pub response_cache_size: usize,
+    /// Conversations whose llama.cpp session is kept between turns, so the next prompt only feeds
+    /// the model what changed. None, if 0.
+    pub kept_sessions: usize,
+    /// Whether new conversations start from a session already fed their system prompt.
+    pub prefix_cache: bool,
+    /// The most system prompts fed ahead of time, the most used first. Each holds a full context
+    /// in memory.
+    pub prefix_cache_size: usize,
+    /// The most of the model's time background generation may take, above 0 and at most 1.
+    pub background_share: f32,
+    /// Threads each generation runs on.
+    pub threads: u32,
+    /// Model layers offloaded to the GPU. None, by default.
+    pub gpu_layers: u32,
+    /// Tokens each generation's context holds. llama.cpp's default, if unset.
+    pub context_size: Option<u32>,
+    /// Prompt tokens evaluated at once. llama.cpp's default, if unset.
+    pub batch_size: Option<u32>,
+    /// Whether models are memory-mapped rather than read into memory.
+    pub mmap: bool,
+    /// Whether models are locked in memory, so the OS never swaps them out.
+    pub mlock: bool,
+    /// Whether a short generation runs at startup before the sidecar reports itself ready.
+    pub warmup: bool,
+    /// The default system message, read from `system_prompt_path` if one is set.
+    pub system_prompt: String,
+    /// Models loaded alongside the default one, by name. Only set in the config file.
+    pub models: BTreeMap<String, ModelConfig>,
+    /// How service degrades under load. Only set in the config file.
+    pub degradation: Option<DegradationConfig>,
+    /// Replies with Anthropic's hosted models instead of a local one, or when it fails with
+    /// `failover`, if set. Only set in the config file, but for its `api_key`.
+    pub anthropic: Option<AnthropicConfig>,
+    /// Replies to a share of sessions with another model, to roll it out gradually. Only set in
+    /// the config file.
+    pub canary: Option<CanaryConfig>,
+    /// Service levels for players' subscription tiers, by name. Only set in the config file.
+    pub tiers: BTreeMap<String, TierConfig>,
+    /// Applied to every generation, on top of the request's own. Only set in the config file.
+    pub bias: Bias,
+    /// How each route's replies are formatted, by path relative to the v1 API. Raw, for those
+    /// left out. Only set in the config file.
+    pub formatting: BTreeMap<String, Formatting>,
+    /// The hooks each route's prompts and replies go through, by path relative to the v1 API.
+    /// None, for those left out. Only set in the config file.
+    pub pipelines: BTreeMap<String, PipelineConfig>,
+    /// Experimental subsystems turned on or off, instead of by their defaults. Only set in the
+    /// config file.
+    pub flags: BTreeMap<Flag, bool>,
+    /// How `/npc/dialogue` renders the game's context. Only set in the config file.
+    npc_context_template: String,
+    /// Replaces `npc_context_template` for sessions in these locales. Only set in the config file.
+    localized_npc_context_templates: BTreeMap<String, String>,
+    /// The config file the settings were read from, if any.
+    pub file: Option<PathBuf>,
+    /// The env vars that overrode the config file, in the order they were read.
+    pub overrides: Vec<&'static str>,
+}
+
+/// Reads env vars, remembering which of them were set.
+struct Env<F> {
+    get: F,
+    set: RefCell<Vec<&'static str>>,
+}
+
+fn override_with<T: FromStr>(
+    env: &Env<impl Fn(&str) -> Option<String>>,
+    key: &'static str,
+    value: Option<T>,
+) -> Result<Option<T>, ConfigError> {
+    match (env.get)(key) {
+        Some(v) => {
+            env.set.borrow_mut().push(key);
+            v.parse()
+                .map(Some)
+                .map_err(|_| ConfigError::Invalid(key, v))
+        }
+        None => Ok(value),
+    }
+}
+
+impl Config {
+    pub fn port(&self) -> Result<u16, ConfigError> {
+        self.port
+            .ok_or(ConfigError::Missing("port", "AI_SIDECAR_PORT"))
+    }
+
+    pub fn secret(&self) -> Result<&str, ConfigError> {
+        self.secret
+            .as_deref()
+            .ok_or(ConfigError::Missing("secret", "AI_SIDECAR_SECRET"))
+    }
+
+    /// How every model is loaded.
+    pub fn model_params(&self) -> LlamaParams {
+        LlamaParams {
+            n_gpu_layers: self.gpu_layers,
+            use_mmap: self.mmap,
+            use_mlock: self.mlock,
+            ..Default::default()
+        }
+    }
+
+    /// How replies from the route at `path`, relative to the v1 API, are formatted.
+    pub fn formatting(&self, path: &str) -> Formatting {
+        self.formatting.get(path).copied().unwrap_or_default()
+    }
+
+    /// The hooks the route at `path`, relative to the v1 API, runs its generations through.
+    pub fn pipeline(&self, path: &str) -> llm::Pipeline {
+        self.pipelines
+            .get(path)
+            .map(PipelineConfig::build)
+            .unwrap_or_default()
+    }
+
+    /// How `/npc/dialogue` renders the game's context for a session in `locale`.
+    pub fn npc_context_template(&self, locale: Option<&Locale>) -> &str {
+        locale
+            .and_then(|locale| locale.pick(&self.localized_npc_context_templates))
+            .unwrap_or(&self.npc_context_template)
+    }
+
+    /// How each generation's session is set up.
+    pub fn session(&self) -> llm::SessionSettings {
+        llm::SessionSettings {
+            threads: self.threads,
+            context_size: self.context_size,
+            batch_size: self.batch_size,
+        }
+    }
+
+    /// The settings in the config file's format, without the secret or the API key, so they can be
+    /// shared.
+    pub fn shareable(&self) -> Result<serde_json::Value, serde_json::Error> {
+        let mut file = serde_json::to_value(ConfigFile {
+            model_path: Some(self.model_path.clone()),
+            prompt_template: Some(self.prompt_template),
+            bind_address: Some(self.bind_address),
+            port: self.port,
+            secret: None,
+            max_tokens: Some(self.max_tokens),
+            summarize_above_tokens: self.summarize_above_tokens,
+            response_cache_size: Some(self.response_cache_size).filter(|size| *size > 0),
+            kept_sessions: Some(self.kept_sessions),
+            prefix_cache: Some(self.prefix_cache),
+            prefix_cache_size: Some(self.prefix_cache_size),
+            background_share: Some(self.background_share),
+            threads: Some(self.threads),
+            gpu_layers: Some(self.gpu_layers),
+            context_size: self.context_size,
+            batch_size: self.batch_size,
+            mmap: Some(self.mmap),
+            mlock: Some(self.mlock),
+            warmup: Some(self.warmup),
+            system_prompt_path: None,
+            models: self.models.clone(),
+            degradation: self.degradation.clone(),
+            anthropic: self.anthropic.clone().map(|anthropic| AnthropicConfig {
+                api_key: None,
+                ..anthropic
+            }),
+            canary: self.canary.clone(),
+            tiers: self.tiers.clone(),
+            logit_bias: Some(self.bias.logit_bias.clone()).filter(|bias| !bias.is_empty()),
+            banned_words: Some(self.bias.banned_words.clone()).filter(|words| !words.is_empty()),
+            formatting: self.formatting.clone(),
+            pipelines: self.pipelines.clone(),
+            flags: self.flags.clone(),
+            npc_context_template: Some(self.npc_context_template.clone())
+                .filter(|template| template != game_context::DEFAULT_TEMPLATE),
+            localized_npc_context_templates: self.localized_npc_context_templates.clone(),
+        })?;
+        // Unset settings are left out, as in a hand-written config file.
+        if let Some(settings) = file.as_object_mut() {
+            settings.retain(|_, value| !value.is_null());
+        }
+
+        Ok(file)
+    }
+
+    /// Loads the file at `AI_SIDECAR_CONFIG`, if it is set, and applies env var overrides.
+    pub fn load() -> Result<Self, ConfigError> {
+        Self::load_from(std::env::var_os("AI_SIDECAR_CONFIG").map(PathBuf::from))
+    }
+
+    /// Loads the file at `path`, if any, and applies env var overrides.
+    pub fn load_from(path: Option<PathBuf>) -> Result<Self, ConfigError> {
+        let file = match &path {
+            Some(path) => serde_yaml::from_str(&std::fs::read_to_string(path)?)?,
+            None => ConfigFile::default(),
+        };
+
+        Ok(Self {
+            file: path,
+            ..Self::resolve(file, |key| std::env::var(key).ok())?
+        })
+    }
+
+    fn resolve(
+        file: ConfigFile,
+        env: impl Fn(&str) -> Option<String>,
+    ) -> Result<Self, ConfigError> {
+        let env = Env {
+            get: env,
+            set: RefCell::default(),
+        };
+        let system_prompt_path: Option<PathBuf> = override_with(
+            &env,
+            "AI_SIDECAR_SYSTEM_PROMPT_PATH",
+            file.system_prompt_path,
+        )?;
+        let system_prompt = match system_prompt_path {
+            Some(path) => read_system_prompt(&path)?,
+            None => DEFAULT_SYSTEM_MESSAGE.to_string(),
+        };
+
+        let reduced_model = file
+            .degradation
+            .as_ref()
+            .and_then(|degradation| degradation.reduced_model.as_ref());
+        if let Some(name) = reduced_model.filter(|name| !file.models.contains_key(*name)) {
+            return Err(ConfigError::Invalid(
+                "degradation.reduced_model",
+                name.clone(),
+            ));
+        }
+
+        for (name, tier) in &file.tiers {
+            let unknown_model = tier
+                .model
+                .as_ref()
+                .is_some_and(|model| !file.models.contains_key(model));
+            let no_quota = tier
+                .quota
+                .is_some_and(|quota| quota.per_minute == 0 || quota.burst == 0);
+            if unknown_model || no_quota {
+                return Err(ConfigError::Invalid("tiers", name.clone()));
+            }
+        }
+
+        if let Some(canary) = &file.canary {
+            let known = match canary.hosted() {
+                true => file
+                    .anthropic
+                    .as_ref()
+                    .is_some_and(|anthropic| anthropic.failover),
+                false => file.models.contains_key(&canary.model),
+            };
+            if !known || canary.percent > 100 {
+                return Err(ConfigError::Invalid("canary", canary.model.clone()));
+            }
+        }
+
+        let anthropic = match file.anthropic {
+            Some(anthropic) => {
+                let api_key =
+                    override_with(&env, "AI_SIDECAR_ANTHROPIC_API_KEY", anthropic.api_key)?.ok_or(
+                        ConfigError::Missing("anthropic.api_key", "AI_SIDECAR_ANTHROPIC_API_KEY"),
+                    )?;
+                Some(AnthropicConfig {
+                    api_key: Some(api_key),
+                    ..anthropic
+                })
+            }
+            None => None,
+        };
+
+        let background_share =
+            override_with(&env, "AI_SIDECAR_BACKGROUND_SHARE", file.background_share)?
+                .unwrap_or(governor::DEFAULT_BACKGROUND_SHARE);
+        if !(background_share > 0.0 && background_share <= 1.0) {
+            return Err(ConfigError::Invalid(
+                "background_share",
+                background_share.to_string(),
+            ));
+        }
+
+        let npc_context_template = file
+            .npc_context_template
+            .unwrap_or_else(|| game_context::DEFAULT_TEMPLATE.to_string());
+        if !game_context::validate(&npc_context_template) {
+            return Err(ConfigError::Invalid(
+                "npc_context_template",
+                npc_context_template,
+            ));
+        }
+        for (locale, template) in &file.localized_npc_context_templates {
+            if locale.parse::<Locale>().is_err() {
+                return Err(ConfigError::Invalid(
+                    "localized_npc_context_templates",
+                    locale.clone(),
+                ));
+            }
+            if !game_context::validate(template) {
+                return Err(ConfigError::Invalid(
+                    "localized_npc_context_templates",
+                    template.clone(),
+                ));
+            }
+        }
+
+        Ok(Self {
+            model_path: override_with(&env, "AI_SIDECAR_MODEL_PATH", file.model_path)?
+                .unwrap_or_else(|| DEFAULT_MODEL_PATH.into()),
+            prompt_template: override_with(
+                &env,
+                "AI_SIDECAR_PROMPT_TEMPLATE",
+                file.prompt_template,
+            )?
+            .unwrap_or_default(),
+            bind_address: override_with(&env, "AI_SIDECAR_BIND_ADDRESS", file.bind_address)?
+                .unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+            port: override_with(&env, "AI_SIDECAR_PORT", file.port)?,
+            secret: override_with(&env, "AI_SIDECAR_SECRET", file.secret)?,
+            max_tokens: override_with(&env, "AI_SIDECAR_MAX_TOKENS", file.max_tokens)?
+                .unwrap_or(llm::DEFAULT_MAX_TOKENS),
+            summarize_above_tokens: override_with(
+                &env,
+                "AI_SIDECAR_SUMMARIZE_ABOVE_TOKENS",
+                file.summarize_above_tokens,
+            )?,
+            response_cache_size: override_with(
+                &env,
+                "AI_SIDECAR_RESPONSE_CACHE_SIZE",
+                file.response_cache_size,
+            )?
+            .unwrap_or_default(),
+            kept_sessions: override_with(&env, "AI_SIDECAR_KEPT_SESSIONS", file.kept_sessions)?
+                .unwrap_or(kv::DEFAULT_KEPT_SESSIONS),
+            prefix_cache: override_with(&env, "AI_SIDECAR_PREFIX_CACHE", file.prefix_cache)?
+                .unwrap_or(true),
+            prefix_cache_size: override_with(
+                &env,
+                "AI_SIDECAR_PREFIX_CACHE_SIZE",
+                file.prefix_cache_size,
+            )?
+            .unwrap_or(kv::DEFAULT_PREFIX_CACHE_SIZE),
+            background_share,
+            threads: override_with(&env, "AI_SIDECAR_THREADS", file.threads)?
+                .unwrap_or(llm::DEFAULT_THREADS),
+            gpu_layers: override_with(&env, "AI_SIDECAR_GPU_LAYERS", file.gpu_layers)?
+                .unwrap_or_default(),
+            context_size: override_with(&env, "AI_SIDECAR_CONTEXT_SIZE", file.context_size)?,
+            batch_size: override_with(&env, "AI_SIDECAR_BATCH_SIZE", file.batch_size)?,
+            mmap: override_with(&env, "AI_SIDECAR_MMAP", file.mmap)?.unwrap_or(true),
+            mlock: override_with(&env, "AI_SIDECAR_MLOCK", file.mlock)?.unwrap_or_default(),
+            warmup: override_with(&env, "AI_SIDECAR_WARMUP", file.warmup)?.unwrap_or_default(),
+            system_prompt,
+            models: file.models,
+            degradation: file.degradation,
+            anthropic,
+            canary: file.canary,
+            tiers: file.tiers,
+            bias: Bias {
+                logit_bias: file.logit_bias.unwrap_or_default(),
+                banned_words: file.banned_words.unwrap_or_default(),
+            },
+            formatting: file.formatting,
+            pipelines: file.pipelines,
+            flags: file.flags,
+            npc_context_template,
+            localized_npc_context_templates: file.localized_npc_context_templates,
+            file: None,
+            overrides: env.set.into_inner(),
+        })
+    }
+}
+
+fn read_system_prompt(path: &Path) -> Result<String, ConfigError> {
+    Ok(std::fs::read_to_string(path)?.trim_end().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    #[test]
+    fn env_overrides_file() {
+        let file: ConfigFile = serde_yaml::from_str(
+            "port: 8080\nsecret: from-file\nthreads: 4\nbind_address: 127.0.0.1\n",
+        )
+        .unwrap();
+        let env = HashMap::from([
+            ("AI_SIDECAR_PORT", "9090"),
+            ("AI_SIDECAR_MAX_TOKENS", "64"),
+            ("AI_SIDECAR_PROMPT_TEMPLATE", "chatml"),
+        ]);
+
+        let config = Config::resolve(file, |key| env.get(key).map(|v| v.to_string())).unwrap();
+        assert_eq!(config.port().unwrap(), 9090);
+        assert_eq!(config.secret().unwrap(), "from-file");
+        assert_eq!(config.threads, 4);
+        assert_eq!(config.max_tokens, 64);
+        assert_eq!(config.prompt_template, Template::ChatMl);
+        assert_eq!(config.bind_address, IpAddr::V4(Ipv4Addr::LOCALHOST));
+        assert_eq!(config.model_path, Path::new(DEFAULT_MODEL_PATH));
+        assert_eq!(config.system_prompt, DEFAULT_SYSTEM_MESSAGE);
+        assert_eq!(
+            config.overrides,
+            [
+                "AI_SIDECAR_PROMPT_TEMPLATE",
+                "AI_SIDECAR_PORT",
+                "AI_SIDECAR_MAX_TOKENS"
+            ]
+        );
+
+        let shared: ConfigFile = serde_json::from_value(config.shareable().unwrap()).unwrap();
+        assert_eq!(shared.secret, None);
+        assert_eq!(shared.port, Some(9090));
+    }
+
+    #[test]
+    fn rejects_invalid_and_missing_settings() {
+        let config = Config::resolve(ConfigFile::default(), |_| None).unwrap();
+        assert!(matches!(
+            config.secret(),
+            Err(ConfigError::Missing("secret", _))
+        ));
+
+        let invalid = Config::resolve(ConfigFile::default(), |key| {
+            (key == "AI_SIDECAR_PORT").then(|| "eighty".to_string())
+        });
+        assert!(matches!(
+            invalid,
+            Err(ConfigError::Invalid("AI_SIDECAR_PORT", _))
+        ));
+    }
+
+    #[test]
+    fn takes_the_anthropic_key_from_the_env() {
+        let file = || -> ConfigFile {
+            serde_yaml::from_str("anthropic:\n  model: claude-3-5-haiku-latest\n").unwrap()
+        };
+        assert!(matches!(
+            Config::resolve(file(), |_| None),
+            Err(ConfigError::Missing("anthropic.api_key", _))
+        ));
+
+        let config = Config::resolve(file(), |key| {
+            (key == "AI_SIDECAR_ANTHROPIC_API_KEY").then(|| "sk-test".to_string())
+        })
+        .unwrap();
+        assert_eq!(
+            config.anthropic.unwrap().api_key.as_deref(),
+            Some("sk-test")
+        );
+    }
+
+    #[test]
+    fn checks_the_canary() {
+        let canary = "canary:\n  model: quests\n  percent: 10\n";
+        let file: ConfigFile = serde_yaml::from_str(canary).unwrap();
+        assert!(matches!(
+            Config::resolve(file, |_| None),
+            Err(ConfigError::Invalid("canary", name)) if name == "quests"
+        ));
+        let file: ConfigFile =
+            serde_yaml::from_str(&format!("models:\n  quests:\n    path: big.gguf\n{canary}"))
+                .unwrap();
+        let config = Config::resolve(file, |_| None).unwrap();
+        assert_eq!(config.canary.unwrap().percent, 10);
+
+        // The hosted backend can only be a canary next to the local model.
+        let hosted = "anthropic:\n  model: claude-3-5-haiku-latest\n  api_key: sk-test\n";
+        let canary = "canary:\n  model: anthropic\n  percent: 5\n";
+        let file: ConfigFile = serde_yaml::from_str(&format!("{hosted}{canary}")).unwrap();
+        assert!(matches!(
+            Config::resolve(file, |_| None),
+            Err(ConfigError::Invalid("canary", _))
+        ));
+        let file: ConfigFile =
+            serde_yaml::from_str(&format!("{hosted}  failover: true\n{canary}")).unwrap();
+        assert!(Config::resolve(file, |_| None).unwrap().canary.is_some());
+    }
+
+    #[test]
+    fn checks_tiers() {
+        let tiers = "tiers:\n  premium:\n    model: quests\n    max_tokens: 512\n";
+        let file: ConfigFile = serde_yaml::from_str(tiers).unwrap();
+        assert!(matches!(
+            Config::resolve(file, |_| None),
+            Err(ConfigError::Invalid("tiers", name)) if name == "premium"
+        ));
+        let file: ConfigFile =
+            serde_yaml::from_str(&format!("models:\n  quests:\n    path: big.gguf\n{tiers}"))
+                .unwrap();
+        let config = Config::resolve(file, |_| None).unwrap();
+        assert_eq!(config.tiers["premium"].max_tokens, Some(512));
+
+        let file: ConfigFile = serde_yaml::from_str(
+            "tiers:\n  free:\n    quota:\n      per_minute: 0\n      burst: 1\n",
+        )
+        .unwrap();
+        assert!(matches!(
+            Config::resolve(file, |_| None),
+            Err(ConfigError::Invalid("tiers", name)) if name == "free"
+        ));
+    }
+
+    #[test]
+    fn example_config_parses() {
+        let file: ConfigFile = serde_yaml::from_str(include_str!(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/config.example.yaml"
+        )))
+        .unwrap();
+
+        assert_eq!(file.port, Some(8080));
+    }
+
+    #[test]
+    fn builds_llama_params() {
+        let file: ConfigFile =
+            serde_yaml::from_str("gpu_layers: 32\ncontext_size: 4096\nmlock: true\n").unwrap();
+        let env = HashMap::from([
+            ("AI_SIDECAR_BATCH_SIZE", "256"),
+            ("AI_SIDECAR_MMAP", "false"),
+        ]);
+
+        let config = Config::resolve(file, |key| env.get(key).map(|v| v.to_string())).unwrap();
+        let params = config.model_params();
+        assert_eq!(params.n_gpu_layers, 32);
+        assert!(!params.use_mmap);
+        assert!(params.use_mlock);
+
+        let session = llama_cpp::SessionParams::from(config.session());
+        assert_eq!(session.n_ctx, 4096);
+        assert_eq!(session.n_batch, 256);
+        assert_eq!(session.n_threads, llm::DEFAULT_THREADS);
+    }
+
+    #[test]
+    fn parses_extra_models() {
+        let file: ConfigFile = serde_yaml::from_str(
+            "models:\n  flavor:\n    path: assets/tiny.gguf\n  quests:\n    path: assets/big.gguf\n    prompt_template: chatml\n",
+        )
+        .unwrap();
+
+        let config = Config::resolve(file, |_| None).unwrap();
+        assert_eq!(
+            config.models.keys().collect::<Vec<_>>(),
+            ["flavor", "quests"]
+        );
+        assert_eq!(
+            config.models["quests"].prompt_template,
+            Some(Template::ChatMl)
+        );
+    }
+}
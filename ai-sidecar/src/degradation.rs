@@ -0,0 +1,262 @@
+//! An explicit ladder of service levels, so the sidecar's behavior under load is predictable
+//! instead of emergent.
+//!
+//! The ladder runs from the full model, to the `reduced_model` if one is configured, to canned
+//! replies only: authored lines and the fallback pack. It steps down a rung whenever the p95 reply
+//! latency or the generation queue goes above its limit, and back up once both are under half of
+//! it, at most once per cooldown. Admins can also pin a level through the admin API, which holds
+//! until it is released.
+
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+const DEFAULT_COOLDOWN_SECS: u64 = 30;
+
+/// How much service `/generate` gives, from most to least.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Level {
+    Full,
+    /// Generates with the smaller `reduced_model` instead of the main one.
+    Reduced,
+    /// Only authored and fallback lines, without generating at all.
+    Canned,
+}
+
+/// The ladder's config file section.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct DegradationConfig {
+    /// One of the extra `models`, for the reduced level. The ladder skips that level if unset.
+    pub reduced_model: Option<String>,
+    /// The p95 reply latency above which the ladder steps down.
+    pub max_p95_ms: Option<u64>,
+    /// The number of waiting generation jobs above which the ladder steps down.
+    pub max_queue: Option<usize>,
+    /// The least time between automatic steps.
+    #[serde(default = "default_cooldown_secs")]
+    pub cooldown_secs: u64,
+}
+
+fn default_cooldown_secs() -> u64 {
+    DEFAULT_COOLDOWN_SECS
+}
+
+/// Load at the time of a request, which drives the automatic steps.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Load {
+    pub p95_ms: Option<u64>,
+    pub queued: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct DegradationStatus {
+    pub level: Level,
+    /// Whether an admin pinned the level, holding off automatic steps.
+    pub pinned: bool,
+}
+
+#[derive(Debug)]
+struct Current {
+    level: Level,
+    pinned: bool,
+    /// When the level last changed, for the cooldown.
+    changed: Option<Instant>,
+}
+
+#[derive(Debug)]
+pub struct Ladder {
+    config: Option<DegradationConfig>,
+    current: Mutex<Current>,
+}
+
+/// Whether `value` is above `limit`, or under half of it, if there is a limit.
+fn compare(value: Option<u64>, limit: Option<u64>) -> (bool, bool) {
+    match (value, limit) {
+        (Some(value), Some(limit)) => (value > limit, value < limit / 2),
+        _ => (false, true),
+    }
+}
+
+impl Ladder {
+    /// A ladder that only moves when an admin pins a level, if `config` is `None`.
+    pub fn new(config: Option<DegradationConfig>) -> Self {
+        Self {
+            config,
+            current: Mutex::new(Current {
+                level: Level::Full,
+                pinned: false,
+                changed: None,
+            }),
+        }
+    }
+
+    pub fn reduced_model(&self) -> Option<&str> {
+        self.config.as_ref()?.reduced_model.as_deref()
+    }
+
+    pub fn status(&self) -> DegradationStatus {
+        let current = self.current.lock().unwrap();
+        DegradationStatus {
+            level: current.level,
+            pinned: current.pinned,
+        }
+    }
+
+    /// The next level down or up from `level`, skipping the ones that aren't configured.
+    fn step(&self, level: Level, down: bool) -> Level {
+        let skip_reduced = self.reduced_model().is_none();
+        match (level, down) {
+            (Level::Full, true) if skip_reduced => Level::Canned,
+            (Level::Full, true) => Level::Reduced,
+            (Level::Reduced | Level::Canned, true) => Level::Canned,
+            (Level::Canned, false) if skip_reduced => Level::Full,
+            (Level::Canned, false) => Level::Reduced,
+            (Level::Full | Level::Reduced, false) => Level::Full,
+        }
+    }
+
+    /// Moves the ladder for the current `load`, returning the level to serve at.
+    pub fn observe(&self, load: Load, now: Instant) -> Level {
+        let mut current = self.current.lock().unwrap();
+        let Some(config) = &self.config else {
+            return current.level;
+        };
+        let cooling = current.changed.is_some_and(|changed| {
+            now.duration_since(changed) < Duration::from_secs(config.cooldown_secs)
+        });
+        if current.pinned || cooling {
+            return current.level;
+        }
+
+        let (slow, fast) = compare(load.p95_ms, config.max_p95_ms);
+        let (backed_up, clear) = compare(
+            Some(load.queued as u64),
+            config.max_queue.map(|max| max as u64),
+        );
+        let level = if slow || backed_up {
+            self.step(current.level, true)
+        } else if fast && clear {
+            self.step(current.level, false)
+        } else {
+            current.level
+        };
+        if level != current.level {
+            match level > current.level {
+                true => tracing::warn!(
+                    "degrading from {:?} to {level:?}, at a p95 of {:?}ms with {} jobs queued",
+                    current.level,
+                    load.p95_ms,
+                    load.queued
+                ),
+                false => tracing::info!("recovering from {:?} to {level:?}", current.level),
+            }
+            current.level = level;
+            current.changed = Some(now);
+        }
+
+        level
+    }
+
+    /// Holds the ladder at `level` until it is released.
+    pub fn pin(&self, level: Level) {
+        let mut current = self.current.lock().unwrap();
+        tracing::info!("pinning service level at {level:?}");
+        current.level = level;
+        current.pinned = true;
+    }
+
+    /// Hands the ladder back to the automatic steps, starting from the pinned level.
+    pub fn release(&self, now: Instant) {
+        let mut current = self.current.lock().unwrap();
+        tracing::info!("releasing service level {:?}", current.level);
+        current.pinned = false;
+        current.changed = Some(now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn configured(reduced_model: Option<&str>) -> Ladder {
+        Ladder::new(Some(DegradationConfig {
+            reduced_model: reduced_model.map(str::to_string),
+            max_p95_ms: Some(1000),
+            max_queue: Some(8),
+            cooldown_secs: 10,
+        }))
+    }
+
+    #[test]
+    fn steps_down_under_load_and_back_up() {
+        let ladder = configured(Some("flavor"));
+        let start = Instant::now();
+        let slow = Load {
+            p95_ms: Some(1500),
+            queued: 0,
+        };
+        let calm = Load {
+            p95_ms: Some(400),
+            queued: 1,
+        };
+
+        assert_eq!(ladder.observe(calm, start), Level::Full);
+        assert_eq!(ladder.observe(slow, start), Level::Reduced);
+        // Within the cooldown, nothing moves.
+        assert_eq!(
+            ladder.observe(slow, start + Duration::from_secs(5)),
+            Level::Reduced
+        );
+        let later = start + Duration::from_secs(10);
+        let backed_up = Load {
+            p95_ms: None,
+            queued: 9,
+        };
+        assert_eq!(ladder.observe(backed_up, later), Level::Canned);
+        // Between half the limits and the limits, the level holds.
+        let middling = Load {
+            p95_ms: Some(800),
+            queued: 1,
+        };
+        assert_eq!(
+            ladder.observe(middling, later + Duration::from_secs(10)),
+            Level::Canned
+        );
+        assert_eq!(
+            ladder.observe(calm, later + Duration::from_secs(10)),
+            Level::Reduced
+        );
+        assert_eq!(
+            ladder.observe(calm, later + Duration::from_secs(20)),
+            Level::Full
+        );
+
+        let skipping = configured(None);
+        assert_eq!(skipping.observe(slow, start), Level::Canned);
+    }
+
+    #[test]
+    fn pinned_levels_hold() {
+        let ladder = configured(Some("flavor"));
+        let start = Instant::now();
+        ladder.pin(Level::Canned);
+        let calm = Load {
+            p95_ms: Some(10),
+            queued: 0,
+        };
+        assert_eq!(ladder.observe(calm, start), Level::Canned);
+        assert!(ladder.status().pinned);
+
+        ladder.release(start);
+        assert_eq!(ladder.observe(calm, start), Level::Canned);
+        assert_eq!(
+            ladder.observe(calm, start + Duration::from_secs(10)),
+            Level::Reduced
+        );
+    }
+}
@@ -0,0 +1,156 @@
+//! Word-level diffs between two versions of generated text.
+
+use serde::Serialize;
+
+/// Texts with more words than this are diffed as a single replacement.
+const MAX_WORDS: usize = 2048;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Change {
+    Equal { text: String },
+    Insert { text: String },
+    Delete { text: String },
+}
+
+/// Splits `text` into alternating runs of whitespace and non-whitespace, so the pieces join
+/// back into the original.
+fn words(text: &str) -> Vec<&str> {
+    let mut words = Vec::new();
+    let mut start = 0;
+    let mut in_space = None;
+
+    for (i, c) in text.char_indices() {
+        let space = c.is_whitespace();
+        if in_space.is_some_and(|s| s != space) {
+            words.push(&text[start..i]);
+            start = i;
+        }
+        in_space = Some(space);
+    }
+    if start < text.len() {
+        words.push(&text[start..]);
+    }
+
+    words
+}
+
+fn push(changes: &mut Vec<Change>, change: Change) {
+    match (changes.last_mut(), &change) {
+        (Some(Change::Equal { text }), Change::Equal { text: next })
+        | (Some(Change::Insert { text }), Change::Insert { text: next })
+        | (Some(Change::Delete { text }), Change::Delete { text: next }) => *text += next,
+        _ => changes.push(change),
+    }
+}
+
+/// Diffs `old` against `new` using the longest common subsequence of their words.
+pub fn diff(old: &str, new: &str) -> Vec<Change> {
+    let (old, new) = (words(old), words(new));
+    let mut changes = Vec::new();
+
+    if old.len() > MAX_WORDS || new.len() > MAX_WORDS {
+        return vec![
+            Change::Delete { text: old.concat() },
+            Change::Insert { text: new.concat() },
+        ];
+    }
+
+    // lcs[i][j] is the length of the longest common subsequence of old[i..] and new[j..].
+    let mut lcs = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lcs[i][j] = match old[i] == new[j] {
+                true => lcs[i + 1][j + 1] + 1,
+                false => lcs[i + 1][j].max(lcs[i][j + 1]),
+            };
+        }
+    }
+
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            push(
+                &mut changes,
+                Change::Equal {
+                    text: old[i].into(),
+                },
+            );
+            i += 1;
+            j += 1;
+        } else if j < new.len() && (i == old.len() || lcs[i][j + 1] >= lcs[i + 1][j]) {
+            push(
+                &mut changes,
+                Change::Insert {
+                    text: new[j].into(),
+                },
+            );
+            j += 1;
+        } else {
+            push(
+                &mut changes,
+                Change::Delete {
+                    text: old[i].into(),
+                },
+            );
+            i += 1;
+        }
+    }
+
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn equal(text: &str) -> Change {
+        Change::Equal { text: text.into() }
+    }
+
+    fn insert(text: &str) -> Change {
+        Change::Insert { text: text.into() }
+    }
+
+    fn delete(text: &str) -> Change {
+        Change::Delete { text: text.into() }
+    }
+
+    #[test]
+    fn diffs_words() {
+        assert_eq!(
+            diff("Find the lost cat.", "Find the lost black dog."),
+            [
+                equal("Find the lost "),
+                insert("black dog."),
+                delete("cat.")
+            ]
+        );
+        assert_eq!(diff("same", "same"), [equal("same")]);
+        assert_eq!(diff("", "new"), [insert("new")]);
+    }
+
+    #[test]
+    fn changes_rebuild_both_versions() {
+        let (old, new) = ("a b  c\nd e", "a  c\nd x e f");
+        let changes = diff(old, new);
+
+        let rebuild = |keep: fn(&Change) -> Option<&str>| -> String {
+            changes.iter().filter_map(keep).collect()
+        };
+        assert_eq!(
+            rebuild(|c| match c {
+                Change::Equal { text } | Change::Delete { text } => Some(text.as_str()),
+                Change::Insert { .. } => None,
+            }),
+            old
+        );
+        assert_eq!(
+            rebuild(|c| match c {
+                Change::Equal { text } | Change::Insert { text } => Some(text.as_str()),
+                Change::Delete { .. } => None,
+            }),
+            new
+        );
+    }
+}
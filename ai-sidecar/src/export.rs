@@ -0,0 +1,115 @@
+//! Exports reviewed content as a JSONL dataset of prompt/response pairs for fine-tuning.
+
+use std::{
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use serde::Serialize;
+
+use crate::review::{self, Entry, ReviewError, ReviewQueue, Status};
+
+#[derive(Debug, thiserror::Error)]
+pub enum ExportError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[error(transparent)]
+    Review(#[from] ReviewError),
+}
+
+#[derive(Debug, Serialize)]
+struct Example<'a> {
+    system: Option<&'a str>,
+    prompt: &'a str,
+    response: String,
+    tags: Vec<&'a str>,
+}
+
+impl<'a> Example<'a> {
+    /// Only approved entries with the prompt they were generated from make a training pair.
+    fn from_entry(entry: &'a Entry) -> Option<Self> {
+        if entry.status != Status::Approved {
+            return None;
+        }
+
+        Some(Self {
+            system: entry.setup.as_deref(),
+            prompt: entry.prompt.as_deref()?,
+            response: review::text(&entry.content),
+            tags: vec!["review", &entry.kind],
+        })
+    }
+}
+
+fn write_dataset<'a>(
+    entries: impl IntoIterator<Item = &'a Entry>,
+    out: impl Write,
+) -> Result<usize, ExportError> {
+    let mut out = BufWriter::new(out);
+    let mut written = 0;
+
+    for example in entries.into_iter().filter_map(Example::from_entry) {
+        serde_json::to_writer(&mut out, &example)?;
+        out.write_all(b"\n")?;
+        written += 1;
+    }
+    out.flush()?;
+
+    Ok(written)
+}
+
+/// Writes every approved entry of the review queue saved at `review_path` to `out` as JSONL.
+pub fn export_dataset(review_path: PathBuf, out: &Path) -> Result<(), ExportError> {
+    let queue = ReviewQueue::load(review_path)?;
+    let written = write_dataset(
+        queue.list(Some(Status::Approved), None),
+        std::fs::File::create(out)?,
+    )?;
+    tracing::info!("wrote {written} examples to {}", out.display());
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn exports_approved_prompted_entries() {
+        let mut queue = ReviewQueue::default();
+        let approved = queue
+            .submit(
+                "quest".into(),
+                Some("You are a quest giver.".into()),
+                Some("Any work?".into()),
+                json!("Find the cat."),
+            )
+            .unwrap();
+        let unprompted = queue
+            .submit("rumor".into(), None, None, json!("Wolves!"))
+            .unwrap();
+        queue
+            .submit(
+                "item".into(),
+                None,
+                Some("An item".into()),
+                json!("A spoon"),
+            )
+            .unwrap();
+        queue.approve(approved.id).unwrap();
+        queue.approve(unprompted.id).unwrap();
+
+        let mut out = Vec::new();
+        let written = write_dataset(queue.list(None, None), &mut out).unwrap();
+
+        assert_eq!(written, 1);
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "{\"system\":\"You are a quest giver.\",\"prompt\":\"Any work?\",\"response\":\"Find the cat.\",\"tags\":[\"review\",\"quest\"]}\n"
+        );
+    }
+}
@@ -0,0 +1,89 @@
+//! Counts how each backend in the chain fared: the local model, then the hosted backend it fails
+//! over to with `anthropic.failover`, then the fallback pack's canned lines.
+
+use std::sync::Mutex;
+
+use serde::Serialize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Link {
+    Local,
+    Remote,
+    Canned,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct LinkStats {
+    /// Replies it gave.
+    pub served: u64,
+    /// Requests it couldn't reply to, because it errored, was too busy or isn't there.
+    pub failed: u64,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct ChainStats {
+    pub local: LinkStats,
+    pub remote: LinkStats,
+    pub canned: LinkStats,
+}
+
+impl ChainStats {
+    fn link(&mut self, link: Link) -> &mut LinkStats {
+        match link {
+            Link::Local => &mut self.local,
+            Link::Remote => &mut self.remote,
+            Link::Canned => &mut self.canned,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Chain {
+    stats: Mutex<ChainStats>,
+}
+
+impl Chain {
+    pub fn served(&self, link: Link) {
+        self.stats.lock().unwrap().link(link).served += 1;
+    }
+
+    pub fn failed(&self, link: Link) {
+        self.stats.lock().unwrap().link(link).failed += 1;
+    }
+
+    pub fn stats(&self) -> ChainStats {
+        *self.stats.lock().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_each_link_apart() {
+        let chain = Chain::default();
+        chain.failed(Link::Local);
+        chain.served(Link::Remote);
+        chain.failed(Link::Local);
+        chain.served(Link::Canned);
+
+        assert_eq!(
+            chain.stats(),
+            ChainStats {
+                local: LinkStats {
+                    served: 0,
+                    failed: 2
+                },
+                remote: LinkStats {
+                    served: 1,
+                    failed: 0
+                },
+                canned: LinkStats {
+                    served: 1,
+                    failed: 0
+                },
+            }
+        );
+    }
+}
@@ -0,0 +1,169 @@
+//! Authored lines served instead of generated text when the model can't answer, so the game stays
+//! playable when the sidecar is busy, failing, or running without a model at all.
+//!
+//! The pack is a YAML file at `AI_SIDECAR_FALLBACK_PATH`, with lines per task and defaults for
+//! any other task, and the same again for each locale sessions may be in. See
+//! `fallback.example.yaml`.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::Path,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{locale::Locale, placeholders};
+
+#[derive(Debug, thiserror::Error)]
+pub enum FallbackError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Yaml(#[from] serde_yaml::Error),
+}
+
+/// Why a fallback line was served instead of generated text.
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FallbackReason {
+    Busy,
+    Unavailable,
+    GenerateError,
+    /// The degradation ladder is down to canned replies.
+    Degraded,
+    /// The generated reply was rejected by moderation.
+    Moderated,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Lines {
+    #[serde(default)]
+    default: Vec<String>,
+    #[serde(default)]
+    tasks: HashMap<String, Vec<String>>,
+}
+
+/// The lines for `task`, or the default ones if it has none.
+fn candidates<'a>(
+    default: &'a [String],
+    tasks: &'a HashMap<String, Vec<String>>,
+    task: Option<&str>,
+) -> &'a [String] {
+    task.and_then(|task| tasks.get(task))
+        .filter(|lines| !lines.is_empty())
+        .map_or(default, Vec::as_slice)
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FallbackPack {
+    #[serde(default)]
+    default: Vec<String>,
+    #[serde(default)]
+    tasks: HashMap<String, Vec<String>>,
+    /// Lines in other languages, used instead of the ones above for sessions in that locale.
+    #[serde(default)]
+    locales: BTreeMap<String, Lines>,
+    /// Rotates through the candidate lines so repeated fallbacks don't all say the same thing.
+    #[serde(skip)]
+    next: AtomicUsize,
+}
+
+impl FallbackPack {
+    /// Loads the pack at `AI_SIDECAR_FALLBACK_PATH`, if it is set.
+    pub fn from_env() -> Result<Option<Self>, FallbackError> {
+        match std::env::var("AI_SIDECAR_FALLBACK_PATH") {
+            Ok(path) => Self::load(path).map(Some),
+            Err(_) => Ok(None),
+        }
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, FallbackError> {
+        Ok(serde_yaml::from_str(&std::fs::read_to_string(path)?)?)
+    }
+
+    /// Picks a line for `task` in `locale`, skipping lines with placeholders `vars` can't fill.
+    pub fn line(
+        &self,
+        task: Option<&str>,
+        vars: &HashMap<String, String>,
+        locale: Option<&Locale>,
+    ) -> Option<String> {
+        let localized = locale
+            .and_then(|locale| locale.pick(&self.locales))
+            .map(|lines| candidates(&lines.default, &lines.tasks, task))
+            .filter(|lines| !lines.is_empty());
+        let lines = localized.unwrap_or_else(|| candidates(&self.default, &self.tasks, task));
+        if lines.is_empty() {
+            return None;
+        }
+
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        (0..lines.len()).find_map(|i| {
+            placeholders::fill(&lines[(start + i) % lines.len()], |key| {
+                vars.get(key).map(String::as_str)
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pack() -> FallbackPack {
+        serde_yaml::from_str(
+            r#"
+            default:
+              - "Not now, {player_name}."
+            tasks:
+              bark:
+                - "Fresh bread!"
+                - "Warm pies!"
+            "#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn rotates_task_lines() {
+        let pack = pack();
+        let vars = HashMap::new();
+
+        let lines = (0..3)
+            .map(|_| pack.line(Some("bark"), &vars, None).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(lines, ["Fresh bread!", "Warm pies!", "Fresh bread!"]);
+    }
+
+    #[test]
+    fn substitutes_vars_in_default_lines() {
+        let pack = pack();
+        let vars = HashMap::from([("player_name".to_string(), "Ada".to_string())]);
+
+        assert_eq!(
+            pack.line(Some("quest"), &vars, None).as_deref(),
+            Some("Not now, Ada.")
+        );
+        assert_eq!(pack.line(None, &HashMap::new(), None), None);
+    }
+
+    #[test]
+    fn example_pack_parses() {
+        let pack: FallbackPack = serde_yaml::from_str(include_str!(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/fallback.example.yaml"
+        )))
+        .unwrap();
+
+        assert!(pack.tasks.contains_key("bark"));
+        let de = "de-AT".parse::<Locale>().ok();
+        let vars = HashMap::from([("player_name".to_string(), "Ada".to_string())]);
+        assert_eq!(
+            pack.line(Some("greeting"), &vars, de.as_ref()).as_deref(),
+            Some("Willkommen zurück, Ada.")
+        );
+    }
+}
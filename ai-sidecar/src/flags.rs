@@ -0,0 +1,90 @@
+//! Runtime flags gating experimental subsystems, so risky ones can ship dark and be turned on per
+//! deployment, e.g. for a single game shard first.
+//!
+//! Each flag has a default, which `flags` in the config file overrides, and the admin API can
+//! toggle flags while serving. Toggles aren't persisted, so a restart goes back to the config.
+//! Subsystems that shipped before their flag default to on, and new ones to off.
+
+use std::{collections::BTreeMap, sync::RwLock};
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Flag {
+    /// Serves authored dialogue for prompts matching one the writers answered, see
+    /// [`crate::retrieval`].
+    Retrieval,
+    /// Folds each player's memories into a running story summary in the background, see
+    /// [`crate::narrative`].
+    Narrative,
+}
+
+impl Flag {
+    const ALL: [Self; 2] = [Self::Retrieval, Self::Narrative];
+
+    /// Whether the subsystem is on unless the config says otherwise.
+    fn default(self) -> bool {
+        match self {
+            Self::Retrieval | Self::Narrative => true,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Flags {
+    enabled: RwLock<BTreeMap<Flag, bool>>,
+}
+
+impl Flags {
+    /// Every flag at its default, unless `configured` sets it.
+    pub fn new(configured: &BTreeMap<Flag, bool>) -> Self {
+        let enabled = Flag::ALL
+            .into_iter()
+            .map(|flag| {
+                let enabled = configured.get(&flag).copied().unwrap_or(flag.default());
+                (flag, enabled)
+            })
+            .collect();
+
+        Self {
+            enabled: RwLock::new(enabled),
+        }
+    }
+
+    pub fn enabled(&self, flag: Flag) -> bool {
+        self.enabled.read().unwrap()[&flag]
+    }
+
+    /// Turns a flag on or off until the next restart.
+    pub fn set(&self, flag: Flag, enabled: bool) {
+        tracing::info!("turning {flag:?} {}", if enabled { "on" } else { "off" });
+        self.enabled.write().unwrap().insert(flag, enabled);
+    }
+
+    /// Every flag and whether it is on.
+    pub fn list(&self) -> BTreeMap<Flag, bool> {
+        self.enabled.read().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_and_toggles_override_defaults() {
+        let configured: BTreeMap<Flag, bool> = serde_yaml::from_str("narrative: false\n").unwrap();
+        let flags = Flags::new(&configured);
+        assert!(flags.enabled(Flag::Retrieval));
+        assert!(!flags.enabled(Flag::Narrative));
+
+        flags.set(Flag::Narrative, true);
+        flags.set(Flag::Retrieval, false);
+        assert_eq!(
+            flags.list(),
+            BTreeMap::from([(Flag::Retrieval, false), (Flag::Narrative, true)])
+        );
+        assert!(serde_yaml::from_str::<BTreeMap<Flag, bool>>("gossip: true\n").is_err());
+    }
+}
@@ -0,0 +1,190 @@
+//! Output formatting profiles, since models reply in Markdown that the game UI shows as literal
+//! asterisks.
+//!
+//! A profile converts a finished reply's Markdown: `plain` strips it, and `bbcode` converts it to
+//! the game's `[b]`/`[i]` markup. Which profile a route uses is set per route in the config file,
+//! and requests can pick another. Conversations keep the model's own text, so only what is sent
+//! back is converted.
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Formatting {
+    /// The reply as the model wrote it.
+    #[default]
+    Raw,
+    Plain,
+    Bbcode,
+}
+
+impl Formatting {
+    pub fn apply(self, text: &str) -> String {
+        if self == Self::Raw {
+            return text.to_string();
+        }
+
+        let mut lines = Vec::new();
+        let mut in_code = false;
+        for line in text.lines() {
+            let trimmed = line.trim_start();
+            if trimmed.starts_with("```") {
+                in_code = !in_code;
+                continue;
+            }
+            if in_code {
+                lines.push(line.to_string());
+                continue;
+            }
+            if is_rule(trimmed) {
+                continue;
+            }
+            lines.push(self.block(trimmed));
+        }
+        let mut formatted = lines.join("\n");
+        if text.ends_with('\n') {
+            formatted.push('\n');
+        }
+
+        formatted
+    }
+
+    fn block(self, line: &str) -> String {
+        let heading = line.trim_start_matches('#');
+        if heading.len() < line.len() && line.len() - heading.len() <= 6 {
+            if let Some(heading) = heading.strip_prefix(' ') {
+                return self.wrap("b", &self.inline(heading.trim()));
+            }
+        }
+        if let Some(quote) = line.strip_prefix('>') {
+            return self.inline(quote.trim_start());
+        }
+        for bullet in ["- ", "* ", "+ "] {
+            if let Some(item) = line.strip_prefix(bullet) {
+                return format!("- {}", self.inline(item));
+            }
+        }
+
+        self.inline(line)
+    }
+
+    fn wrap(self, tag: &str, inner: &str) -> String {
+        match self {
+            Self::Bbcode => format!("[{tag}]{inner}[/{tag}]"),
+            _ => inner.to_string(),
+        }
+    }
+
+    /// Converts emphasis, code spans and links.
+    fn inline(self, text: &str) -> String {
+        let mut out = String::new();
+        let mut i = 0;
+        while i < text.len() {
+            let rest = &text[i..];
+            let previous = text[..i].chars().next_back();
+            if let Some((consumed, converted)) = self.span(rest, previous) {
+                out.push_str(&converted);
+                i += consumed;
+                continue;
+            }
+            let c = rest.chars().next().expect("not at the end");
+            out.push(c);
+            i += c.len_utf8();
+        }
+
+        out
+    }
+
+    /// The span at the start of `text`, as how many bytes it takes up and what it becomes.
+    fn span(self, text: &str, previous: Option<char>) -> Option<(usize, String)> {
+        if let Some(code) = text.strip_prefix('`') {
+            let end = code.find('`')?;
+            return Some((end + 2, self.wrap("code", &code[..end])));
+        }
+        if let Some(label) = text.strip_prefix('[') {
+            let close = label.find("](")?;
+            let url_len = label[close + 2..].find(')')?;
+            let url = &label[close + 2..close + 2 + url_len];
+            let label = self.inline(&label[..close]);
+            let converted = match self {
+                Self::Bbcode => format!("[url={url}]{label}[/url]"),
+                _ => label,
+            };
+            return Some((close + url_len + 4, converted));
+        }
+        for (delimiter, tag) in [
+            ("**", "b"),
+            ("__", "b"),
+            ("~~", "s"),
+            ("*", "i"),
+            ("_", "i"),
+        ] {
+            let Some(inner) = text.strip_prefix(delimiter) else {
+                continue;
+            };
+            // Underscores inside words, as in snake_case, aren't emphasis.
+            if delimiter.starts_with('_') && previous.is_some_and(char::is_alphanumeric) {
+                return None;
+            }
+            if inner.starts_with(char::is_whitespace) {
+                continue;
+            }
+            let end = inner.find(delimiter)?;
+            let body = &inner[..end];
+            if body.is_empty() || body.ends_with(char::is_whitespace) {
+                continue;
+            }
+            return Some((
+                end + 2 * delimiter.len(),
+                self.wrap(tag, &self.inline(body)),
+            ));
+        }
+
+        None
+    }
+}
+
+/// Whether `line` is a horizontal rule, which can't be shown either way.
+fn is_rule(line: &str) -> bool {
+    let line = line.trim_end();
+    line.len() >= 3
+        && ['-', '*', '_'].iter().any(|rule| {
+            line.chars()
+                .filter(|c| !c.is_whitespace())
+                .all(|c| c == *rule)
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_markdown() {
+        let reply = "## The Rusty Flagon\n\
+            Welcome, *traveler*! Try our **honey mead** or the `house_special`.\n\
+            ---\n\
+            * Ale: 2 gold\n\
+            - [Map](https://example.com/map) of ~~the~~ town";
+
+        assert_eq!(Formatting::Raw.apply(reply), reply);
+        assert_eq!(
+            Formatting::Plain.apply(reply),
+            "The Rusty Flagon\n\
+            Welcome, traveler! Try our honey mead or the house_special.\n\
+            - Ale: 2 gold\n\
+            - Map of the town"
+        );
+        assert_eq!(
+            Formatting::Bbcode.apply(reply),
+            "[b]The Rusty Flagon[/b]\n\
+            Welcome, [i]traveler[/i]! Try our [b]honey mead[/b] or the [code]house_special[/code].\n\
+            - Ale: 2 gold\n\
+            - [url=https://example.com/map]Map[/url] of [s]the[/s] town"
+        );
+        assert_eq!(
+            Formatting::Plain.apply("snake_case_name and 2 * 3 * 4"),
+            "snake_case_name and 2 * 3 * 4"
+        );
+    }
+}
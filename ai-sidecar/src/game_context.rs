@@ -0,0 +1,104 @@
+//! Structured game state for NPC dialogue, rendered into the NPC's background so the game server
+//! doesn't have to piece it into the prompt by hand.
+//!
+//! The template has a line per detail, using the `{player_name}`, `{location}`, `{relationship}`
+//! and `{quest_flags}` placeholders, and lines whose details weren't given are left out. The
+//! config file's `npc_context_template` replaces the default one.
+
+use std::collections::BTreeMap;
+
+use serde::Deserialize;
+
+use crate::placeholders;
+
+pub const DEFAULT_TEMPLATE: &str = "The player you are talking to is {player_name}.
+You are at {location}.
+How much you like the player, from -100 to 100: {relationship}.
+The player's quests: {quest_flags}.";
+
+const PLACEHOLDERS: [&str; 4] = ["player_name", "location", "relationship", "quest_flags"];
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GameContext {
+    pub player_name: Option<String>,
+    /// Where the conversation takes place, in the game's words.
+    pub location: Option<String>,
+    /// How much the NPC likes the player, from -100 to 100.
+    pub relationship: Option<i32>,
+    /// Whether the player has done each quest step, by flag name.
+    #[serde(default)]
+    pub quest_flags: BTreeMap<String, bool>,
+}
+
+/// Whether `template` only uses known placeholders, and closes every brace.
+pub fn validate(template: &str) -> bool {
+    template.lines().all(|line| {
+        placeholders::fill(line, |key| PLACEHOLDERS.contains(&key).then_some("")).is_some()
+    })
+}
+
+impl GameContext {
+    /// The details that were given, rendered with `template`, or `None` if there are none.
+    pub fn render(&self, template: &str) -> Option<String> {
+        let relationship = self.relationship.map(|score| score.to_string());
+        let quest_flags = (!self.quest_flags.is_empty()).then(|| {
+            self.quest_flags
+                .iter()
+                .map(|(flag, done)| match done {
+                    true => format!("{flag} (done)"),
+                    false => format!("{flag} (not done)"),
+                })
+                .collect::<Vec<_>>()
+                .join(", ")
+        });
+
+        let lines = template
+            .lines()
+            .filter_map(|line| {
+                placeholders::fill(line, |key| match key {
+                    "player_name" => self.player_name.as_deref(),
+                    "location" => self.location.as_deref(),
+                    "relationship" => relationship.as_deref(),
+                    "quest_flags" => quest_flags.as_deref(),
+                    _ => None,
+                })
+            })
+            .filter(|line| !line.trim().is_empty())
+            .collect::<Vec<_>>();
+        (!lines.is_empty()).then(|| lines.join("\n"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_the_details_given() {
+        let context = GameContext {
+            player_name: Some("Ada".into()),
+            relationship: Some(-20),
+            quest_flags: BTreeMap::from([
+                ("found_the_map".into(), true),
+                ("rescued_the_miller".into(), false),
+            ]),
+            ..GameContext::default()
+        };
+
+        assert_eq!(
+            context.render(DEFAULT_TEMPLATE).as_deref(),
+            Some(
+                "The player you are talking to is Ada.\n\
+                How much you like the player, from -100 to 100: -20.\n\
+                The player's quests: found_the_map (done), rescued_the_miller (not done)."
+            )
+        );
+        assert_eq!(GameContext::default().render(DEFAULT_TEMPLATE), None);
+
+        assert!(validate(DEFAULT_TEMPLATE));
+        assert!(validate("{player_name} is at {location}"));
+        assert!(!validate("{player_name} owes {gold} gold"));
+        assert!(!validate("{player_name"));
+    }
+}
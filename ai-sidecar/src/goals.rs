@@ -0,0 +1,118 @@
+//! Goals a session's NPC steers the conversation toward, e.g. "get the player to accept quest 12".
+//!
+//! The goal is part of the NPC's setup until it is reached. After every NPC turn the model judges
+//! whether it has been, so the game can act on it.
+
+use llama_cpp::LlamaModel;
+use serde::Serialize;
+
+use crate::{handoff, history::History, llm, templates::Template};
+
+const CLASSIFIER_MAX_TOKENS: usize = 4;
+
+const CLASSIFIER_SYSTEM_MESSAGE: &str = "You judge conversations between a player and an \
+NPC in a fantasy world. Given the NPC's goal and the conversation so far, reply with yes if the \
+goal has been reached, or no if it hasn't. Reply with yes or no only.";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Goal {
+    pub description: String,
+    pub reached: bool,
+}
+
+impl Goal {
+    pub fn new(description: String) -> Self {
+        Self {
+            description,
+            reached: false,
+        }
+    }
+
+    /// Background for the system message, or `None` once the goal has been reached.
+    pub fn context(&self) -> Option<String> {
+        (!self.reached).then(|| {
+            format!(
+                "Your goal in this conversation: {}. Steer the conversation toward it, without \
+                 breaking character or forcing it on the player.",
+                self.description.trim_end_matches('.')
+            )
+        })
+    }
+}
+
+/// The prompt asking whether the conversation's goal has been reached, or `None` if there is no
+/// goal left to reach.
+pub fn classifier_prompt(history: &History) -> Option<String> {
+    let goal = history.goal.as_ref().filter(|goal| !goal.reached)?;
+
+    Some(format!(
+        "Goal: {}\n\nConversation:\n{}",
+        goal.description,
+        handoff::transcript(history)
+    ))
+}
+
+/// Whether the classifier judged the goal reached.
+fn is_reached(verdict: &str) -> bool {
+    verdict
+        .trim_start()
+        .get(..3)
+        .is_some_and(|word| word.eq_ignore_ascii_case("yes"))
+}
+
+/// Asks the model whether the goal in `prompt` has been reached, on the calling thread.
+pub fn judge(
+    model: &LlamaModel,
+    template: Template,
+    prompt: &str,
+    session: llm::SessionSettings,
+) -> bool {
+    let verdict = llm::complete(
+        model,
+        template,
+        CLASSIFIER_SYSTEM_MESSAGE.into(),
+        prompt.into(),
+        CLASSIFIER_MAX_TOKENS,
+        session,
+    );
+    match verdict {
+        Ok(verdict) => is_reached(&verdict),
+        Err(e) => {
+            tracing::warn!("unable to judge whether the goal was reached: {e}");
+            false
+        }
+    }
+}
+
+/// Marks the conversation's goal reached, returning its description.
+pub fn reach(history: &mut History) -> Option<String> {
+    let goal = history.goal.as_mut()?;
+    goal.reached = true;
+
+    Some(goal.description.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn asks_until_the_goal_is_reached() {
+        let mut history = History::new("You are a quest giver.".into());
+        assert_eq!(classifier_prompt(&history), None);
+
+        history.goal = Some(Goal::new("Get the player to accept quest 12.".into()));
+        assert_eq!(
+            classifier_prompt(&history).unwrap(),
+            "Goal: Get the player to accept quest 12.\n\nConversation:\nNPC: Hello, how may I \
+             help you today?\n"
+        );
+        assert!(is_reached(" Yes."));
+        assert!(!is_reached("No"));
+        assert!(!is_reached("ye"));
+
+        history.goal.as_mut().unwrap().reached = true;
+        assert_eq!(classifier_prompt(&history), None);
+        assert_eq!(history.goal.unwrap().context(), None);
+    }
+}
@@ -0,0 +1,131 @@
+//! Paces background generation, such as narrative summaries and content packs, so it never takes
+//! more than `background_share` of the model's time, and waits while players are.
+//!
+//! After each background job the governor rests long enough for that job to make up only its share
+//! of the time since the last one started. Before the next, it waits for the generation queue to
+//! empty, backing off for longer each time it still finds players waiting on it.
+
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use serde::Serialize;
+use tokio::time::Instant;
+
+use crate::jobs::{JobError, Jobs};
+
+pub const DEFAULT_BACKGROUND_SHARE: f32 = 0.5;
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// How long to rest after background work that took `took`, for it to be `share` of the time.
+pub fn rest(took: Duration, share: f32) -> Duration {
+    took.mul_f32((1.0 - share) / share)
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct GovernorStats {
+    pub share: f32,
+    /// How many times background work waited for players' requests to finish.
+    pub backoffs: u64,
+}
+
+#[derive(Debug)]
+pub struct Governor {
+    share: f32,
+    /// When the next background job may start.
+    resume: Mutex<Instant>,
+    backoffs: AtomicU64,
+}
+
+impl Governor {
+    pub fn new(share: f32) -> Self {
+        Self {
+            share,
+            resume: Mutex::new(Instant::now()),
+            backoffs: AtomicU64::new(0),
+        }
+    }
+
+    /// Runs `work` on the inference thread once its share allows and no other job is waiting or
+    /// running.
+    pub async fn run<T: Send + 'static>(
+        &self,
+        jobs: &Jobs,
+        work: impl FnOnce() -> T + Send + 'static,
+    ) -> Result<T, JobError> {
+        let mut backoff = MIN_BACKOFF;
+        loop {
+            let resume = *self.resume.lock().unwrap();
+            tokio::time::sleep_until(resume).await;
+            if !jobs.active() {
+                break;
+            }
+            self.backoffs.fetch_add(1, Ordering::Relaxed);
+            tracing::debug!(
+                "players are waiting, backing off background generation for {backoff:?}"
+            );
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+
+        let (output, timings) = jobs.run_timed(None, false, work).await?;
+        let took = Duration::from_millis(timings.generating_ms);
+        *self.resume.lock().unwrap() = Instant::now() + rest(took, self.share);
+
+        Ok(output)
+    }
+
+    pub fn stats(&self) -> GovernorStats {
+        GovernorStats {
+            share: self.share,
+            backoffs: self.backoffs.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tokio::sync::oneshot;
+
+    use super::*;
+
+    #[test]
+    fn rests_for_the_rest_of_the_share() {
+        let took = Duration::from_secs(2);
+        assert_eq!(rest(took, 1.0), Duration::ZERO);
+        assert_eq!(rest(took, 0.5), took);
+        assert_eq!(rest(took, 0.25), Duration::from_secs(6));
+    }
+
+    #[tokio::test]
+    async fn waits_for_other_jobs() {
+        let jobs = Arc::new(Jobs::new(4));
+        let governor = Arc::new(Governor::new(1.0));
+        let (release, blocked) = std::sync::mpsc::channel::<()>();
+        let (started, running) = oneshot::channel();
+        jobs.submit(move || {
+            let _ = started.send(());
+            let _ = blocked.recv();
+        })
+        .unwrap();
+        running.await.unwrap();
+
+        let background = tokio::spawn({
+            let (jobs, governor) = (jobs.clone(), governor.clone());
+            async move { governor.run(&jobs, || "summarized").await }
+        });
+        while governor.stats().backoffs == 0 {
+            tokio::task::yield_now().await;
+        }
+        release.send(()).unwrap();
+
+        assert_eq!(background.await.unwrap().unwrap(), "summarized");
+    }
+}
@@ -0,0 +1,91 @@
+//! Group conversations, where several NPCs take turns replying to the player.
+//!
+//! The game can name who speaks next. Otherwise it is whoever the prompt addresses by name, or
+//! else whoever has gone longest without speaking.
+//!
+//! A conversation can also be shared by a party of players, e.g. for a group quest turn-in. The
+//! game names which player each prompt is from, so the NPC can tell them apart.
+
+use crate::history::History;
+
+fn addresses(prompt: &str, speaker: &str) -> Option<usize> {
+    let prompt = prompt.to_lowercase();
+    let speaker = speaker.to_lowercase();
+
+    prompt.match_indices(&speaker).find_map(|(i, _)| {
+        let before = prompt[..i].chars().next_back();
+        let after = prompt[i + speaker.len()..].chars().next();
+        let is_word = |c: Option<char>| !c.is_some_and(char::is_alphanumeric);
+        (is_word(before) && is_word(after)).then_some(i)
+    })
+}
+
+/// Who replies to `prompt` next, or `None` if the conversation has a single NPC.
+pub fn next_speaker<'a>(history: &'a History, prompt: &str) -> Option<&'a str> {
+    let addressed = history
+        .speakers
+        .iter()
+        .filter_map(|speaker| Some((addresses(prompt, speaker)?, speaker)))
+        .min_by_key(|(i, _)| *i);
+    if let Some((_, speaker)) = addressed {
+        return Some(speaker);
+    }
+
+    let last_spoke = |speaker: &str| {
+        history
+            .history
+            .iter()
+            .rposition(|message| message.speaker() == Some(speaker))
+    };
+    history
+        .speakers
+        .iter()
+        .min_by_key(|speaker| last_spoke(speaker))
+        .map(String::as_str)
+}
+
+/// Background for the system message, telling the model who is present and who it speaks for.
+pub fn context(speakers: &[String], speaker: &str) -> String {
+    format!(
+        "This is a group conversation between the player and {}. Each reply starts with the name \
+         of the character speaking it. Reply as {speaker} only, and never speak for anyone else.",
+        speakers.join(", ")
+    )
+}
+
+/// Background for the system message, telling the model which players it is speaking with.
+pub fn party_context(party: &[String]) -> String {
+    format!(
+        "You are speaking with a party of players: {}. Each of their messages starts with the \
+         name of the player saying it. Address them by name, and keep track of who said what.",
+        party.join(", ")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::history::MessageType;
+
+    #[test]
+    fn picks_the_addressed_or_quietest_speaker() {
+        let mut history = History::new("A tavern scene.".into());
+        assert_eq!(next_speaker(&history, "Hello"), None);
+
+        history.speakers = vec!["Bram".into(), "Elsie".into(), "Tom".into()];
+        assert_eq!(next_speaker(&history, "Hello"), Some("Bram"));
+
+        history.push_reply(Some("Bram".into()), "Evening.".into());
+        history.push_reply(Some("Tom".into()), "Hmph.".into());
+        assert_eq!(next_speaker(&history, "Hello"), Some("Elsie"));
+        assert_eq!(next_speaker(&history, "What say you, tom?"), Some("Tom"));
+        assert_eq!(
+            next_speaker(&history, "Tomorrow, Bram? Or Elsie?"),
+            Some("Bram")
+        );
+
+        history.push(MessageType::User, "Well?".into());
+        history.push_reply(Some("Elsie".into()), "Well what?".into());
+        assert_eq!(next_speaker(&history, "Hello"), Some("Bram"));
+    }
+}
@@ -0,0 +1,87 @@
+//! Hands a conversation over to another NPC, e.g. when a guard goes to fetch the captain.
+//!
+//! The new NPC either inherits the whole conversation under its own system message, or only a
+//! summary of it folded into that system message, so it knows what was said without parroting
+//! the previous NPC's voice.
+
+use crate::history::{History, Message, MessageType};
+
+pub const SUMMARY_MAX_TOKENS: usize = 128;
+
+pub const SYSTEM_MESSAGE: &str = "You summarize conversations between a player and an NPC in a \
+fantasy world for the next NPC they will speak to. Reply with the summary only, in at most three \
+sentences, in the third person.";
+
+/// The conversation as a script, for the model to summarize.
+pub fn transcript(history: &History) -> String {
+    script(&history.history)
+}
+
+/// `messages` as a script, leaving out system messages.
+pub fn script(messages: &[Message]) -> String {
+    let mut transcript = String::new();
+    for message in messages {
+        let speaker = match (message.message_type(), message.speaker()) {
+            (MessageType::System, _) => continue,
+            (_, Some(speaker)) => speaker,
+            (MessageType::User, None) => "Player",
+            (MessageType::Assistant, None) => "NPC",
+        };
+        transcript += &format!("{speaker}: {}\n", message.content());
+    }
+
+    transcript
+}
+
+/// Starts the conversation over with `setup` as its system message, and `summary` of what was
+/// said so far if there is one.
+pub fn hand_over(history: &mut History, setup: String, summary: Option<&str>) {
+    match summary {
+        Some(summary) => {
+            history.clear();
+            history.set_system(format!(
+                "{setup}\n\nWhat the player discussed before you joined: {}",
+                summary.trim()
+            ));
+        }
+        None => history.set_system(setup),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn history() -> History {
+        let mut history = History::new("You are a guard.".into());
+        history.push(MessageType::User, "I want to see the captain.".into());
+        history.push(MessageType::Assistant, "Wait here.".into());
+
+        history
+    }
+
+    #[test]
+    fn keeps_or_summarizes_the_conversation() {
+        let mut kept = history();
+        hand_over(&mut kept, "You are the captain.".into(), None);
+        assert_eq!(kept.system.content(), "You are the captain.");
+        assert_eq!(kept.history.len(), 3);
+
+        let mut summarized = history();
+        assert_eq!(
+            transcript(&summarized),
+            "NPC: Hello, how may I help you today?\nPlayer: I want to see the captain.\nNPC: Wait here.\n"
+        );
+        hand_over(
+            &mut summarized,
+            "You are the captain.".into(),
+            Some("The player asked for you.\n"),
+        );
+        assert_eq!(
+            summarized.system.content(),
+            "You are the captain.\n\nWhat the player discussed before you joined: The player asked \
+             for you."
+        );
+        assert!(summarized.history.is_empty());
+    }
+}
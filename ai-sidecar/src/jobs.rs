@@ -0,0 +1,590 @@
+//! Generation jobs, run one at a time on a dedicated inference thread.
+//!
+//! Callers enqueue work and wait for it instead of racing for the model, so concurrent requests
+//! queue up rather than being turned away. Only `AI_SIDECAR_QUEUE_CAPACITY` jobs can wait at once;
+//! beyond that new jobs are refused as busy. Requests that don't want to wait can be detached,
+//! leaving their result to be polled for by job id, along with how many jobs are ahead of them.
+//! They are refused as busy too once the queue's capacity of them is waiting, and results nobody
+//! collects are dropped after ten minutes.
+//!
+//! Priority jobs, from players in a priority tier, have a queue of the same capacity of their own,
+//! which is always emptied first. Queue positions don't count the priority jobs that jump ahead.
+//!
+//! Each conversation's generation, queued or running, can be cancelled through [`Cancellations`].
+//! Jobs given a deadline are skipped if it passes while they are queued, so work nobody is waiting
+//! for anymore never takes the model.
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    panic::AssertUnwindSafe,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+
+use serde::Serialize;
+use tokio::sync::{mpsc, oneshot};
+use tokio_util::sync::CancellationToken;
+
+use crate::server::ServerError;
+
+const DEFAULT_CAPACITY: usize = 32;
+/// How long a detached job's result is kept for its client to collect.
+const RESULT_TTL: Duration = Duration::from_secs(10 * 60);
+
+type Work = Box<dyn FnOnce() + Send>;
+
+#[derive(Debug, thiserror::Error)]
+pub enum JobError {
+    #[error("the generation queue is full")]
+    Full,
+    #[error("the inference thread has stopped")]
+    Stopped,
+    #[error("the generation job panicked")]
+    Panicked,
+    #[error("the deadline passed before the generation job started")]
+    Expired,
+}
+
+/// How long a job spent waiting in the queue, and then running.
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct Timings {
+    pub queued_ms: u64,
+    pub generating_ms: u64,
+}
+
+/// Whether `deadline` has passed. Never, if there is none.
+pub fn expired(deadline: Option<SystemTime>) -> bool {
+    deadline.is_some_and(|deadline| deadline <= SystemTime::now())
+}
+
+fn millis(since: Instant) -> u64 {
+    since.elapsed().as_millis().try_into().unwrap_or(u64::MAX)
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum JobStatus {
+    /// `position` jobs are ahead of this one, or none once it is next or running.
+    Pending {
+        position: u64,
+    },
+    Done {
+        result: serde_json::Value,
+    },
+}
+
+#[derive(Debug)]
+enum Detached {
+    /// The number of jobs submitted before this one was detached.
+    Pending {
+        ticket: u64,
+    },
+    Done {
+        result: serde_json::Value,
+        finished: Instant,
+    },
+}
+
+/// Jobs counted as they pass through the queue, for queue positions.
+#[derive(Debug, Default)]
+struct Counts {
+    submitted: AtomicU64,
+    started: AtomicU64,
+    finished: AtomicU64,
+}
+
+pub struct Jobs {
+    queue: mpsc::Sender<Work>,
+    priority: mpsc::Sender<Work>,
+    counts: Arc<Counts>,
+    /// How many jobs can wait in each queue, and how many detached jobs can be pending.
+    capacity: usize,
+    /// Detached jobs, until their result is collected or expires.
+    detached: Arc<Mutex<HashMap<String, Detached>>>,
+}
+
+impl Jobs {
+    /// Starts the inference thread, with room for `AI_SIDECAR_QUEUE_CAPACITY` waiting jobs.
+    pub fn from_env() -> Result<Self, ServerError> {
+        let capacity = match std::env::var("AI_SIDECAR_QUEUE_CAPACITY") {
+            Ok(v) => v
+                .parse()
+                .ok()
+                .filter(|capacity| *capacity > 0)
+                .ok_or(ServerError::InvalidSetting("AI_SIDECAR_QUEUE_CAPACITY", v))?,
+            Err(_) => DEFAULT_CAPACITY,
+        };
+
+        Ok(Self::new(capacity))
+    }
+
+    pub fn new(capacity: usize) -> Self {
+        let (queue, mut rx) = mpsc::channel::<Work>(capacity);
+        let (priority, mut priority_rx) = mpsc::channel::<Work>(capacity);
+        std::thread::spawn(move || {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .build()
+                .expect("unable to start the inference thread's runtime");
+            // Jobs run outside of the runtime, since they block on async locks and channels.
+            while let Some(work) = runtime.block_on(next(&mut priority_rx, &mut rx)) {
+                work();
+            }
+        });
+
+        Self {
+            queue,
+            priority,
+            counts: Default::default(),
+            capacity,
+            detached: Default::default(),
+        }
+    }
+
+    /// How many jobs are waiting to run.
+    pub fn queued(&self) -> usize {
+        [&self.queue, &self.priority]
+            .iter()
+            .map(|queue| queue.max_capacity() - queue.capacity())
+            .sum()
+    }
+
+    /// Whether a job is running or waiting to.
+    pub fn active(&self) -> bool {
+        self.counts.finished.load(Ordering::SeqCst) < self.counts.submitted.load(Ordering::SeqCst)
+    }
+
+    /// Enqueues `work` without waiting for it to run.
+    pub fn submit(&self, work: impl FnOnce() + Send + 'static) -> Result<(), JobError> {
+        self.submit_with(false, work)
+    }
+
+    /// Enqueues `work` without waiting for it to run, ahead of every job without `priority`.
+    pub fn submit_with(
+        &self,
+        priority: bool,
+        work: impl FnOnce() + Send + 'static,
+    ) -> Result<(), JobError> {
+        let counts = self.counts.clone();
+        let queue = match priority {
+            true => &self.priority,
+            false => &self.queue,
+        };
+        queue
+            .try_send(Box::new(move || {
+                counts.started.fetch_add(1, Ordering::SeqCst);
+                if std::panic::catch_unwind(AssertUnwindSafe(work)).is_err() {
+                    tracing::error!("generation job panicked");
+                }
+                counts.finished.fetch_add(1, Ordering::SeqCst);
+            }))
+            .map_err(|e| match e {
+                mpsc::error::TrySendError::Full(_) => JobError::Full,
+                mpsc::error::TrySendError::Closed(_) => JobError::Stopped,
+            })?;
+        self.counts.submitted.fetch_add(1, Ordering::SeqCst);
+
+        Ok(())
+    }
+
+    /// Enqueues `work` and waits for its result.
+    pub async fn run<T: Send + 'static>(
+        &self,
+        work: impl FnOnce() -> T + Send + 'static,
+    ) -> Result<T, JobError> {
+        self.run_with(false, work).await
+    }
+
+    /// Like [`Jobs::run`], but ahead of every job without `priority`.
+    pub async fn run_with<T: Send + 'static>(
+        &self,
+        priority: bool,
+        work: impl FnOnce() -> T + Send + 'static,
+    ) -> Result<T, JobError> {
+        let (tx, rx) = oneshot::channel();
+        self.submit_with(priority, move || {
+            let _ = tx.send(work());
+        })?;
+
+        rx.await.map_err(|_| JobError::Panicked)
+    }
+
+    /// Like [`Jobs::run`], but skips `work` if `deadline` passes while it is queued, and reports
+    /// how long it waited and ran. Goes ahead of other jobs if it has `priority`.
+    pub async fn run_timed<T: Send + 'static>(
+        &self,
+        deadline: Option<SystemTime>,
+        priority: bool,
+        work: impl FnOnce() -> T + Send + 'static,
+    ) -> Result<(T, Timings), JobError> {
+        let submitted = Instant::now();
+        let (tx, rx) = oneshot::channel();
+        self.submit_with(priority, move || {
+            let queued_ms = millis(submitted);
+            if expired(deadline) {
+                tracing::debug!("skipping a job whose deadline passed after {queued_ms}ms");
+                let _ = tx.send(Err(JobError::Expired));
+                return;
+            }
+            let started = Instant::now();
+            let output = work();
+            let _ = tx.send(Ok((
+                output,
+                Timings {
+                    queued_ms,
+                    generating_ms: millis(started),
+                },
+            )));
+        })?;
+
+        rx.await.map_err(|_| JobError::Panicked)?
+    }
+
+    /// Runs `job` in the background, returning an id to poll for its result with and its
+    /// position in the queue. Refuses it if the queue is full, or as many detached jobs as it has
+    /// room for are already pending.
+    pub fn detach(
+        &self,
+        job: impl Future<Output = serde_json::Value> + Send + 'static,
+    ) -> Result<(String, u64), JobError> {
+        let id = crate::sessions::generate_id();
+        let ticket = self.counts.submitted.load(Ordering::SeqCst);
+        let detached = self.detached.clone();
+        {
+            let mut detached = detached.lock().unwrap();
+            evict(&mut detached, Instant::now());
+            let pending = detached
+                .values()
+                .filter(|job| matches!(job, Detached::Pending { .. }))
+                .count();
+            if pending.max(self.queued()) >= self.capacity {
+                return Err(JobError::Full);
+            }
+            detached.insert(id.clone(), Detached::Pending { ticket });
+        }
+
+        let job_id = id.clone();
+        tokio::spawn(async move {
+            let result = job.await;
+            let finished = Instant::now();
+            detached
+                .lock()
+                .unwrap()
+                .insert(job_id, Detached::Done { result, finished });
+        });
+
+        Ok((id, self.position(ticket)))
+    }
+
+    /// How many of the jobs submitted before `ticket` are still ahead of it.
+    fn position(&self, ticket: u64) -> u64 {
+        ticket.saturating_sub(self.counts.started.load(Ordering::SeqCst))
+    }
+
+    /// A detached job's status. Finished jobs are forgotten once their result is collected.
+    pub fn poll(&self, id: &str) -> Option<JobStatus> {
+        let mut detached = self.detached.lock().unwrap();
+        evict(&mut detached, Instant::now());
+        match detached.get(id)? {
+            Detached::Pending { ticket } => Some(JobStatus::Pending {
+                position: self.position(*ticket),
+            }),
+            Detached::Done { .. } => match detached.remove(id)? {
+                Detached::Done { result, .. } => Some(JobStatus::Done { result }),
+                Detached::Pending { .. } => None,
+            },
+        }
+    }
+}
+
+/// Drops the results that have waited longer than [`RESULT_TTL`] to be collected by `now`.
+fn evict(detached: &mut HashMap<String, Detached>, now: Instant) {
+    detached.retain(|_, job| match job {
+        Detached::Pending { .. } => true,
+        Detached::Done { finished, .. } => now.duration_since(*finished) < RESULT_TTL,
+    });
+}
+
+/// The next job to run, priority ones first, or `None` once [`Jobs`] is dropped.
+async fn next(
+    priority: &mut mpsc::Receiver<Work>,
+    queue: &mut mpsc::Receiver<Work>,
+) -> Option<Work> {
+    tokio::select! {
+        biased;
+        Some(work) = priority.recv() => Some(work),
+        Some(work) = queue.recv() => Some(work),
+        else => None,
+    }
+}
+
+/// The generation of each conversation, by session id with `None` for the default conversation.
+#[derive(Debug, Default)]
+pub struct Cancellations {
+    tokens: Mutex<HashMap<Option<String>, (u64, CancellationToken)>>,
+    next: AtomicU64,
+}
+
+/// Keeps a generation cancellable until it is dropped.
+#[derive(Debug)]
+pub struct Cancellable {
+    cancellations: Arc<Cancellations>,
+    session_id: Option<String>,
+    id: u64,
+    token: CancellationToken,
+}
+
+impl Cancellable {
+    pub fn token(&self) -> CancellationToken {
+        self.token.clone()
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.token.is_cancelled()
+    }
+}
+
+impl Drop for Cancellable {
+    fn drop(&mut self) {
+        let mut tokens = self.cancellations.tokens.lock().unwrap();
+        if tokens
+            .get(&self.session_id)
+            .is_some_and(|(id, _)| *id == self.id)
+        {
+            tokens.remove(&self.session_id);
+        }
+    }
+}
+
+impl Cancellations {
+    /// Makes a conversation's next generation cancellable.
+    pub fn register(self: &Arc<Self>, session_id: Option<String>) -> Cancellable {
+        let id = self.next.fetch_add(1, Ordering::Relaxed);
+        let token = CancellationToken::new();
+        self.tokens
+            .lock()
+            .unwrap()
+            .insert(session_id.clone(), (id, token.clone()));
+
+        Cancellable {
+            cancellations: self.clone(),
+            session_id,
+            id,
+            token,
+        }
+    }
+
+    /// Cancels a conversation's generation, returning `false` if it has none.
+    pub fn cancel(&self, session_id: Option<&str>) -> bool {
+        let tokens = self.tokens.lock().unwrap();
+        let Some((_, token)) = tokens.get(&session_id.map(str::to_string)) else {
+            return false;
+        };
+        token.cancel();
+
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn runs_jobs_in_order() {
+        let jobs = Jobs::new(4);
+        let order = Arc::new(Mutex::new(Vec::new()));
+
+        for i in 0..3 {
+            let order = order.clone();
+            jobs.submit(move || order.lock().unwrap().push(i)).unwrap();
+        }
+        assert_eq!(jobs.run(|| 3).await.unwrap(), 3);
+        assert_eq!(*order.lock().unwrap(), [0, 1, 2]);
+
+        assert!(matches!(
+            jobs.run(|| panic!("boom")).await,
+            Err(JobError::Panicked)
+        ));
+        assert_eq!(jobs.run(|| "still running").await.unwrap(), "still running");
+    }
+
+    #[tokio::test]
+    async fn runs_priority_jobs_first() {
+        let jobs = Jobs::new(4);
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let (release, blocked) = std::sync::mpsc::channel::<()>();
+        let (started, running) = oneshot::channel();
+
+        jobs.submit(move || {
+            let _ = started.send(());
+            let _ = blocked.recv();
+        })
+        .unwrap();
+        running.await.unwrap();
+        for (i, priority) in [(0, false), (1, true), (2, false), (3, true)] {
+            let order = order.clone();
+            jobs.submit_with(priority, move || order.lock().unwrap().push(i))
+                .unwrap();
+        }
+        assert_eq!(jobs.queued(), 4);
+
+        release.send(()).unwrap();
+        jobs.run(|| ()).await.unwrap();
+        assert_eq!(*order.lock().unwrap(), [1, 3, 0, 2]);
+    }
+
+    #[tokio::test]
+    async fn skips_jobs_past_their_deadline() {
+        let jobs = Jobs::new(4);
+        let ran = Arc::new(AtomicU64::new(0));
+
+        let counter = ran.clone();
+        let passed = SystemTime::now() - std::time::Duration::from_secs(1);
+        let skipped = jobs
+            .run_timed(Some(passed), false, move || {
+                counter.fetch_add(1, Ordering::SeqCst)
+            })
+            .await;
+        assert!(matches!(skipped, Err(JobError::Expired)));
+
+        let counter = ran.clone();
+        let later = SystemTime::now() + std::time::Duration::from_secs(60);
+        assert!(jobs
+            .run_timed(Some(later), false, move || {
+                counter.fetch_add(1, Ordering::SeqCst)
+            })
+            .await
+            .is_ok());
+        assert!(jobs.run_timed(None, false, || ()).await.is_ok());
+        assert_eq!(ran.load(Ordering::SeqCst), 1);
+        assert!(!expired(None));
+    }
+
+    #[tokio::test]
+    async fn refuses_jobs_beyond_capacity() {
+        let jobs = Jobs::new(1);
+        let (release, blocked) = std::sync::mpsc::channel::<()>();
+        let (started, running) = oneshot::channel();
+
+        jobs.submit(move || {
+            let _ = started.send(());
+            let _ = blocked.recv();
+        })
+        .unwrap();
+        running.await.unwrap();
+        jobs.submit(|| {}).unwrap();
+
+        assert!(matches!(jobs.submit(|| {}), Err(JobError::Full)));
+        assert!(jobs.active());
+        assert!(matches!(
+            jobs.detach(async { serde_json::Value::Null }),
+            Err(JobError::Full)
+        ));
+        release.send(()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn tracks_detached_positions() {
+        let jobs = Jobs::new(2);
+        let (release, blocked) = std::sync::mpsc::channel::<()>();
+        let (started, running) = oneshot::channel();
+
+        jobs.submit(move || {
+            let _ = started.send(());
+            let _ = blocked.recv();
+        })
+        .unwrap();
+        running.await.unwrap();
+        jobs.submit(|| {}).unwrap();
+
+        let (tx, rx) = oneshot::channel::<()>();
+        let (id, position) = jobs
+            .detach(async move {
+                let _ = rx.await;
+                serde_json::Value::Null
+            })
+            .unwrap();
+        // The running job has started, the queued one is still ahead.
+        assert_eq!(position, 1);
+        assert!(jobs.detach(std::future::pending()).is_ok());
+        assert!(matches!(
+            jobs.detach(async { serde_json::Value::Null }),
+            Err(JobError::Full)
+        ));
+
+        release.send(()).unwrap();
+        jobs.run(|| ()).await.unwrap();
+        assert!(matches!(
+            jobs.poll(&id),
+            Some(JobStatus::Pending { position: 0 })
+        ));
+        tx.send(()).unwrap();
+        while matches!(jobs.poll(&id), Some(JobStatus::Pending { .. })) {
+            tokio::task::yield_now().await;
+        }
+        assert!(jobs.poll(&id).is_none());
+    }
+
+    #[test]
+    fn drops_uncollected_results() {
+        let now = Instant::now();
+        let mut detached = HashMap::from([
+            ("pending".to_string(), Detached::Pending { ticket: 0 }),
+            (
+                "done".to_string(),
+                Detached::Done {
+                    result: serde_json::Value::Null,
+                    finished: now,
+                },
+            ),
+        ]);
+
+        evict(&mut detached, now + RESULT_TTL - Duration::from_secs(1));
+        assert_eq!(detached.len(), 2);
+        evict(&mut detached, now + RESULT_TTL);
+        assert!(detached.contains_key("pending"));
+        assert!(!detached.contains_key("done"));
+    }
+
+    #[tokio::test]
+    async fn collects_detached_results_once() {
+        let jobs = Jobs::new(1);
+        let (tx, rx) = oneshot::channel::<()>();
+        let (id, position) = jobs
+            .detach(async move {
+                let _ = rx.await;
+                serde_json::json!({ "type": "success" })
+            })
+            .unwrap();
+        assert_eq!(position, 0);
+
+        assert!(matches!(
+            jobs.poll(&id),
+            Some(JobStatus::Pending { position: 0 })
+        ));
+        tx.send(()).unwrap();
+        while matches!(jobs.poll(&id), Some(JobStatus::Pending { .. })) {
+            tokio::task::yield_now().await;
+        }
+        assert!(jobs.poll(&id).is_none());
+    }
+
+    #[test]
+    fn cancels_only_registered_generations() {
+        let cancellations = Arc::new(Cancellations::default());
+        assert!(!cancellations.cancel(None));
+
+        let first = cancellations.register(Some("a".into()));
+        let second = cancellations.register(Some("a".into()));
+        drop(first);
+        assert!(cancellations.cancel(Some("a")));
+        assert!(second.is_cancelled());
+
+        drop(second);
+        assert!(!cancellations.cancel(Some("a")));
+    }
+}
@@ -0,0 +1,281 @@
+//! API keys, each allowed a set of scopes, so the game server, an ops dashboard and test scripts
+//! don't all have to share one credential.
+//!
+//! The configured `secret` is the root key: it is allowed everything and can't be revoked. Other
+//! keys are kept in a YAML file at `AI_SIDECAR_KEYS_PATH`, see `keys.example.yaml`. Keys created
+//! or revoked through the admin API are written back to it, or only kept in memory without it.
+//!
+//! A key can be pinned to one of the configured tiers, which every request made with it gets, e.g.
+//! to hand a separate game server for premium players its own key.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, thiserror::Error)]
+pub enum KeysError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Yaml(#[from] serde_yaml::Error),
+    #[error("a key named {0:?} already exists")]
+    Exists(String),
+    #[error("unable to generate a key: {0}")]
+    Random(#[from] getrandom::Error),
+}
+
+/// What a key may do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Scope {
+    /// Generating replies and running conversations, as the game server does.
+    Generate,
+    /// Reading conversations back.
+    HistoryRead,
+    /// Managing the sidecar: models, keys, reviews and stats.
+    Admin,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct ApiKey {
+    key: String,
+    scopes: BTreeSet<Scope>,
+    /// The tier every request made with the key gets, whatever it names.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tier: Option<String>,
+    /// Whether requests made with the key may name the client they are made for, which is then
+    /// rate-limited instead of the key as a whole.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    forwards_clients: bool,
+    /// Revoked keys are kept, so it stays clear who held them.
+    #[serde(default)]
+    revoked: bool,
+}
+
+/// A key as listed through the admin API, without the key itself.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct KeyInfo {
+    pub name: String,
+    pub scopes: BTreeSet<Scope>,
+    pub tier: Option<String>,
+    pub revoked: bool,
+}
+
+#[derive(Debug)]
+pub struct KeyStore {
+    root: String,
+    path: Option<PathBuf>,
+    /// By name.
+    keys: Mutex<BTreeMap<String, ApiKey>>,
+}
+
+impl KeyStore {
+    /// Loads the keys at `AI_SIDECAR_KEYS_PATH`, if it is set, alongside the `root` key.
+    pub fn from_env(root: String) -> Result<Self, KeysError> {
+        match std::env::var("AI_SIDECAR_KEYS_PATH") {
+            Ok(path) => Self::load(root, path.into()),
+            Err(_) => Ok(Self::new(root)),
+        }
+    }
+
+    pub fn new(root: String) -> Self {
+        Self {
+            root,
+            path: None,
+            keys: Default::default(),
+        }
+    }
+
+    /// Loads the keys at `path`, which is created on the first change if it doesn't exist yet.
+    pub fn load(root: String, path: PathBuf) -> Result<Self, KeysError> {
+        let keys = match std::fs::read_to_string(&path) {
+            Ok(yaml) => serde_yaml::from_str(&yaml)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e.into()),
+        };
+
+        Ok(Self {
+            root,
+            path: Some(path),
+            keys: Mutex::new(keys),
+        })
+    }
+
+    fn save(path: &Path, keys: &BTreeMap<String, ApiKey>) -> Result<(), KeysError> {
+        std::fs::write(path, serde_yaml::to_string(keys)?)?;
+        Ok(())
+    }
+
+    /// Whether `key` is allowed `scope`.
+    pub fn allows(&self, key: &str, scope: Scope) -> bool {
+        if key == self.root {
+            return true;
+        }
+
+        self.keys.lock().unwrap().values().any(|api_key| {
+            api_key.key == key && !api_key.revoked && api_key.scopes.contains(&scope)
+        })
+    }
+
+    /// The tier `key` is pinned to, if it is a valid key pinned to one.
+    pub fn tier(&self, key: &str) -> Option<String> {
+        self.keys
+            .lock()
+            .unwrap()
+            .values()
+            .find(|api_key| api_key.key == key && !api_key.revoked)
+            .and_then(|api_key| api_key.tier.clone())
+    }
+
+    /// The name of whoever holds `key`, and whether they may name the clients they make requests
+    /// for. The root key may.
+    pub fn holder(&self, key: &str) -> Option<(String, bool)> {
+        if key == self.root {
+            return Some(("root".into(), true));
+        }
+
+        self.keys
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|(_, api_key)| api_key.key == key && !api_key.revoked)
+            .map(|(name, api_key)| (name.clone(), api_key.forwards_clients))
+    }
+
+    pub fn list(&self) -> Vec<KeyInfo> {
+        self.keys
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, api_key)| KeyInfo {
+                name: name.clone(),
+                scopes: api_key.scopes.clone(),
+                tier: api_key.tier.clone(),
+                revoked: api_key.revoked,
+            })
+            .collect()
+    }
+
+    /// Creates a key named `name`, optionally pinned to `tier`, returning it. This is the only time
+    /// the key is shown.
+    pub fn create(
+        &self,
+        name: String,
+        scopes: BTreeSet<Scope>,
+        tier: Option<String>,
+    ) -> Result<String, KeysError> {
+        let mut keys = self.keys.lock().unwrap();
+        if keys.contains_key(&name) {
+            return Err(KeysError::Exists(name));
+        }
+
+        // Drawn from the OS, since anyone who could guess a key could use it.
+        let mut bytes = [0; 32];
+        getrandom::fill(&mut bytes)?;
+        let key = bytes
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect::<String>();
+        keys.insert(
+            name.clone(),
+            ApiKey {
+                key: key.clone(),
+                scopes,
+                tier,
+                forwards_clients: false,
+                revoked: false,
+            },
+        );
+        if let Some(path) = &self.path {
+            if let Err(e) = Self::save(path, &keys) {
+                keys.remove(&name);
+                return Err(e);
+            }
+        }
+        tracing::info!("created API key {name:?}");
+
+        Ok(key)
+    }
+
+    /// Revokes the key named `name`, returning `false` if there is none.
+    pub fn revoke(&self, name: &str) -> Result<bool, KeysError> {
+        let mut keys = self.keys.lock().unwrap();
+        let Some(api_key) = keys.get_mut(name) else {
+            return Ok(false);
+        };
+        let was_revoked = std::mem::replace(&mut api_key.revoked, true);
+        if let Some(path) = &self.path {
+            if let Err(e) = Self::save(path, &keys) {
+                if let Some(api_key) = keys.get_mut(name) {
+                    api_key.revoked = was_revoked;
+                }
+                return Err(e);
+            }
+        }
+        tracing::info!("revoked API key {name:?}");
+
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_are_scoped_and_revocable() {
+        let keys: BTreeMap<String, ApiKey> = serde_yaml::from_str(include_str!(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/keys.example.yaml"
+        )))
+        .unwrap();
+        let store = KeyStore {
+            keys: Mutex::new(keys),
+            ..KeyStore::new("root".into())
+        };
+
+        assert!(store.allows("root", Scope::Admin));
+        assert!(store.allows("change-me-game-server", Scope::Generate));
+        assert!(!store.allows("change-me-game-server", Scope::Admin));
+        assert!(store.allows("change-me-dashboard", Scope::HistoryRead));
+        assert!(!store.allows("wrong", Scope::Generate));
+
+        let key = store
+            .create(
+                "tests".into(),
+                BTreeSet::from([Scope::Generate]),
+                Some("premium".into()),
+            )
+            .unwrap();
+        assert_eq!(key.len(), 64);
+        assert!(store.allows(&key, Scope::Generate));
+        assert_eq!(store.tier(&key).as_deref(), Some("premium"));
+        assert_eq!(store.tier("change-me-game-server"), None);
+        assert_eq!(store.holder(&key), Some(("tests".into(), false)));
+        assert_eq!(
+            store.holder("change-me-game-server"),
+            Some(("game-server".into(), true))
+        );
+        assert_eq!(store.holder("root"), Some(("root".into(), true)));
+        assert_eq!(store.holder("wrong"), None);
+        assert!(matches!(
+            store.create("tests".into(), BTreeSet::new(), None),
+            Err(KeysError::Exists(_))
+        ));
+
+        assert!(store.revoke("tests").unwrap());
+        assert!(!store.allows(&key, Scope::Generate));
+        assert_eq!(store.tier(&key), None);
+        assert_eq!(store.holder(&key), None);
+        assert!(!store.revoke("missing").unwrap());
+        assert!(store
+            .list()
+            .iter()
+            .any(|info| info.name == "tests" && info.revoked));
+    }
+}
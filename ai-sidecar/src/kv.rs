@@ -0,0 +1,398 @@
+//! Each conversation's llama.cpp session, kept between its turns so a prompt only feeds the model
+//! what changed since the last one. Feeding it the whole conversation again is most of the wait
+//! for a reply.
+//!
+//! A kept session holds the last prompt and its reply. The next prompt keeps the tokens it starts
+//! with in common with those, and the session is cut back to where they part, so an edited or
+//! cleared history is fed again from the first change. Sessions are kept for the `kept_sessions`
+//! most recently used conversations, one per model. Seeded generations always get a fresh session,
+//! since a kept one has already drawn from its random numbers.
+//!
+//! Conversations without a kept session start from a copy of one fed only their opening: the
+//! default system prompt or a persona's, and its greeting. Those are evaluated once at startup and
+//! whenever the model is swapped, unless `prefix_cache` is off. Each one holds a full context's
+//! cache in memory, so only the `prefix_cache_size` most used are, by their generations so far.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, Weak,
+    },
+};
+
+use llama_cpp::{LlamaModel, LlamaSession, SessionParams, Token};
+use serde::Serialize;
+
+use crate::{history::History, llm::SessionSettings, server::AppState, templates::Template};
+
+pub const DEFAULT_KEPT_SESSIONS: usize = 4;
+pub const DEFAULT_PREFIX_CACHE_SIZE: usize = 8;
+
+struct Kept {
+    /// What the session was made with, since it can't be changed afterwards.
+    settings: SessionSettings,
+    session: LlamaSession,
+}
+
+/// A conversation's session from its last turn, once it has had one.
+#[derive(Clone, Default)]
+pub struct KeptSession(Arc<Mutex<Option<Kept>>>);
+
+impl std::fmt::Debug for KeptSession {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("KeptSession").finish_non_exhaustive()
+    }
+}
+
+impl KeptSession {
+    /// Takes the session to generate with, if it was made with `settings`.
+    pub fn take(&self, settings: SessionSettings) -> Option<LlamaSession> {
+        self.0
+            .lock()
+            .unwrap()
+            .take()
+            .filter(|kept| kept.settings == settings)
+            .map(|kept| kept.session)
+    }
+
+    pub fn keep(&self, settings: SessionSettings, session: LlamaSession) {
+        *self.0.lock().unwrap() = Some(Kept { settings, session });
+    }
+}
+
+/// How many of `held` tokens `prompt` starts with.
+pub fn shared_prefix(held: &[Token], prompt: &[Token]) -> usize {
+    held.iter()
+        .zip(prompt)
+        .take_while(|(held, prompt)| held == prompt)
+        .count()
+}
+
+/// A conversation, by session id, on a model, by name.
+type Key = (Option<String>, Option<String>);
+
+#[derive(Debug, Default)]
+struct Entries {
+    sessions: HashMap<Key, KeptSession>,
+    /// Least recently used first.
+    order: VecDeque<Key>,
+}
+
+#[derive(Debug, Default)]
+pub struct KeptSessions {
+    capacity: usize,
+    entries: Mutex<Entries>,
+}
+
+impl KeptSessions {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            ..Self::default()
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    /// The session kept for the conversation on the model picked by name, if any, unless sessions
+    /// aren't kept at all.
+    pub fn get(&self, session_id: Option<&str>, model: Option<&str>) -> Option<KeptSession> {
+        if !self.enabled() {
+            return None;
+        }
+        let key = (session_id.map(str::to_string), model.map(str::to_string));
+        let mut entries = self.entries.lock().unwrap();
+        entries.order.retain(|used| *used != key);
+        if !entries.sessions.contains_key(&key) && entries.order.len() == self.capacity {
+            if let Some(evicted) = entries.order.pop_front() {
+                entries.sessions.remove(&evicted);
+            }
+        }
+        entries.order.push_back(key.clone());
+
+        Some(entries.sessions.entry(key).or_default().clone())
+    }
+
+    pub fn clear(&self) {
+        *self.entries.lock().unwrap() = Entries::default();
+    }
+}
+
+/// What a prefix's session was fed, and how it was made.
+#[derive(Debug)]
+struct Opening {
+    system: String,
+    template: Template,
+    settings: SessionSettings,
+}
+
+/// A session fed only the opening of conversations with `system` as their system message.
+struct Prefix {
+    model: Weak<LlamaModel>,
+    opening: Opening,
+    session: LlamaSession,
+}
+
+/// The candidate with the longest opening that a conversation with `system`, in `template` with
+/// `settings`, starts with, if any does.
+fn best_fit<'a, T>(
+    candidates: impl IntoIterator<Item = (&'a Opening, T)>,
+    system: &str,
+    template: Template,
+    settings: SessionSettings,
+) -> Option<T> {
+    candidates
+        .into_iter()
+        .filter(|(opening, _)| {
+            opening.template == template
+                && opening.settings == settings
+                && system.starts_with(&opening.system)
+        })
+        .max_by_key(|(opening, _)| opening.system.len())
+        .map(|(_, candidate)| candidate)
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct PrefixStats {
+    /// Openings evaluated ahead of time.
+    pub prompts: usize,
+    /// Sessions started from a copy of one.
+    pub hits: u64,
+    /// Prompt tokens those copies didn't have to be fed.
+    pub tokens_saved: u64,
+}
+
+#[derive(Default)]
+pub struct Prefixes {
+    prefixes: Mutex<Vec<Prefix>>,
+    hits: AtomicU64,
+    tokens_saved: AtomicU64,
+}
+
+impl std::fmt::Debug for Prefixes {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Prefixes").finish_non_exhaustive()
+    }
+}
+
+impl Prefixes {
+    /// Feeds the opening of a conversation with each system message and greeting to a session of
+    /// its own, replacing those fed before.
+    pub fn evaluate(
+        &self,
+        model: &Arc<LlamaModel>,
+        template: Template,
+        settings: SessionSettings,
+        openings: impl IntoIterator<Item = (String, Option<String>)>,
+    ) {
+        let mut prefixes = Vec::new();
+        for (system, greeting) in openings {
+            let mut history = History::new(system.clone());
+            if let Some(greeting) = greeting {
+                history.set_greeting(greeting);
+            }
+            let fed = model
+                .create_session(SessionParams::from(settings))
+                .map_err(|e| e.to_string())
+                .and_then(|mut session| {
+                    session
+                        .advance_context(history.prompt(template, None, None))
+                        .map_err(|e| e.to_string())?;
+                    Ok(session)
+                });
+            match fed {
+                Ok(session) => prefixes.push(Prefix {
+                    model: Arc::downgrade(model),
+                    opening: Opening {
+                        system,
+                        template,
+                        settings,
+                    },
+                    session,
+                }),
+                Err(e) => tracing::warn!("unable to evaluate a system prompt ahead of time: {e}"),
+            }
+        }
+        tracing::info!("evaluated {} system prompts ahead of time", prefixes.len());
+        *self.prefixes.lock().unwrap() = prefixes;
+    }
+
+    /// A copy of the session fed the longest opening of a conversation with `system` on `model`,
+    /// in `template` with `settings`, if there is one.
+    pub fn copy(
+        &self,
+        model: &LlamaModel,
+        system: &str,
+        template: Template,
+        settings: SessionSettings,
+    ) -> Option<LlamaSession> {
+        let prefixes = self.prefixes.lock().unwrap();
+        let on_model = prefixes
+            .iter()
+            .filter(|prefix| std::ptr::eq(prefix.model.as_ptr(), model))
+            .map(|prefix| (&prefix.opening, &prefix.session));
+        let session = best_fit(on_model, system, template, settings)?;
+
+        session
+            .deep_copy()
+            .inspect_err(|e| tracing::warn!("unable to copy a system prompt's session: {e}"))
+            .ok()
+    }
+
+    /// Counts a copy that spared feeding `tokens` prompt tokens.
+    pub fn saved(&self, tokens: usize) {
+        self.hits.fetch_add(1, Ordering::Relaxed);
+        self.tokens_saved
+            .fetch_add(tokens.try_into().unwrap_or(u64::MAX), Ordering::Relaxed);
+    }
+
+    pub fn clear(&self) {
+        self.prefixes.lock().unwrap().clear();
+    }
+
+    pub fn stats(&self) -> PrefixStats {
+        PrefixStats {
+            prompts: self.prefixes.lock().unwrap().len(),
+            hits: self.hits.load(Ordering::Relaxed),
+            tokens_saved: self.tokens_saved.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// The first `limit` openings, by persona, once the most used are first.
+fn most_used<T>(
+    mut openings: Vec<(Option<String>, T)>,
+    generations: &HashMap<Option<String>, u64>,
+    limit: usize,
+) -> Vec<T> {
+    openings.sort_by_key(|(persona, _)| {
+        std::cmp::Reverse(generations.get(persona).copied().unwrap_or_default())
+    });
+    openings
+        .into_iter()
+        .take(limit)
+        .map(|(_, opening)| opening)
+        .collect()
+}
+
+/// Evaluates the openings of conversations with the default system prompt and the most used
+/// personas', on the inference thread with whichever model is loaded by the time it gets there.
+pub fn prepare(state: &AppState) {
+    state.prefixes.clear();
+    if !state.config.prefix_cache || state.config.prefix_cache_size == 0 || state.replica.is_some()
+    {
+        return;
+    }
+
+    let state = state.clone();
+    let jobs = state.jobs.clone();
+    let queued = jobs.submit(move || {
+        let Some(model) = state.ai_model.load_full() else {
+            return;
+        };
+        let openings = std::iter::once((None, (state.config.system_prompt.clone(), None)))
+            .chain(
+                state
+                    .personas
+                    .list()
+                    .into_iter()
+                    .map(|(id, persona)| (Some(id), (persona.system_prompt, persona.greeting))),
+            )
+            .collect();
+        let openings = most_used(
+            openings,
+            &state.tallies.generations(),
+            state.config.prefix_cache_size,
+        );
+        state.prefixes.evaluate(
+            &model,
+            state.config.prompt_template,
+            state.config.session(),
+            openings,
+        );
+    });
+    if let Err(e) = queued {
+        tracing::warn!("unable to evaluate system prompts ahead of time: {e}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_the_most_recently_used() {
+        assert_eq!(shared_prefix(&[Token(1), Token(2), Token(3)], &[Token(1), Token(2)]), 2);
+        assert_eq!(shared_prefix(&[Token(1), Token(2)], &[Token(1), Token(4), Token(2)]), 1);
+
+        assert!(KeptSessions::new(0).get(None, None).is_none());
+
+        let sessions = KeptSessions::new(2);
+        let default = sessions.get(None, None).unwrap();
+        let tavern = sessions.get(Some("tavern"), None).unwrap();
+        assert!(Arc::ptr_eq(&default.0, &sessions.get(None, None).unwrap().0));
+        sessions.get(Some("tavern"), Some("flavor"));
+        assert!(Arc::ptr_eq(&default.0, &sessions.get(None, None).unwrap().0));
+        assert!(!Arc::ptr_eq(&tavern.0, &sessions.get(Some("tavern"), None).unwrap().0));
+    }
+
+    #[test]
+    fn prefers_the_most_used_openings() {
+        let openings = vec![
+            (None, "default"),
+            (Some("bram".into()), "bram"),
+            (Some("smith".into()), "smith"),
+        ];
+        let generations = HashMap::from([(Some("smith".to_string()), 3), (None, 1)]);
+
+        assert_eq!(
+            most_used(openings.clone(), &generations, 2),
+            ["smith", "default"]
+        );
+        assert_eq!(
+            most_used(openings.clone(), &HashMap::new(), 2),
+            ["default", "bram"]
+        );
+        assert!(most_used(openings, &generations, 0).is_empty());
+    }
+
+    #[test]
+    fn copies_the_longest_fitting_opening() {
+        let settings = SessionSettings::default();
+        let opening = |system: &str, template| Opening {
+            system: system.into(),
+            template,
+            settings,
+        };
+        let openings = [
+            opening("You are a tavern keeper.", Template::Zephyr),
+            opening("You are a tavern keeper.\nYou are Bram", Template::Zephyr),
+            opening(
+                "You are a tavern keeper.\nYou are Bram, who owes money.",
+                Template::ChatMl,
+            ),
+            opening("You are a smith.", Template::Zephyr),
+        ];
+        let fit = |system: &str, template, settings| {
+            best_fit(openings.iter().zip(0..), system, template, settings)
+        };
+
+        let bram = "You are a tavern keeper.\nYou are Bram, who owes money.";
+        assert_eq!(fit(bram, Template::Zephyr, settings), Some(1));
+        assert_eq!(fit(bram, Template::ChatMl, settings), Some(2));
+        assert_eq!(
+            fit("You are a tavern keeper.", Template::Zephyr, settings),
+            Some(0)
+        );
+        assert_eq!(fit("You are a baker.", Template::Zephyr, settings), None);
+
+        let bigger = SessionSettings {
+            context_size: Some(8192),
+            ..settings
+        };
+        assert_eq!(fit(bram, Template::Zephyr, bigger), None);
+    }
+}
@@ -1,6 +1,74 @@
+pub(crate) mod analytics;
 pub(crate) mod api;
+pub(crate) mod backend;
+pub(crate) mod banner;
+pub(crate) mod bias;
+pub(crate) mod bounds;
+pub(crate) mod budgets;
+pub(crate) mod bundle;
+pub(crate) mod cache;
+pub(crate) mod canary;
+#[cfg(feature = "chaos")]
+pub(crate) mod chaos;
+pub(crate) mod check;
+pub(crate) mod compaction;
+pub(crate) mod config;
+pub(crate) mod degradation;
+pub(crate) mod diff;
+pub(crate) mod exploits;
+pub(crate) mod export;
+pub(crate) mod failover;
+pub(crate) mod fallback;
+pub(crate) mod flags;
+pub(crate) mod formatting;
+pub(crate) mod game_context;
+pub(crate) mod goals;
+pub(crate) mod governor;
+pub(crate) mod group;
+pub(crate) mod handoff;
 pub(crate) mod history;
+pub(crate) mod hooks;
+pub(crate) mod jobs;
+pub(crate) mod keys;
+pub(crate) mod kv;
 pub(crate) mod llm;
+pub(crate) mod locale;
+pub(crate) mod locations;
+pub(crate) mod lore;
+pub(crate) mod memory;
+pub(crate) mod migrations;
+pub(crate) mod models;
+pub(crate) mod moderation;
+pub(crate) mod monitor;
+pub(crate) mod narrative;
+pub(crate) mod outbound;
+pub(crate) mod outcomes;
+pub(crate) mod pack;
+pub(crate) mod persist;
+pub(crate) mod personas;
+pub(crate) mod placeholders;
+pub(crate) mod quests;
+pub(crate) mod ratelimit;
+pub(crate) mod replica;
+pub(crate) mod retrieval;
+pub(crate) mod review;
 pub(crate) mod server;
+pub(crate) mod sessions;
+pub(crate) mod slo;
+pub(crate) mod streams;
+pub(crate) mod tallies;
+pub(crate) mod templates;
+pub(crate) mod temporal;
+pub(crate) mod tiers;
+pub(crate) mod tokenizer;
+pub(crate) mod tracecontext;
+pub(crate) mod traces;
+pub(crate) mod turns;
+pub(crate) mod utf8;
 
-pub use server::serve;
+pub use bundle::{export_state, import_state};
+pub use check::check_config;
+pub use export::export_dataset;
+pub use keys::Scope;
+pub use pack::generate_pack;
+pub use server::{serve, AppState, ServerError, Sidecar};
@@ -1,44 +1,683 @@
-use llama_cpp::{standard_sampler::StandardSampler, LlamaModel, SessionParams};
+use std::{fmt, sync::Arc};
 
-use crate::history::{self, History};
+use llama_cpp::{
+    grammar::LlamaGrammar,
+    standard_sampler::{SamplerStage, StandardSampler},
+    CompletionHandle, LlamaModel, LlamaSession, SessionParams, Token,
+};
+use serde::{Deserialize, Serialize};
+use tokio_util::sync::CancellationToken;
 
-const DEFAULT_MAX_TOKENS: usize = 128;
+use crate::{
+    bias::{Bias, BiasedSampler},
+    formatting::Formatting,
+    history::History,
+    kv::{self, KeptSession, Prefixes},
+    templates::Template,
+    tokenizer,
+    utf8::Utf8Buffer,
+};
 
-#[derive(Debug)]
+pub const DEFAULT_MAX_TOKENS: usize = 128;
+pub const DEFAULT_THREADS: u32 = 1;
+
+// Same as `StandardSampler::default()`.
+const DEFAULT_TEMPERATURE: f32 = 0.8;
+const DEFAULT_TOP_P: f32 = 0.95;
+const DEFAULT_TOP_K: i32 = 40;
+const DEFAULT_MIN_P: f32 = 0.05;
+const DEFAULT_REPEAT_PENALTY: f32 = 1.1;
+const REPEAT_PENALTY_LAST_N: i32 = 64;
+// Same as llama.cpp's server.
+const DEFAULT_MIROSTAT_TAU: f32 = 5.0;
+const DEFAULT_MIROSTAT_ETA: f32 = 0.1;
+const MIROSTAT_M: i32 = 100;
+
+/// llama.cpp's grammar for a JSON object.
+pub const JSON_GRAMMAR: &str =
+    include_str!(concat!(env!("CARGO_MANIFEST_DIR"), "/grammars/json.gbnf"));
+
+/// The shape the output must take.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ResponseFormat {
+    #[default]
+    Text,
+    /// A single JSON object.
+    Json,
+}
+
+/// What becomes of the special tokens a model produces, such as `<|im_end|>` between turns.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SpecialTokens {
+    /// Left out of the reply.
+    #[default]
+    Strip,
+    /// Written into the reply as their text.
+    Keep,
+    /// Left out of the reply's text, but listed alongside it with where they fell.
+    Mark,
+}
+
+/// A special token the model produced, `offset` bytes into the reply's text.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SpecialToken {
+    pub offset: usize,
+    pub token: String,
+}
+
+/// Which Mirostat version picks tokens, numbered like llama.cpp's `mirostat` setting.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "u8", into = "u8")]
+pub enum Mirostat {
+    #[default]
+    Off,
+    V1,
+    V2,
+}
+
+impl TryFrom<u8> for Mirostat {
+    type Error = String;
+
+    fn try_from(version: u8) -> Result<Self, Self::Error> {
+        match version {
+            0 => Ok(Self::Off),
+            1 => Ok(Self::V1),
+            2 => Ok(Self::V2),
+            _ => Err(format!("mirostat must be 0, 1 or 2, not {version}")),
+        }
+    }
+}
+
+impl From<Mirostat> for u8 {
+    fn from(mirostat: Mirostat) -> Self {
+        match mirostat {
+            Mirostat::Off => 0,
+            Mirostat::V1 => 1,
+            Mirostat::V2 => 2,
+        }
+    }
+}
+
+/// How the next token is picked. Unset values keep `StandardSampler`'s defaults.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub struct SamplerOptions {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_k: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub repeat_penalty: Option<f32>,
+    /// Replaces top-p, top-k and min-p with Mirostat's target-perplexity sampling.
+    #[serde(default)]
+    pub mirostat: Mirostat,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mirostat_tau: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mirostat_eta: Option<f32>,
+}
+
+impl SamplerOptions {
+    /// These settings, with the ones left unset taken from `defaults`. Mirostat counts as unset
+    /// while it is off.
+    pub fn or(self, defaults: &SamplerOptions) -> SamplerOptions {
+        SamplerOptions {
+            temperature: self.temperature.or(defaults.temperature),
+            top_p: self.top_p.or(defaults.top_p),
+            top_k: self.top_k.or(defaults.top_k),
+            min_p: self.min_p.or(defaults.min_p),
+            repeat_penalty: self.repeat_penalty.or(defaults.repeat_penalty),
+            mirostat: match self.mirostat {
+                Mirostat::Off => defaults.mirostat,
+                mirostat => mirostat,
+            },
+            mirostat_tau: self.mirostat_tau.or(defaults.mirostat_tau),
+            mirostat_eta: self.mirostat_eta.or(defaults.mirostat_eta),
+        }
+    }
+
+    /// Builds the sampler, constrained to `grammar` if one is given.
+    pub fn build(&self, grammar: Option<LlamaGrammar>) -> StandardSampler {
+        let mut stages = vec![SamplerStage::RepetitionPenalty {
+            repetition_penalty: self.repeat_penalty.unwrap_or(DEFAULT_REPEAT_PENALTY),
+            frequency_penalty: 0.0,
+            presence_penalty: 0.0,
+            last_n: REPEAT_PENALTY_LAST_N,
+        }];
+        // Filtering by grammar first leaves the rest to pick among valid tokens only.
+        if let Some(grammar) = grammar {
+            stages.push(SamplerStage::from_grammar(grammar, None));
+        }
+        let temperature =
+            SamplerStage::Temperature(self.temperature.unwrap_or(DEFAULT_TEMPERATURE));
+        let tau = self.mirostat_tau.unwrap_or(DEFAULT_MIROSTAT_TAU);
+        let eta = self.mirostat_eta.unwrap_or(DEFAULT_MIROSTAT_ETA);
+
+        match self.mirostat {
+            Mirostat::Off => {
+                stages.extend([
+                    SamplerStage::TopK(self.top_k.unwrap_or(DEFAULT_TOP_K)),
+                    SamplerStage::TopP(self.top_p.unwrap_or(DEFAULT_TOP_P)),
+                    SamplerStage::MinP(self.min_p.unwrap_or(DEFAULT_MIN_P)),
+                    temperature,
+                ]);
+                StandardSampler::new_softmax(stages, 1)
+            }
+            Mirostat::V1 => {
+                stages.push(temperature);
+                StandardSampler::new_mirostat(stages, 1, tau, eta, MIROSTAT_M)
+            }
+            Mirostat::V2 => {
+                stages.push(temperature);
+                StandardSampler::new_mirostat_v2(stages, 1, tau, eta)
+            }
+        }
+    }
+}
+
+/// How each generation's llama.cpp session is set up.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SessionSettings {
+    pub threads: u32,
+    /// Tokens the session holds, prompt and reply together. llama.cpp's default, if unset.
+    pub context_size: Option<u32>,
+    /// Prompt tokens evaluated at once. llama.cpp's default, if unset.
+    pub batch_size: Option<u32>,
+}
+
+impl Default for SessionSettings {
+    fn default() -> Self {
+        Self {
+            threads: DEFAULT_THREADS,
+            context_size: None,
+            batch_size: None,
+        }
+    }
+}
+
+impl From<SessionSettings> for SessionParams {
+    fn from(settings: SessionSettings) -> Self {
+        let defaults = Self::default();
+        Self {
+            n_threads: settings.threads,
+            n_ctx: settings.context_size.unwrap_or(defaults.n_ctx),
+            n_batch: settings.batch_size.unwrap_or(defaults.n_batch),
+            ..defaults
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct Options {
     pub setup: Option<String>,
     pub prompt: String,
     pub max_tokens: Option<usize>,
+    /// Extra background appended to the system message, e.g. memories about the player.
+    pub context: Option<String>,
+    pub session: SessionSettings,
+    pub sampler: SamplerOptions,
+    /// Stops generation early, leaving the history as it was before.
+    pub cancel: Option<CancellationToken>,
+    /// Who replies, in a group conversation.
+    pub speaker: Option<String>,
+    /// Who the prompt is from, in a party conversation.
+    pub player: Option<String>,
+    /// Constrains the output, e.g. to valid JSON.
+    pub grammar: Option<LlamaGrammar>,
+    /// How the prompt is marked up for the model.
+    pub template: Template,
+    /// Ends the reply as soon as it produces any of these, leaving them out of it.
+    pub stop: Vec<String>,
+    /// Makes tokens more or less likely, and bans words outright.
+    pub bias: Bias,
+    pub special_tokens: SpecialTokens,
+    /// Seeds the sampler, so the same prompt and seed give the same reply on the same model and
+    /// settings. Random, if unset.
+    pub seed: Option<u32>,
+    pub pipeline: Pipeline,
+    /// The conversation's session from its last turn, fed only what changed since and kept for
+    /// the next. A fresh one, if unset. Never set along with `seed`.
+    pub kept_session: Option<KeptSession>,
+    /// Sessions fed only a system prompt, a copy of which a fresh session starts from if the
+    /// conversation opens the same way.
+    pub prefixes: Option<Arc<Prefixes>>,
 }
 
-pub fn generate_text(
+/// Rewrites a prompt before it is added to the conversation.
+pub trait PrePrompt: fmt::Debug + Send + Sync {
+    fn pre_prompt(&self, prompt: &str) -> String;
+}
+
+/// Rewrites a finished reply before it is stored or sent. Streamed tokens still go out as the
+/// model wrote them.
+pub trait PostCompletion: fmt::Debug + Send + Sync {
+    fn post_completion(&self, text: &str) -> String;
+}
+
+/// The hooks a generation's prompt and reply go through, each in order. None, by default.
+#[derive(Debug, Clone, Default)]
+pub struct Pipeline {
+    pub pre_prompt: Vec<Arc<dyn PrePrompt>>,
+    pub post_completion: Vec<Arc<dyn PostCompletion>>,
+}
+
+impl Pipeline {
+    pub fn prompt(&self, prompt: String) -> String {
+        self.pre_prompt
+            .iter()
+            .fold(prompt, |prompt, hook| hook.pre_prompt(&prompt))
+    }
+
+    pub fn completion(&self, generated: &mut Generated) {
+        for hook in &self.post_completion {
+            generated.map_text(|text| hook.post_completion(text));
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("generation was cancelled")]
+pub struct Cancelled;
+
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct Usage {
+    pub prompt_tokens: usize,
+    pub completion_tokens: usize,
+}
+
+/// A piece of a streamed reply.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Chunk<'a> {
+    Text(&'a str),
+    /// A special token, when they are marked.
+    Special(&'a str),
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Generated {
+    pub text: String,
+    pub usage: Usage,
+    /// Where the special tokens fell in `text`, unless they were stripped.
+    pub special_tokens: Vec<SpecialToken>,
+    /// How many times the reply was generated again before it passed moderation.
+    pub regenerations: usize,
+}
+
+impl Generated {
+    /// The text with its special tokens written back in where they fell.
+    pub fn with_special_tokens(&self) -> String {
+        let mut text = String::new();
+        let mut at = 0;
+        for special in &self.special_tokens {
+            text.push_str(&self.text[at..special.offset]);
+            text.push_str(&special.token);
+            at = special.offset;
+        }
+        text.push_str(&self.text[at..]);
+
+        text
+    }
+
+    /// Converts the text's Markdown, between special tokens so their offsets still hold.
+    pub fn format(&mut self, formatting: Formatting) {
+        self.map_text(|text| formatting.apply(text));
+    }
+
+    /// Rewrites the text between special tokens, keeping their offsets in step.
+    pub fn map_text(&mut self, f: impl Fn(&str) -> String) {
+        let mut text = String::new();
+        let mut at = 0;
+        for special in &mut self.special_tokens {
+            text.push_str(&f(&self.text[at..special.offset]));
+            at = special.offset;
+            special.offset = text.len();
+        }
+        text.push_str(&f(&self.text[at..]));
+        self.text = text;
+    }
+
+    pub fn trim_start(&mut self) {
+        let trimmed = self.text.len() - self.text.trim_start().len();
+        self.text.drain(..trimmed);
+        for special in &mut self.special_tokens {
+            special.offset = special.offset.saturating_sub(trimmed);
+        }
+    }
+}
+
+/// The text of `token`, whose piece is empty, if it is a special one rather than nothing at all.
+fn special_text(model: &LlamaModel, token: Token) -> Option<String> {
+    let text = model.detokenize(token);
+    (!text.is_empty()).then(|| String::from_utf8_lossy(text).into_owned())
+}
+
+/// Where the earliest of the `stop` sequences in `text` starts, if any.
+fn find_stop(text: &str, stop: &[String]) -> Option<usize> {
+    stop.iter()
+        .filter_map(|stop| text.find(stop.as_str()))
+        .min()
+}
+
+/// How much of the end of `text` to hold back, since it could still grow into a stop sequence.
+fn held_back(text: &str, stop: &[String]) -> usize {
+    text.char_indices()
+        .map(|(i, _)| &text[i..])
+        .find(|tail| stop.iter().any(|stop| stop.starts_with(tail)))
+        .map_or(0, str::len)
+}
+
+/// The system message replacing the conversation's own, if `setup` or extra `context` is given.
+pub fn system_message(
+    history: &History,
+    setup: Option<String>,
+    context: Option<String>,
+) -> Option<String> {
+    match (setup, context) {
+        (Some(v), Some(context)) => Some(format!("{v}\n\n{context}")),
+        (Some(v), None) => Some(v),
+        (None, Some(context)) => Some(format!("{}\n\n{context}", history.system.content())),
+        (None, None) => None,
+    }
+}
+
+/// Feeds the prompt to the kept session, or else a copy of one fed its system prompt or a fresh
+/// one, and starts completing it, returning the completion, the number of prompt tokens and the
+/// session.
+fn start_completion(
     model: &LlamaModel,
     history: &mut History,
-    opts: impl Into<Options>,
-) -> Result<String, Box<dyn std::error::Error>> {
+    opts: Options,
+) -> Result<(CompletionHandle, usize, LlamaSession), Box<dyn std::error::Error>> {
     let Options {
         setup,
         prompt,
         max_tokens,
-    } = opts.into();
-
-    let mut ctx = model.create_session(SessionParams {
-        n_threads: 1,
-        ..Default::default()
-    })?;
-
-    history.push(history::MessageType::User, prompt);
-    ctx.advance_context(match setup {
-        Some(v) => history.get_with_system(v),
-        None => history.get(),
-    })?;
-
-    let completions = ctx
-        .start_completing_with(
-            StandardSampler::default(),
-            max_tokens.unwrap_or(DEFAULT_MAX_TOKENS),
-        )?
-        .into_string();
-
-    Ok(completions)
+        context,
+        session,
+        sampler,
+        cancel: _,
+        speaker,
+        player,
+        grammar,
+        template,
+        stop: _,
+        bias,
+        special_tokens: _,
+        seed,
+        pipeline,
+        kept_session,
+        prefixes,
+    } = opts;
+
+    let system = system_message(history, setup, context);
+    let mut copied = false;
+    let mut ctx = match kept_session.and_then(|kept| kept.take(session)) {
+        Some(ctx) => ctx,
+        None => {
+            let opening = system.as_deref().unwrap_or(history.system.content());
+            // A copy has drawn from the prefix's random numbers rather than the seed's.
+            let copy = prefixes
+                .as_ref()
+                .filter(|_| seed.is_none())
+                .and_then(|prefixes| prefixes.copy(model, opening, template, session));
+            copied = copy.is_some();
+            match copy {
+                Some(ctx) => ctx,
+                None => {
+                    let mut params = SessionParams::from(session);
+                    if let Some(seed) = seed {
+                        params.seed = seed;
+                    }
+                    model.create_session(params)?
+                }
+            }
+        }
+    };
+
+    history.push_prompt(player, pipeline.prompt(prompt));
+    // Leaves room in the context for the reply.
+    let max_tokens = max_tokens.unwrap_or(DEFAULT_MAX_TOKENS);
+    let budget = ctx.context_size().saturating_sub(max_tokens);
+    let prompt = model.tokenize_bytes(
+        history.prompt_within(template, system, speaker.as_deref(), budget, |text| {
+            tokenizer::count(model, text)
+        }),
+        false,
+        false,
+    )?;
+    // Only what changed since the session's last turn is fed again, but at least the last token,
+    // since the reply is sampled from what it predicts.
+    let shared = kv::shared_prefix(&ctx.context(), &prompt).min(prompt.len().saturating_sub(1));
+    if let Some(prefixes) = prefixes.filter(|_| copied) {
+        prefixes.saved(shared);
+    }
+    ctx.truncate_context(shared);
+    ctx.advance_context_with_tokens(&prompt[shared..])?;
+
+    let sampler = BiasedSampler::new(sampler.build(grammar), &bias, model);
+    let completion = ctx.start_completing_with(sampler, max_tokens)?;
+    let prompt_tokens = ctx.context().len();
+
+    Ok((completion, prompt_tokens, ctx))
+}
+
+pub fn generate_text(
+    model: &LlamaModel,
+    history: &mut History,
+    opts: impl Into<Options>,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let generated = generate_text_streaming(model, history, opts, |_| true)?;
+
+    Ok(generated.text)
+}
+
+/// Like [`generate_text`], but hands each piece of text to `on_chunk` as soon as it is decoded.
+/// Generation stops early if `on_chunk` returns `false`. Text that could be the start of a stop
+/// sequence is only passed on once it turns out not to be, and never runs on into one across a
+/// special token.
+pub fn generate_text_streaming(
+    model: &LlamaModel,
+    history: &mut History,
+    opts: impl Into<Options>,
+    mut on_chunk: impl FnMut(Chunk) -> bool,
+) -> Result<Generated, Box<dyn std::error::Error>> {
+    let opts = opts.into();
+    let cancel = opts.cancel.clone().unwrap_or_default();
+    if cancel.is_cancelled() {
+        return Err(Box::new(Cancelled));
+    }
+    let stop = opts
+        .stop
+        .iter()
+        .filter(|stop| !stop.is_empty())
+        .cloned()
+        .collect::<Vec<_>>();
+    let mode = opts.special_tokens;
+    let pipeline = opts.pipeline.clone();
+    let settings = opts.session;
+    let kept_session = opts.kept_session.clone();
+    let (completion, prompt_tokens, ctx) = start_completion(model, history, opts)?;
+
+    let mut completion_tokens = 0;
+    let mut output = String::new();
+    let mut special_tokens = Vec::new();
+    let mut emitted = 0;
+    // Stop sequences are only looked for since the last special token.
+    let mut searched = 0;
+    let mut listening = true;
+    let mut utf8 = Utf8Buffer::default();
+    // `None` marks the end, so whatever the buffer still holds is let out.
+    for token in completion.map(Some).chain([None]) {
+        if cancel.is_cancelled() {
+            tracing::debug!("generation cancelled");
+            history.history.pop();
+            return Err(Box::new(Cancelled));
+        }
+        let (chunk, special) = match token {
+            Some(token) => {
+                completion_tokens += 1;
+                let piece = model.token_to_byte_piece(token);
+                let special = match mode {
+                    SpecialTokens::Keep | SpecialTokens::Mark if piece.is_empty() => {
+                        special_text(model, token)
+                    }
+                    _ => None,
+                };
+                (utf8.push(&piece), special)
+            }
+            None => (utf8.finish(), None),
+        };
+        if let Some(token) = special {
+            if emitted < output.len() {
+                listening = on_chunk(Chunk::Text(&output[emitted..]));
+                emitted = output.len();
+            }
+            searched = output.len();
+            if listening {
+                listening = on_chunk(match mode {
+                    SpecialTokens::Mark => Chunk::Special(&token),
+                    _ => Chunk::Text(&token),
+                });
+            }
+            special_tokens.push(SpecialToken {
+                offset: output.len(),
+                token,
+            });
+            if !listening {
+                tracing::debug!("stopping generation early");
+                break;
+            }
+            continue;
+        }
+
+        output += &chunk;
+        if let Some(at) = find_stop(&output[searched..], &stop) {
+            tracing::debug!("stop sequence reached");
+            output.truncate(searched + at);
+            break;
+        }
+        let ready = output.len() - held_back(&output[searched..], &stop);
+        if ready > emitted {
+            listening = on_chunk(Chunk::Text(&output[emitted..ready]));
+            emitted = ready;
+        }
+        if !listening {
+            tracing::debug!("stopping generation early");
+            break;
+        }
+    }
+    if listening && emitted < output.len() {
+        on_chunk(Chunk::Text(&output[emitted..]));
+    }
+    if let Some(kept) = kept_session {
+        kept.keep(settings, ctx);
+    }
+
+    let mut generated = Generated {
+        text: output,
+        usage: Usage {
+            prompt_tokens,
+            completion_tokens,
+        },
+        special_tokens,
+        regenerations: 0,
+    };
+    pipeline.completion(&mut generated);
+
+    Ok(generated)
+}
+
+/// Generates a one-off completion outside of the shared conversation history.
+pub fn complete(
+    model: &LlamaModel,
+    template: Template,
+    system: String,
+    prompt: String,
+    max_tokens: usize,
+    session: SessionSettings,
+) -> Result<String, Box<dyn std::error::Error>> {
+    complete_constrained(model, template, system, prompt, max_tokens, session, None)
+}
+
+/// Like [`complete`], but constrained to `grammar` if one is given.
+pub fn complete_constrained(
+    model: &LlamaModel,
+    template: Template,
+    system: String,
+    prompt: String,
+    max_tokens: usize,
+    session: SessionSettings,
+    grammar: Option<LlamaGrammar>,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let mut history = History::new(system);
+    history.clear();
+
+    generate_text(
+        model,
+        &mut history,
+        Options {
+            setup: None,
+            prompt,
+            max_tokens: Some(max_tokens),
+            context: None,
+            session,
+            sampler: SamplerOptions::default(),
+            cancel: None,
+            speaker: None,
+            player: None,
+            grammar,
+            template,
+            stop: Vec::new(),
+            bias: Bias::default(),
+            special_tokens: SpecialTokens::Strip,
+            seed: None,
+            pipeline: Pipeline::default(),
+            kept_session: None,
+            prefixes: None,
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_stop_sequences_across_chunks() {
+        let stop = ["<|user|>".to_string(), "\n\n".to_string()];
+        assert_eq!(find_stop("Welcome!<|user|>Hi", &stop), Some(8));
+        assert_eq!(find_stop("Welcome!\n\n<|user|>", &stop), Some(8));
+        assert_eq!(find_stop("Welcome!", &stop), None);
+
+        assert_eq!(held_back("Welcome!<|us", &stop), 4);
+        assert_eq!(held_back("Welcome!\n", &stop), 1);
+        assert_eq!(held_back("Welcome!", &stop), 0);
+        assert_eq!(held_back("Ça va", &[]), 0);
+    }
+
+    #[test]
+    fn writes_special_tokens_back_in() {
+        let mut generated = Generated {
+            text: " Hello.Bye.".into(),
+            special_tokens: vec![
+                SpecialToken {
+                    offset: 7,
+                    token: "<|im_end|>".into(),
+                },
+                SpecialToken {
+                    offset: 11,
+                    token: "</s>".into(),
+                },
+            ],
+            ..Default::default()
+        };
+        generated.trim_start();
+        assert_eq!(generated.text, "Hello.Bye.");
+        assert_eq!(generated.with_special_tokens(), "Hello.<|im_end|>Bye.</s>");
+    }
 }
@@ -0,0 +1,240 @@
+//! Each session's locale, so the game sets the NPC's language once instead of hinting at it in
+//! every request.
+//!
+//! A locale is a BCP 47 tag such as `de` or `pt-BR`. It tells the model which language to reply
+//! in and how to write numbers and dates, and picks the localized NPC context template and
+//! fallback lines if there are any. Localized templates are looked up by the whole tag first, then
+//! by ever shorter prefixes of it, so `de-AT` falls back to `de`.
+
+use std::{collections::BTreeMap, fmt, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, thiserror::Error)]
+#[error("{0:?} isn't a locale tag such as \"de\" or \"pt-BR\"")]
+pub struct LocaleError(String);
+
+/// How a language is called and writes numbers and dates.
+struct Conventions {
+    tag: &'static str,
+    name: &'static str,
+    number: &'static str,
+    date: &'static str,
+}
+
+const KNOWN: [Conventions; 14] = [
+    Conventions {
+        tag: "en",
+        name: "English",
+        number: "1,234.5",
+        date: "12/31/2025",
+    },
+    Conventions {
+        tag: "en-GB",
+        name: "British English",
+        number: "1,234.5",
+        date: "31/12/2025",
+    },
+    Conventions {
+        tag: "de",
+        name: "German",
+        number: "1.234,5",
+        date: "31.12.2025",
+    },
+    Conventions {
+        tag: "fr",
+        name: "French",
+        number: "1 234,5",
+        date: "31/12/2025",
+    },
+    Conventions {
+        tag: "es",
+        name: "Spanish",
+        number: "1.234,5",
+        date: "31/12/2025",
+    },
+    Conventions {
+        tag: "it",
+        name: "Italian",
+        number: "1.234,5",
+        date: "31/12/2025",
+    },
+    Conventions {
+        tag: "pt",
+        name: "Portuguese",
+        number: "1 234,5",
+        date: "31/12/2025",
+    },
+    Conventions {
+        tag: "pt-BR",
+        name: "Brazilian Portuguese",
+        number: "1.234,5",
+        date: "31/12/2025",
+    },
+    Conventions {
+        tag: "nl",
+        name: "Dutch",
+        number: "1.234,5",
+        date: "31-12-2025",
+    },
+    Conventions {
+        tag: "pl",
+        name: "Polish",
+        number: "1 234,5",
+        date: "31.12.2025",
+    },
+    Conventions {
+        tag: "ru",
+        name: "Russian",
+        number: "1 234,5",
+        date: "31.12.2025",
+    },
+    Conventions {
+        tag: "ja",
+        name: "Japanese",
+        number: "1,234.5",
+        date: "2025/12/31",
+    },
+    Conventions {
+        tag: "zh",
+        name: "Chinese",
+        number: "1,234.5",
+        date: "2025/12/31",
+    },
+    Conventions {
+        tag: "ko",
+        name: "Korean",
+        number: "1,234.5",
+        date: "2025. 12. 31.",
+    },
+];
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Locale(String);
+
+impl FromStr for Locale {
+    type Err = LocaleError;
+
+    /// Parses a tag, writing it the usual way: `pt-br` becomes `pt-BR`, `zh-hant` `zh-Hant`.
+    fn from_str(tag: &str) -> Result<Self, Self::Err> {
+        let mut subtags = tag.split('-');
+        let language = subtags.next().unwrap_or_default();
+        if !(2..=3).contains(&language.len()) || !language.chars().all(|c| c.is_ascii_alphabetic())
+        {
+            return Err(LocaleError(tag.to_string()));
+        }
+
+        let mut canonical = language.to_ascii_lowercase();
+        for subtag in subtags {
+            if !(2..=8).contains(&subtag.len())
+                || !subtag.chars().all(|c| c.is_ascii_alphanumeric())
+            {
+                return Err(LocaleError(tag.to_string()));
+            }
+            canonical.push('-');
+            match subtag.len() {
+                2 => canonical += &subtag.to_ascii_uppercase(),
+                4 => {
+                    canonical += &subtag[..1].to_ascii_uppercase();
+                    canonical += &subtag[1..].to_ascii_lowercase();
+                }
+                _ => canonical += &subtag.to_ascii_lowercase(),
+            }
+        }
+
+        Ok(Self(canonical))
+    }
+}
+
+impl TryFrom<String> for Locale {
+    type Error = LocaleError;
+
+    fn try_from(tag: String) -> Result<Self, Self::Error> {
+        tag.parse()
+    }
+}
+
+impl From<Locale> for String {
+    fn from(locale: Locale) -> Self {
+        locale.0
+    }
+}
+
+impl fmt::Display for Locale {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl Locale {
+    /// The tag, then each shorter prefix of it.
+    fn fallbacks(&self) -> impl Iterator<Item = &str> {
+        let tag = self.0.as_str();
+        std::iter::successors(Some(tag), |tag| {
+            tag.rsplit_once('-').map(|(prefix, _)| prefix)
+        })
+    }
+
+    fn conventions(&self) -> Option<&'static Conventions> {
+        self.fallbacks()
+            .find_map(|tag| KNOWN.iter().find(|known| known.tag == tag))
+    }
+
+    /// The value localized for this locale, or its closest parent, in `localized`, whose keys
+    /// are tags in any case.
+    pub fn pick<'a, T>(&self, localized: &'a BTreeMap<String, T>) -> Option<&'a T> {
+        self.fallbacks().find_map(|tag| {
+            localized
+                .iter()
+                .find(|(key, _)| key.eq_ignore_ascii_case(tag))
+                .map(|(_, value)| value)
+        })
+    }
+
+    /// Tells the model which language to reply in, and how to write numbers and dates.
+    pub fn instruction(&self) -> String {
+        match self.conventions() {
+            Some(conventions) => format!(
+                "Always reply in {}, whatever language the player writes in. Write numbers like \
+                 {} and dates like {}.",
+                conventions.name, conventions.number, conventions.date
+            ),
+            None => format!(
+                "Always reply in the language with the BCP 47 tag {self}, whatever language the \
+                 player writes in."
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn picks_the_closest_localization() {
+        let locale: Locale = "de-at".parse().unwrap();
+        assert_eq!(locale.to_string(), "de-AT");
+        assert!(locale.instruction().starts_with("Always reply in German,"));
+        assert_eq!(
+            "zh-hant-tw".parse::<Locale>().unwrap().to_string(),
+            "zh-Hant-TW"
+        );
+        assert!("tlh"
+            .parse::<Locale>()
+            .unwrap()
+            .instruction()
+            .contains("BCP 47 tag tlh"));
+        assert!("german".parse::<Locale>().is_err());
+        assert!("de-".parse::<Locale>().is_err());
+
+        let localized = BTreeMap::from([("DE".to_string(), 1), ("de-ch".to_string(), 2)]);
+        assert_eq!(locale.pick(&localized), Some(&1));
+        assert_eq!(
+            "de-CH".parse::<Locale>().unwrap().pick(&localized),
+            Some(&2)
+        );
+        assert_eq!("fr".parse::<Locale>().unwrap().pick(&localized), None);
+    }
+}
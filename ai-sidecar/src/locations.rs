@@ -0,0 +1,95 @@
+//! Static descriptions of the game's locations, merged into prompts for requests made there so the
+//! game doesn't have to send them with every call.
+//!
+//! The registry is a YAML map of location ids at `AI_SIDECAR_LOCATIONS_PATH`, see
+//! `locations.example.yaml`.
+
+use std::{collections::HashMap, path::Path};
+
+use serde::Deserialize;
+
+#[derive(Debug, thiserror::Error)]
+pub enum LocationsError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Yaml(#[from] serde_yaml::Error),
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Location {
+    pub name: String,
+    pub description: String,
+    /// Sights, sounds and smells an NPC there might mention.
+    #[serde(default)]
+    pub ambient: Vec<String>,
+    /// NPCs who live or work there.
+    #[serde(default)]
+    pub residents: Vec<String>,
+}
+
+impl Location {
+    /// Background for the system message of an NPC at this location.
+    pub fn context(&self) -> String {
+        let mut context = format!("You are at {}. {}\n", self.name, self.description.trim());
+        if !self.ambient.is_empty() {
+            context += "Around you:\n";
+            for detail in &self.ambient {
+                context += &format!("- {detail}\n");
+            }
+        }
+        if !self.residents.is_empty() {
+            context += &format!("Who lives here: {}.\n", self.residents.join(", "));
+        }
+
+        context.trim_end().to_string()
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(transparent)]
+pub struct Locations {
+    locations: HashMap<String, Location>,
+}
+
+impl Locations {
+    /// Loads the registry at `AI_SIDECAR_LOCATIONS_PATH`, or an empty one if it isn't set.
+    pub fn from_env() -> Result<Self, LocationsError> {
+        match std::env::var("AI_SIDECAR_LOCATIONS_PATH") {
+            Ok(path) => Self::load(path),
+            Err(_) => Ok(Self::default()),
+        }
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, LocationsError> {
+        Ok(serde_yaml::from_str(&std::fs::read_to_string(path)?)?)
+    }
+
+    pub fn get(&self, id: &str) -> Option<&Location> {
+        self.locations.get(id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn example_locations_parse() {
+        let locations: Locations = serde_yaml::from_str(include_str!(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/locations.example.yaml"
+        )))
+        .unwrap();
+
+        let square = locations.get("market_square").unwrap();
+        assert!(square
+            .context()
+            .starts_with("You are at the Market Square."));
+        assert!(square
+            .context()
+            .ends_with("Who lives here: Hilde the baker, Old Tom."));
+        assert!(locations.get("nowhere").is_none());
+    }
+}
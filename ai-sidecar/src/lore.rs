@@ -0,0 +1,202 @@
+//! Lore snippets for items and locations, generated from their stats for bulk world-building.
+//!
+//! Left alone, the model describes every iron sword the same way. Each description is indexed by
+//! a similarity hash of its wording, and one too close to any indexed so far is generated again,
+//! up to [`MAX_ATTEMPTS`] times. The index keeps the latest [`MAX_INDEXED`] descriptions and
+//! isn't persisted, so it starts over on restart.
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    sync::Mutex,
+};
+
+use llama_cpp::LlamaModel;
+use serde::Deserialize;
+
+use crate::{llm, templates::Template};
+
+pub const MAX_TOKENS: usize = 160;
+/// How many times a description is generated before giving up on a fresh one.
+pub const MAX_ATTEMPTS: usize = 3;
+/// How many descriptions the index remembers.
+pub const MAX_INDEXED: usize = 10_000;
+/// How many of their hash bits two descriptions may differ by and still be near-duplicates.
+const MAX_DISTANCE: u32 = 3;
+
+const SYSTEM_MESSAGE: &str = "You write lore for a fantasy browser game: two or three evocative \
+sentences, without repeating the stats as numbers. Reply with the description alone.";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LoreKind {
+    Item,
+    Location,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Subject {
+    pub kind: LoreKind,
+    pub name: String,
+    /// What the game knows about it, e.g. `{"damage": 12, "rarity": "rare"}`.
+    #[serde(default)]
+    pub stats: BTreeMap<String, serde_json::Value>,
+}
+
+impl Subject {
+    pub fn prompt(&self) -> String {
+        let kind = match self.kind {
+            LoreKind::Item => "item",
+            LoreKind::Location => "location",
+        };
+        let mut prompt = format!("Describe this {kind}: {}", self.name);
+        for (stat, value) in &self.stats {
+            let value = match value {
+                serde_json::Value::String(value) => value.clone(),
+                value => value.to_string(),
+            };
+            prompt += &format!("\n- {stat}: {value}");
+        }
+        prompt
+    }
+}
+
+/// A generated description, and whether it is still a near-duplicate after every attempt.
+#[derive(Debug)]
+pub struct Lore {
+    pub description: String,
+    pub duplicate: bool,
+}
+
+/// 64-bit FNV-1a, which unlike the standard library's hasher is the same on every build.
+pub fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// A similarity hash of the text's wording, ignoring case and punctuation: texts sharing most of
+/// their word pairs differ in few bits.
+fn simhash(text: &str) -> u64 {
+    let words = text
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .collect::<Vec<_>>();
+    let shingles = match words.len() {
+        0 => return 0,
+        1 => vec![fnv1a(words[0].as_bytes())],
+        _ => words
+            .windows(2)
+            .map(|pair| fnv1a(pair.join(" ").as_bytes()))
+            .collect(),
+    };
+
+    let mut weights = [0i32; 64];
+    for shingle in shingles {
+        for (bit, weight) in weights.iter_mut().enumerate() {
+            *weight += if (shingle >> bit) & 1 == 1 { 1 } else { -1 };
+        }
+    }
+    weights
+        .iter()
+        .enumerate()
+        .filter(|(_, weight)| **weight > 0)
+        .fold(0, |hash, (bit, _)| hash | (1 << bit))
+}
+
+#[derive(Debug, Default)]
+pub struct LoreIndex {
+    hashes: Mutex<VecDeque<u64>>,
+}
+
+impl LoreIndex {
+    /// Indexes `text` unless it is a near-duplicate of a description indexed already, returning
+    /// whether it was.
+    pub fn admit(&self, text: &str) -> bool {
+        let hash = simhash(text);
+        let mut hashes = self.hashes.lock().unwrap();
+        if hashes
+            .iter()
+            .any(|indexed| (indexed ^ hash).count_ones() <= MAX_DISTANCE)
+        {
+            return false;
+        }
+        if hashes.len() == MAX_INDEXED {
+            hashes.pop_front();
+        }
+        hashes.push_back(hash);
+        true
+    }
+}
+
+/// Generates a description of `subject`, generating it again while it is a near-duplicate.
+pub fn generate(
+    model: &LlamaModel,
+    template: Template,
+    session: llm::SessionSettings,
+    subject: &Subject,
+    index: &LoreIndex,
+) -> Result<Lore, Box<dyn std::error::Error>> {
+    let mut prompt = subject.prompt();
+    let mut description = String::new();
+    for attempt in 1..=MAX_ATTEMPTS {
+        description = llm::complete(
+            model,
+            template,
+            SYSTEM_MESSAGE.to_string(),
+            prompt.clone(),
+            MAX_TOKENS,
+            session,
+        )?
+        .trim()
+        .to_string();
+        if index.admit(&description) {
+            return Ok(Lore {
+                description,
+                duplicate: false,
+            });
+        }
+        tracing::debug!(
+            "lore for {:?}, attempt {attempt}: near-duplicate",
+            subject.name
+        );
+        prompt += &format!("\nWord it differently from: {description}");
+    }
+
+    tracing::warn!("no fresh lore for {:?}", subject.name);
+    Ok(Lore {
+        description,
+        duplicate: true,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn near_duplicates_are_refused() {
+        let index = LoreIndex::default();
+        assert!(index.admit(
+            "Forged in the fires of Emberdeep, this blade hums softly whenever goblins are near."
+        ));
+        assert!(!index.admit(
+            "forged in the fires of emberdeep -- this blade hums softly whenever goblins are near!"
+        ));
+        assert!(index.admit(
+            "A crooked lighthouse on the northern cliffs, abandoned since the keeper vanished."
+        ));
+
+        let subject: Subject = serde_json::from_value(serde_json::json!({
+            "kind": "item",
+            "name": "Goblin Bane",
+            "stats": { "damage": 12, "rarity": "rare" },
+        }))
+        .unwrap();
+        assert_eq!(
+            subject.prompt(),
+            "Describe this item: Goblin Bane\n- damage: 12\n- rarity: rare"
+        );
+    }
+}
@@ -1,14 +1,73 @@
+use std::path::PathBuf;
+
+use clap::{Parser, Subcommand};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
+#[derive(Debug, Parser)]
+struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Run the sidecar server. This is the default.
+    Serve,
+    /// Generate a seasonal content pack from a spec file for review before import.
+    Pack {
+        /// YAML file describing the event and how much content to generate.
+        spec: PathBuf,
+        /// Directory to write the generated JSON to.
+        #[arg(long, short)]
+        out: PathBuf,
+    },
+    /// Validate a config file, its models and data files without starting the server.
+    CheckConfig {
+        /// The config file to check, with env var overrides applied as when serving.
+        config: PathBuf,
+    },
+    /// Export approved review entries as a JSONL fine-tuning dataset.
+    Export {
+        /// The review queue file. Defaults to `AI_SIDECAR_REVIEW_PATH`.
+        #[arg(long, env = "AI_SIDECAR_REVIEW_PATH")]
+        review: PathBuf,
+        /// File to write the dataset to.
+        #[arg(long, short)]
+        out: PathBuf,
+    },
+    /// Export the config, without secrets, data files and conversations as a single bundle.
+    ExportState {
+        /// File to write the bundle to.
+        #[arg(long, short)]
+        out: PathBuf,
+    },
+    /// Unpack a bundle from `export-state` into a directory to serve from.
+    ImportState {
+        bundle: PathBuf,
+        /// Directory to write the config, data files and history database to.
+        #[arg(long, short)]
+        dir: PathBuf,
+    },
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     tracing_subscriber::registry()
         .with(tracing_subscriber::fmt::layer())
         .init();
 
-    tracing::info!("starting");
+    match Args::parse().command.unwrap_or(Command::Serve) {
+        Command::Serve => {
+            tracing::info!("starting");
 
-    ai_sidecar::serve().await?;
+            ai_sidecar::serve().await?;
+        }
+        Command::Pack { spec, out } => ai_sidecar::generate_pack(&spec, &out)?,
+        Command::CheckConfig { config } => ai_sidecar::check_config(&config)?,
+        Command::Export { review, out } => ai_sidecar::export_dataset(review, &out)?,
+        Command::ExportState { out } => ai_sidecar::export_state(&out)?,
+        Command::ImportState { bundle, dir } => ai_sidecar::import_state(&bundle, &dir)?,
+    }
 
     Ok(())
 }
@@ -0,0 +1,463 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{narrative::Narrative, placeholders};
+
+/// How many memories each store keeps before the least important are forgotten.
+const MAX_MEMORIES_PER_STORE: usize = 64;
+/// How many memories of each store are surfaced in a prompt.
+const MAX_MEMORIES_IN_CONTEXT: usize = 5;
+
+#[derive(Debug, thiserror::Error)]
+pub enum RulesError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Yaml(#[from] serde_yaml::Error),
+}
+
+/// How events of one kind turn into memories.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    /// Builds the memory from the event, e.g. `slew {target} near {location}`. `{summary}` and
+    /// any key in the event's `details` may be used. Falls back to the event's summary.
+    pub template: Option<String>,
+    /// How memorable the event is, from 0 to 1. Events at or below `forget_below` are ignored.
+    #[serde(default = "default_importance")]
+    pub importance: f64,
+    /// Hours until the memory is half as important. Memories never fade if unset.
+    pub half_life_hours: Option<f64>,
+    /// Whether the whole world hears about it, regardless of the event's own flag.
+    #[serde(default)]
+    pub public: bool,
+}
+
+fn default_importance() -> f64 {
+    0.5
+}
+
+impl Default for Rule {
+    fn default() -> Self {
+        Self {
+            template: None,
+            importance: default_importance(),
+            half_life_hours: None,
+            public: false,
+        }
+    }
+}
+
+/// Data-driven mapping from event kinds to [`Rule`]s, loaded from YAML.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MemoryRules {
+    /// Memories whose decayed importance falls to or below this are forgotten.
+    #[serde(default)]
+    pub forget_below: f64,
+    /// Applied to event kinds without their own rule.
+    #[serde(default)]
+    pub default: Rule,
+    #[serde(default)]
+    pub events: HashMap<String, Rule>,
+}
+
+impl MemoryRules {
+    /// Loads rules from the file at `AI_SIDECAR_MEMORY_RULES`, or the defaults if it is unset.
+    pub fn from_env() -> Result<Self, RulesError> {
+        match std::env::var("AI_SIDECAR_MEMORY_RULES") {
+            Ok(path) => Self::load(path),
+            Err(_) => Ok(Self::default()),
+        }
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, RulesError> {
+        Ok(serde_yaml::from_str(&std::fs::read_to_string(path)?)?)
+    }
+
+    fn rule(&self, kind: &str) -> &Rule {
+        self.events.get(kind).unwrap_or(&self.default)
+    }
+}
+
+/// A notable event reported by the game server.
+#[derive(Debug, Clone, Deserialize)]
+pub struct GameEvent {
+    /// Free-form event type, e.g. `kill`, `trade`, `death` or `discovery`.
+    pub kind: String,
+    /// The player the event is about, if any. Events without a player are world events.
+    pub player_id: Option<String>,
+    /// A short, past-tense description, e.g. "slew the bog wyrm".
+    #[serde(default)]
+    pub summary: String,
+    /// Values for the event kind's memory template.
+    #[serde(default)]
+    pub details: HashMap<String, String>,
+    /// Whether the event is common knowledge and should also be remembered by the world.
+    #[serde(default)]
+    pub public: bool,
+    /// Unix timestamp in seconds. Defaults to when the event was received.
+    pub at: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Memory {
+    pub kind: String,
+    pub player_id: Option<String>,
+    pub summary: String,
+    pub at: u64,
+    pub importance: f64,
+    pub half_life_hours: Option<f64>,
+}
+
+impl Memory {
+    /// Importance after decaying from `at` until `now`.
+    fn importance_at(&self, now: u64) -> f64 {
+        match self.half_life_hours {
+            Some(half_life) if half_life > 0.0 => {
+                let hours = now.saturating_sub(self.at) as f64 / 3600.0;
+                self.importance * 0.5f64.powf(hours / half_life)
+            }
+            _ => self.importance,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    rules: MemoryRules,
+    players: HashMap<String, VecDeque<Memory>>,
+    world: VecDeque<Memory>,
+    narratives: HashMap<String, Narrative>,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// Fills `{key}` placeholders from `summary` and `details`, or returns `None` if any are unknown.
+fn render(template: &str, summary: &str, details: &HashMap<String, String>) -> Option<String> {
+    placeholders::fill(template, |key| match key {
+        "summary" if !summary.is_empty() => Some(summary),
+        _ => details.get(key).map(String::as_str),
+    })
+}
+
+impl MemoryStore {
+    pub fn new(rules: MemoryRules) -> Self {
+        Self {
+            rules,
+            ..Default::default()
+        }
+    }
+
+    pub fn ingest(&mut self, event: GameEvent) {
+        self.ingest_at(event, now());
+    }
+
+    fn ingest_at(&mut self, event: GameEvent, now: u64) {
+        let rule = self.rules.rule(&event.kind);
+        if rule.importance <= self.rules.forget_below {
+            tracing::debug!("ignoring unmemorable {} event", event.kind);
+            return;
+        }
+
+        let summary = rule
+            .template
+            .as_deref()
+            .and_then(|template| render(template, &event.summary, &event.details))
+            .unwrap_or(event.summary);
+        if summary.is_empty() {
+            tracing::warn!("dropping {} event with nothing to remember", event.kind);
+            return;
+        }
+
+        let public = event.public || rule.public;
+        let memory = Memory {
+            summary,
+            importance: rule.importance,
+            half_life_hours: rule.half_life_hours,
+            kind: event.kind,
+            player_id: event.player_id,
+            at: event.at.unwrap_or(now),
+        };
+
+        let forget_below = self.rules.forget_below;
+        match memory.player_id.clone() {
+            Some(player_id) => {
+                if public {
+                    remember(&mut self.world, memory.clone(), forget_below, now);
+                }
+                self.note(&player_id, memory.summary.clone());
+                let store = self.players.entry(player_id).or_default();
+                remember(store, memory, forget_below, now);
+            }
+            None => remember(&mut self.world, memory, forget_below, now),
+        }
+    }
+
+    /// Queues a past-tense line for the player's next narrative summary.
+    pub fn note(&mut self, player_id: &str, line: String) {
+        self.narratives
+            .entry(player_id.to_string())
+            .or_default()
+            .note(line);
+    }
+
+    /// Each player with unsummarized lines, with their current summary, those lines, and how many
+    /// lines a summary of them folds up to.
+    pub fn pending_narratives(&self) -> Vec<(String, Option<String>, Vec<String>, u64)> {
+        self.narratives
+            .iter()
+            .filter(|(_, narrative)| !narrative.pending().is_empty())
+            .map(|(player_id, narrative)| {
+                (
+                    player_id.clone(),
+                    narrative.summary().map(String::from),
+                    narrative.pending().to_vec(),
+                    narrative.noted(),
+                )
+            })
+            .collect()
+    }
+
+    pub fn update_narrative(&mut self, player_id: &str, summary: String, folded: u64) {
+        if let Some(narrative) = self.narratives.get_mut(player_id) {
+            narrative.update(summary, folded);
+        }
+    }
+
+    pub fn player(&self, player_id: &str) -> impl DoubleEndedIterator<Item = &Memory> {
+        self.players.get(player_id).into_iter().flatten()
+    }
+
+    pub fn world(&self) -> impl DoubleEndedIterator<Item = &Memory> {
+        self.world.iter()
+    }
+
+    /// Renders the most important memories about `player_id` and the world as prompt context.
+    pub fn context_for(&self, player_id: &str) -> Option<String> {
+        self.context_at(player_id, now())
+    }
+
+    fn context_at(&self, player_id: &str, now: u64) -> Option<String> {
+        let forget_below = self.rules.forget_below;
+        let mut context = String::new();
+
+        if let Some(summary) = self.narratives.get(player_id).and_then(Narrative::summary) {
+            context += &format!("The player's story so far: {summary}\n");
+        }
+
+        let player = salient(self.player(player_id), forget_below, now);
+        if !player.is_empty() {
+            context += "Things you have heard about the player:\n";
+            for memory in player {
+                context += &format!("- They {}.\n", memory.summary);
+            }
+        }
+
+        let world = salient(
+            self.world()
+                .filter(|m| m.player_id.as_deref() != Some(player_id)),
+            forget_below,
+            now,
+        );
+        if !world.is_empty() {
+            context += "Recent happenings in the world:\n";
+            for memory in world {
+                context += &format!("- {}.\n", memory.summary);
+            }
+        }
+
+        (!context.is_empty()).then(|| context.trim_end().to_string())
+    }
+}
+
+/// Adds `memory` to `store`, first forgetting faded memories and, if still full, the least
+/// important one.
+fn remember(store: &mut VecDeque<Memory>, memory: Memory, forget_below: f64, now: u64) {
+    store.retain(|m| m.importance_at(now) > forget_below);
+
+    if store.len() >= MAX_MEMORIES_PER_STORE {
+        let weakest = store
+            .iter()
+            .enumerate()
+            .min_by(|(_, a), (_, b)| a.importance_at(now).total_cmp(&b.importance_at(now)))
+            .map(|(i, _)| i);
+        if let Some(i) = weakest {
+            store.remove(i);
+        }
+    }
+
+    store.push_back(memory);
+}
+
+/// Picks the most important memories that haven't faded, preferring newer ones on ties, and
+/// returns them in the order they happened.
+fn salient<'a>(
+    memories: impl DoubleEndedIterator<Item = &'a Memory>,
+    forget_below: f64,
+    now: u64,
+) -> Vec<&'a Memory> {
+    let mut salient = memories
+        .rev()
+        .enumerate()
+        .filter(|(_, m)| m.importance_at(now) > forget_below)
+        .collect::<Vec<_>>();
+    salient.sort_by(|(_, a), (_, b)| b.importance_at(now).total_cmp(&a.importance_at(now)));
+    salient.truncate(MAX_MEMORIES_IN_CONTEXT);
+    salient.sort_by_key(|(age, _)| std::cmp::Reverse(*age));
+
+    salient.into_iter().map(|(_, m)| m).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(player_id: Option<&str>, summary: &str, public: bool) -> GameEvent {
+        GameEvent {
+            kind: "kill".into(),
+            player_id: player_id.map(Into::into),
+            summary: summary.into(),
+            details: HashMap::new(),
+            public,
+            at: Some(0),
+        }
+    }
+
+    #[test]
+    fn routes_events_to_stores() {
+        let mut store = MemoryStore::default();
+        store.ingest(event(Some("p1"), "slew the bog wyrm", true));
+        store.ingest(event(Some("p1"), "sold a rusty sword", false));
+        store.ingest(event(None, "A comet was seen over the hills", false));
+
+        assert_eq!(store.player("p1").count(), 2);
+        assert_eq!(store.player("p2").count(), 0);
+        assert_eq!(store.world().count(), 2);
+    }
+
+    #[test]
+    fn forgets_oldest_memories() {
+        let mut store = MemoryStore::default();
+        for i in 0..MAX_MEMORIES_PER_STORE + 3 {
+            store.ingest(event(Some("p1"), &format!("did thing {i}"), false));
+        }
+
+        let memories = store.player("p1").collect::<Vec<_>>();
+        assert_eq!(memories.len(), MAX_MEMORIES_PER_STORE);
+        assert_eq!(memories[0].summary, "did thing 3");
+    }
+
+    #[test]
+    fn renders_context() {
+        let mut store = MemoryStore::default();
+        assert_eq!(store.context_for("p1"), None);
+
+        store.ingest(event(Some("p1"), "slew the bog wyrm", true));
+        store.ingest(event(Some("p2"), "found the lost crown", true));
+
+        assert_eq!(
+            store.context_for("p1").unwrap(),
+            "Things you have heard about the player:\n- They slew the bog wyrm.\nRecent happenings in the world:\n- found the lost crown."
+        );
+    }
+
+    #[test]
+    fn example_rules_parse() {
+        let rules: MemoryRules = serde_yaml::from_str(include_str!(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/memory_rules.example.yaml"
+        )))
+        .unwrap();
+
+        assert!(rules.events.contains_key("kill"));
+    }
+
+    #[test]
+    fn applies_rules() {
+        let rules: MemoryRules = serde_yaml::from_str(
+            r#"
+            forget_below: 0.1
+            events:
+              kill:
+                template: "slew {target}"
+                importance: 0.9
+                public: true
+              trade:
+                importance: 0.4
+                half_life_hours: 1
+              footstep:
+                importance: 0
+            "#,
+        )
+        .unwrap();
+        let mut store = MemoryStore::new(rules);
+
+        let mut kill = event(Some("p1"), "won a fight", false);
+        kill.details.insert("target".into(), "the bog wyrm".into());
+        store.ingest_at(kill, 0);
+
+        let mut unknown_target = event(Some("p1"), "won a fight", false);
+        unknown_target.at = Some(1);
+        store.ingest_at(unknown_target, 1);
+
+        let mut trade = event(Some("p1"), "sold a rusty sword", false);
+        trade.kind = "trade".into();
+        store.ingest_at(trade, 1);
+
+        let mut footstep = event(Some("p1"), "walked north", false);
+        footstep.kind = "footstep".into();
+        store.ingest_at(footstep, 1);
+
+        let summaries = |now| {
+            store
+                .context_at("p1", now)
+                .unwrap()
+                .lines()
+                .filter_map(|line| line.strip_prefix("- They "))
+                .map(String::from)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            summaries(0),
+            ["slew the bog wyrm.", "won a fight.", "sold a rusty sword."]
+        );
+        // Three half-lives take the trade from 0.4 to 0.05, below `forget_below`.
+        assert_eq!(summaries(3 * 3600), ["slew the bog wyrm.", "won a fight."]);
+        assert_eq!(store.world().count(), 2);
+    }
+
+    #[test]
+    fn includes_narrative() {
+        let mut store = MemoryStore::default();
+        store.ingest(event(Some("p1"), "slew the bog wyrm", false));
+
+        let pending = store.pending_narratives();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].2, ["slew the bog wyrm"]);
+
+        store.update_narrative("p1", "A hunter of wyrms.".into(), pending[0].3);
+        assert!(store.pending_narratives().is_empty());
+        assert!(store
+            .context_for("p1")
+            .unwrap()
+            .starts_with("The player's story so far: A hunter of wyrms.\n"));
+    }
+
+    #[test]
+    fn rejects_unknown_rule_fields() {
+        let typo = "events: { kill: { importnce: 1 } }";
+        assert!(serde_yaml::from_str::<MemoryRules>(typo).is_err());
+    }
+}
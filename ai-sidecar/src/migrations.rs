@@ -0,0 +1,235 @@
+//! Versioned migrations for the history database, run whenever it is opened so sidecar upgrades
+//! never strand existing conversations.
+//!
+//! The schema version is SQLite's `user_version`. Each migration is either SQL embedded from
+//! `migrations/` or a Rust function, for changes SQL alone can't make, such as rewriting stored
+//! history formats. Every migration runs in its own transaction. Before a database file is
+//! migrated it is copied next to itself as `<path>.v<version>.bak`, so a bad upgrade can be rolled
+//! back by hand.
+
+use std::path::Path;
+
+use rusqlite::{params, Connection, Transaction};
+
+use crate::persist::PersistError;
+
+enum Step {
+    Sql(&'static str),
+    Data(fn(&Transaction) -> Result<(), PersistError>),
+}
+
+struct Migration {
+    version: u32,
+    name: &'static str,
+    step: Step,
+}
+
+/// Every migration, oldest first. Versions count up from 1, and released ones must never change.
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "initial",
+        step: Step::Sql(include_str!(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/migrations/0001_initial.sql"
+        ))),
+    },
+    Migration {
+        version: 2,
+        name: "legacy_columns",
+        step: Step::Data(legacy_columns),
+    },
+    Migration {
+        version: 3,
+        name: "message_timestamps",
+        step: Step::Sql(include_str!(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/migrations/0003_message_timestamps.sql"
+        ))),
+    },
+    Migration {
+        version: 4,
+        name: "conversation_flags",
+        step: Step::Sql(include_str!(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/migrations/0004_conversation_flags.sql"
+        ))),
+    },
+    Migration {
+        version: 5,
+        name: "conversation_locale",
+        step: Step::Sql(include_str!(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/migrations/0005_conversation_locale.sql"
+        ))),
+    },
+    Migration {
+        version: 6,
+        name: "generation_tallies",
+        step: Step::Sql(include_str!(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/migrations/0006_generation_tallies.sql"
+        ))),
+    },
+    Migration {
+        version: 7,
+        name: "conversation_budget",
+        step: Step::Sql(include_str!(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/migrations/0007_conversation_budget.sql"
+        ))),
+    },
+];
+
+/// The schema version this build migrates databases up to.
+pub const LATEST: u32 = MIGRATIONS.len() as u32;
+
+/// Databases from before migrations, group and party conversations and goals lack their columns.
+fn legacy_columns(tx: &Transaction) -> Result<(), PersistError> {
+    add_column(
+        tx,
+        "conversations",
+        "speakers",
+        "TEXT NOT NULL DEFAULT '[]'",
+    )?;
+    add_column(tx, "conversations", "party", "TEXT NOT NULL DEFAULT '[]'")?;
+    add_column(tx, "conversations", "goal", "TEXT")?;
+    add_column(
+        tx,
+        "conversations",
+        "goal_reached",
+        "INTEGER NOT NULL DEFAULT 0",
+    )?;
+    add_column(tx, "messages", "speaker", "TEXT")
+}
+
+fn add_column(
+    conn: &Connection,
+    table: &str,
+    column: &str,
+    definition: &str,
+) -> Result<(), PersistError> {
+    let exists = conn
+        .prepare(&format!("PRAGMA table_info({table})"))?
+        .query_map([], |row| row.get::<_, String>(1))?
+        .collect::<Result<Vec<_>, _>>()?
+        .iter()
+        .any(|name| name == column);
+    if !exists {
+        conn.execute_batch(&format!(
+            "ALTER TABLE {table} ADD COLUMN {column} {definition}"
+        ))?;
+    }
+
+    Ok(())
+}
+
+pub fn version(conn: &Connection) -> Result<u32, PersistError> {
+    Ok(conn.pragma_query_value(None, "user_version", |row| row.get(0))?)
+}
+
+/// Copies the database file aside before it is migrated from `version`, returning the copy's
+/// path. In-memory and still empty databases have nothing to keep.
+fn backup(conn: &Connection, version: u32) -> Result<Option<String>, PersistError> {
+    let Some(path) = conn.path().filter(|path| !path.is_empty()) else {
+        return Ok(None);
+    };
+    let empty: bool = conn.query_row(
+        "SELECT NOT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table')",
+        [],
+        |row| row.get(0),
+    )?;
+    if empty {
+        return Ok(None);
+    }
+
+    let backup = format!("{path}.v{version}.bak");
+    // Left over from an upgrade that failed, at the same version, so it holds the same data.
+    if Path::new(&backup).exists() {
+        std::fs::remove_file(&backup)?;
+    }
+    conn.execute("VACUUM INTO ?1", params![backup])?;
+
+    Ok(Some(backup))
+}
+
+/// Brings the database up to [`LATEST`], refusing ones migrated by a newer sidecar.
+pub fn run(conn: &mut Connection) -> Result<(), PersistError> {
+    let version = version(conn)?;
+    if version > LATEST {
+        return Err(PersistError::NewerSchema(version));
+    }
+    let pending = MIGRATIONS
+        .iter()
+        .filter(|migration| migration.version > version)
+        .collect::<Vec<_>>();
+    if pending.is_empty() {
+        return Ok(());
+    }
+
+    if let Some(backup) = backup(conn, version)? {
+        tracing::info!("backed up the history database to {backup}");
+    }
+    tracing::info!("migrating the history database from v{version} to v{LATEST}");
+    for migration in pending {
+        let tx = conn.transaction()?;
+        match migration.step {
+            Step::Sql(sql) => tx.execute_batch(sql)?,
+            Step::Data(migrate) => migrate(&tx)?,
+        }
+        tx.pragma_update(None, "user_version", migration.version)?;
+        tx.commit()?;
+        tracing::debug!(
+            "applied migration {} ({})",
+            migration.version,
+            migration.name
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn versions_are_sequential() {
+        for (i, migration) in MIGRATIONS.iter().enumerate() {
+            assert_eq!(migration.version as usize, i + 1, "{}", migration.name);
+        }
+    }
+
+    #[test]
+    fn backs_up_before_migrating() {
+        let path =
+            std::env::temp_dir().join(format!("ai-sidecar-migrations-{}.db", std::process::id()));
+        let backup = format!("{}.v0.bak", path.display());
+        let _ = std::fs::remove_file(&path);
+        let mut conn = Connection::open(&path).unwrap();
+        conn.execute_batch(
+            "CREATE TABLE conversations (key TEXT PRIMARY KEY, system TEXT NOT NULL);
+            INSERT INTO conversations VALUES ('default', 'Old');",
+        )
+        .unwrap();
+
+        run(&mut conn).unwrap();
+        assert_eq!(version(&conn).unwrap(), LATEST);
+        let backed_up = Connection::open(&backup).unwrap();
+        assert_eq!(version(&backed_up).unwrap(), 0);
+        let system: String = backed_up
+            .query_row("SELECT system FROM conversations", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(system, "Old");
+
+        // Up to date, so there is nothing to migrate.
+        run(&mut conn).unwrap();
+        conn.pragma_update(None, "user_version", LATEST + 1)
+            .unwrap();
+        assert!(matches!(run(&mut conn), Err(PersistError::NewerSchema(_))));
+
+        drop((conn, backed_up));
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&backup).unwrap();
+    }
+}
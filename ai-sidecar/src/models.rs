@@ -0,0 +1,57 @@
+//! Extra models loaded alongside the default one, so requests can pick the model that suits them,
+//! e.g. a tiny fast one for flavor text and a bigger one for quests.
+//!
+//! They are declared under `models` in the config file. Unlike the default model, they can't be
+//! swapped at runtime.
+
+use std::{collections::BTreeMap, path::PathBuf, sync::Arc};
+
+use llama_cpp::LlamaModel;
+
+use crate::{config::Config, templates::Template};
+
+pub struct Model {
+    pub model: Arc<LlamaModel>,
+    pub path: PathBuf,
+    /// Overrides the default prompt template, if set.
+    pub template: Option<Template>,
+}
+
+#[derive(Default)]
+pub struct ModelRegistry {
+    models: BTreeMap<String, Model>,
+}
+
+impl ModelRegistry {
+    /// Loads every model the config declares.
+    pub fn load(config: &Config) -> Result<Self, llama_cpp::LlamaLoadError> {
+        let mut models = BTreeMap::new();
+        for (name, model) in &config.models {
+            tracing::info!("loading model {name:?} from {}", model.path.display());
+            models.insert(
+                name.clone(),
+                Model {
+                    model: Arc::new(crate::server::load_model(
+                        &model.path,
+                        config.model_params(),
+                    )?),
+                    path: model.path.clone(),
+                    template: model.prompt_template,
+                },
+            );
+        }
+
+        Ok(Self { models })
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Model> {
+        self.models.get(name)
+    }
+
+    /// Every model, by name in order.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &Model)> {
+        self.models
+            .iter()
+            .map(|(name, model)| (name.as_str(), model))
+    }
+}
@@ -0,0 +1,354 @@
+//! Moderates replies before they are stored or sent, since the game is public-facing and raw model
+//! output can't go straight to players.
+//!
+//! Rules are kept in a YAML file at `AI_SIDECAR_MODERATION_PATH`, see `moderation.example.yaml`.
+//! Each rule matches a regex or a list of keywords, case-insensitively, and either redacts what it
+//! matched, rejects the reply, or has it generated again. An optional classifier pass then asks
+//! the model itself whether the reply is fit for players. A reply still failing after
+//! `max_regenerations` is rejected. Replies from a hosted backend only go through the rules.
+
+use std::path::Path;
+
+use llama_cpp::{grammar::LlamaGrammar, LlamaModel};
+use regex::{Regex, RegexBuilder};
+use serde::Deserialize;
+
+use crate::{history::History, llm, templates::Template};
+
+const DEFAULT_REDACTION: &str = "***";
+const DEFAULT_MAX_REGENERATIONS: usize = 2;
+
+const CLASSIFIER_SYSTEM_MESSAGE: &str = "You review what an NPC in a public fantasy browser game \
+is about to say to players. Answer no if it is hateful, sexual, gratuitously violent, mentions \
+real-world people or personal data, or breaks character by talking about being an AI. Otherwise \
+answer yes.";
+const CLASSIFIER_GRAMMAR: &str = r#"root ::= "yes" | "no""#;
+const CLASSIFIER_MAX_TOKENS: usize = 2;
+
+#[derive(Debug, thiserror::Error)]
+pub enum ModerationError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Yaml(#[from] serde_yaml::Error),
+    #[error("moderation rule {0:?} has an invalid pattern: {1}")]
+    Pattern(String, regex::Error),
+    #[error("moderation rule {0:?} needs either a pattern or keywords")]
+    Match(String),
+    #[error("the moderation classifier can only reject or regenerate")]
+    ClassifierAction,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    /// Replaces what the rule matched with the redaction.
+    Redact,
+    Reject,
+    Regenerate,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleSpec {
+    /// Logged when the rule is hit, never shown to players.
+    name: String,
+    pattern: Option<String>,
+    #[serde(default)]
+    keywords: Vec<String>,
+    action: Action,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ClassifierSpec {
+    action: Action,
+    /// Replaces the classifier's system message, e.g. to describe the game's own policy.
+    instructions: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ModerationFile {
+    redaction: Option<String>,
+    max_regenerations: Option<usize>,
+    #[serde(default)]
+    rules: Vec<RuleSpec>,
+    classifier: Option<ClassifierSpec>,
+}
+
+#[derive(Debug)]
+struct Rule {
+    name: String,
+    regex: Regex,
+    action: Action,
+}
+
+#[derive(Debug)]
+struct Classifier {
+    action: Action,
+    system: String,
+}
+
+/// What to do with a reply.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    Pass,
+    Reject { rule: String },
+    Regenerate { rule: String },
+}
+
+impl Verdict {
+    fn of(action: Action, rule: &str) -> Self {
+        let rule = rule.to_string();
+        match action {
+            Action::Redact => Self::Pass,
+            Action::Reject => Self::Reject { rule },
+            Action::Regenerate => Self::Regenerate { rule },
+        }
+    }
+}
+
+/// A reply that passed moderation, or word that it didn't. The rule it broke is logged.
+#[derive(Debug)]
+pub enum Outcome {
+    Passed(llm::Generated),
+    Rejected,
+}
+
+#[derive(Debug)]
+pub struct Moderator {
+    rules: Vec<Rule>,
+    classifier: Option<Classifier>,
+    redaction: String,
+    max_regenerations: usize,
+}
+
+impl Moderator {
+    /// Loads the rules at `AI_SIDECAR_MODERATION_PATH`, if it is set.
+    pub fn from_env() -> Result<Option<Self>, ModerationError> {
+        match std::env::var("AI_SIDECAR_MODERATION_PATH") {
+            Ok(path) => Self::load(path).map(Some),
+            Err(_) => Ok(None),
+        }
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, ModerationError> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+
+    fn parse(yaml: &str) -> Result<Self, ModerationError> {
+        let file: ModerationFile = serde_yaml::from_str(yaml)?;
+
+        let rules = file
+            .rules
+            .into_iter()
+            .map(|spec| {
+                let pattern = match (spec.pattern, spec.keywords.is_empty()) {
+                    (Some(pattern), true) => pattern,
+                    (None, false) => {
+                        let keywords = spec
+                            .keywords
+                            .iter()
+                            .map(|keyword| regex::escape(keyword))
+                            .collect::<Vec<_>>();
+                        format!(r"\b(?:{})\b", keywords.join("|"))
+                    }
+                    _ => return Err(ModerationError::Match(spec.name)),
+                };
+                let regex = RegexBuilder::new(&pattern)
+                    .case_insensitive(true)
+                    .build()
+                    .map_err(|e| ModerationError::Pattern(spec.name.clone(), e))?;
+
+                Ok(Rule {
+                    name: spec.name,
+                    regex,
+                    action: spec.action,
+                })
+            })
+            .collect::<Result<_, _>>()?;
+        let classifier = match file.classifier {
+            Some(spec) if spec.action == Action::Redact => {
+                return Err(ModerationError::ClassifierAction)
+            }
+            Some(spec) => Some(Classifier {
+                action: spec.action,
+                system: spec
+                    .instructions
+                    .unwrap_or_else(|| CLASSIFIER_SYSTEM_MESSAGE.to_string()),
+            }),
+            None => None,
+        };
+
+        Ok(Self {
+            rules,
+            classifier,
+            redaction: file
+                .redaction
+                .unwrap_or_else(|| DEFAULT_REDACTION.to_string()),
+            max_regenerations: file.max_regenerations.unwrap_or(DEFAULT_MAX_REGENERATIONS),
+        })
+    }
+
+    /// The first rejecting or regenerating rule `text` matches, if any.
+    fn check(&self, text: &str) -> Verdict {
+        self.rules
+            .iter()
+            .filter(|rule| rule.action != Action::Redact && rule.regex.is_match(text))
+            .map(|rule| Verdict::of(rule.action, &rule.name))
+            .next()
+            .unwrap_or(Verdict::Pass)
+    }
+
+    fn redact(&self, text: &str) -> String {
+        self.rules
+            .iter()
+            .filter(|rule| rule.action == Action::Redact)
+            .fold(text.to_string(), |text, rule| {
+                rule.regex
+                    .replace_all(&text, self.redaction.as_str())
+                    .into_owned()
+            })
+    }
+
+    /// Asks the model whether `text` is fit for players, on the calling thread. Lets the reply
+    /// through if the model can't answer, since the rules have already been applied.
+    fn classify(
+        &self,
+        model: &LlamaModel,
+        template: Template,
+        session: llm::SessionSettings,
+        text: &str,
+    ) -> Verdict {
+        let Some(classifier) = &self.classifier else {
+            return Verdict::Pass;
+        };
+        let answer = CLASSIFIER_GRAMMAR
+            .parse::<LlamaGrammar>()
+            .map_err(|e| e.to_string())
+            .and_then(|grammar| {
+                llm::complete_constrained(
+                    model,
+                    template,
+                    classifier.system.clone(),
+                    format!("The NPC says:\n{text}\n\nIs it fit for players?"),
+                    CLASSIFIER_MAX_TOKENS,
+                    session,
+                    Some(grammar),
+                )
+                .map_err(|e| e.to_string())
+            });
+        match answer {
+            Ok(answer) if answer.trim().eq_ignore_ascii_case("no") => {
+                Verdict::of(classifier.action, "classifier")
+            }
+            Ok(_) => Verdict::Pass,
+            Err(e) => {
+                tracing::warn!("unable to classify a reply: {e}");
+                Verdict::Pass
+            }
+        }
+    }
+
+    /// Applies the rules alone to a finished reply, redacting it in place, for replies from a
+    /// hosted backend with no local model to classify them.
+    pub fn screen(&self, generated: &mut llm::Generated) -> Verdict {
+        let verdict = self.check(&generated.text);
+        if verdict == Verdict::Pass {
+            generated.map_text(|text| self.redact(text));
+        }
+
+        verdict
+    }
+
+    /// Moderates a finished reply, redacting it in place.
+    pub fn review(
+        &self,
+        model: &LlamaModel,
+        template: Template,
+        session: llm::SessionSettings,
+        generated: &mut llm::Generated,
+    ) -> Verdict {
+        let verdict = self.screen(generated);
+        if verdict != Verdict::Pass {
+            return verdict;
+        }
+        self.classify(model, template, session, &generated.text)
+    }
+
+    /// Generates a reply like [`llm::generate_text_streaming`], generating it again while
+    /// moderation asks for it. A rejected reply and its prompt are left out of the history.
+    pub fn generate(
+        &self,
+        model: &LlamaModel,
+        history: &mut History,
+        mut opts: llm::Options,
+    ) -> Result<Outcome, Box<dyn std::error::Error>> {
+        let (template, session) = (opts.template, opts.session);
+        let start = history.history.len();
+        let mut regenerations = 0;
+        loop {
+            let mut generated =
+                llm::generate_text_streaming(model, history, opts.clone(), |_| true)?;
+            let rule = match self.review(model, template, session, &mut generated) {
+                Verdict::Pass => {
+                    generated.regenerations = regenerations;
+                    return Ok(Outcome::Passed(generated));
+                }
+                Verdict::Regenerate { rule } if regenerations < self.max_regenerations => {
+                    tracing::info!("regenerating a reply that broke moderation rule {rule:?}");
+                    regenerations += 1;
+                    history.history.truncate(start);
+                    // A seeded reply would come out the same again.
+                    opts.seed = opts.seed.map(|seed| seed.wrapping_add(1));
+                    continue;
+                }
+                Verdict::Reject { rule } | Verdict::Regenerate { rule } => rule,
+            };
+            tracing::warn!("rejected a reply that broke moderation rule {rule:?}");
+            history.history.truncate(start);
+            return Ok(Outcome::Rejected);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn example_rules_redact_reject_and_regenerate() {
+        let moderator = Moderator::parse(include_str!(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/moderation.example.yaml"
+        )))
+        .unwrap();
+
+        let mut generated = llm::Generated {
+            text: "Take your DAMN sword and go.".into(),
+            ..llm::Generated::default()
+        };
+        assert_eq!(moderator.check(&generated.text), Verdict::Pass);
+        generated.map_text(|text| moderator.redact(text));
+        assert_eq!(generated.text, "Take your *** sword and go.");
+
+        assert!(matches!(
+            moderator.check("Call me at 555-123-4567."),
+            Verdict::Reject { .. }
+        ));
+        assert!(matches!(
+            moderator.check("As an AI language model, I can't."),
+            Verdict::Regenerate { .. }
+        ));
+
+        assert!(matches!(
+            Moderator::parse("rules:\n  - name: empty\n    action: reject\n"),
+            Err(ModerationError::Match(_))
+        ));
+        assert!(matches!(
+            Moderator::parse("classifier:\n  action: redact\n"),
+            Err(ModerationError::ClassifierAction)
+        ));
+    }
+}
@@ -0,0 +1,81 @@
+//! Lets game masters watch conversations live, for moderation during events.
+//!
+//! Every message added to a conversation is broadcast to whoever is watching. A watcher that falls
+//! too far behind misses the oldest messages and is told how many, instead of holding up the
+//! conversations.
+
+use tokio::sync::broadcast;
+
+use crate::history::Message;
+
+/// How many messages a watcher can fall behind by.
+const CAPACITY: usize = 256;
+
+/// A message added to a conversation.
+#[derive(Debug, Clone)]
+pub struct Observed {
+    pub session_id: Option<String>,
+    pub message: Message,
+}
+
+#[derive(Debug)]
+pub struct Monitor {
+    tx: broadcast::Sender<Observed>,
+}
+
+impl Default for Monitor {
+    fn default() -> Self {
+        Self {
+            tx: broadcast::channel(CAPACITY).0,
+        }
+    }
+}
+
+impl Monitor {
+    /// Broadcasts messages just added to a conversation.
+    pub fn observe(&self, session_id: Option<&str>, messages: &[Message]) {
+        if self.tx.receiver_count() == 0 {
+            return;
+        }
+        for message in messages {
+            // Only fails once every watcher has gone.
+            let _ = self.tx.send(Observed {
+                session_id: session_id.map(str::to_string),
+                message: message.clone(),
+            });
+        }
+    }
+
+    /// Watches every conversation from now on.
+    pub fn watch(&self) -> broadcast::Receiver<Observed> {
+        self.tx.subscribe()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::history::History;
+
+    #[test]
+    fn watchers_see_new_messages() {
+        let monitor = Monitor::default();
+        let history = History::new("Setup".into());
+        monitor.observe(None, &history.history);
+
+        let mut watcher = monitor.watch();
+        assert!(watcher.try_recv().is_err());
+        monitor.observe(Some("a"), &history.history);
+        let observed = watcher.try_recv().unwrap();
+        assert_eq!(observed.session_id.as_deref(), Some("a"));
+        assert_eq!(observed.message.content(), history.history[0].content());
+
+        for _ in 0..=CAPACITY {
+            monitor.observe(None, &history.history);
+        }
+        assert!(matches!(
+            watcher.try_recv(),
+            Err(broadcast::error::TryRecvError::Lagged(1))
+        ));
+    }
+}
@@ -0,0 +1,190 @@
+//! A compact, running summary of each player's story.
+//!
+//! New memories and conversations are queued per player, and a background task periodically asks
+//! the model to fold them into the player's summary, yielding to players' requests, see
+//! [`crate::governor`].
+
+use std::time::Duration;
+
+use crate::{
+    flags::Flag,
+    jobs::JobError,
+    llm,
+    server::{AppState, ServerError},
+};
+
+/// How many unsummarized lines a player may queue before the oldest are dropped.
+const MAX_PENDING: usize = 32;
+const SUMMARY_MAX_TOKENS: usize = 96;
+const DEFAULT_INTERVAL_SECS: u64 = 300;
+
+const SYSTEM_MESSAGE: &str = "You keep the chronicle of one player's adventures in a fantasy \
+world. Rewrite the chronicle so it includes the new events. Reply with the chronicle only, in at \
+most three sentences, in the third person.";
+
+#[derive(Debug, Default)]
+pub struct Narrative {
+    summary: Option<String>,
+    pending: Vec<String>,
+    /// How many lines were noted before the first pending one, folded or dropped, so a summary
+    /// finished after more were noted knows which it includes.
+    first: u64,
+}
+
+impl Narrative {
+    pub fn summary(&self) -> Option<&str> {
+        self.summary.as_deref()
+    }
+
+    /// Queues a past-tense line about the player, e.g. "slew the bog wyrm".
+    pub fn note(&mut self, line: String) {
+        if self.pending.len() >= MAX_PENDING {
+            self.pending.remove(0);
+            self.first += 1;
+        }
+        self.pending.push(line);
+    }
+
+    pub fn pending(&self) -> &[String] {
+        &self.pending
+    }
+
+    /// How many lines have been noted, which a summary of the pending ones folds up to.
+    pub fn noted(&self) -> u64 {
+        self.first + self.pending.len() as u64
+    }
+
+    /// Replaces the summary with one that includes the lines noted before `folded`, as counted by
+    /// [`Narrative::noted`].
+    pub fn update(&mut self, summary: String, folded: u64) {
+        self.summary = Some(summary);
+        let drained = usize::try_from(folded.saturating_sub(self.first))
+            .unwrap_or(usize::MAX)
+            .min(self.pending.len());
+        self.pending.drain(..drained);
+        self.first += drained as u64;
+    }
+}
+
+fn prompt(previous: Option<&str>, pending: &[String]) -> String {
+    let mut prompt = format!(
+        "Chronicle so far: {}\n\nNew events:\n",
+        previous.unwrap_or("Nothing has happened yet.")
+    );
+    for line in pending {
+        prompt += &format!("- They {line}.\n");
+    }
+
+    prompt
+}
+
+/// Starts summarizing every `AI_SIDECAR_NARRATIVE_INTERVAL_SECS` seconds, or never if it is 0.
+pub fn spawn(state: AppState) -> Result<(), ServerError> {
+    let secs = match std::env::var("AI_SIDECAR_NARRATIVE_INTERVAL_SECS") {
+        Ok(v) => v
+            .parse()
+            .map_err(|_| ServerError::InvalidSetting("AI_SIDECAR_NARRATIVE_INTERVAL_SECS", v))?,
+        Err(_) => DEFAULT_INTERVAL_SECS,
+    };
+    if secs == 0 {
+        tracing::info!("narrative summaries disabled");
+        return Ok(());
+    }
+
+    let period = Duration::from_secs(secs);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+        loop {
+            interval.tick().await;
+            if state.flags.enabled(Flag::Narrative) {
+                summarize_pending(&state).await;
+            }
+        }
+    });
+
+    Ok(())
+}
+
+async fn summarize_pending(state: &AppState) {
+    let pending = state.memory.lock().await.pending_narratives();
+
+    for (player_id, previous, lines, folded) in pending {
+        let Some(model) = state.ai_model.load_full() else {
+            return;
+        };
+        let prompt = prompt(previous.as_deref(), &lines);
+        let session = state.config.session();
+        let template = state.config.prompt_template;
+        let summary = state
+            .governor
+            .run(&state.jobs, move || {
+                llm::complete(
+                    &model,
+                    template,
+                    SYSTEM_MESSAGE.into(),
+                    prompt,
+                    SUMMARY_MAX_TOKENS,
+                    session,
+                )
+                .map_err(|e| e.to_string())
+            })
+            .await;
+
+        match summary {
+            Ok(Ok(summary)) => state.memory.lock().await.update_narrative(
+                &player_id,
+                summary.trim().to_string(),
+                folded,
+            ),
+            Ok(Err(e)) => tracing::warn!("unable to summarize narrative for {player_id}: {e}"),
+            Err(JobError::Full) => {
+                tracing::debug!("busy, postponing narrative summaries");
+                return;
+            }
+            Err(e) => tracing::error!("narrative summary job failed: {e}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn folds_only_summarized_lines() {
+        let mut narrative = Narrative::default();
+        narrative.note("slew the bog wyrm".into());
+        narrative.note("sold a rusty sword".into());
+
+        let folded = narrative.noted();
+        narrative.note("found the lost crown".into());
+        narrative.update("A hunter of wyrms.".into(), folded);
+
+        assert_eq!(narrative.summary(), Some("A hunter of wyrms."));
+        assert_eq!(narrative.pending(), ["found the lost crown"]);
+    }
+
+    #[test]
+    fn keeps_lines_noted_while_summarizing_after_an_overflow() {
+        let mut narrative = Narrative::default();
+        for i in 0..MAX_PENDING {
+            narrative.note(format!("won duel {i}"));
+        }
+        let folded = narrative.noted();
+        narrative.note("found the lost crown".into());
+        narrative.update("A duelist.".into(), folded);
+
+        assert_eq!(narrative.pending(), ["found the lost crown"]);
+        // A summary older than the last one doesn't drop anything again.
+        narrative.update("A duelist.".into(), folded);
+        assert_eq!(narrative.pending(), ["found the lost crown"]);
+    }
+
+    #[test]
+    fn builds_prompt() {
+        assert_eq!(
+            prompt(Some("A hunter of wyrms."), &["found the lost crown".into()]),
+            "Chronicle so far: A hunter of wyrms.\n\nNew events:\n- They found the lost crown.\n"
+        );
+    }
+}
@@ -0,0 +1,139 @@
+//! The HTTP client the sidecar calls out with, for a remote backend, webhooks, the world clock and
+//! a read-only replica's primary, so it works from behind corporate and home-lab proxies.
+//!
+//! `AI_SIDECAR_HTTP_PROXY` sends every outbound request through a proxy, except to the hosts in
+//! `AI_SIDECAR_NO_PROXY`, e.g. `localhost,.internal`. Without it, the usual `HTTP_PROXY` and
+//! `NO_PROXY` env vars apply. `AI_SIDECAR_HTTP_CONNECT_TIMEOUT_SECS` bounds connecting, and
+//! `AI_SIDECAR_HTTP_READ_TIMEOUT_SECS` each wait for data, so streamed replies can take as long as
+//! they keep streaming.
+//!
+//! TLS is handled by rustls with its bundled root certificates. `AI_SIDECAR_HTTP_CA_BUNDLE` names a
+//! PEM file of further roots to trust, e.g. a corporate proxy's or a home lab's own CA.
+
+use std::{path::PathBuf, time::Duration};
+
+use reqwest::{Certificate, NoProxy, Proxy};
+
+use crate::server::ServerError;
+
+const DEFAULT_CONNECT_TIMEOUT_SECS: u64 = 10;
+const DEFAULT_READ_TIMEOUT_SECS: u64 = 60;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Outbound {
+    pub proxy: Option<String>,
+    pub no_proxy: Option<String>,
+    pub connect_timeout: Duration,
+    pub read_timeout: Duration,
+    pub ca_bundle: Option<PathBuf>,
+}
+
+fn secs(key: &'static str, default: u64) -> Result<Duration, ServerError> {
+    match std::env::var(key) {
+        Ok(v) => v
+            .parse()
+            .ok()
+            .filter(|secs| *secs > 0)
+            .map(Duration::from_secs)
+            .ok_or(ServerError::InvalidSetting(key, v)),
+        Err(_) => Ok(Duration::from_secs(default)),
+    }
+}
+
+impl Outbound {
+    pub fn from_env() -> Result<Self, ServerError> {
+        Ok(Self {
+            proxy: std::env::var("AI_SIDECAR_HTTP_PROXY").ok(),
+            no_proxy: std::env::var("AI_SIDECAR_NO_PROXY").ok(),
+            connect_timeout: secs(
+                "AI_SIDECAR_HTTP_CONNECT_TIMEOUT_SECS",
+                DEFAULT_CONNECT_TIMEOUT_SECS,
+            )?,
+            read_timeout: secs(
+                "AI_SIDECAR_HTTP_READ_TIMEOUT_SECS",
+                DEFAULT_READ_TIMEOUT_SECS,
+            )?,
+            ca_bundle: std::env::var_os("AI_SIDECAR_HTTP_CA_BUNDLE").map(PathBuf::from),
+        })
+    }
+
+    pub fn client(&self) -> Result<reqwest::Client, ServerError> {
+        let mut builder = reqwest::Client::builder()
+            .connect_timeout(self.connect_timeout)
+            .read_timeout(self.read_timeout);
+        if let Some(url) = &self.proxy {
+            let proxy = Proxy::all(url)
+                .map_err(|_| ServerError::InvalidSetting("AI_SIDECAR_HTTP_PROXY", url.clone()))?
+                .no_proxy(self.no_proxy.as_deref().and_then(NoProxy::from_string));
+            tracing::info!("sending outbound requests through {url}");
+            builder = builder.proxy(proxy);
+        }
+        if let Some(path) = &self.ca_bundle {
+            let invalid = || {
+                ServerError::InvalidSetting("AI_SIDECAR_HTTP_CA_BUNDLE", path.display().to_string())
+            };
+            let certificates = Certificate::from_pem_bundle(&std::fs::read(path)?)
+                .ok()
+                .filter(|certificates| !certificates.is_empty())
+                .ok_or_else(invalid)?;
+            tracing::info!(
+                "trusting {} extra root certificates from {}",
+                certificates.len(),
+                path.display()
+            );
+            for certificate in certificates {
+                builder = builder.add_root_certificate(certificate);
+            }
+        }
+
+        Ok(builder.build()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builds_proxied_clients() {
+        let outbound = Outbound {
+            proxy: Some("http://proxy.internal:3128".into()),
+            no_proxy: Some("localhost,.internal".into()),
+            connect_timeout: Duration::from_secs(1),
+            read_timeout: Duration::from_secs(1),
+            ca_bundle: None,
+        };
+        assert!(outbound.client().is_ok());
+
+        let invalid = Outbound {
+            proxy: Some("not a url".into()),
+            ..outbound
+        };
+        assert!(matches!(
+            invalid.client(),
+            Err(ServerError::InvalidSetting("AI_SIDECAR_HTTP_PROXY", _))
+        ));
+    }
+
+    #[test]
+    fn rejects_unusable_ca_bundles() {
+        let path =
+            std::env::temp_dir().join(format!("ai-sidecar-ca-bundle-{}.pem", std::process::id()));
+        let outbound = Outbound {
+            proxy: None,
+            no_proxy: None,
+            connect_timeout: Duration::from_secs(1),
+            read_timeout: Duration::from_secs(1),
+            ca_bundle: Some(path.clone()),
+        };
+        assert!(matches!(outbound.client(), Err(ServerError::Io(_))));
+
+        std::fs::write(&path, "not a certificate").unwrap();
+        let result = outbound.client();
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(
+            result,
+            Err(ServerError::InvalidSetting("AI_SIDECAR_HTTP_CA_BUNDLE", _))
+        ));
+    }
+}
@@ -0,0 +1,170 @@
+//! Structured outcomes extracted from a freeform conversation, e.g. whether the player accepted a
+//! quest and at what price, so the game can apply their mechanical effects.
+//!
+//! The game names the fields it wants and their types. The model reads the conversation and fills
+//! them in, constrained by a grammar built from those fields so the reply always parses. Fields
+//! the conversation didn't settle are `null`. Numbers are then held to the configured bounds, see
+//! [`crate::bounds`].
+
+use serde::Deserialize;
+
+use crate::{handoff, history::History};
+
+pub const MAX_TOKENS: usize = 256;
+
+pub const SYSTEM_MESSAGE: &str = "You read conversations between a player and an NPC in a \
+fantasy world and record what they agreed on, as JSON. Use null for anything the conversation \
+didn't settle. Never record anything the player didn't agree to.";
+
+/// How many fields a single extraction may have.
+const MAX_FIELDS: usize = 32;
+
+// Rules shared by every outcome grammar, adapted from llama.cpp's `json.gbnf`.
+pub const VALUE_RULES: &str = r#"boolean ::= "true" | "false"
+integer ::= "-"? ([0-9] | [1-9] [0-9]*)
+string ::= "\"" ([^"\\\x7F\x00-\x1F] | "\\" (["\\/bfnrt] | "u" [0-9a-fA-F] [0-9a-fA-F] [0-9a-fA-F] [0-9a-fA-F]))* "\""
+list ::= "[" ws (string ("," ws string)*)? "]"
+ws ::= ([ \t\n] ws)?
+"#;
+
+#[derive(Debug, thiserror::Error)]
+pub enum OutcomeError {
+    #[error("no outcome fields were given")]
+    NoFields,
+    #[error("at most {MAX_FIELDS} outcome fields may be given")]
+    TooManyFields,
+    #[error("outcome field name {0:?} isn't made of ASCII letters, digits and underscores")]
+    InvalidName(String),
+    #[error("outcome field {0:?} is given twice")]
+    DuplicateName(String),
+    #[error("invalid outcome grammar: {0}")]
+    Grammar(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FieldType {
+    Boolean,
+    Integer,
+    String,
+    /// A list of strings, e.g. the items promised.
+    List,
+}
+
+impl FieldType {
+    fn rule(self) -> &'static str {
+        match self {
+            Self::Boolean => "boolean",
+            Self::Integer => "integer",
+            Self::String => "string",
+            Self::List => "list",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Field {
+    /// The key the outcome is returned under, e.g. `quest_accepted`.
+    pub name: String,
+    #[serde(rename = "type")]
+    pub field_type: FieldType,
+    /// What the field means, e.g. "the price agreed for the sword, in gold".
+    pub description: Option<String>,
+}
+
+/// Checks the fields can be safely spelled out in a grammar.
+pub fn validate(fields: &[Field]) -> Result<(), OutcomeError> {
+    if fields.is_empty() {
+        return Err(OutcomeError::NoFields);
+    }
+    if fields.len() > MAX_FIELDS {
+        return Err(OutcomeError::TooManyFields);
+    }
+    for (i, field) in fields.iter().enumerate() {
+        if field.name.is_empty()
+            || !field
+                .name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_')
+        {
+            return Err(OutcomeError::InvalidName(field.name.clone()));
+        }
+        if fields[..i].iter().any(|other| other.name == field.name) {
+            return Err(OutcomeError::DuplicateName(field.name.clone()));
+        }
+    }
+
+    Ok(())
+}
+
+/// A GBNF grammar for a JSON object with exactly `fields`, in order.
+pub fn grammar(fields: &[Field]) -> String {
+    let members = fields
+        .iter()
+        .map(|field| {
+            format!(
+                r#""\"{}\":" ws ({} | "null") ws"#,
+                field.name,
+                field.field_type.rule()
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(r#" "," ws "#);
+
+    format!("root ::= \"{{\" ws {members} \"}}\"\n{VALUE_RULES}")
+}
+
+/// The prompt asking for `fields` to be filled in from the conversation.
+pub fn prompt(fields: &[Field], history: &History) -> String {
+    let mut prompt = String::from("Record these outcomes:\n");
+    for field in fields {
+        prompt += &format!("- {} ({})", field.name, field.field_type.rule());
+        if let Some(description) = &field.description {
+            prompt += &format!(": {description}");
+        }
+        prompt += "\n";
+    }
+
+    format!("{prompt}\nConversation:\n{}", handoff::transcript(history))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields() -> Vec<Field> {
+        serde_json::from_value(serde_json::json!([
+            { "name": "quest_accepted", "type": "boolean" },
+            { "name": "price", "type": "integer", "description": "in gold" },
+        ]))
+        .unwrap()
+    }
+
+    #[test]
+    fn spells_out_each_field() {
+        let fields = fields();
+        validate(&fields).unwrap();
+        assert_eq!(
+            grammar(&fields).lines().next().unwrap(),
+            r#"root ::= "{" ws "\"quest_accepted\":" ws (boolean | "null") ws "," ws "\"price\":" ws (integer | "null") ws "}""#
+        );
+        assert!(
+            prompt(&fields, &History::new("A merchant.".into())).starts_with(
+                "Record these outcomes:\n- quest_accepted (boolean)\n- price (integer): in gold\n"
+            )
+        );
+
+        let mut invalid = fields;
+        invalid[1].name = "price\" ws".into();
+        assert!(matches!(
+            validate(&invalid),
+            Err(OutcomeError::InvalidName(_))
+        ));
+        invalid[1].name = "quest_accepted".into();
+        assert!(matches!(
+            validate(&invalid),
+            Err(OutcomeError::DuplicateName(_))
+        ));
+        assert!(matches!(validate(&[]), Err(OutcomeError::NoFields)));
+    }
+}
@@ -0,0 +1,270 @@
+//! Batch generation of seasonal content packs for designers to review before importing.
+//!
+//! A pack spec is a YAML file naming a theme and how many of each kind of content to generate:
+//!
+//! ```yaml
+//! name: harvest-festival
+//! theme: The village celebrates the last reaping of the year with games, feasts and bonfires.
+//! quests: 5
+//! items: 8
+//! rumors: 10
+//! ```
+//!
+//! Each kind is written to its own JSON file in the output directory. Generation rests between
+//! entries so it takes at most `background_share` of the model's time, leaving the rest to a
+//! sidecar serving players on the same machine.
+
+use std::{path::Path, time::Instant};
+
+use llama_cpp::LlamaModel;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{config::Config, governor, llm, templates::Template};
+
+/// How many times an entry is regenerated when the model's output fails validation.
+const MAX_ATTEMPTS: usize = 3;
+const ENTRY_MAX_TOKENS: usize = 192;
+
+#[derive(Debug, thiserror::Error)]
+pub enum PackError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Yaml(#[from] serde_yaml::Error),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[error(transparent)]
+    LlamaLoad(#[from] llama_cpp::LlamaLoadError),
+    #[error(transparent)]
+    Config(#[from] crate::config::ConfigError),
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PackSpec {
+    pub name: String,
+    pub theme: String,
+    #[serde(default)]
+    pub quests: usize,
+    #[serde(default)]
+    pub items: usize,
+    #[serde(default)]
+    pub rumors: usize,
+}
+
+trait Content: DeserializeOwned + Serialize {
+    /// File name stem and label for this kind of content.
+    const KIND: &'static str;
+    /// What to ask for, with an example of the expected JSON.
+    const INSTRUCTIONS: &'static str;
+
+    /// A short label used to steer the model away from repeating itself.
+    fn label(&self) -> &str;
+
+    fn validate(&self) -> Result<(), String>;
+}
+
+fn require(field: &str, value: &str, max_len: usize) -> Result<(), String> {
+    match value.trim().len() {
+        0 => Err(format!("{field} is empty")),
+        len if len > max_len => Err(format!("{field} is longer than {max_len} bytes")),
+        _ => Ok(()),
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct Quest {
+    title: String,
+    objective: String,
+    reward: String,
+}
+
+impl Content for Quest {
+    const KIND: &'static str = "quests";
+    const INSTRUCTIONS: &'static str = "Write one quest for this event as a JSON object, e.g. \
+{\"title\": \"...\", \"objective\": \"...\", \"reward\": \"...\"}";
+
+    fn label(&self) -> &str {
+        &self.title
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        require("title", &self.title, 80)?;
+        require("objective", &self.objective, 400)?;
+        require("reward", &self.reward, 120)
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct Item {
+    name: String,
+    description: String,
+}
+
+impl Content for Item {
+    const KIND: &'static str = "items";
+    const INSTRUCTIONS: &'static str = "Write the flavor text for one item sold or found during \
+this event as a JSON object, e.g. {\"name\": \"...\", \"description\": \"...\"}";
+
+    fn label(&self) -> &str {
+        &self.name
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        require("name", &self.name, 60)?;
+        require("description", &self.description, 300)
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct Rumor {
+    text: String,
+}
+
+impl Content for Rumor {
+    const KIND: &'static str = "rumors";
+    const INSTRUCTIONS: &'static str = "Write one rumor villagers whisper during this event as a \
+JSON object, e.g. {\"text\": \"...\"}";
+
+    fn label(&self) -> &str {
+        &self.text
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        require("text", &self.text, 240)
+    }
+}
+
+/// Pulls the first JSON object out of the model's output, ignoring any chatter around it.
+fn extract_json(output: &str) -> Option<&str> {
+    let start = output.find('{')?;
+    let end = output.rfind('}')?;
+    (start < end).then(|| &output[start..=end])
+}
+
+fn parse<T: Content>(output: &str) -> Result<T, String> {
+    let json = extract_json(output).ok_or("no JSON object in output")?;
+    let entry: T = serde_json::from_str(json).map_err(|e| e.to_string())?;
+    entry.validate()?;
+    Ok(entry)
+}
+
+fn generate<T: Content>(
+    model: &LlamaModel,
+    template: Template,
+    session: llm::SessionSettings,
+    share: f32,
+    spec: &PackSpec,
+    count: usize,
+) -> Vec<T> {
+    let mut entries: Vec<T> = Vec::with_capacity(count);
+
+    for i in 0..count {
+        let mut prompt = T::INSTRUCTIONS.to_string();
+        if !entries.is_empty() {
+            let labels = entries.iter().map(T::label).collect::<Vec<_>>();
+            prompt += &format!("\nDo not repeat any of these: {}", labels.join("; "));
+        }
+
+        let entry = (1..=MAX_ATTEMPTS).find_map(|attempt| {
+            let system = format!(
+                "You write content for a fantasy browser game's seasonal event, {name}. {theme} \
+                 Reply with a single JSON object and nothing else.",
+                name = spec.name,
+                theme = spec.theme
+            );
+
+            let started = Instant::now();
+            let result = llm::complete(
+                model,
+                template,
+                system,
+                prompt.clone(),
+                ENTRY_MAX_TOKENS,
+                session,
+            )
+            .map_err(|e| e.to_string())
+            .and_then(|output| parse::<T>(&output));
+            std::thread::sleep(governor::rest(started.elapsed(), share));
+
+            match result {
+                Ok(entry) => Some(entry),
+                Err(e) => {
+                    tracing::warn!("{} {i}, attempt {attempt}: {e}", T::KIND);
+                    None
+                }
+            }
+        });
+
+        match entry {
+            Some(entry) => entries.push(entry),
+            None => tracing::error!("giving up on {} {i}", T::KIND),
+        }
+    }
+
+    tracing::info!("generated {}/{count} {}", entries.len(), T::KIND);
+    entries
+}
+
+fn write<T: Content>(out_dir: &Path, entries: &[T]) -> Result<(), PackError> {
+    let path = out_dir.join(format!("{}.json", T::KIND));
+    std::fs::write(&path, serde_json::to_string_pretty(entries)?)?;
+    tracing::info!("wrote {}", path.display());
+    Ok(())
+}
+
+/// Generates the pack described by the spec at `spec_path` into `out_dir`.
+pub fn generate_pack(spec_path: &Path, out_dir: &Path) -> Result<(), PackError> {
+    let spec: PackSpec = serde_yaml::from_str(&std::fs::read_to_string(spec_path)?)?;
+    let config = Config::load()?;
+    let model = crate::server::load_model(&config.model_path, config.model_params())?;
+    std::fs::create_dir_all(out_dir)?;
+
+    let session = config.session();
+    let template = config.prompt_template;
+    let share = config.background_share;
+    write(
+        out_dir,
+        &generate::<Quest>(&model, template, session, share, &spec, spec.quests),
+    )?;
+    write(
+        out_dir,
+        &generate::<Item>(&model, template, session, share, &spec, spec.items),
+    )?;
+    write(
+        out_dir,
+        &generate::<Rumor>(&model, template, session, share, &spec, spec.rumors),
+    )?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_spec() {
+        let spec: PackSpec = serde_yaml::from_str(
+            "name: harvest-festival\ntheme: Bonfires and feasts.\nquests: 5\nrumors: 2\n",
+        )
+        .unwrap();
+
+        assert_eq!((spec.quests, spec.items, spec.rumors), (5, 0, 2));
+        assert!(serde_yaml::from_str::<PackSpec>("name: a\ntheme: b\nquest: 1\n").is_err());
+    }
+
+    #[test]
+    fn validates_entries() {
+        let quest = parse::<Quest>(
+            "Sure! {\"title\": \"Bonfire Night\", \"objective\": \"Gather kindling.\", \
+             \"reward\": \"A toasted apple\"} Enjoy!",
+        )
+        .unwrap();
+        assert_eq!(quest.title, "Bonfire Night");
+
+        assert!(parse::<Rumor>("{\"text\": \"  \"}").is_err());
+        assert!(parse::<Item>("{\"name\": \"Scythe\"}").is_err());
+        assert!(parse::<Item>("no json here").is_err());
+    }
+}
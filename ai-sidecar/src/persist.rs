@@ -0,0 +1,518 @@
+//! Keeps conversation histories in SQLite so they survive sidecar restarts.
+//!
+//! The database lives at `AI_SIDECAR_HISTORY_DB`; histories only stay in memory without it.
+//! Each conversation is stored under a key: `default` for the default history, and
+//! `session:<id>` for sessions. Its schema is brought up to date on open, see [`migrations`].
+//! Generation tallies are kept alongside, see [`crate::tallies`].
+
+use std::path::Path;
+
+use rusqlite::{params, Connection};
+
+use crate::{
+    budgets::Budget,
+    goals::Goal,
+    history::{History, Message, MessageType},
+    locale::Locale,
+    migrations,
+    tallies::Tally,
+    templates::Template,
+};
+
+const DEFAULT_KEY: &str = "default";
+const SESSION_PREFIX: &str = "session:";
+
+#[derive(Debug, thiserror::Error)]
+pub enum PersistError {
+    #[error(transparent)]
+    Sqlite(#[from] rusqlite::Error),
+    #[error("unknown message type {0:?}")]
+    UnknownMessageType(String),
+    #[error("{0}")]
+    UnknownTemplate(String),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error("the history database is at schema v{0}, newer than this sidecar supports")]
+    NewerSchema(u32),
+}
+
+fn key(session_id: Option<&str>) -> String {
+    match session_id {
+        Some(session_id) => format!("{SESSION_PREFIX}{session_id}"),
+        None => DEFAULT_KEY.into(),
+    }
+}
+
+pub fn message_type_name(message_type: MessageType) -> &'static str {
+    match message_type {
+        MessageType::System => "system",
+        MessageType::User => "user",
+        MessageType::Assistant => "assistant",
+    }
+}
+
+pub fn parse_message_type(name: &str) -> Result<MessageType, PersistError> {
+    match name {
+        "system" => Ok(MessageType::System),
+        "user" => Ok(MessageType::User),
+        "assistant" => Ok(MessageType::Assistant),
+        _ => Err(PersistError::UnknownMessageType(name.into())),
+    }
+}
+
+/// A stored conversation: the default one has no session id.
+pub struct StoredConversation {
+    pub session_id: Option<String>,
+    pub history: History,
+}
+
+pub struct HistoryDb {
+    conn: Connection,
+}
+
+impl HistoryDb {
+    /// Opens the database at `AI_SIDECAR_HISTORY_DB`, if it is set.
+    pub fn from_env() -> Result<Option<Self>, PersistError> {
+        match std::env::var("AI_SIDECAR_HISTORY_DB") {
+            Ok(path) => Self::open(path).map(Some),
+            Err(_) => Ok(None),
+        }
+    }
+
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, PersistError> {
+        Self::init(Connection::open(path)?)
+    }
+
+    #[cfg(test)]
+    fn open_in_memory() -> Result<Self, PersistError> {
+        Self::init(Connection::open_in_memory()?)
+    }
+
+    fn init(mut conn: Connection) -> Result<Self, PersistError> {
+        conn.execute_batch("PRAGMA foreign_keys = ON;")?;
+        migrations::run(&mut conn)?;
+
+        Ok(Self { conn })
+    }
+
+    /// Replaces everything stored for a conversation with `history`.
+    pub fn save(
+        &mut self,
+        session_id: Option<&str>,
+        history: &History,
+    ) -> Result<(), PersistError> {
+        let key = key(session_id);
+        let tx = self.conn.transaction()?;
+        tx.execute(
+            "INSERT INTO conversations
+                (key, system, speakers, party, goal, goal_reached, flags, locale, budget)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)
+            ON CONFLICT (key) DO UPDATE SET system = excluded.system,
+                speakers = excluded.speakers, party = excluded.party, goal = excluded.goal,
+                goal_reached = excluded.goal_reached, flags = excluded.flags,
+                locale = excluded.locale, budget = excluded.budget",
+            params![
+                key,
+                history.system.content(),
+                serde_json::to_string(&history.speakers)?,
+                serde_json::to_string(&history.party)?,
+                history.goal.as_ref().map(|goal| &goal.description),
+                history.goal.as_ref().is_some_and(|goal| goal.reached),
+                serde_json::to_string(&history.flags)?,
+                history.locale.as_ref().map(Locale::to_string),
+                history
+                    .budget
+                    .as_ref()
+                    .map(|budget| serde_json::to_string(&budget.limits()))
+                    .transpose()?
+            ],
+        )?;
+        tx.execute("DELETE FROM messages WHERE conversation = ?1", params![key])?;
+        insert_messages(&tx, &key, &history.history)?;
+        tx.commit()?;
+
+        Ok(())
+    }
+
+    /// Appends `messages` to a conversation that has already been saved.
+    pub fn append(
+        &mut self,
+        session_id: Option<&str>,
+        messages: &[Message],
+    ) -> Result<(), PersistError> {
+        let tx = self.conn.transaction()?;
+        insert_messages(&tx, &key(session_id), messages)?;
+        tx.commit()?;
+
+        Ok(())
+    }
+
+    pub fn remove(&self, session_id: Option<&str>) -> Result<(), PersistError> {
+        self.conn.execute(
+            "DELETE FROM conversations WHERE key = ?1",
+            params![key(session_id)],
+        )?;
+
+        Ok(())
+    }
+
+    /// Loads every stored conversation.
+    pub fn load(&self) -> Result<Vec<StoredConversation>, PersistError> {
+        let stored = self
+            .conn
+            .prepare(
+                "SELECT key, system, speakers, party, goal, goal_reached, flags, locale, budget
+                FROM conversations ORDER BY key",
+            )?
+            .query_map([], |row| {
+                let goal = row
+                    .get::<_, Option<String>>(4)?
+                    .map(|description| -> rusqlite::Result<_> {
+                        Ok(Goal {
+                            description,
+                            reached: row.get(5)?,
+                        })
+                    })
+                    .transpose()?;
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get(1)?,
+                    row.get(2)?,
+                    row.get(3)?,
+                    goal,
+                    row.get::<_, String>(6)?,
+                    row.get::<_, Option<String>>(7)?,
+                    row.get::<_, Option<String>>(8)?,
+                ))
+            })?
+            .collect::<Result<Vec<(String, String, String, String, Option<Goal>, _, _, _)>, _>>()?;
+        let mut messages = self.conn.prepare(
+            "SELECT message_type, content, speaker, created_at FROM messages WHERE conversation = ?1
+            ORDER BY id",
+        )?;
+
+        let mut conversations = Vec::with_capacity(stored.len());
+        for (key, system, speakers, party, goal, flags, locale, budget) in stored {
+            let session_id = match key.strip_prefix(SESSION_PREFIX) {
+                Some(session_id) => Some(session_id.to_string()),
+                None if key == DEFAULT_KEY => None,
+                None => {
+                    tracing::warn!("skipping stored conversation with unknown key {key:?}");
+                    continue;
+                }
+            };
+
+            let mut history = History::new(system);
+            history.clear();
+            history.speakers = serde_json::from_str(&speakers)?;
+            history.party = serde_json::from_str(&party)?;
+            history.goal = goal;
+            history.flags = serde_json::from_str(&flags)?;
+            // Locales were checked before they were stored.
+            history.locale = locale.and_then(|tag| tag.parse().ok());
+            history.budget = budget
+                .map(|limits| serde_json::from_str(&limits))
+                .transpose()?
+                .map(Budget::new);
+            let mut rows = messages.query(params![key])?;
+            while let Some(row) = rows.next()? {
+                match parse_message_type(&row.get::<_, String>(0)?)? {
+                    MessageType::User => history.push_prompt(row.get(2)?, row.get(1)?),
+                    MessageType::Assistant => history.push_reply(row.get(2)?, row.get(1)?),
+                    message_type => history.push(message_type, row.get(1)?),
+                }
+                if let Some(message) = history.history.last_mut() {
+                    message.set_created_at(row.get(3)?);
+                }
+            }
+
+            conversations.push(StoredConversation {
+                session_id,
+                history,
+            });
+        }
+
+        Ok(conversations)
+    }
+
+    /// Replaces the tally stored for a persona, or none, and template.
+    pub fn save_tally(
+        &self,
+        persona: Option<&str>,
+        template: Template,
+        tally: &Tally,
+    ) -> Result<(), PersistError> {
+        self.conn.execute(
+            "INSERT INTO generation_tallies
+                (persona, template, generations, characters, latency_ms, regenerations, blocked)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+            ON CONFLICT (persona, template) DO UPDATE SET generations = excluded.generations,
+                characters = excluded.characters, latency_ms = excluded.latency_ms,
+                regenerations = excluded.regenerations, blocked = excluded.blocked",
+            params![
+                persona.unwrap_or_default(),
+                template.name(),
+                tally.generations,
+                tally.characters,
+                tally.latency_ms,
+                tally.regenerations,
+                tally.blocked
+            ],
+        )?;
+
+        Ok(())
+    }
+
+    /// Loads every stored tally.
+    pub fn load_tallies(&self) -> Result<Vec<(Option<String>, Template, Tally)>, PersistError> {
+        let stored = self
+            .conn
+            .prepare(
+                "SELECT persona, template, generations, characters, latency_ms, regenerations,
+                    blocked
+                FROM generation_tallies",
+            )?
+            .query_map([], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    Tally {
+                        generations: row.get(2)?,
+                        characters: row.get(3)?,
+                        latency_ms: row.get(4)?,
+                        regenerations: row.get(5)?,
+                        blocked: row.get(6)?,
+                    },
+                ))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        stored
+            .into_iter()
+            .map(|(persona, template, tally)| {
+                let template = template.parse().map_err(PersistError::UnknownTemplate)?;
+                Ok((Some(persona).filter(|persona| !persona.is_empty()), template, tally))
+            })
+            .collect()
+    }
+}
+
+fn insert_messages(
+    tx: &rusqlite::Transaction,
+    key: &str,
+    messages: &[Message],
+) -> Result<(), PersistError> {
+    let mut insert = tx.prepare_cached(
+        "INSERT INTO messages (conversation, message_type, content, speaker, created_at)
+        VALUES (?1, ?2, ?3, ?4, ?5)",
+    )?;
+    for message in messages {
+        insert.execute(params![
+            key,
+            message_type_name(message.message_type()),
+            message.content(),
+            message.speaker(),
+            message.created_at()
+        ])?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{budgets::Limits, history::Flags};
+
+    #[test]
+    fn round_trips_conversations() {
+        let mut db = HistoryDb::open_in_memory().unwrap();
+
+        let mut default = History::new("Default".into());
+        db.save(None, &default).unwrap();
+        default.push(MessageType::User, "Hi".into());
+        default.push(MessageType::Assistant, "Hello!".into());
+        db.append(None, &default.history[1..]).unwrap();
+
+        let mut session = History::new("Custom".into());
+        session.speakers = vec!["Bram".into(), "Elsie".into()];
+        session.party = vec!["Ayla".into(), "Corwin".into()];
+        session.goal = Some(Goal {
+            description: "Sell them ale.".into(),
+            reached: true,
+        });
+        session.flags.frozen = true;
+        session.locale = Some("de-AT".parse().unwrap());
+        session.budget = Some(Budget::new(Limits {
+            max_tokens: Some(2000),
+            max_generating_ms: None,
+        }));
+        session.push_prompt(Some("Ayla".into()), "Two ales.".into());
+        session.push_reply(Some("Bram".into()), "Ale?".into());
+        db.save(Some("a"), &session).unwrap();
+        db.save(Some("b"), &session).unwrap();
+        db.remove(Some("b")).unwrap();
+
+        let loaded = db.load().unwrap();
+        assert_eq!(loaded.len(), 2);
+        assert_eq!(loaded[0].session_id, None);
+        assert_eq!(
+            loaded[0].history.prompt(Template::default(), None, None),
+            default.prompt(Template::default(), None, None)
+        );
+        assert_eq!(loaded[1].session_id.as_deref(), Some("a"));
+        assert_eq!(
+            loaded[1].history.prompt(Template::default(), None, None),
+            session.prompt(Template::default(), None, None)
+        );
+        assert_eq!(loaded[1].history.speakers, session.speakers);
+        assert_eq!(loaded[1].history.party, session.party);
+        assert_eq!(loaded[1].history.goal, session.goal);
+        assert_eq!(loaded[1].history.flags, session.flags);
+        assert_eq!(loaded[1].history.locale, session.locale);
+        assert_eq!(
+            loaded[1].history.budget.as_ref().map(Budget::limits),
+            session.budget.as_ref().map(Budget::limits)
+        );
+        assert_eq!(
+            loaded[1].history.history[1].created_at(),
+            session.history[1].created_at()
+        );
+    }
+
+    #[test]
+    fn round_trips_tallies() {
+        let db = HistoryDb::open_in_memory().unwrap();
+        let tally = Tally {
+            generations: 2,
+            characters: 40,
+            latency_ms: 900,
+            regenerations: 1,
+            blocked: 0,
+        };
+        db.save_tally(None, Template::Zephyr, &Tally::default()).unwrap();
+        db.save_tally(Some("smith"), Template::ChatMl, &Tally::default()).unwrap();
+        db.save_tally(Some("smith"), Template::ChatMl, &tally).unwrap();
+
+        let mut loaded = db.load_tallies().unwrap();
+        loaded.sort_by_key(|(persona, _, _)| persona.clone());
+        assert_eq!(
+            loaded,
+            [
+                (None, Template::Zephyr, Tally::default()),
+                (Some("smith".into()), Template::ChatMl, tally)
+            ]
+        );
+    }
+
+    #[test]
+    fn adds_columns_to_old_databases() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE conversations (key TEXT PRIMARY KEY, system TEXT NOT NULL);
+            CREATE TABLE messages (
+                id INTEGER PRIMARY KEY,
+                conversation TEXT NOT NULL,
+                message_type TEXT NOT NULL,
+                content TEXT NOT NULL
+            );
+            INSERT INTO conversations VALUES ('default', 'Old');
+            INSERT INTO messages (conversation, message_type, content)
+            VALUES ('default', 'assistant', 'Hello');",
+        )
+        .unwrap();
+
+        let db = HistoryDb::init(conn).unwrap();
+        let loaded = db.load().unwrap();
+        assert_eq!(loaded[0].history.history[0].content(), "Hello");
+        assert_eq!(loaded[0].history.history[0].created_at(), None);
+        assert!(loaded[0].history.speakers.is_empty());
+        assert!(loaded[0].history.party.is_empty());
+        assert_eq!(loaded[0].history.flags, Flags::default());
+        assert_eq!(loaded[0].history.locale, None);
+        assert!(loaded[0].history.budget.is_none());
+    }
+
+    #[test]
+    fn leaves_timestamps_of_old_messages_empty() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE conversations (key TEXT PRIMARY KEY, system TEXT NOT NULL);
+            CREATE TABLE messages (
+                id INTEGER PRIMARY KEY,
+                conversation TEXT NOT NULL,
+                message_type TEXT NOT NULL,
+                content TEXT NOT NULL
+            );
+            INSERT INTO conversations VALUES ('default', 'Old');
+            INSERT INTO messages (conversation, message_type, content)
+            VALUES ('default', 'user', 'Hi'), ('default', 'assistant', 'Hello');",
+        )
+        .unwrap();
+
+        let mut db = HistoryDb::init(conn).unwrap();
+        let mut history = History::new("Old".into());
+        history.push(MessageType::User, "Still open?".into());
+        db.append(None, &history.history).unwrap();
+
+        let loaded = db.load().unwrap();
+        let created_at = loaded[0]
+            .history
+            .history
+            .iter()
+            .map(Message::created_at)
+            .collect::<Vec<_>>();
+        assert_eq!(created_at, [None, None, history.history[0].created_at()]);
+        assert!(created_at[2].is_some());
+    }
+
+    #[test]
+    fn round_trips_flags() {
+        let mut db = HistoryDb::open_in_memory().unwrap();
+        let flags = [
+            Flags {
+                paused: true,
+                ..Flags::default()
+            },
+            Flags {
+                frozen: true,
+                ..Flags::default()
+            },
+            Flags {
+                paused: true,
+                frozen: true,
+                archived: true,
+            },
+        ];
+        for (i, flags) in flags.iter().enumerate() {
+            let mut history = History::new("Setup".into());
+            history.push_prompt(None, "Hi".into());
+            history.flags = *flags;
+            db.save(Some(&i.to_string()), &history).unwrap();
+        }
+
+        let loaded = db.load().unwrap();
+        assert_eq!(loaded.len(), flags.len());
+        for (stored, flags) in loaded.iter().zip(flags) {
+            assert_eq!(stored.history.flags, flags);
+            // Read-only conversations still load their messages.
+            assert_eq!(stored.history.history.len(), 1);
+        }
+    }
+
+    #[test]
+    fn save_replaces_messages() {
+        let mut db = HistoryDb::open_in_memory().unwrap();
+
+        let mut history = History::new("Default".into());
+        history.push(MessageType::User, "Hi".into());
+        db.save(None, &history).unwrap();
+        history.clear();
+        db.save(None, &history).unwrap();
+
+        let loaded = db.load().unwrap();
+        assert!(loaded[0].history.history.is_empty());
+    }
+}
@@ -0,0 +1,153 @@
+//! Named NPC personas, so the game server can ask for `persona: "blacksmith"` instead of sending
+//! the NPC's system message and sampler settings with every call.
+//!
+//! Personas are kept in a YAML file at `AI_SIDECAR_PERSONAS_PATH`, see `personas.example.yaml`.
+//! Personas defined or removed through the admin API are written back to it, or only kept in
+//! memory without it.
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::llm::SamplerOptions;
+
+#[derive(Debug, thiserror::Error)]
+pub enum PersonasError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Yaml(#[from] serde_yaml::Error),
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Persona {
+    /// What the NPC is called in game.
+    pub name: String,
+    pub system_prompt: String,
+    /// Sampler settings for the persona's replies, unless a request sets them itself.
+    #[serde(default)]
+    pub sampler: SamplerOptions,
+    /// The NPC's opening line in sessions started as this persona, instead of the generic one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub greeting: Option<String>,
+}
+
+#[derive(Debug, Default)]
+pub struct Personas {
+    path: Option<PathBuf>,
+    /// By id.
+    personas: Mutex<BTreeMap<String, Persona>>,
+}
+
+impl Personas {
+    /// Loads the personas at `AI_SIDECAR_PERSONAS_PATH`, or none if it isn't set.
+    pub fn from_env() -> Result<Self, PersonasError> {
+        match std::env::var("AI_SIDECAR_PERSONAS_PATH") {
+            Ok(path) => Self::load(path.into()),
+            Err(_) => Ok(Self::default()),
+        }
+    }
+
+    /// Loads the personas at `path`, which is created on the first change if it doesn't exist yet.
+    pub fn load(path: PathBuf) -> Result<Self, PersonasError> {
+        let personas = match std::fs::read_to_string(&path) {
+            Ok(yaml) => serde_yaml::from_str(&yaml)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e.into()),
+        };
+
+        Ok(Self {
+            path: Some(path),
+            personas: Mutex::new(personas),
+        })
+    }
+
+    fn save(path: &Path, personas: &BTreeMap<String, Persona>) -> Result<(), PersonasError> {
+        std::fs::write(path, serde_yaml::to_string(personas)?)?;
+        Ok(())
+    }
+
+    pub fn get(&self, id: &str) -> Option<Persona> {
+        self.personas.lock().unwrap().get(id).cloned()
+    }
+
+    pub fn list(&self) -> BTreeMap<String, Persona> {
+        self.personas.lock().unwrap().clone()
+    }
+
+    /// Defines the persona `id`, replacing any there was. Returns whether it is new.
+    pub fn define(&self, id: String, persona: Persona) -> Result<bool, PersonasError> {
+        let mut personas = self.personas.lock().unwrap();
+        let previous = personas.insert(id.clone(), persona);
+        if let Some(path) = &self.path {
+            if let Err(e) = Self::save(path, &personas) {
+                match previous {
+                    Some(previous) => personas.insert(id, previous),
+                    None => personas.remove(&id),
+                };
+                return Err(e);
+            }
+        }
+        tracing::info!("defined persona {id:?}");
+
+        Ok(previous.is_none())
+    }
+
+    /// Removes the persona `id`, returning `false` if there is none.
+    pub fn remove(&self, id: &str) -> Result<bool, PersonasError> {
+        let mut personas = self.personas.lock().unwrap();
+        let Some(removed) = personas.remove(id) else {
+            return Ok(false);
+        };
+        if let Some(path) = &self.path {
+            if let Err(e) = Self::save(path, &personas) {
+                personas.insert(id.to_string(), removed);
+                return Err(e);
+            }
+        }
+        tracing::info!("removed persona {id:?}");
+
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn example_personas_parse_and_change() {
+        let personas: BTreeMap<String, Persona> = serde_yaml::from_str(include_str!(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/personas.example.yaml"
+        )))
+        .unwrap();
+        let store = Personas {
+            personas: Mutex::new(personas),
+            ..Personas::default()
+        };
+
+        let blacksmith = store.get("blacksmith").unwrap();
+        assert_eq!(blacksmith.name, "Brunhild");
+        assert_eq!(blacksmith.sampler.temperature, Some(0.6));
+        assert!(blacksmith.greeting.is_some());
+        assert!(store.get("innkeeper").unwrap().greeting.is_none());
+
+        let bard = Persona {
+            name: "Lute".into(),
+            system_prompt: "You are a travelling bard.".into(),
+            sampler: SamplerOptions::default(),
+            greeting: None,
+        };
+        assert!(store.define("bard".into(), bard.clone()).unwrap());
+        assert!(!store.define("bard".into(), bard.clone()).unwrap());
+        assert_eq!(store.get("bard"), Some(bard));
+        assert!(store.remove("bard").unwrap());
+        assert!(!store.remove("bard").unwrap());
+    }
+}
@@ -0,0 +1,47 @@
+//! `{key}` templating shared by memory rules and fallback lines.
+
+/// Fills `{key}` placeholders in `template` using `lookup`, or returns `None` if any key is
+/// unknown or a brace is left unclosed.
+pub fn fill<'a>(template: &str, lookup: impl Fn(&str) -> Option<&'a str>) -> Option<String> {
+    let mut filled = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find('{') {
+        let end = start + rest[start..].find('}')?;
+        let value = lookup(&rest[start + 1..end])?;
+
+        filled += &rest[..start];
+        filled += value;
+        rest = &rest[end + 1..];
+    }
+    filled += rest;
+
+    Some(filled)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lookup(key: &str) -> Option<&'static str> {
+        match key {
+            "name" => Some("Ada"),
+            "place" => Some("the mill"),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn fills_known_keys() {
+        assert_eq!(
+            fill("{name} went to {place}.", lookup).as_deref(),
+            Some("Ada went to the mill.")
+        );
+        assert_eq!(
+            fill("No placeholders", lookup).as_deref(),
+            Some("No placeholders")
+        );
+        assert_eq!(fill("{name} met {friend}", lookup), None);
+        assert_eq!(fill("Unclosed {name", lookup), None);
+    }
+}
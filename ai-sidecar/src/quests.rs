@@ -0,0 +1,195 @@
+//! Quests generated as typed JSON, for the game to hand out without parsing prose.
+//!
+//! The model's reply is constrained by a grammar to the quest's shape, then validated: a quest
+//! with an empty title, too many objectives or a reward of nothing is generated again, up to
+//! [`MAX_ATTEMPTS`] times.
+
+use llama_cpp::{grammar::LlamaGrammar, LlamaModel};
+use serde::{Deserialize, Serialize};
+
+use crate::{llm, outcomes, templates::Template};
+
+pub const MAX_TOKENS: usize = 384;
+/// How many times a quest is generated before giving up on the model's output.
+pub const MAX_ATTEMPTS: usize = 3;
+
+const MAX_OBJECTIVES: usize = 5;
+const MAX_REWARDS: usize = 5;
+
+const SYSTEM_MESSAGE: &str = "You design quests for a fantasy browser game, as JSON. Objectives \
+are short instructions for the player, and rewards are items or currencies with an amount.";
+
+const GRAMMAR: &str = r#"root ::= "{" ws "\"title\":" ws string "," ws "\"objectives\":" ws list "," ws "\"rewards\":" ws rewards "," ws "\"difficulty\":" ws difficulty ws "}"
+rewards ::= "[" ws (reward ("," ws reward)*)? "]"
+reward ::= "{" ws "\"item\":" ws string "," ws "\"amount\":" ws integer ws "}"
+difficulty ::= "\"easy\"" | "\"normal\"" | "\"hard\"" | "\"deadly\""
+"#;
+
+#[derive(Debug, thiserror::Error)]
+pub enum QuestError {
+    #[error("unable to parse the quest: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("invalid quest: {0}")]
+    Invalid(String),
+    #[error("invalid quest grammar: {0}")]
+    Grammar(String),
+    #[error("unable to generate a quest: {0}")]
+    Generate(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Difficulty {
+    Easy,
+    Normal,
+    Hard,
+    Deadly,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Reward {
+    /// What the player gets, e.g. "gold" or "iron sword".
+    pub item: String,
+    pub amount: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Quest {
+    pub title: String,
+    /// What the player has to do, in order.
+    pub objectives: Vec<String>,
+    pub rewards: Vec<Reward>,
+    pub difficulty: Difficulty,
+}
+
+fn require(field: &str, value: &str, max_len: usize) -> Result<(), QuestError> {
+    match value.trim().len() {
+        0 => Err(QuestError::Invalid(format!("{field} is empty"))),
+        len if len > max_len => Err(QuestError::Invalid(format!(
+            "{field} is longer than {max_len} bytes"
+        ))),
+        _ => Ok(()),
+    }
+}
+
+impl Quest {
+    /// Checks the quest is playable, and as difficult as asked if a difficulty was given.
+    pub fn validate(&self, difficulty: Option<Difficulty>) -> Result<(), QuestError> {
+        require("title", &self.title, 80)?;
+        if self.objectives.is_empty() || self.objectives.len() > MAX_OBJECTIVES {
+            return Err(QuestError::Invalid(format!(
+                "a quest has 1 to {MAX_OBJECTIVES} objectives"
+            )));
+        }
+        for objective in &self.objectives {
+            require("objective", objective, 200)?;
+        }
+        if self.rewards.len() > MAX_REWARDS {
+            return Err(QuestError::Invalid(format!(
+                "a quest has at most {MAX_REWARDS} rewards"
+            )));
+        }
+        for reward in &self.rewards {
+            require("reward item", &reward.item, 60)?;
+            if reward.amount == 0 {
+                return Err(QuestError::Invalid(format!("no {} rewarded", reward.item)));
+            }
+        }
+        match difficulty {
+            Some(difficulty) if difficulty != self.difficulty => Err(QuestError::Invalid(format!(
+                "the quest is {:?} instead of {difficulty:?}",
+                self.difficulty
+            ))),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// The prompt asking for a quest from the game's `brief`, e.g. "the miller's daughter is missing".
+pub fn prompt(brief: &str, difficulty: Option<Difficulty>) -> String {
+    let mut prompt = format!("Write a quest about this: {brief}");
+    if let Some(difficulty) = difficulty {
+        let difficulty = serde_json::to_value(difficulty).expect("difficulties always serialize");
+        prompt += &format!("\nIts difficulty is {difficulty}.");
+    }
+    prompt
+}
+
+/// Parses and validates the model's output.
+pub fn parse(output: &str, difficulty: Option<Difficulty>) -> Result<Quest, QuestError> {
+    let quest: Quest = serde_json::from_str(output)?;
+    quest.validate(difficulty)?;
+    Ok(quest)
+}
+
+/// Generates a quest from `brief`, generating it again while the output fails validation.
+pub fn generate(
+    model: &LlamaModel,
+    template: Template,
+    session: llm::SessionSettings,
+    brief: &str,
+    difficulty: Option<Difficulty>,
+) -> Result<Quest, QuestError> {
+    let grammar = format!("{GRAMMAR}{}", outcomes::VALUE_RULES);
+    let mut error = None;
+    for attempt in 1..=MAX_ATTEMPTS {
+        let grammar = grammar
+            .parse::<LlamaGrammar>()
+            .map_err(|e| QuestError::Grammar(e.to_string()))?;
+        let result = llm::complete_constrained(
+            model,
+            template,
+            SYSTEM_MESSAGE.to_string(),
+            prompt(brief, difficulty),
+            MAX_TOKENS,
+            session,
+            Some(grammar),
+        )
+        .map_err(|e| QuestError::Generate(e.to_string()))
+        .and_then(|output| parse(&output, difficulty));
+
+        match result {
+            Ok(quest) => return Ok(quest),
+            Err(e) => {
+                tracing::warn!("quest attempt {attempt}: {e}");
+                error = Some(e);
+            }
+        }
+    }
+
+    Err(error.expect("there is at least one attempt"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validates_the_model_output() {
+        let output = r#"{"title": "The Missing Ore", "objectives": ["Find the thieves", "Bring back the ore"], "rewards": [{"item": "gold", "amount": 50}], "difficulty": "normal"}"#;
+        let quest = parse(output, Some(Difficulty::Normal)).unwrap();
+        assert_eq!(quest.objectives.len(), 2);
+        assert_eq!(quest.rewards[0].amount, 50);
+
+        assert!(matches!(
+            parse(output, Some(Difficulty::Hard)),
+            Err(QuestError::Invalid(_))
+        ));
+        assert!(matches!(
+            parse(&output.replace("50", "0"), None),
+            Err(QuestError::Invalid(_))
+        ));
+        assert!(matches!(
+            parse(&output.replace("The Missing Ore", " "), None),
+            Err(QuestError::Invalid(_))
+        ));
+        assert!(matches!(
+            parse(&output.replace("50", "-5"), None),
+            Err(QuestError::Json(_))
+        ));
+        assert_eq!(
+            prompt("the ore is gone", Some(Difficulty::Deadly)),
+            "Write a quest about this: the ore is gone\nIts difficulty is \"deadly\"."
+        );
+    }
+}
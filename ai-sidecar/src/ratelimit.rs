@@ -0,0 +1,173 @@
+//! Per-client rate limiting, so one misbehaving client can't monopolize the single inference slot.
+//!
+//! Each client gets a token bucket, keyed by the API key it authenticates with or, failing that,
+//! its IP address. Keys trusted to forward clients' requests, like a game server relaying its
+//! players', can name the client with an `x-client-id` header instead. Configured through `AI_SIDECAR_RATE_LIMIT`, e.g. `per_minute=60,burst=10`, where
+//! `burst` defaults to `per_minute`. Every client is unlimited if it isn't set.
+
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::server::ServerError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Limit {
+    /// Requests each client regains a minute.
+    pub per_minute: u32,
+    /// Requests a client can make at once, after being idle.
+    pub burst: u32,
+}
+
+impl Limit {
+    fn parse(spec: &str) -> Option<Self> {
+        let mut per_minute = None;
+        let mut burst = None;
+        for setting in spec.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            let (key, value) = setting.split_once('=')?;
+            let value = value.trim().parse().ok().filter(|value| *value > 0)?;
+            match key.trim() {
+                "per_minute" => per_minute = Some(value),
+                "burst" => burst = Some(value),
+                _ => return None,
+            }
+        }
+
+        let per_minute = per_minute?;
+        Some(Self {
+            per_minute,
+            burst: burst.unwrap_or(per_minute),
+        })
+    }
+
+    fn refill_rate(&self) -> f64 {
+        f64::from(self.per_minute) / 60.0
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Whether a request was let through, with what its rate-limit headers report.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Decision {
+    pub allowed: bool,
+    pub limit: u32,
+    /// Requests the client can still make right away.
+    pub remaining: u32,
+    /// How long until the client can make its next request, if it can't right away.
+    pub retry_after: Duration,
+    /// How long until the client's bucket is full again.
+    pub reset: Duration,
+}
+
+#[derive(Debug)]
+pub struct RateLimiter {
+    limit: Limit,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl RateLimiter {
+    /// The limiter for `AI_SIDECAR_RATE_LIMIT`, or `None` if clients are unlimited.
+    pub fn from_env() -> Result<Option<Self>, ServerError> {
+        match std::env::var("AI_SIDECAR_RATE_LIMIT") {
+            Ok(spec) => match Limit::parse(&spec) {
+                Some(limit) => Ok(Some(Self::new(limit))),
+                None => Err(ServerError::InvalidSetting("AI_SIDECAR_RATE_LIMIT", spec)),
+            },
+            Err(_) => Ok(None),
+        }
+    }
+
+    pub fn new(limit: Limit) -> Self {
+        Self {
+            limit,
+            buckets: Default::default(),
+        }
+    }
+
+    /// Takes a token from `client`'s bucket at `now`, if it has one.
+    pub fn check(&self, client: &str, now: Instant) -> Decision {
+        let burst = f64::from(self.limit.burst);
+        let rate = self.limit.refill_rate();
+        let mut buckets = self.buckets.lock().unwrap();
+        // Full buckets are the same as missing ones, so idle clients are forgotten.
+        buckets.retain(|_, bucket| {
+            let refilled = bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * rate;
+            refilled < burst
+        });
+
+        let bucket = buckets.entry(client.to_string()).or_insert(Bucket {
+            tokens: burst,
+            updated: now,
+        });
+        bucket.tokens =
+            (bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * rate).min(burst);
+        bucket.updated = now;
+
+        let allowed = bucket.tokens >= 1.0;
+        if allowed {
+            bucket.tokens -= 1.0;
+        }
+
+        Decision {
+            allowed,
+            limit: self.limit.burst,
+            remaining: bucket.tokens.floor() as u32,
+            retry_after: match allowed {
+                true => Duration::ZERO,
+                false => Duration::from_secs_f64((1.0 - bucket.tokens) / rate),
+            },
+            reset: Duration::from_secs_f64((burst - bucket.tokens) / rate),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_the_limit() {
+        assert_eq!(
+            Limit::parse("per_minute=30, burst=5"),
+            Some(Limit {
+                per_minute: 30,
+                burst: 5
+            })
+        );
+        assert_eq!(Limit::parse("per_minute=30").unwrap().burst, 30);
+        assert_eq!(Limit::parse("burst=5"), None);
+        assert_eq!(Limit::parse("per_minute=0"), None);
+        assert_eq!(Limit::parse("per_second=1"), None);
+    }
+
+    #[test]
+    fn limits_each_client_separately() {
+        let limiter = RateLimiter::new(Limit {
+            per_minute: 60,
+            burst: 2,
+        });
+        let start = Instant::now();
+
+        assert_eq!(limiter.check("a", start).remaining, 1);
+        assert!(limiter.check("a", start).allowed);
+        let refused = limiter.check("a", start);
+        assert!(!refused.allowed);
+        assert_eq!(refused.retry_after, Duration::from_secs(1));
+        assert!(limiter.check("b", start).allowed);
+
+        assert!(limiter.check("a", start + Duration::from_secs(1)).allowed);
+        let refilled = limiter.check("a", start + Duration::from_secs(10));
+        assert!(refilled.allowed);
+        assert_eq!(refilled.remaining, 1);
+    }
+}
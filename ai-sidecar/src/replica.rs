@@ -0,0 +1,216 @@
+//! Read-only replica mode, for instances that serve dashboards and the web client without putting
+//! any load on the inference instance.
+//!
+//! Enabled with `AI_SIDECAR_READ_ONLY=true`. A replica loads no model and never writes to the
+//! history database it shares with the primary, re-reading it every
+//! `AI_SIDECAR_REPLICA_REFRESH_SECS` instead. It answers reads itself. Everything else, along with
+//! polls for jobs, which only the primary has, is forwarded to `AI_SIDECAR_PRIMARY_URL` if it is
+//! set, or declined. WebSockets can't be forwarded, so chat and monitoring are always declined. How
+//! long the primary takes to respond is tracked apart from the total.
+
+use std::time::{Duration, Instant};
+
+use axum::{
+    body::{Body, Bytes},
+    extract::{OriginalUri, Request},
+    http::{header, Method},
+    response::Response,
+};
+use tokio_stream::wrappers::ReceiverStream;
+
+use crate::{
+    history::History,
+    persist::{HistoryDb, PersistError},
+    server::{AppState, ServerError},
+    sessions::Sessions,
+    slo::{self, Latency, LatencyStats},
+    tracecontext::TraceContext,
+};
+
+const DEFAULT_REFRESH_SECS: u64 = 5;
+/// The largest request body forwarded to the primary, matching axum's default body limit.
+const MAX_BODY_BYTES: usize = 2 * 1024 * 1024;
+
+#[derive(Debug)]
+pub struct Replica {
+    primary: Option<(reqwest::Client, String)>,
+    refresh: Duration,
+    /// Until the primary's response headers arrive.
+    backend_latency: Latency,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ForwardError {
+    #[error("unable to read the request body: {0}")]
+    Body(#[from] axum::Error),
+    #[error("unable to reach the primary: {0}")]
+    Primary(#[from] reqwest::Error),
+}
+
+impl Replica {
+    /// The replica settings, or `None` unless `AI_SIDECAR_READ_ONLY` is `true`.
+    pub fn from_env(http: &reqwest::Client) -> Result<Option<Self>, ServerError> {
+        match std::env::var("AI_SIDECAR_READ_ONLY") {
+            Ok(v) => match v.parse() {
+                Ok(true) => {}
+                Ok(false) => return Ok(None),
+                Err(_) => return Err(ServerError::InvalidSetting("AI_SIDECAR_READ_ONLY", v)),
+            },
+            Err(_) => return Ok(None),
+        }
+        let refresh = match std::env::var("AI_SIDECAR_REPLICA_REFRESH_SECS") {
+            Ok(v) => v
+                .parse()
+                .ok()
+                .filter(|secs| *secs > 0)
+                .ok_or(ServerError::InvalidSetting(
+                    "AI_SIDECAR_REPLICA_REFRESH_SECS",
+                    v,
+                ))?,
+            Err(_) => DEFAULT_REFRESH_SECS,
+        };
+        let primary = std::env::var("AI_SIDECAR_PRIMARY_URL")
+            .ok()
+            .map(|url| (http.clone(), url.trim_end_matches('/').to_string()));
+
+        Ok(Some(Self {
+            primary,
+            refresh: Duration::from_secs(refresh),
+            backend_latency: Latency::new(None, slo::DEFAULT_WINDOW),
+        }))
+    }
+
+    /// Whether the replica answers a request for `path`, relative to the v1 API, itself.
+    pub fn serves(method: &Method, path: &str) -> bool {
+        matches!(*method, Method::GET | Method::HEAD)
+            && !path.starts_with("/jobs")
+            && !path.starts_with("/generate")
+            && !path.starts_with("/monitor")
+            && path != "/ws"
+    }
+
+    /// Whether a request for `path` opens a WebSocket, which can't be forwarded to the primary.
+    pub fn upgrades(method: &Method, path: &str) -> bool {
+        *method == Method::GET && matches!(path.trim_end_matches('/'), "/ws" | "/monitor")
+    }
+
+    pub fn forwards(&self) -> bool {
+        self.primary.is_some()
+    }
+
+    pub fn backend_latency(&self) -> LatencyStats {
+        self.backend_latency.snapshot()
+    }
+
+    /// Sends `request` on to the primary as part of its trace, streaming the response back as it
+    /// arrives.
+    pub async fn forward(&self, request: Request) -> Result<Response, ForwardError> {
+        let Some((client, url)) = &self.primary else {
+            unreachable!("only called when forwarding");
+        };
+        let (parts, body) = request.into_parts();
+        let uri = parts
+            .extensions
+            .get::<OriginalUri>()
+            .map(|OriginalUri(uri)| uri)
+            .unwrap_or(&parts.uri);
+        let path = uri.path_and_query().map(|p| p.as_str()).unwrap_or("/");
+        let mut headers = parts.headers.clone();
+        headers.remove(header::HOST);
+        if let Some(context) = parts.extensions.get::<TraceContext>() {
+            context.inject(&mut headers);
+        }
+        let body = axum::body::to_bytes(body, MAX_BODY_BYTES).await?;
+
+        let started = Instant::now();
+        let mut upstream = client
+            .request(parts.method, format!("{url}{path}"))
+            .headers(headers)
+            .body(body)
+            .send()
+            .await?;
+        let backend = started.elapsed();
+        self.backend_latency.record(backend);
+        tracing::Span::current().record("backend_ms", backend.as_millis() as u64);
+
+        let mut response = Response::builder().status(upstream.status());
+        for (name, value) in upstream.headers() {
+            if name != header::TRANSFER_ENCODING && name != header::CONNECTION {
+                response = response.header(name, value);
+            }
+        }
+        // Streamed generations arrive bit by bit, so they are passed on the same way.
+        let (tx, rx) = tokio::sync::mpsc::channel::<Result<Bytes, reqwest::Error>>(32);
+        tokio::spawn(async move {
+            loop {
+                match upstream.chunk().await {
+                    Ok(Some(chunk)) => {
+                        if tx.send(Ok(chunk)).await.is_err() {
+                            break;
+                        }
+                    }
+                    Ok(None) => break,
+                    Err(e) => {
+                        let _ = tx.send(Err(e)).await;
+                        break;
+                    }
+                }
+            }
+        });
+
+        Ok(response
+            .body(Body::from_stream(ReceiverStream::new(rx)))
+            .expect("headers copied from a valid response"))
+    }
+
+    /// Re-reads the shared history database in the background.
+    pub fn spawn(&self, state: AppState) {
+        let Some(db) = state.history_db.clone() else {
+            tracing::warn!("AI_SIDECAR_HISTORY_DB isn't set, so the replica has no history");
+            return;
+        };
+        let mut interval = tokio::time::interval(self.refresh);
+        tokio::spawn(async move {
+            loop {
+                interval.tick().await;
+                if let Err(e) = refresh(&state, &mut *db.lock().await).await {
+                    tracing::error!("unable to refresh from the history database: {e}");
+                }
+            }
+        });
+    }
+}
+
+/// Replaces every conversation with the stored one, dropping sessions the primary deleted.
+async fn refresh(state: &AppState, db: &mut HistoryDb) -> Result<(), PersistError> {
+    let mut history = History::new(state.config.system_prompt.clone());
+    let mut sessions = Sessions::new(state.config.system_prompt.clone());
+    crate::server::restore(db, &mut history, &mut sessions)?;
+
+    state.history.lock().await.history = history.history;
+    *state.sessions.lock().await = sessions;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serves_only_reads() {
+        assert!(Replica::serves(&Method::GET, "/sessions/a"));
+        assert!(Replica::serves(&Method::GET, "/stats"));
+        assert!(!Replica::serves(&Method::GET, "/jobs/a"));
+        assert!(!Replica::serves(&Method::GET, "/generate/subscribe"));
+        assert!(!Replica::serves(&Method::GET, "/monitor"));
+        assert!(!Replica::serves(&Method::GET, "/ws"));
+        assert!(!Replica::serves(&Method::POST, "/generate"));
+        assert!(!Replica::serves(&Method::DELETE, "/clearhistory"));
+
+        assert!(Replica::upgrades(&Method::GET, "/ws"));
+        assert!(Replica::upgrades(&Method::GET, "/monitor"));
+        assert!(!Replica::upgrades(&Method::POST, "/monitor/reply"));
+        assert!(!Replica::upgrades(&Method::GET, "/sessions/a"));
+    }
+}
@@ -0,0 +1,162 @@
+//! Authored dialogue served instead of generated text when a prompt closely matches one the
+//! writers already answered, which is cheaper, safer, and more on-voice for common questions.
+//!
+//! The corpus is a YAML list of prompt/response pairs at `AI_SIDECAR_DIALOGUE_PATH`, see
+//! `dialogue.example.yaml`. Prompts are compared by the cosine similarity of their embeddings,
+//! and only matches at or above `AI_SIDECAR_DIALOGUE_THRESHOLD` are served.
+
+use std::path::Path;
+
+use llama_cpp::{EmbeddingsParams, LlamaModel};
+use serde::Deserialize;
+
+use crate::server::ServerError;
+
+const DEFAULT_THRESHOLD: f32 = 0.9;
+
+#[derive(Debug, thiserror::Error)]
+pub enum DialogueError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Yaml(#[from] serde_yaml::Error),
+    #[error(transparent)]
+    Embeddings(#[from] llama_cpp::LlamaContextError),
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Dialogue {
+    pub prompt: String,
+    pub response: String,
+}
+
+pub struct DialogueCorpus {
+    dialogue: Vec<Dialogue>,
+    embeddings: Vec<Vec<f32>>,
+    threshold: f32,
+}
+
+fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let dot = a.iter().zip(b).map(|(a, b)| a * b).sum::<f32>();
+    let norm = |v: &[f32]| v.iter().map(|x| x * x).sum::<f32>().sqrt();
+
+    let norms = norm(a) * norm(b);
+    if norms == 0.0 {
+        return 0.0;
+    }
+
+    dot / norms
+}
+
+impl DialogueCorpus {
+    /// Loads and embeds the corpus at `AI_SIDECAR_DIALOGUE_PATH`, if it is set.
+    pub fn from_env(model: &LlamaModel) -> Result<Option<Self>, ServerError> {
+        let Ok(path) = std::env::var("AI_SIDECAR_DIALOGUE_PATH") else {
+            return Ok(None);
+        };
+        let threshold = match std::env::var("AI_SIDECAR_DIALOGUE_THRESHOLD") {
+            Ok(v) => v
+                .parse()
+                .map_err(|_| ServerError::InvalidSetting("AI_SIDECAR_DIALOGUE_THRESHOLD", v))?,
+            Err(_) => DEFAULT_THRESHOLD,
+        };
+
+        let corpus = Self::load(path, threshold, model)?;
+        tracing::info!("embedded {} authored lines", corpus.dialogue.len());
+
+        Ok(Some(corpus))
+    }
+
+    pub fn load(
+        path: impl AsRef<Path>,
+        threshold: f32,
+        model: &LlamaModel,
+    ) -> Result<Self, DialogueError> {
+        let dialogue: Vec<Dialogue> = serde_yaml::from_str(&std::fs::read_to_string(path)?)?;
+        let prompts = dialogue
+            .iter()
+            .map(|d| d.prompt.as_str())
+            .collect::<Vec<_>>();
+        let embeddings = model.embeddings(&prompts, EmbeddingsParams::default())?;
+
+        Ok(Self {
+            dialogue,
+            embeddings,
+            threshold,
+        })
+    }
+
+    /// The authored line whose prompt is most similar to `embedding`, if it is similar enough.
+    pub fn find(&self, embedding: &[f32]) -> Option<&Dialogue> {
+        self.embeddings
+            .iter()
+            .map(|candidate| cosine_similarity(candidate, embedding))
+            .zip(&self.dialogue)
+            .filter(|(similarity, _)| *similarity >= self.threshold)
+            .max_by(|(a, _), (b, _)| a.total_cmp(b))
+            .map(|(_, dialogue)| dialogue)
+    }
+
+    /// Embeds `prompt` with `model` and looks up an authored reply for it.
+    pub fn reply(&self, model: &LlamaModel, prompt: &str) -> Option<&str> {
+        let embedding = match model.embeddings(&[prompt], EmbeddingsParams::default()) {
+            Ok(mut embeddings) => embeddings.pop()?,
+            Err(e) => {
+                tracing::warn!("unable to embed prompt, generating instead: {e}");
+                return None;
+            }
+        };
+
+        self.find(&embedding).map(|d| d.response.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn corpus() -> DialogueCorpus {
+        DialogueCorpus {
+            dialogue: vec![
+                Dialogue {
+                    prompt: "Where is the inn?".into(),
+                    response: "Down the road, past the well.".into(),
+                },
+                Dialogue {
+                    prompt: "Any work?".into(),
+                    response: "Ask the miller.".into(),
+                },
+            ],
+            embeddings: vec![vec![1.0, 0.0, 0.0], vec![0.0, 1.0, 0.0]],
+            threshold: 0.9,
+        }
+    }
+
+    #[test]
+    fn finds_the_most_similar_prompt_above_the_threshold() {
+        let corpus = corpus();
+
+        assert_eq!(
+            corpus.find(&[0.9, 0.1, 0.0]).map(|d| d.response.as_str()),
+            Some("Down the road, past the well.")
+        );
+        assert_eq!(
+            corpus.find(&[0.1, 2.0, 0.0]).map(|d| d.response.as_str()),
+            Some("Ask the miller.")
+        );
+        assert!(corpus.find(&[1.0, 1.0, 0.0]).is_none());
+        assert!(corpus.find(&[0.0, 0.0, 0.0]).is_none());
+    }
+
+    #[test]
+    fn example_corpus_parses() {
+        let dialogue: Vec<Dialogue> = serde_yaml::from_str(include_str!(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/dialogue.example.yaml"
+        )))
+        .unwrap();
+
+        assert!(!dialogue.is_empty());
+    }
+}
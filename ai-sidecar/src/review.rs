@@ -0,0 +1,336 @@
+//! A queue of generated content awaiting designer review.
+//!
+//! Entries start out pending and only become fetchable by the game server once approved. The
+//! queue is kept in memory and, if `AI_SIDECAR_REVIEW_PATH` is set, saved to that JSON file after
+//! every change so reviews survive restarts.
+
+use std::{
+    collections::BTreeMap,
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, thiserror::Error)]
+pub enum ReviewError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[error("no review entry with id {0}")]
+    NotFound(u64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    Pending,
+    Approved,
+    Rejected,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Entry {
+    pub id: u64,
+    /// What the content is for, e.g. `quest`, `item` or `rumor`.
+    pub kind: String,
+    /// The setup and prompt the content was generated from, if it can be regenerated.
+    pub setup: Option<String>,
+    pub prompt: Option<String>,
+    pub content: serde_json::Value,
+    pub status: Status,
+    /// The reviewer's reason for rejecting the content.
+    pub note: Option<String>,
+    /// Earlier versions of the content, oldest first.
+    #[serde(default)]
+    pub revisions: Vec<Revision>,
+    pub updated_at: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Revision {
+    pub content: serde_json::Value,
+    /// Why this version was rejected, if it was.
+    pub note: Option<String>,
+}
+
+/// Content as plain text, for prompts and diffs.
+pub fn text(content: &serde_json::Value) -> String {
+    match content {
+        serde_json::Value::String(s) => s.clone(),
+        other => serde_json::to_string_pretty(other).unwrap_or_default(),
+    }
+}
+
+impl Entry {
+    /// The prompt to regenerate this entry from. A rejected entry's prompt carries the rejected
+    /// version and every note so far, so each attempt builds on the last instead of starting over.
+    pub fn regeneration_prompt(&self) -> Option<String> {
+        let mut prompt = self.prompt.clone()?;
+        let Some(note) = self
+            .note
+            .as_deref()
+            .filter(|_| self.status == Status::Rejected)
+        else {
+            return Some(prompt);
+        };
+
+        let earlier = self
+            .revisions
+            .iter()
+            .filter_map(|r| r.note.as_deref())
+            .collect::<Vec<_>>();
+        if !earlier.is_empty() {
+            prompt += "\n\nNotes on earlier versions:\n";
+            for note in earlier {
+                prompt += &format!("- {note}\n");
+            }
+        }
+
+        prompt += &format!(
+            "\n\nA reviewer rejected this version:\n{}\n\nReviewer's note: {note}\n\nRevise it to \
+             address the note, keeping anything the note does not ask to change.",
+            text(&self.content)
+        );
+
+        Some(prompt)
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Saved {
+    next_id: u64,
+    entries: BTreeMap<u64, Entry>,
+}
+
+#[derive(Debug, Default)]
+pub struct ReviewQueue {
+    saved: Saved,
+    path: Option<PathBuf>,
+}
+
+impl ReviewQueue {
+    /// Loads the queue from `AI_SIDECAR_REVIEW_PATH`, or starts an unsaved one if it is unset.
+    pub fn from_env() -> Result<Self, ReviewError> {
+        match std::env::var("AI_SIDECAR_REVIEW_PATH") {
+            Ok(path) => Self::load(path.into()),
+            Err(_) => Ok(Self::default()),
+        }
+    }
+
+    /// Loads the queue saved at `path`, starting an empty one there if the file doesn't exist.
+    pub fn load(path: PathBuf) -> Result<Self, ReviewError> {
+        let saved = match std::fs::read_to_string(&path) {
+            Ok(json) => serde_json::from_str(&json)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Saved::default(),
+            Err(e) => return Err(e.into()),
+        };
+
+        Ok(Self {
+            saved,
+            path: Some(path),
+        })
+    }
+
+    fn save(&self) -> Result<(), ReviewError> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, serde_json::to_string_pretty(&self.saved)?)?;
+        std::fs::rename(tmp, path)?;
+
+        Ok(())
+    }
+
+    pub fn submit(
+        &mut self,
+        kind: String,
+        setup: Option<String>,
+        prompt: Option<String>,
+        content: serde_json::Value,
+    ) -> Result<Entry, ReviewError> {
+        let id = self.saved.next_id;
+        self.saved.next_id += 1;
+
+        let entry = Entry {
+            id,
+            kind,
+            setup,
+            prompt,
+            content,
+            status: Status::Pending,
+            note: None,
+            revisions: Vec::new(),
+            updated_at: now(),
+        };
+        self.saved.entries.insert(id, entry.clone());
+        self.save()?;
+
+        Ok(entry)
+    }
+
+    pub fn get(&self, id: u64) -> Result<&Entry, ReviewError> {
+        self.saved.entries.get(&id).ok_or(ReviewError::NotFound(id))
+    }
+
+    pub fn list(&self, status: Option<Status>, kind: Option<&str>) -> Vec<&Entry> {
+        self.saved
+            .entries
+            .values()
+            .filter(|e| status.is_none_or(|s| e.status == s))
+            .filter(|e| kind.is_none_or(|k| e.kind == k))
+            .collect()
+    }
+
+    fn update(&mut self, id: u64, f: impl FnOnce(&mut Entry)) -> Result<Entry, ReviewError> {
+        let entry = self
+            .saved
+            .entries
+            .get_mut(&id)
+            .ok_or(ReviewError::NotFound(id))?;
+        f(entry);
+        entry.updated_at = now();
+
+        let entry = entry.clone();
+        self.save()?;
+
+        Ok(entry)
+    }
+
+    pub fn approve(&mut self, id: u64) -> Result<Entry, ReviewError> {
+        self.update(id, |e| {
+            e.status = Status::Approved;
+            e.note = None;
+        })
+    }
+
+    pub fn reject(&mut self, id: u64, note: Option<String>) -> Result<Entry, ReviewError> {
+        self.update(id, |e| {
+            e.status = Status::Rejected;
+            e.note = note;
+        })
+    }
+
+    /// Replaces the content, e.g. after an edit or regeneration, and sends it back for review.
+    /// The old content and any rejection note are kept as a revision.
+    pub fn replace(&mut self, id: u64, content: serde_json::Value) -> Result<Entry, ReviewError> {
+        self.update(id, |e| {
+            let revision = Revision {
+                content: std::mem::replace(&mut e.content, content),
+                note: e.note.take(),
+            };
+            e.revisions.push(revision);
+            e.status = Status::Pending;
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn only_approved_entries_are_listed_as_approved() {
+        let mut queue = ReviewQueue::default();
+        let quest = queue
+            .submit(
+                "quest".into(),
+                None,
+                Some("A quest".into()),
+                json!("Find the cat"),
+            )
+            .unwrap();
+        let rumor = queue
+            .submit(
+                "rumor".into(),
+                None,
+                None,
+                json!({ "text": "The mill is haunted" }),
+            )
+            .unwrap();
+
+        queue.approve(quest.id).unwrap();
+        queue.reject(rumor.id, Some("Too spooky".into())).unwrap();
+
+        let approved = queue.list(Some(Status::Approved), None);
+        assert_eq!(approved.len(), 1);
+        assert_eq!(approved[0].id, quest.id);
+        assert!(queue.list(Some(Status::Approved), Some("rumor")).is_empty());
+        assert_eq!(
+            queue.get(rumor.id).unwrap().note.as_deref(),
+            Some("Too spooky")
+        );
+    }
+
+    #[test]
+    fn replacing_content_needs_another_review() {
+        let mut queue = ReviewQueue::default();
+        let entry = queue
+            .submit("item".into(), None, None, json!("A dull spoon"))
+            .unwrap();
+        queue.approve(entry.id).unwrap();
+
+        let entry = queue.replace(entry.id, json!("A gleaming spoon")).unwrap();
+        assert_eq!(entry.status, Status::Pending);
+        assert_eq!(entry.revisions.len(), 1);
+        assert_eq!(entry.revisions[0].content, "A dull spoon");
+        assert!(matches!(queue.approve(99), Err(ReviewError::NotFound(99))));
+    }
+
+    #[test]
+    fn rejection_notes_carry_into_regeneration() {
+        let mut queue = ReviewQueue::default();
+        let id = queue
+            .submit(
+                "rumor".into(),
+                None,
+                Some("Write a rumor.".into()),
+                json!("Wolves!"),
+            )
+            .unwrap()
+            .id;
+        assert_eq!(
+            queue.get(id).unwrap().regeneration_prompt().as_deref(),
+            Some("Write a rumor.")
+        );
+
+        queue.reject(id, Some("Too short".into())).unwrap();
+        queue.replace(id, json!("Wolves in the woods!")).unwrap();
+        let entry = queue.reject(id, Some("Mention the mill".into())).unwrap();
+
+        let prompt = entry.regeneration_prompt().unwrap();
+        assert!(prompt.starts_with("Write a rumor.\n\nNotes on earlier versions:\n- Too short\n"));
+        assert!(prompt.contains("rejected this version:\nWolves in the woods!\n"));
+        assert!(prompt.contains("Reviewer's note: Mention the mill"));
+    }
+
+    #[test]
+    fn persists_to_file() {
+        let path =
+            std::env::temp_dir().join(format!("ai-sidecar-review-{}.json", std::process::id()));
+        let mut queue = ReviewQueue {
+            path: Some(path.clone()),
+            ..Default::default()
+        };
+        queue
+            .submit("quest".into(), None, None, json!("Find the cat"))
+            .unwrap();
+
+        let saved: Saved = serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(saved.next_id, 1);
+        assert_eq!(saved.entries[&0].kind, "quest");
+    }
+}
@@ -1,56 +1,622 @@
-use std::sync::Arc;
+use std::{
+    future::IntoFuture,
+    net::SocketAddr,
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
-use axum::Router;
+use arc_swap::ArcSwapOption;
+
+use axum::{http::StatusCode, Router};
 use llama_cpp::{LlamaModel, LlamaParams};
-use tokio::{net::TcpListener, sync::Mutex};
+use tokio::{
+    net::TcpListener,
+    sync::{oneshot, Mutex},
+    time::Instant,
+};
 
-use crate::history::History;
+use crate::{
+    analytics::Report,
+    api::v1::{GenerateResponse, StreamEvent},
+    backend::{Anthropic, LlmBackend},
+    banner::Banner,
+    bounds::{Bounds, BoundsError},
+    cache::ResponseCache,
+    config::{Config, ConfigError},
+    degradation::Ladder,
+    exploits::ExploitDetector,
+    failover::Chain,
+    fallback::{FallbackError, FallbackPack},
+    flags::Flags,
+    governor::Governor,
+    history::History,
+    jobs::{Cancellations, Jobs},
+    keys::{KeyStore, KeysError},
+    kv::{KeptSessions, Prefixes},
+    llm,
+    locations::{Locations, LocationsError},
+    lore::LoreIndex,
+    memory::{MemoryRules, MemoryStore, RulesError},
+    models::ModelRegistry,
+    moderation::{ModerationError, Moderator},
+    monitor::Monitor,
+    outbound::Outbound,
+    persist::{HistoryDb, PersistError},
+    personas::{Personas, PersonasError},
+    ratelimit::RateLimiter,
+    replica::Replica,
+    retrieval::{DialogueCorpus, DialogueError},
+    review::{ReviewError, ReviewQueue},
+    sessions::Sessions,
+    slo::{Latency, SloError},
+    streams::Streams,
+    tallies::Tallies,
+    temporal::WorldTime,
+    tiers::Tiers,
+    tokenizer::{HfTokenizer, TokenizerError},
+    traces::Traces,
+    turns::Turns,
+};
 
 #[derive(Debug, thiserror::Error)]
 pub enum ServerError {
     #[error(transparent)]
-    EnvVarError(#[from] std::env::VarError),
+    Config(#[from] ConfigError),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    LlamaLoad(#[from] llama_cpp::LlamaLoadError),
+    #[error(transparent)]
+    MemoryRules(#[from] RulesError),
+    #[error(transparent)]
+    Review(#[from] ReviewError),
+    #[error(transparent)]
+    Fallback(#[from] FallbackError),
+    #[error(transparent)]
+    Moderation(#[from] ModerationError),
+    #[error(transparent)]
+    Persist(#[from] PersistError),
     #[error(transparent)]
-    IoError(#[from] std::io::Error),
+    Dialogue(#[from] DialogueError),
     #[error(transparent)]
-    LlamaLoadError(#[from] llama_cpp::LlamaLoadError),
+    Locations(#[from] LocationsError),
+    #[error(transparent)]
+    Personas(#[from] PersonasError),
+    #[error(transparent)]
+    Bounds(#[from] BoundsError),
+    #[error(transparent)]
+    Slo(#[from] SloError),
+    #[error(transparent)]
+    Keys(#[from] KeysError),
+    #[error(transparent)]
+    Http(#[from] reqwest::Error),
+    #[error(transparent)]
+    Tokenizer(#[from] TokenizerError),
+    #[error("invalid value {1:?} for {0}")]
+    InvalidSetting(&'static str, String),
+    #[cfg(feature = "chaos")]
+    #[error(transparent)]
+    Chaos(#[from] crate::chaos::ChaosError),
 }
 
 #[derive(Clone)]
 pub struct AppState {
-    pub ai_model: Arc<LlamaModel>,
-    pub secret: Arc<String>,
+    /// Swapped at runtime through the admin API. Empty when replying with a hosted backend that
+    /// isn't a failover, or offline on fallback lines alone.
+    pub ai_model: Arc<ArcSwapOption<LlamaModel>>,
+    /// Replies to `/generate` instead of the local model, if a hosted one is configured, or after
+    /// it with `anthropic.failover`.
+    pub backend: Option<Arc<dyn LlmBackend>>,
+    pub fallback: Option<Arc<FallbackPack>>,
+    /// How the local model, hosted backend and fallback lines each fared.
+    pub chain: Arc<Chain>,
+    /// Checks replies before they are stored or sent, if rules are loaded.
+    pub moderator: Option<Arc<Moderator>>,
+    /// Only loaded alongside a model, since matching prompts needs its embeddings.
+    pub dialogue: Arc<ArcSwapOption<DialogueCorpus>>,
+    /// Serves `/embeddings` instead of the main model, if one is set.
+    pub embedding_model: Option<Arc<LlamaModel>>,
+    /// Counts tokens for the tokenize API instead of the loaded model, if
+    /// `AI_SIDECAR_TOKENIZER_PATH` is set.
+    pub tokenizer: Option<Arc<HfTokenizer>>,
+    /// Models requests can pick by name instead of the main one.
+    pub models: Arc<ModelRegistry>,
+    pub config: Arc<Config>,
+    pub locations: Arc<Locations>,
+    /// NPC personas requests can pick by id.
+    pub personas: Arc<Personas>,
+    /// Limits on the numbers the model proposes for game mechanics.
+    pub bounds: Arc<Bounds>,
+    /// Runs generations one at a time on the inference thread.
+    pub jobs: Arc<Jobs>,
+    /// Paces background generation to its `background_share` of the inference thread.
+    pub governor: Arc<Governor>,
+    /// Lets each conversation's generation be cancelled while it is queued or running.
+    pub cancellations: Arc<Cancellations>,
+    /// Each conversation's streaming generation, for other clients to follow along.
+    pub streams: Arc<Streams<StreamEvent>>,
+    /// Broadcasts new messages to game masters watching the conversations.
+    pub monitor: Arc<Monitor>,
+    /// Replies to requests identical to earlier ones, if `response_cache_size` is set.
+    pub cache: Arc<ResponseCache>,
+    /// Each recent conversation's llama.cpp session, kept between its turns.
+    pub kept_sessions: Arc<KeptSessions>,
+    /// Sessions fed only the system prompts, copied for new conversations.
+    pub prefixes: Arc<Prefixes>,
+    /// How generations fare per persona and template, restored from the history database.
+    pub tallies: Arc<Tallies>,
+    /// Whether the model has warmed up, or didn't need to, so `/isbusy` can report ready.
+    pub warm: Arc<AtomicBool>,
+    /// Similarity hashes of the lore generated so far, to refuse near-duplicates.
+    pub lore: Arc<LoreIndex>,
+    /// Queues and rate-limits the players' turns in party sessions, passing a refused turn's
+    /// answer on to the messages folded into it.
+    pub turns: Arc<Turns<(StatusCode, GenerateResponse)>>,
+    /// Flags prompts that try to extract mechanical advantages.
+    pub exploits: Arc<ExploitDetector>,
+    /// Limits how often each client can call the API, if `AI_SIDECAR_RATE_LIMIT` is set.
+    pub rate_limiter: Option<Arc<RateLimiter>>,
+    /// The root `secret` and any scoped keys besides it.
+    pub keys: Arc<KeyStore>,
+    /// Set when running as a read-only replica of another instance.
+    pub replica: Option<Arc<Replica>>,
     pub history: Arc<Mutex<History>>,
+    pub memory: Arc<Mutex<MemoryStore>>,
+    pub review: Arc<Mutex<ReviewQueue>>,
+    pub sessions: Arc<Mutex<Sessions>>,
+    /// The service levels of players' subscription tiers, with their quotas.
+    pub tiers: Arc<Tiers>,
+    pub history_db: Option<Arc<Mutex<HistoryDb>>>,
+    /// The latest conversation analytics, refreshed in the background.
+    pub analytics: Arc<Mutex<Option<Report>>>,
+    /// Recent replies, so players can rate them.
+    pub traces: Arc<Mutex<Traces>>,
+    /// How long players wait for replies, against the latency objective.
+    pub latency: Arc<Latency>,
+    /// Which level of service `/generate` gives under the current load.
+    pub ladder: Arc<Ladder>,
+    /// Which experimental subsystems are turned on.
+    pub flags: Arc<Flags>,
+    pub world_time: Arc<Mutex<WorldTime>>,
+    /// Where the API is being served.
+    pub address: SocketAddr,
+    #[cfg(feature = "chaos")]
+    pub chaos: Option<Arc<crate::chaos::Chaos>>,
 }
 
-pub async fn serve() -> Result<(), ServerError> {
-    let ai_model = LlamaModel::load_from_file(
-        "assets/tinyllama-1.1b-chat-v1.0.Q5_K_M.gguf",
-        LlamaParams::default(),
-    )?;
-    let secret = std::env::var("AI_SIDECAR_SECRET")?;
-    let history = History::new(
-        include_str!(concat!(
-            env!("CARGO_MANIFEST_DIR"),
-            "./src/default_system_message.txt"
-        ))
-        .to_string(),
-    );
+const DEFAULT_SHUTDOWN_TIMEOUT_SECS: u64 = 30;
+const WARMUP_PROMPT: &str = "Hello!";
+const WARMUP_TOKENS: usize = 8;
+
+pub(crate) fn load_model(
+    path: impl AsRef<Path>,
+    params: LlamaParams,
+) -> Result<LlamaModel, llama_cpp::LlamaLoadError> {
+    LlamaModel::load_from_file(path, params)
+}
 
-    let state = AppState {
-        ai_model: Arc::new(ai_model),
-        secret: Arc::new(secret),
-        history: Arc::new(Mutex::new(history)),
+/// Runs a short generation on the inference thread, if `warmup` is set, so the first player's
+/// request doesn't wait for the model's weights to be paged in. The sidecar is marked warm once it
+/// is done, whether it worked or not, since a model that can't generate won't warm up by waiting.
+/// Runs again whenever the model is swapped.
+pub(crate) fn warm_up(state: &AppState) {
+    let Some(model) = state.ai_model.load_full().filter(|_| state.config.warmup) else {
+        state.warm.store(true, Ordering::SeqCst);
+        return;
     };
 
-    let router = Router::new()
-        .nest("/api", crate::api::route())
-        .with_state(state);
+    tracing::info!("warming up the model");
+    let job_state = state.clone();
+    let queued = state.jobs.submit(move || {
+        let started = Instant::now();
+        let config = &job_state.config;
+        match llm::complete(
+            &model,
+            config.prompt_template,
+            config.system_prompt.clone(),
+            WARMUP_PROMPT.into(),
+            WARMUP_TOKENS,
+            config.session(),
+        ) {
+            Ok(_) => tracing::info!("warmed up in {}ms", started.elapsed().as_millis()),
+            Err(e) => tracing::warn!("unable to warm up the model: {e}"),
+        }
+        job_state.warm.store(true, Ordering::SeqCst);
+    });
+    if let Err(e) = queued {
+        tracing::warn!("unable to warm up the model: {e}");
+        state.warm.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Restores stored conversations, returning whether the default one was stored. It keeps the
+/// current default system message.
+pub(crate) fn restore(
+    db: &mut HistoryDb,
+    history: &mut History,
+    sessions: &mut Sessions,
+) -> Result<bool, PersistError> {
+    let mut restored_default = false;
+    for stored in db.load()? {
+        match stored.session_id {
+            Some(session_id) => sessions.restore(session_id, stored.history),
+            None => {
+                history.history = stored.history.history;
+                history.flags = stored.history.flags;
+                restored_default = true;
+            }
+        }
+    }
 
-    let port = std::env::var("AI_SIDECAR_PORT")?;
-    let listener = TcpListener::bind(format!("0.0.0.0:{port}")).await?;
+    Ok(restored_default)
+}
 
-    axum::serve(listener, router).await?;
+/// Restores stored conversations, storing the default one if it wasn't yet.
+fn rehydrate(
+    db: &mut HistoryDb,
+    history: &mut History,
+    sessions: &mut Sessions,
+) -> Result<(), PersistError> {
+    if !restore(db, history, sessions)? {
+        db.save(None, history)?;
+    }
 
     Ok(())
 }
+
+/// Resolves on Ctrl+C, or SIGTERM on Unix.
+async fn shutdown_signal() {
+    let interrupt = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            tracing::error!("unable to listen for Ctrl+C: {e}");
+            std::future::pending::<()>().await;
+        }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(e) => {
+                tracing::error!("unable to listen for SIGTERM: {e}");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        () = interrupt => {}
+        () = terminate => {}
+    }
+}
+
+/// Saves every conversation, waiting until `deadline` for generations still running in them.
+async fn flush(state: &AppState, deadline: Instant) {
+    let Some(db) = &state.history_db else {
+        return;
+    };
+    if state.replica.is_some() {
+        return;
+    }
+
+    let mut conversations = vec![(None, state.history.clone())];
+    conversations.extend(
+        state
+            .sessions
+            .lock()
+            .await
+            .iter()
+            .map(|(session_id, history)| (Some(session_id.to_string()), history.clone())),
+    );
+
+    for (session_id, history) in conversations {
+        let Ok(history) = tokio::time::timeout_at(deadline, history.lock()).await else {
+            tracing::warn!("still generating in {session_id:?}, not saving it");
+            continue;
+        };
+        if let Err(e) = db.lock().await.save(session_id.as_deref(), &history) {
+            tracing::error!("unable to persist {session_id:?}: {e}");
+        }
+    }
+}
+
+/// Runs the sidecar with only its own routes.
+pub async fn serve() -> Result<(), ServerError> {
+    Sidecar::new().serve().await
+}
+
+/// Builds the sidecar with routes of a game's own, so forks can add endpoints without editing the
+/// core routes. They are served under `/api/v1` like those, behind the same tracing, rate limiting
+/// and replica forwarding, and check keys with [`AppState::authorized`].
+#[derive(Default)]
+pub struct Sidecar {
+    extensions: Router<AppState>,
+}
+
+impl Sidecar {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds `routes`, with their paths relative to `/api/v1`, e.g. `/game/trade`. Panics, once
+    /// serving at the latest, if a path is already routed.
+    pub fn routes(mut self, routes: Router<AppState>) -> Self {
+        self.extensions = self.extensions.merge(routes);
+        self
+    }
+
+    pub async fn serve(self) -> Result<(), ServerError> {
+        let config = Config::load()?;
+        let http = Outbound::from_env()?.client()?;
+        let backend = config.anthropic.clone().map(|anthropic| {
+            let backend = Anthropic::new(http.clone(), anthropic);
+            tracing::info!("generating with {}", backend.name());
+            Arc::new(backend) as Arc<dyn LlmBackend>
+        });
+        let fallback = FallbackPack::from_env()?;
+        let moderator = Moderator::from_env()?;
+        let replica = Replica::from_env(&http)?;
+        let failover = config
+            .anthropic
+            .as_ref()
+            .is_some_and(|anthropic| anthropic.failover);
+        let ai_model = match (&replica, &backend) {
+            (Some(_), _) => {
+                tracing::info!("running as a read-only replica, without a model");
+                None
+            }
+            (None, Some(_)) if !failover => None,
+            (None, _) => match load_model(&config.model_path, config.model_params()) {
+                Ok(model) => Some(Arc::new(model)),
+                Err(e) if failover => {
+                    tracing::warn!("unable to load model, failing over from the start: {e}");
+                    None
+                }
+                Err(e) if fallback.is_some() => {
+                    tracing::warn!("unable to load model, serving fallback lines only: {e}");
+                    None
+                }
+                Err(e) => return Err(e.into()),
+            },
+        };
+        let dialogue = match &ai_model {
+            Some(model) => DialogueCorpus::from_env(model)?,
+            None => None,
+        };
+        let embedding_model = match std::env::var("AI_SIDECAR_EMBEDDING_MODEL_PATH") {
+            Ok(_) if replica.is_some() => None,
+            Ok(path) => Some(Arc::new(load_model(path, config.model_params())?)),
+            Err(_) => None,
+        };
+        let models = match replica {
+            Some(_) => ModelRegistry::default(),
+            None => ModelRegistry::load(&config)?,
+        };
+        let secret = config.secret()?.to_string();
+        let port = config.port()?;
+        let shutdown_timeout = match std::env::var("AI_SIDECAR_SHUTDOWN_TIMEOUT_SECS") {
+            Ok(v) => v
+                .parse()
+                .map_err(|_| ServerError::InvalidSetting("AI_SIDECAR_SHUTDOWN_TIMEOUT_SECS", v))?,
+            Err(_) => DEFAULT_SHUTDOWN_TIMEOUT_SECS,
+        };
+        let mut history = History::new(config.system_prompt.clone());
+        let mut sessions = Sessions::new(config.system_prompt.clone());
+        let mut history_db = HistoryDb::from_env()?;
+        match (&mut history_db, &replica) {
+            (Some(db), Some(_)) => {
+                restore(db, &mut history, &mut sessions)?;
+            }
+            (Some(db), None) => rehydrate(db, &mut history, &mut sessions)?,
+            (None, _) => {}
+        }
+
+        let tallies = match &history_db {
+            Some(db) => Tallies::restore(db.load_tallies()?),
+            None => Tallies::default(),
+        };
+
+        let listener = TcpListener::bind((config.bind_address, port)).await?;
+        let config = Arc::new(config);
+        let state = AppState {
+            ai_model: Arc::new(ArcSwapOption::new(ai_model)),
+            backend,
+            fallback: fallback.map(Arc::new),
+            chain: Arc::new(Chain::default()),
+            moderator: moderator.map(Arc::new),
+            dialogue: Arc::new(ArcSwapOption::new(dialogue.map(Arc::new))),
+            embedding_model,
+            tokenizer: HfTokenizer::from_env()?.map(Arc::new),
+            models: Arc::new(models),
+            config: config.clone(),
+            locations: Arc::new(Locations::from_env()?),
+            personas: Arc::new(Personas::from_env()?),
+            bounds: Arc::new(Bounds::from_env()?),
+            jobs: Arc::new(Jobs::from_env()?),
+            governor: Arc::new(Governor::new(config.background_share)),
+            cancellations: Arc::new(Cancellations::default()),
+            streams: Arc::new(Streams::default()),
+            monitor: Arc::new(Monitor::default()),
+            cache: Arc::new(ResponseCache::new(config.response_cache_size)),
+            kept_sessions: Arc::new(KeptSessions::new(config.kept_sessions)),
+            prefixes: Arc::new(Prefixes::default()),
+            tallies: Arc::new(tallies),
+            warm: Arc::new(AtomicBool::new(false)),
+            lore: Arc::new(LoreIndex::default()),
+            turns: Arc::new(Turns::from_env()?),
+            exploits: Arc::new(ExploitDetector::from_env(&http)),
+            rate_limiter: RateLimiter::from_env()?.map(Arc::new),
+            keys: Arc::new(KeyStore::from_env(secret)?),
+            replica: replica.map(Arc::new),
+            history: Arc::new(Mutex::new(history)),
+            memory: Arc::new(Mutex::new(MemoryStore::new(MemoryRules::from_env()?))),
+            review: Arc::new(Mutex::new(ReviewQueue::from_env()?)),
+            sessions: Arc::new(Mutex::new(sessions)),
+            tiers: Arc::new(Tiers::new(&config.tiers)),
+            history_db: history_db.map(|db| Arc::new(Mutex::new(db))),
+            analytics: Arc::new(Mutex::new(None)),
+            traces: Arc::new(Mutex::new(Traces::default())),
+            latency: Arc::new(Latency::from_env(&http)?),
+            ladder: Arc::new(Ladder::new(config.degradation.clone())),
+            flags: Arc::new(Flags::new(&config.flags)),
+            world_time: Arc::new(Mutex::new(WorldTime::default())),
+            address: listener.local_addr()?,
+            #[cfg(feature = "chaos")]
+            chaos: crate::chaos::Chaos::from_env()?.map(Arc::new),
+        };
+
+        match &state.replica {
+            Some(replica) => replica.spawn(state.clone()),
+            None => crate::narrative::spawn(state.clone())?,
+        }
+        crate::analytics::spawn(state.clone())?;
+        crate::temporal::spawn(state.clone(), http)?;
+        crate::kv::prepare(&state);
+        warm_up(&state);
+
+        let router = Router::new()
+            .nest("/api", crate::api::route(state.clone(), self.extensions))
+            .with_state(state.clone());
+        match Banner::new(&state) {
+            Ok(banner) => banner.log(),
+            Err(e) => tracing::error!("unable to summarize the configuration: {e}"),
+        }
+
+        let (stop, stopping) = oneshot::channel::<()>();
+        let server = axum::serve(
+            listener,
+            router.into_make_service_with_connect_info::<SocketAddr>(),
+        )
+        .with_graceful_shutdown(async {
+            let _ = stopping.await;
+        })
+        .into_future();
+        tokio::pin!(server);
+
+        tokio::select! {
+            result = &mut server => return Ok(result?),
+            () = shutdown_signal() => {}
+        }
+
+        tracing::info!("shutting down, waiting up to {shutdown_timeout}s for requests to finish");
+        let _ = stop.send(());
+        let deadline = Instant::now() + Duration::from_secs(shutdown_timeout);
+        match tokio::time::timeout_at(deadline, &mut server).await {
+            Ok(result) => result?,
+            Err(_) => tracing::warn!("requests still running, shutting down anyway"),
+        }
+        flush(&state, deadline).await;
+        tracing::info!("shut down");
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::{to_bytes, Body},
+        extract::State,
+        http::{HeaderMap, Request, StatusCode},
+        routing::get,
+    };
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::keys::Scope;
+
+    const SECRET: &str = "test-secret";
+
+    /// The state of a sidecar without a model, database or any optional subsystem.
+    fn state() -> AppState {
+        let config = Arc::new(Config::load_from(None).unwrap());
+        let http = reqwest::Client::new();
+
+        AppState {
+            ai_model: Arc::new(ArcSwapOption::empty()),
+            fallback: None,
+            moderator: None,
+            dialogue: Arc::new(ArcSwapOption::empty()),
+            embedding_model: None,
+            tokenizer: None,
+            models: Arc::new(ModelRegistry::default()),
+            backend: None,
+            chain: Arc::new(Chain::default()),
+            config: config.clone(),
+            locations: Arc::new(Locations::default()),
+            personas: Arc::new(Personas::default()),
+            bounds: Arc::new(Bounds::default()),
+            jobs: Arc::new(Jobs::new(1)),
+            governor: Arc::new(Governor::new(config.background_share)),
+            cancellations: Arc::new(Cancellations::default()),
+            streams: Arc::new(Streams::default()),
+            monitor: Arc::new(Monitor::default()),
+            cache: Arc::new(ResponseCache::new(0)),
+            kept_sessions: Arc::new(KeptSessions::new(0)),
+            prefixes: Arc::new(Prefixes::default()),
+            tallies: Arc::new(Tallies::default()),
+            warm: Arc::new(AtomicBool::new(false)),
+            lore: Arc::new(LoreIndex::default()),
+            turns: Arc::new(Turns::new(1)),
+            exploits: Arc::new(ExploitDetector::from_env(&http)),
+            rate_limiter: None,
+            keys: Arc::new(KeyStore::new(SECRET.into())),
+            replica: None,
+            history: Arc::new(Mutex::new(History::new(config.system_prompt.clone()))),
+            memory: Arc::new(Mutex::new(MemoryStore::new(MemoryRules::default()))),
+            review: Arc::new(Mutex::new(ReviewQueue::default())),
+            sessions: Arc::new(Mutex::new(Sessions::new(config.system_prompt.clone()))),
+            history_db: None,
+            analytics: Arc::new(Mutex::new(None)),
+            traces: Arc::new(Mutex::new(Traces::default())),
+            latency: Arc::new(Latency::new(None, 1)),
+            ladder: Arc::new(Ladder::new(None)),
+            tiers: Arc::new(Tiers::default()),
+            flags: Arc::new(Flags::new(&config.flags)),
+            world_time: Arc::new(Mutex::new(WorldTime::default())),
+            address: SocketAddr::from(([127, 0, 0, 1], 5000)),
+            #[cfg(feature = "chaos")]
+            chaos: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn mounts_extension_routes_with_the_app_state() {
+        async fn forge(State(state): State<AppState>, headers: HeaderMap) -> String {
+            match state.authorized(&headers, Scope::Generate) {
+                true => state.address.to_string(),
+                false => "unauthorized".into(),
+            }
+        }
+
+        let sidecar = Sidecar::new().routes(Router::new().route("/forge", get(forge)));
+        let state = state();
+        let router = Router::new()
+            .nest("/api", crate::api::route(state.clone(), sidecar.extensions))
+            .with_state(state);
+        let get_forge = |secret: &str| {
+            Request::get("/api/v1/forge")
+                .header("secret", secret)
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let response = router.clone().oneshot(get_forge(SECRET)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(&body[..], b"127.0.0.1:5000");
+
+        let response = router.oneshot(get_forge("wrong")).await.unwrap();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(&body[..], b"unauthorized");
+    }
+}
@@ -0,0 +1,219 @@
+//! Independent conversation histories, one per session, so players don't share a conversation.
+//!
+//! Each session has its own lock, which doubles as its busy flag, just like the default history
+//! used by requests without a session.
+
+use std::{
+    collections::HashMap,
+    hash::{BuildHasher, Hasher, RandomState},
+    sync::Arc,
+};
+
+use tokio::sync::Mutex;
+
+use crate::{goals::Goal, history::History, locale::Locale};
+
+/// How many sessions may exist at once, to bound memory use.
+const MAX_SESSIONS: usize = 1024;
+
+#[derive(Debug, thiserror::Error)]
+pub enum SessionError {
+    #[error("session {0:?} already exists")]
+    AlreadyExists(String),
+    #[error("too many sessions, at most {MAX_SESSIONS} may exist")]
+    TooMany,
+}
+
+#[derive(Debug)]
+pub struct Sessions {
+    default_system: String,
+    sessions: HashMap<String, Arc<Mutex<History>>>,
+    /// Each party session's players, readable without waiting for the conversation.
+    parties: HashMap<String, Vec<String>>,
+    /// Each session's locale, readable without waiting for the conversation, e.g. to pick a
+    /// fallback line while it is busy.
+    locales: HashMap<String, Locale>,
+}
+
+pub(crate) fn generate_id() -> String {
+    format!("{:016x}", RandomState::new().build_hasher().finish())
+}
+
+impl Sessions {
+    pub fn new(default_system: String) -> Self {
+        Self {
+            default_system,
+            sessions: HashMap::new(),
+            parties: HashMap::new(),
+            locales: HashMap::new(),
+        }
+    }
+
+    /// Starts a session with `system` as its system message, or the default one, `speakers`
+    /// taking turns if it is a group conversation, `party` sharing it if it is a party
+    /// conversation, `goal` for the NPC to steer toward, and `locale` for it to speak in.
+    /// Generates an id if none is given.
+    pub fn create(
+        &mut self,
+        session_id: Option<String>,
+        system: Option<String>,
+        speakers: Vec<String>,
+        party: Vec<String>,
+        goal: Option<String>,
+        locale: Option<Locale>,
+    ) -> Result<String, SessionError> {
+        if self.sessions.len() >= MAX_SESSIONS {
+            return Err(SessionError::TooMany);
+        }
+
+        let session_id = session_id.unwrap_or_else(generate_id);
+        if self.sessions.contains_key(&session_id) {
+            return Err(SessionError::AlreadyExists(session_id));
+        }
+
+        let mut history = History::new(system.unwrap_or_else(|| self.default_system.clone()));
+        history.speakers = speakers;
+        if !party.is_empty() {
+            self.parties.insert(session_id.clone(), party.clone());
+        }
+        history.party = party;
+        history.goal = goal.map(Goal::new);
+        if let Some(locale) = &locale {
+            self.locales.insert(session_id.clone(), locale.clone());
+        }
+        history.locale = locale;
+        self.sessions
+            .insert(session_id.clone(), Arc::new(Mutex::new(history)));
+
+        Ok(session_id)
+    }
+
+    /// Brings back a session saved before a restart.
+    pub fn restore(&mut self, session_id: String, history: History) {
+        if !history.party.is_empty() {
+            self.parties
+                .insert(session_id.clone(), history.party.clone());
+        }
+        if let Some(locale) = &history.locale {
+            self.locales.insert(session_id.clone(), locale.clone());
+        }
+        self.sessions
+            .insert(session_id, Arc::new(Mutex::new(history)));
+    }
+
+    /// Replaces the system message of sessions started without one from now on.
+    pub fn set_default_system(&mut self, system: String) {
+        self.default_system = system;
+    }
+
+    pub fn histories(&self) -> impl Iterator<Item = &Arc<Mutex<History>>> {
+        self.sessions.values()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &Arc<Mutex<History>>)> {
+        self.sessions
+            .iter()
+            .map(|(session_id, history)| (session_id.as_str(), history))
+    }
+
+    pub fn get(&self, session_id: &str) -> Option<Arc<Mutex<History>>> {
+        self.sessions.get(session_id).cloned()
+    }
+
+    /// The players sharing a session, or none if it isn't a party session.
+    pub fn party(&self, session_id: &str) -> &[String] {
+        self.parties.get(session_id).map_or(&[], Vec::as_slice)
+    }
+
+    pub fn locale(&self, session_id: &str) -> Option<&Locale> {
+        self.locales.get(session_id)
+    }
+
+    /// Keeps the session's locale readable without waiting for it, once it has been set in the
+    /// conversation itself.
+    pub fn set_locale(&mut self, session_id: &str, locale: Option<Locale>) {
+        match locale {
+            Some(locale) => self.locales.insert(session_id.to_string(), locale),
+            None => self.locales.remove(session_id),
+        };
+    }
+
+    /// Removes a session. A generation already running in it still finishes.
+    pub fn remove(&mut self, session_id: &str) -> bool {
+        self.parties.remove(session_id);
+        self.locales.remove(session_id);
+        self.sessions.remove(session_id).is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sessions_are_independent() {
+        let mut sessions = Sessions::new("Default".into());
+        let a = sessions
+            .create(None, None, Vec::new(), Vec::new(), None, None)
+            .unwrap();
+        let b = sessions
+            .create(
+                Some("b".into()),
+                Some("Custom".into()),
+                Vec::new(),
+                Vec::new(),
+                None,
+                None,
+            )
+            .unwrap();
+        assert_ne!(a, b);
+
+        sessions
+            .get(&a)
+            .unwrap()
+            .try_lock()
+            .unwrap()
+            .push(crate::history::MessageType::User, "Hi".into());
+
+        let a = sessions.get(&a).unwrap();
+        let b = sessions.get("b").unwrap();
+        assert_eq!(a.try_lock().unwrap().history.len(), 2);
+        assert_eq!(b.try_lock().unwrap().history.len(), 1);
+        assert_eq!(a.try_lock().unwrap().system.content(), "Default");
+        assert_eq!(b.try_lock().unwrap().system.content(), "Custom");
+
+        sessions.set_default_system("Replaced".into());
+        let c = sessions
+            .create(None, None, Vec::new(), Vec::new(), None, None)
+            .unwrap();
+        let c = sessions.get(&c).unwrap();
+        assert_eq!(c.try_lock().unwrap().system.content(), "Replaced");
+        assert_eq!(a.try_lock().unwrap().system.content(), "Default");
+    }
+
+    #[test]
+    fn rejects_duplicates_and_removes() {
+        let mut sessions = Sessions::new("Default".into());
+        let locale = "de".parse::<Locale>().ok();
+        sessions
+            .create(
+                Some("a".into()),
+                None,
+                Vec::new(),
+                Vec::new(),
+                None,
+                locale.clone(),
+            )
+            .unwrap();
+        assert_eq!(sessions.locale("a"), locale.as_ref());
+
+        assert!(matches!(
+            sessions.create(Some("a".into()), None, Vec::new(), Vec::new(), None, None),
+            Err(SessionError::AlreadyExists(_))
+        ));
+        assert!(sessions.remove("a"));
+        assert!(!sessions.remove("a"));
+        assert!(sessions.get("a").is_none());
+        assert_eq!(sessions.locale("a"), None);
+    }
+}
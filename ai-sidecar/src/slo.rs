@@ -0,0 +1,288 @@
+//! Response time tracking against a latency objective, so degradation is caught before players
+//! complain.
+//!
+//! The latency of every interactive `/generate` reply is kept over a rolling window, and served
+//! by `/stats`. The objective is configured through `AI_SIDECAR_SLO`, e.g.
+//! `percentile=95,target_ms=3000,window=200`. Whenever the window's percentile crosses the target,
+//! either way, it is logged and posted to `AI_SIDECAR_SLO_WEBHOOK_URL` if that is set.
+
+use std::{
+    collections::VecDeque,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::Serialize;
+
+const DEFAULT_PERCENTILE: f64 = 95.0;
+pub(crate) const DEFAULT_WINDOW: usize = 200;
+/// How many replies the window needs before the objective is judged.
+const MIN_SAMPLES: usize = 20;
+
+#[derive(Debug, thiserror::Error)]
+#[error("invalid SLO setting {0:?}")]
+pub struct SloError(String);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Slo {
+    /// Which percentile of the window is held to the target, e.g. 95 for p95.
+    pub percentile: f64,
+    pub target: Duration,
+}
+
+impl Slo {
+    /// Parses the objective and window size from a spec like `percentile=95,target_ms=3000`.
+    fn parse(spec: &str) -> Result<(Self, usize), SloError> {
+        let mut percentile = DEFAULT_PERCENTILE;
+        let mut target = None;
+        let mut window = DEFAULT_WINDOW;
+
+        for setting in spec.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            let invalid = || SloError(setting.to_string());
+            let (key, value) = setting.split_once('=').ok_or_else(invalid)?;
+            match key.trim() {
+                "percentile" => {
+                    percentile = value
+                        .trim()
+                        .parse()
+                        .ok()
+                        .filter(|p| (0.0..=100.0).contains(p))
+                        .ok_or_else(invalid)?
+                }
+                "target_ms" => {
+                    target = Some(Duration::from_millis(
+                        value.trim().parse().map_err(|_| invalid())?,
+                    ))
+                }
+                "window" => {
+                    window = value
+                        .trim()
+                        .parse()
+                        .ok()
+                        .filter(|window| *window >= MIN_SAMPLES)
+                        .ok_or_else(invalid)?
+                }
+                _ => return Err(invalid()),
+            }
+        }
+
+        let target = target.ok_or_else(|| SloError(format!("{spec} (target_ms is required)")))?;
+        Ok((Self { percentile, target }, window))
+    }
+}
+
+/// Each latency is in milliseconds, over the replies in the window.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct LatencyStats {
+    pub samples: usize,
+    pub p50_ms: Option<u64>,
+    pub p95_ms: Option<u64>,
+    pub p99_ms: Option<u64>,
+    pub slo: Option<SloStatus>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SloStatus {
+    pub percentile: f64,
+    pub target_ms: u64,
+    /// The window's latency at the objective's percentile, once it has enough replies.
+    pub observed_ms: Option<u64>,
+    pub violated: bool,
+}
+
+/// What is logged and posted to the webhook when the objective is violated or recovers.
+#[derive(Debug, Serialize)]
+struct Alert<'a> {
+    status: &'a str,
+    #[serde(flatten)]
+    slo: &'a SloStatus,
+    at: u64,
+}
+
+#[derive(Debug, Default)]
+struct Window {
+    latencies: VecDeque<Duration>,
+    violated: bool,
+}
+
+#[derive(Debug)]
+pub struct Latency {
+    slo: Option<Slo>,
+    window: usize,
+    recent: Mutex<Window>,
+    webhook: Option<(reqwest::Client, String)>,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// The nearest-rank `p`th percentile of `sorted`, in milliseconds.
+fn percentile(sorted: &[Duration], p: f64) -> Option<u64> {
+    let rank = ((p / 100.0) * sorted.len() as f64).ceil() as usize;
+    sorted
+        .get(rank.clamp(1, sorted.len().max(1)) - 1)
+        .map(|latency| latency.as_millis() as u64)
+}
+
+impl Latency {
+    pub fn from_env(http: &reqwest::Client) -> Result<Self, SloError> {
+        let (slo, window) = match std::env::var("AI_SIDECAR_SLO") {
+            Ok(spec) => {
+                let (slo, window) = Slo::parse(&spec)?;
+                (Some(slo), window)
+            }
+            Err(_) => (None, DEFAULT_WINDOW),
+        };
+        let webhook = std::env::var("AI_SIDECAR_SLO_WEBHOOK_URL")
+            .ok()
+            .map(|url| (http.clone(), url));
+
+        Ok(Self {
+            webhook,
+            ..Self::new(slo, window)
+        })
+    }
+
+    pub fn new(slo: Option<Slo>, window: usize) -> Self {
+        Self {
+            slo,
+            window,
+            recent: Default::default(),
+            webhook: None,
+        }
+    }
+
+    fn stats(&self, window: &Window) -> LatencyStats {
+        let mut sorted = window.latencies.iter().copied().collect::<Vec<_>>();
+        sorted.sort_unstable();
+
+        LatencyStats {
+            samples: sorted.len(),
+            p50_ms: percentile(&sorted, 50.0),
+            p95_ms: percentile(&sorted, 95.0),
+            p99_ms: percentile(&sorted, 99.0),
+            slo: self.slo.map(|slo| SloStatus {
+                percentile: slo.percentile,
+                target_ms: slo.target.as_millis() as u64,
+                observed_ms: (sorted.len() >= MIN_SAMPLES)
+                    .then(|| percentile(&sorted, slo.percentile))
+                    .flatten(),
+                violated: window.violated,
+            }),
+        }
+    }
+
+    pub fn snapshot(&self) -> LatencyStats {
+        self.stats(&self.recent.lock().unwrap())
+    }
+
+    /// Adds a reply's latency to the window, alerting if that moves it across the objective.
+    pub fn record(&self, latency: Duration) {
+        let status = {
+            let mut window = self.recent.lock().unwrap();
+            window.latencies.push_back(latency);
+            while window.latencies.len() > self.window {
+                window.latencies.pop_front();
+            }
+
+            let stats = self.stats(&window);
+            let Some(mut slo) = stats.slo else {
+                return;
+            };
+            let Some(observed) = slo.observed_ms else {
+                return;
+            };
+            let violated = observed > slo.target_ms;
+            if violated == window.violated {
+                return;
+            }
+            window.violated = violated;
+            slo.violated = violated;
+            slo
+        };
+
+        let alert = Alert {
+            status: match status.violated {
+                true => "violated",
+                false => "recovered",
+            },
+            slo: &status,
+            at: now(),
+        };
+        match status.violated {
+            true => tracing::warn!(
+                "p{} latency is {}ms, above the {}ms objective",
+                status.percentile,
+                status.observed_ms.unwrap_or_default(),
+                status.target_ms
+            ),
+            false => tracing::info!(
+                "p{} latency is back within the {}ms objective",
+                status.percentile,
+                status.target_ms
+            ),
+        }
+
+        if let Some((client, url)) = &self.webhook {
+            let request = client.post(url).json(&alert);
+            let url = url.clone();
+            tokio::spawn(async move {
+                let response = match request.send().await {
+                    Ok(response) => response.error_for_status(),
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = response {
+                    tracing::warn!("unable to post SLO alert to {url}: {e}");
+                }
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_the_objective() {
+        let (slo, window) = Slo::parse("percentile=99, target_ms=2500, window=50").unwrap();
+        assert_eq!(slo.percentile, 99.0);
+        assert_eq!(slo.target, Duration::from_millis(2500));
+        assert_eq!(window, 50);
+
+        assert!(Slo::parse("percentile=95").is_err());
+        assert!(Slo::parse("target_ms=100,percentile=101").is_err());
+        assert!(Slo::parse("target_ms=100,window=5").is_err());
+    }
+
+    #[test]
+    fn flags_violations_and_recovery() {
+        let slo = Slo {
+            percentile: 95.0,
+            target: Duration::from_millis(100),
+        };
+        let latency = Latency::new(Some(slo), MIN_SAMPLES);
+
+        for _ in 0..MIN_SAMPLES {
+            latency.record(Duration::from_millis(50));
+        }
+        let stats = latency.snapshot();
+        assert_eq!(stats.p95_ms, Some(50));
+        assert!(!stats.slo.unwrap().violated);
+
+        latency.record(Duration::from_millis(500));
+        latency.record(Duration::from_millis(500));
+        let status = latency.snapshot().slo.unwrap();
+        assert_eq!(status.observed_ms, Some(500));
+        assert!(status.violated);
+
+        for _ in 0..MIN_SAMPLES {
+            latency.record(Duration::from_millis(50));
+        }
+        assert!(!latency.snapshot().slo.unwrap().violated);
+    }
+}
@@ -0,0 +1,207 @@
+//! Lets more than one client follow the same streaming generation, e.g. the player's client and a
+//! game master's spectator view.
+//!
+//! Each conversation streams at most one generation at a time, so streams are kept by session. A
+//! subscriber first gets every event sent so far, then the rest as they are sent. Each one has a
+//! buffer of its own, and one that falls further behind than that is disconnected instead of
+//! slowing down the generation or the other subscribers. It can subscribe again to catch up.
+//!
+//! The client that asked for the generation is never disconnected: the generation waits for it
+//! instead, as it would if nobody else were watching. Spectators keep following along if it goes
+//! away, and the generation only stops once nobody is following it.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use tokio::sync::mpsc::{self, error::TrySendError};
+
+/// How many events a subscriber can fall behind by.
+const SUBSCRIBER_BUFFER: usize = 64;
+
+#[derive(Debug)]
+struct Stream<T> {
+    id: u64,
+    sent: Vec<T>,
+    subscribers: Vec<mpsc::Sender<T>>,
+}
+
+#[derive(Debug)]
+pub struct Streams<T> {
+    streams: Mutex<HashMap<Option<String>, Stream<T>>>,
+    next: AtomicU64,
+}
+
+impl<T> Default for Streams<T> {
+    fn default() -> Self {
+        Self {
+            streams: Mutex::new(HashMap::new()),
+            next: AtomicU64::new(0),
+        }
+    }
+}
+
+/// Sends a conversation's events to its subscribers, ending their streams when it is dropped.
+#[derive(Debug)]
+pub struct Publisher<T> {
+    streams: Arc<Streams<T>>,
+    session_id: Option<String>,
+    id: u64,
+    requester: mpsc::Sender<T>,
+}
+
+impl<T: Clone> Publisher<T> {
+    /// Sends `event` to the requester and every subscriber, returning whether any are left. Waits
+    /// for the requester to make room for it, so this mustn't be called from an async context.
+    pub fn publish(&self, event: &T) -> bool {
+        let spectators = {
+            let mut streams = self.streams.streams.lock().unwrap();
+            match streams.get_mut(&self.session_id) {
+                Some(stream) => {
+                    stream.sent.push(event.clone());
+                    stream.subscribers.retain(|subscriber| {
+                        match subscriber.try_send(event.clone()) {
+                            Ok(()) => true,
+                            Err(TrySendError::Full(_)) => {
+                                tracing::warn!(
+                                    "disconnecting a stream subscriber that fell behind"
+                                );
+                                false
+                            }
+                            Err(TrySendError::Closed(_)) => false,
+                        }
+                    });
+                    !stream.subscribers.is_empty()
+                }
+                None => false,
+            }
+        };
+        // Outside the lock, so spectators can still subscribe while the requester catches up.
+        let requester = self.requester.blocking_send(event.clone()).is_ok();
+
+        requester || spectators
+    }
+}
+
+impl<T> Drop for Publisher<T> {
+    fn drop(&mut self) {
+        let mut streams = self.streams.streams.lock().unwrap();
+        if streams
+            .get(&self.session_id)
+            .is_some_and(|stream| stream.id == self.id)
+        {
+            streams.remove(&self.session_id);
+        }
+    }
+}
+
+impl<T: Clone> Streams<T> {
+    /// Starts streaming a conversation's generation to the requester and whoever subscribes.
+    pub fn start(
+        self: &Arc<Self>,
+        session_id: Option<String>,
+    ) -> (Publisher<T>, mpsc::Receiver<T>) {
+        let id = self.next.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = mpsc::channel(SUBSCRIBER_BUFFER);
+        self.streams.lock().unwrap().insert(
+            session_id.clone(),
+            Stream {
+                id,
+                sent: Vec::new(),
+                subscribers: Vec::new(),
+            },
+        );
+
+        let publisher = Publisher {
+            streams: self.clone(),
+            session_id,
+            id,
+            requester: tx,
+        };
+        (publisher, rx)
+    }
+
+    /// Follows a conversation's generation from its first event, or `None` if it isn't streaming
+    /// one.
+    pub fn subscribe(&self, session_id: Option<&str>) -> Option<mpsc::Receiver<T>> {
+        let mut streams = self.streams.lock().unwrap();
+        let stream = streams.get_mut(&session_id.map(str::to_string))?;
+        let (tx, rx) = mpsc::channel(stream.sent.len() + SUBSCRIBER_BUFFER);
+        for event in &stream.sent {
+            tx.try_send(event.clone())
+                .expect("the buffer fits what was sent");
+        }
+        stream.subscribers.push(tx);
+
+        Some(rx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn subscribers_catch_up_and_lag_independently() {
+        let streams = Arc::new(Streams::default());
+        assert!(streams.subscribe(None).is_none());
+
+        let (publisher, requester) = streams.start(Some("a".into()));
+        drop(requester);
+        let mut idle = streams.subscribe(Some("a")).unwrap();
+        publisher.publish(&0);
+        let mut late = streams.subscribe(Some("a")).unwrap();
+        for event in 1..=SUBSCRIBER_BUFFER {
+            publisher.publish(&event);
+            assert_eq!(late.try_recv().ok(), Some(event - 1));
+        }
+        assert_eq!(late.try_recv().ok(), Some(SUBSCRIBER_BUFFER));
+
+        // The idle subscriber's buffer filled up, so it was dropped without holding anyone up.
+        assert!(publisher.publish(&(SUBSCRIBER_BUFFER + 1)));
+        assert_eq!(late.try_recv().ok(), Some(SUBSCRIBER_BUFFER + 1));
+        assert_eq!(idle.try_recv().ok(), Some(0));
+        while idle.try_recv().is_ok() {}
+        assert!(idle.is_closed());
+
+        drop(late);
+        assert!(!publisher.publish(&(SUBSCRIBER_BUFFER + 2)));
+        drop(publisher);
+        assert!(streams.subscribe(Some("a")).is_none());
+    }
+
+    #[test]
+    fn waits_for_the_requester_instead_of_dropping_it() {
+        let streams = Arc::new(Streams::default());
+        let (publisher, mut requester) = streams.start(None);
+        let events = 2 * SUBSCRIBER_BUFFER;
+        let generation =
+            std::thread::spawn(move || (0..events).all(|event| publisher.publish(&event)));
+
+        for event in 0..events {
+            assert_eq!(requester.blocking_recv(), Some(event));
+        }
+        assert!(generation.join().unwrap());
+        assert_eq!(requester.blocking_recv(), None);
+    }
+
+    #[test]
+    fn keeps_streaming_once_the_requester_leaves() {
+        let streams = Arc::new(Streams::default());
+        let (publisher, requester) = streams.start(None);
+        publisher.publish(&0);
+        let mut spectator = streams.subscribe(None).unwrap();
+
+        drop(requester);
+        assert!(publisher.publish(&1));
+        assert_eq!(spectator.try_recv().ok(), Some(0));
+        assert_eq!(spectator.try_recv().ok(), Some(1));
+
+        drop(publisher);
+        assert!(spectator.try_recv().is_err() && spectator.is_closed());
+    }
+}
@@ -0,0 +1,180 @@
+//! How generations fare per persona and prompt template: how long the replies run and take, and
+//! how often moderation has them generated again or blocks them, so content owners can see which
+//! prompts perform poorly in production.
+//!
+//! Tallies are served with the analytics report. They are also kept in the history database, if
+//! there is one, so they survive restarts.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Mutex,
+};
+
+use serde::Serialize;
+
+use crate::templates::Template;
+
+/// Generations counted together, under a persona, or none, and a template.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Tally {
+    pub generations: u64,
+    /// Characters across every reply that wasn't blocked.
+    pub characters: u64,
+    pub latency_ms: u64,
+    /// Replies generated again because moderation asked for it.
+    pub regenerations: u64,
+    /// Replies moderation blocked outright.
+    pub blocked: u64,
+}
+
+/// How a single generation went.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Observation {
+    pub characters: usize,
+    pub latency_ms: u64,
+    pub regenerations: usize,
+    pub blocked: bool,
+}
+
+impl Tally {
+    fn add(&mut self, observation: Observation) {
+        self.generations += 1;
+        self.characters += observation.characters as u64;
+        self.latency_ms += observation.latency_ms;
+        self.regenerations += observation.regenerations as u64;
+        self.blocked += u64::from(observation.blocked);
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TallySummary {
+    pub persona: Option<String>,
+    pub template: Template,
+    pub generations: u64,
+    /// In characters, over the replies that weren't blocked.
+    pub average_length: f64,
+    pub average_latency_ms: f64,
+    /// Regenerations per generation.
+    pub regeneration_rate: f64,
+    /// The share of generations moderation blocked.
+    pub moderation_block_rate: f64,
+}
+
+fn ratio(count: u64, total: u64) -> f64 {
+    match total {
+        0 => 0.0,
+        total => count as f64 / total as f64,
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Tallies {
+    tallies: Mutex<BTreeMap<(Option<String>, Template), Tally>>,
+}
+
+impl Tallies {
+    pub fn restore(stored: impl IntoIterator<Item = (Option<String>, Template, Tally)>) -> Self {
+        Self {
+            tallies: Mutex::new(
+                stored
+                    .into_iter()
+                    .map(|(persona, template, tally)| ((persona, template), tally))
+                    .collect(),
+            ),
+        }
+    }
+
+    /// Counts a generation, returning its persona and template's tally so far.
+    pub fn record(
+        &self,
+        persona: Option<&str>,
+        template: Template,
+        observation: Observation,
+    ) -> Tally {
+        let mut tallies = self.tallies.lock().unwrap();
+        let tally = tallies
+            .entry((persona.map(str::to_string), template))
+            .or_default();
+        tally.add(observation);
+
+        *tally
+    }
+
+    /// How many generations each persona has had, over every template.
+    pub fn generations(&self) -> HashMap<Option<String>, u64> {
+        let mut generations = HashMap::new();
+        for ((persona, _), tally) in self.tallies.lock().unwrap().iter() {
+            *generations.entry(persona.clone()).or_default() += tally.generations;
+        }
+
+        generations
+    }
+
+    /// Every tally, by persona and then template.
+    pub fn summaries(&self) -> Vec<TallySummary> {
+        self.tallies
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|((persona, template), tally)| TallySummary {
+                persona: persona.clone(),
+                template: *template,
+                generations: tally.generations,
+                average_length: ratio(tally.characters, tally.generations - tally.blocked),
+                average_latency_ms: ratio(tally.latency_ms, tally.generations),
+                regeneration_rate: ratio(tally.regenerations, tally.generations),
+                moderation_block_rate: ratio(tally.blocked, tally.generations),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn summarizes_by_persona_and_template() {
+        let tallies = Tallies::restore([(
+            Some("smith".into()),
+            Template::ChatMl,
+            Tally {
+                generations: 1,
+                characters: 30,
+                latency_ms: 300,
+                ..Tally::default()
+            },
+        )]);
+        tallies.record(
+            Some("smith"),
+            Template::ChatMl,
+            Observation {
+                characters: 10,
+                latency_ms: 100,
+                regenerations: 1,
+                blocked: false,
+            },
+        );
+        let tally = tallies.record(
+            Some("smith"),
+            Template::ChatMl,
+            Observation {
+                latency_ms: 200,
+                regenerations: 1,
+                blocked: true,
+                ..Observation::default()
+            },
+        );
+        assert_eq!(tally.generations, 3);
+        tallies.record(None, Template::Zephyr, Observation::default());
+
+        let summaries = tallies.summaries();
+        assert_eq!(summaries.len(), 2);
+        assert_eq!(summaries[0].persona, None);
+        let smith = &summaries[1];
+        assert_eq!(smith.average_length, 20.0);
+        assert_eq!(smith.average_latency_ms, 200.0);
+        assert_eq!(smith.regeneration_rate, 2.0 / 3.0);
+        assert_eq!(smith.moderation_block_rate, 1.0 / 3.0);
+    }
+}
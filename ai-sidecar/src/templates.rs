@@ -0,0 +1,218 @@
+//! Chat prompt templates, so the sidecar can run models from different families without a rebuild.
+//!
+//! Each family marks up turns its own way: Zephyr and ChatML with role headers, Llama 2 and
+//! Mistral by wrapping the player's turns in `[INST]` tags. The template is picked in the config
+//! and can be overridden per request.
+
+use std::{borrow::Cow, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+use crate::history::MessageType;
+
+/// A turn ready to be marked up, its content already escaped.
+#[derive(Debug)]
+pub struct Turn<'a> {
+    pub message_type: MessageType,
+    pub text: Cow<'a, str>,
+}
+
+pub trait PromptTemplate {
+    /// Markers the tokenizer treats as control tokens under this template. None contain spaces.
+    fn control_markers(&self) -> &'static [&'static str];
+
+    /// Marks up the system message and turns, ending where the reply starts, after
+    /// `reply_prefix`.
+    fn render(&self, system: &str, turns: &[Turn<'_>], reply_prefix: &str) -> String;
+}
+
+/// The built-in templates.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Template {
+    /// What the default TinyLlama chat model was trained on.
+    #[default]
+    Zephyr,
+    ChatMl,
+    Llama2,
+    Mistral,
+}
+
+impl Template {
+    pub fn get(self) -> &'static dyn PromptTemplate {
+        match self {
+            Self::Zephyr => &Zephyr,
+            Self::ChatMl => &ChatMl,
+            Self::Llama2 => &LLAMA_2,
+            Self::Mistral => &MISTRAL,
+        }
+    }
+
+    /// What the template is called in requests and the config file.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Zephyr => "zephyr",
+            Self::ChatMl => "chatml",
+            Self::Llama2 => "llama2",
+            Self::Mistral => "mistral",
+        }
+    }
+}
+
+impl FromStr for Template {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "zephyr" => Ok(Self::Zephyr),
+            "chatml" => Ok(Self::ChatMl),
+            "llama2" => Ok(Self::Llama2),
+            "mistral" => Ok(Self::Mistral),
+            _ => Err(format!("unknown prompt template {s:?}")),
+        }
+    }
+}
+
+const HEADER_MARKERS: &[&str] = &["<|", "</s>", "<s>"];
+
+/// `<|system|>`, `<|user|>` and `<|assistant|>` headers, with turns ending in `</s>`.
+#[derive(Debug)]
+struct Zephyr;
+
+impl PromptTemplate for Zephyr {
+    fn control_markers(&self) -> &'static [&'static str] {
+        HEADER_MARKERS
+    }
+
+    fn render(&self, system: &str, turns: &[Turn<'_>], reply_prefix: &str) -> String {
+        let mut prompt = format!("<|system|>\n{system}</s>\n");
+        for turn in turns {
+            let header = match turn.message_type {
+                MessageType::System => "<|system|>",
+                MessageType::User => "<|user|>",
+                MessageType::Assistant => "<|assistant|>",
+            };
+            prompt += &format!("{header}\n{}</s>\n", turn.text);
+        }
+        prompt += "<|assistant|>";
+        if !reply_prefix.is_empty() {
+            prompt += &format!("\n{reply_prefix}");
+        }
+
+        prompt
+    }
+}
+
+/// `<|im_start|>role` headers, with turns ending in `<|im_end|>`.
+#[derive(Debug)]
+struct ChatMl;
+
+impl PromptTemplate for ChatMl {
+    fn control_markers(&self) -> &'static [&'static str] {
+        HEADER_MARKERS
+    }
+
+    fn render(&self, system: &str, turns: &[Turn<'_>], reply_prefix: &str) -> String {
+        let mut prompt = format!("<|im_start|>system\n{system}<|im_end|>\n");
+        for turn in turns {
+            let role = match turn.message_type {
+                MessageType::System => "system",
+                MessageType::User => "user",
+                MessageType::Assistant => "assistant",
+            };
+            prompt += &format!("<|im_start|>{role}\n{}<|im_end|>\n", turn.text);
+        }
+
+        prompt + "<|im_start|>assistant\n" + reply_prefix
+    }
+}
+
+/// The player's turns wrapped in `[INST]` tags, with each exchange ending in `</s>`.
+///
+/// Models trained this way expect turns to alternate starting with the player, so an NPC turn
+/// without a player turn before it gets an empty instruction.
+#[derive(Debug)]
+struct Instruct {
+    /// Opens the first instruction, around the system message.
+    system: fn(&str) -> String,
+    /// Opens every later instruction.
+    open: &'static str,
+    /// Goes around NPC turns, after `[/INST]` and before `</s>`.
+    padding: &'static str,
+}
+
+const LLAMA_2: Instruct = Instruct {
+    system: |system| format!("<s>[INST] <<SYS>>\n{system}\n<</SYS>>\n\n"),
+    open: "<s>[INST] ",
+    padding: " ",
+};
+
+// Mistral has no system role, so the system message opens the first instruction.
+const MISTRAL: Instruct = Instruct {
+    system: |system| format!("<s>[INST] {system}\n\n"),
+    open: "[INST] ",
+    padding: "",
+};
+
+/// Where an [`Instruct`] prompt is up to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Position {
+    /// Inside an instruction, waiting for the player's turn.
+    Instruction,
+    /// After `[/INST]`, waiting for the NPC's turn.
+    Reply,
+    /// After `</s>`, waiting for the next instruction.
+    Closed,
+}
+
+impl Instruct {
+    /// Moves on to where the NPC's turn goes.
+    fn to_reply(&self, prompt: &mut String, position: Position) {
+        match position {
+            Position::Instruction => *prompt += " [/INST]",
+            Position::Reply => {}
+            Position::Closed => *prompt += &format!("{}[/INST]", self.open),
+        }
+    }
+}
+
+impl PromptTemplate for Instruct {
+    fn control_markers(&self) -> &'static [&'static str] {
+        &["[INST]", "[/INST]", "<<SYS>>", "<</SYS>>", "</s>", "<s>"]
+    }
+
+    fn render(&self, system: &str, turns: &[Turn<'_>], reply_prefix: &str) -> String {
+        let mut prompt = (self.system)(system);
+        let mut position = Position::Instruction;
+        for turn in turns {
+            match turn.message_type {
+                MessageType::User | MessageType::System => {
+                    match position {
+                        Position::Instruction => {}
+                        Position::Reply => prompt += &format!("{}</s>{}", self.padding, self.open),
+                        Position::Closed => prompt += self.open,
+                    }
+                    prompt += &format!("{} [/INST]", turn.text);
+                    position = Position::Reply;
+                }
+                MessageType::Assistant => {
+                    self.to_reply(&mut prompt, position);
+                    prompt += &format!(
+                        "{padding}{}{padding}</s>",
+                        turn.text,
+                        padding = self.padding
+                    );
+                    position = Position::Closed;
+                }
+            }
+        }
+        self.to_reply(&mut prompt, position);
+        if !reply_prefix.is_empty() {
+            prompt += &format!("{}{reply_prefix}", self.padding);
+        }
+
+        prompt
+    }
+}
@@ -0,0 +1,103 @@
+//! The in-game time, season and weather, included in every prompt so NPCs know when they are.
+//!
+//! The game either pushes it to `/game/time` whenever it changes, or serves it at
+//! `AI_SIDECAR_TIME_URL` for the sidecar to poll every `AI_SIDECAR_TIME_POLL_SECS` seconds.
+
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::server::{AppState, ServerError};
+
+const DEFAULT_POLL_SECS: u64 = 30;
+
+/// Each field is free text in whatever form the game uses, e.g. `"23:40"` or `"late evening"`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct WorldTime {
+    pub time: Option<String>,
+    pub date: Option<String>,
+    pub season: Option<String>,
+    pub weather: Option<String>,
+}
+
+impl WorldTime {
+    /// Background for the system message, or `None` if the game hasn't said anything yet.
+    pub fn context(&self) -> Option<String> {
+        let mut context = String::new();
+
+        match (&self.time, &self.date) {
+            (Some(time), Some(date)) => context += &format!("It is {time} on {date}.\n"),
+            (Some(time), None) => context += &format!("It is {time}.\n"),
+            (None, Some(date)) => context += &format!("It is {date}.\n"),
+            (None, None) => {}
+        }
+        if let Some(season) = &self.season {
+            context += &format!("The season is {season}.\n");
+        }
+        if let Some(weather) = &self.weather {
+            context += &format!("The weather: {weather}.\n");
+        }
+
+        match context.is_empty() {
+            true => None,
+            false => Some(context.trim_end().to_string()),
+        }
+    }
+}
+
+/// Polls `AI_SIDECAR_TIME_URL` for the world time, if it is set.
+pub fn spawn(state: AppState, client: reqwest::Client) -> Result<(), ServerError> {
+    let Ok(url) = std::env::var("AI_SIDECAR_TIME_URL") else {
+        return Ok(());
+    };
+    let secs = match std::env::var("AI_SIDECAR_TIME_POLL_SECS") {
+        Ok(v) => v
+            .parse()
+            .ok()
+            .filter(|secs| *secs > 0)
+            .ok_or(ServerError::InvalidSetting("AI_SIDECAR_TIME_POLL_SECS", v))?,
+        Err(_) => DEFAULT_POLL_SECS,
+    };
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(secs));
+        loop {
+            interval.tick().await;
+            let response = match client.get(&url).send().await {
+                Ok(response) => response.error_for_status(),
+                Err(e) => Err(e),
+            };
+            match response {
+                Ok(response) => match response.json::<WorldTime>().await {
+                    Ok(time) => *state.world_time.lock().await = time,
+                    Err(e) => tracing::warn!("invalid world time from {url}: {e}"),
+                },
+                Err(e) => tracing::warn!("unable to poll world time from {url}: {e}"),
+            }
+        }
+    });
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn describes_only_what_is_known() {
+        assert_eq!(WorldTime::default().context(), None);
+
+        let time = WorldTime {
+            time: Some("midnight".into()),
+            season: Some("deep winter".into()),
+            weather: Some("heavy snow".into()),
+            ..Default::default()
+        };
+        assert_eq!(
+            time.context().unwrap(),
+            "It is midnight.\nThe season is deep winter.\nThe weather: heavy snow."
+        );
+    }
+}
@@ -0,0 +1,101 @@
+//! Service levels for players' subscription tiers, so premium subscribers get the bigger model and
+//! the faster queue without a deployment of their own.
+//!
+//! Tiers are set in the config file, by name. A `/generate` request names its player's with `tier`,
+//! unless its key is pinned to one. A tier's `model` replies to requests that don't name one,
+//! `max_tokens` caps every reply, `priority` jobs go ahead of the others in the queue, and `quota`
+//! limits how many requests each player makes a minute, by `player_id` or else by session. Only
+//! requests that would otherwise be served count against it, and each prompt of a batch counts.
+
+use std::{collections::BTreeMap, time::Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::ratelimit::{Limit, RateLimiter};
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct TierConfig {
+    /// One of the config's extra `models`, instead of the default one.
+    pub model: Option<String>,
+    /// The most tokens a reply may have, whatever the request asks for.
+    pub max_tokens: Option<usize>,
+    /// Whether its generations go ahead of those of tiers without priority.
+    #[serde(default)]
+    pub priority: bool,
+    /// Requests each player may make, unlimited if unset.
+    pub quota: Option<Limit>,
+}
+
+#[derive(Debug)]
+pub struct Tier {
+    pub config: TierConfig,
+    quota: Option<RateLimiter>,
+}
+
+impl Tier {
+    /// Counts a request from `player` against the quota at `now`, returning how many seconds until
+    /// they can make another if they are over it.
+    pub fn admit(&self, player: &str, now: Instant) -> Option<u64> {
+        let decision = self.quota.as_ref()?.check(player, now);
+
+        (!decision.allowed).then(|| decision.retry_after.as_secs_f64().ceil() as u64)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Tiers {
+    tiers: BTreeMap<String, Tier>,
+}
+
+impl Tiers {
+    pub fn new(configs: &BTreeMap<String, TierConfig>) -> Self {
+        let tiers = configs
+            .iter()
+            .map(|(name, config)| {
+                let tier = Tier {
+                    config: config.clone(),
+                    quota: config.quota.map(RateLimiter::new),
+                };
+                (name.clone(), tier)
+            })
+            .collect();
+
+        Self { tiers }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Tier> {
+        self.tiers.get(name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn limits_each_player_to_the_quota() {
+        let tiers = Tiers::new(&BTreeMap::from([
+            (
+                "free".into(),
+                serde_yaml::from_str("max_tokens: 128\nquota:\n  per_minute: 60\n  burst: 1\n")
+                    .unwrap(),
+            ),
+            ("premium".into(), TierConfig::default()),
+        ]));
+        let free = tiers.get("free").unwrap();
+        let start = Instant::now();
+
+        assert_eq!(free.config.max_tokens, Some(128));
+        assert_eq!(free.admit("ayla", start), None);
+        assert_eq!(free.admit("ayla", start), Some(1));
+        assert_eq!(free.admit("corwin", start), None);
+        assert_eq!(free.admit("ayla", start + Duration::from_secs(1)), None);
+
+        let premium = tiers.get("premium").unwrap();
+        assert!((0..10).all(|_| premium.admit("ayla", start).is_none()));
+        assert!(tiers.get("gold").is_none());
+    }
+}
@@ -0,0 +1,499 @@
+//! Tokenizers behind one trait, so truncation, budgets and the tokenize API count tokens the same
+//! way whichever tokenizer applies.
+//!
+//! The loaded GGUF model is its own tokenizer, and local generations always budget with it, since
+//! its tokens are what fill the context. A Hugging Face `tokenizer.json` can be loaded from
+//! `AI_SIDECAR_TOKENIZER_PATH` for the tokenize API to match a model served elsewhere. Only BPE
+//! tokenizers are supported, in either the byte-level style of GPT-2 and Llama 3 or the
+//! SentencePiece style of Llama 2 and Mistral.
+
+use std::{collections::HashMap, path::Path};
+
+use llama_cpp::{LlamaModel, Token};
+use serde::Deserialize;
+
+/// SentencePiece's stand-in for a space.
+const METASPACE: char = '▁';
+
+#[derive(Debug, thiserror::Error)]
+pub enum TokenizerError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[error("unsupported tokenizer: {0}")]
+    Unsupported(String),
+    #[error("token {0} is outside the vocabulary")]
+    InvalidToken(i32),
+    #[error("unable to tokenize: {0}")]
+    Tokenize(String),
+}
+
+pub trait Tokenizer: Send + Sync {
+    /// The tokens of `text`, after the beginning-of-sequence token if `add_bos` is set.
+    fn encode(&self, text: &str, add_bos: bool) -> Result<Vec<i32>, TokenizerError>;
+
+    fn decode(&self, tokens: &[i32]) -> Result<String, TokenizerError>;
+}
+
+/// How many tokens `text` takes up as a prompt, erring high if it can't be tokenized.
+pub fn count(tokenizer: &dyn Tokenizer, text: &str) -> usize {
+    tokenizer
+        .encode(text, true)
+        .map_or(text.len() + 1, |tokens| tokens.len())
+}
+
+impl Tokenizer for LlamaModel {
+    fn encode(&self, text: &str, add_bos: bool) -> Result<Vec<i32>, TokenizerError> {
+        self.tokenize_bytes(text, add_bos, false)
+            .map(|tokens| tokens.into_iter().map(|token| token.0).collect())
+            .map_err(|e| TokenizerError::Tokenize(e.to_string()))
+    }
+
+    fn decode(&self, tokens: &[i32]) -> Result<String, TokenizerError> {
+        // Decoding an id outside the vocabulary would read past the model's token table.
+        let vocabulary = self.vocabulary_size();
+        if let Some(&token) = tokens
+            .iter()
+            .find(|token| usize::try_from(**token).map_or(true, |token| token >= vocabulary))
+        {
+            return Err(TokenizerError::InvalidToken(token));
+        }
+
+        Ok(self.decode_tokens(tokens.iter().map(|token| Token(*token))))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct AddedToken {
+    id: i32,
+    content: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Merge {
+    Joined(String),
+    Pair(String, String),
+}
+
+#[derive(Debug, Deserialize)]
+struct BpeModel {
+    #[serde(rename = "type")]
+    kind: String,
+    vocab: HashMap<String, i32>,
+    merges: Vec<Merge>,
+    #[serde(default)]
+    byte_fallback: bool,
+    unk_token: Option<String>,
+}
+
+/// The parts of a `tokenizer.json` that BPE tokenizers need.
+#[derive(Debug, Deserialize)]
+struct TokenizerFile {
+    #[serde(default)]
+    added_tokens: Vec<AddedToken>,
+    #[serde(default)]
+    normalizer: serde_json::Value,
+    #[serde(default)]
+    pre_tokenizer: serde_json::Value,
+    #[serde(default)]
+    post_processor: serde_json::Value,
+    model: BpeModel,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Style {
+    /// Bytes mapped to printable characters, as in GPT-2.
+    ByteLevel,
+    /// Spaces replaced with `▁`, as in SentencePiece.
+    Metaspace,
+}
+
+/// GPT-2's reversible mapping of bytes to printable characters.
+fn byte_chars() -> [char; 256] {
+    let mut chars = ['\0'; 256];
+    let mut next = 256;
+    for (byte, c) in chars.iter_mut().enumerate() {
+        let printable = matches!(byte, 0x21..=0x7e | 0xa1..=0xac | 0xae..=0xff);
+        let code = match printable {
+            true => byte as u32,
+            false => {
+                next += 1;
+                next - 1
+            }
+        };
+        *c = char::from_u32(code).expect("below the surrogate range");
+    }
+    chars
+}
+
+/// The id of the token the post-processor puts before a single sequence, if any.
+fn bos_content(post_processor: &serde_json::Value) -> Option<String> {
+    match post_processor {
+        serde_json::Value::Object(map) => match map.get("single") {
+            Some(single) => single.get(0)?["SpecialToken"]["id"]
+                .as_str()
+                .map(str::to_string),
+            None => map.values().find_map(bos_content),
+        },
+        serde_json::Value::Array(values) => values.iter().find_map(bos_content),
+        _ => None,
+    }
+}
+
+/// A Hugging Face BPE tokenizer read from its `tokenizer.json`.
+#[derive(Debug)]
+pub struct HfTokenizer {
+    style: Style,
+    vocab: HashMap<String, i32>,
+    /// Each token's text, by id.
+    pieces: HashMap<i32, String>,
+    ranks: HashMap<(String, String), usize>,
+    /// Matched verbatim before anything else, longest first.
+    added: Vec<(String, i32)>,
+    bos: Option<i32>,
+    unk: Option<i32>,
+    byte_fallback: bool,
+    byte_chars: [char; 256],
+}
+
+impl HfTokenizer {
+    /// The tokenizer at `AI_SIDECAR_TOKENIZER_PATH`, if it is set.
+    pub fn from_env() -> Result<Option<Self>, TokenizerError> {
+        match std::env::var("AI_SIDECAR_TOKENIZER_PATH") {
+            Ok(path) => Self::load(path).map(Some),
+            Err(_) => Ok(None),
+        }
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, TokenizerError> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+
+    pub fn parse(json: &str) -> Result<Self, TokenizerError> {
+        let file: TokenizerFile = serde_json::from_str(json)?;
+        if file.model.kind != "BPE" {
+            return Err(TokenizerError::Unsupported(format!(
+                "{} models",
+                file.model.kind
+            )));
+        }
+        let describes = |kind: &str| {
+            [&file.normalizer, &file.pre_tokenizer]
+                .iter()
+                .any(|section| section.to_string().contains(kind))
+        };
+        let style = if describes("ByteLevel") {
+            Style::ByteLevel
+        } else if describes("Metaspace") || describes(&METASPACE.to_string()) {
+            Style::Metaspace
+        } else {
+            return Err(TokenizerError::Unsupported(
+                "pre-tokenizers other than ByteLevel and Metaspace".into(),
+            ));
+        };
+
+        let ranks = file
+            .model
+            .merges
+            .into_iter()
+            .enumerate()
+            .filter_map(|(rank, merge)| {
+                let (left, right) = match merge {
+                    Merge::Joined(joined) => {
+                        let (left, right) = joined.split_once(' ')?;
+                        (left.to_string(), right.to_string())
+                    }
+                    Merge::Pair(left, right) => (left, right),
+                };
+                Some(((left, right), rank))
+            })
+            .collect();
+        let mut added = file
+            .added_tokens
+            .into_iter()
+            .map(|token| (token.content, token.id))
+            .collect::<Vec<_>>();
+        added.sort_by_key(|(content, _)| std::cmp::Reverse(content.len()));
+        let mut pieces = file
+            .model
+            .vocab
+            .iter()
+            .map(|(piece, id)| (*id, piece.clone()))
+            .collect::<HashMap<_, _>>();
+        pieces.extend(added.iter().map(|(content, id)| (*id, content.clone())));
+        let lookup = |content: &str| {
+            added
+                .iter()
+                .find(|(added, _)| added == content)
+                .map(|(_, id)| *id)
+                .or_else(|| file.model.vocab.get(content).copied())
+        };
+
+        Ok(Self {
+            style,
+            bos: bos_content(&file.post_processor).and_then(|content| lookup(&content)),
+            unk: file.model.unk_token.as_deref().and_then(lookup),
+            vocab: file.model.vocab,
+            pieces,
+            ranks,
+            added,
+            byte_fallback: file.model.byte_fallback,
+            byte_chars: byte_chars(),
+        })
+    }
+
+    /// Splits `text` into runs of plain text and the added tokens between them.
+    fn split_added<'a>(&self, text: &'a str) -> Vec<Result<&'a str, i32>> {
+        let mut parts = Vec::new();
+        let mut start = 0;
+        let mut i = 0;
+        while i < text.len() {
+            let found = self.added.iter().find(|(content, _)| {
+                !content.is_empty() && text[i..].starts_with(content.as_str())
+            });
+            match found {
+                Some((content, id)) => {
+                    if start < i {
+                        parts.push(Ok(&text[start..i]));
+                    }
+                    parts.push(Err(*id));
+                    i += content.len();
+                    start = i;
+                }
+                None => i += text[i..].chars().next().map_or(1, char::len_utf8),
+            }
+        }
+        if start < text.len() {
+            parts.push(Ok(&text[start..]));
+        }
+        parts
+    }
+
+    /// The words BPE runs on separately, already in the vocabulary's alphabet.
+    fn words(&self, text: &str, first: bool) -> Vec<String> {
+        match self.style {
+            Style::Metaspace => {
+                let mut normalized = String::new();
+                if first {
+                    normalized.push(METASPACE);
+                }
+                normalized.extend(text.chars().map(|c| if c == ' ' { METASPACE } else { c }));
+
+                let mut words = Vec::new();
+                let mut word = String::new();
+                for c in normalized.chars() {
+                    if c == METASPACE && !word.is_empty() && !word.ends_with(METASPACE) {
+                        words.push(std::mem::take(&mut word));
+                    }
+                    word.push(c);
+                }
+                words.extend((!word.is_empty()).then_some(word));
+                words
+            }
+            Style::ByteLevel => {
+                // Roughly GPT-2's split: a space joins the letters, digits or symbols after it.
+                let class = |c: char| match c {
+                    c if c.is_whitespace() => 0,
+                    c if c.is_alphabetic() => 1,
+                    c if c.is_numeric() => 2,
+                    _ => 3,
+                };
+                let mut words = Vec::<String>::new();
+                let mut word = String::new();
+                let mut previous = None;
+                for c in text.chars() {
+                    let current = class(c);
+                    let joins = match previous {
+                        None => true,
+                        Some(previous) => previous == current || word == " " && current != 0,
+                    };
+                    if !joins {
+                        words.push(std::mem::take(&mut word));
+                    }
+                    word.push(c);
+                    previous = Some(current);
+                }
+                words.extend((!word.is_empty()).then_some(word));
+                words
+                    .into_iter()
+                    .map(|word| {
+                        word.bytes()
+                            .map(|byte| self.byte_chars[usize::from(byte)])
+                            .collect()
+                    })
+                    .collect()
+            }
+        }
+    }
+
+    fn bpe(&self, word: &str, tokens: &mut Vec<i32>) -> Result<(), TokenizerError> {
+        let mut symbols = word.chars().map(String::from).collect::<Vec<_>>();
+        loop {
+            let best = symbols
+                .windows(2)
+                .enumerate()
+                .filter_map(|(i, pair)| {
+                    self.ranks
+                        .get(&(pair[0].clone(), pair[1].clone()))
+                        .map(|rank| (*rank, i))
+                })
+                .min();
+            let Some((_, i)) = best else {
+                break;
+            };
+            let right = symbols.remove(i + 1);
+            symbols[i].push_str(&right);
+        }
+
+        for symbol in symbols {
+            if let Some(id) = self.vocab.get(&symbol) {
+                tokens.push(*id);
+                continue;
+            }
+            let fallback = self
+                .byte_fallback
+                .then(|| {
+                    symbol
+                        .bytes()
+                        .map(|byte| self.vocab.get(&format!("<0x{byte:02X}>")).copied())
+                        .collect::<Option<Vec<_>>>()
+                })
+                .flatten();
+            match (fallback, self.unk) {
+                (Some(bytes), _) => tokens.extend(bytes),
+                (None, Some(unk)) => tokens.push(unk),
+                (None, None) => {
+                    return Err(TokenizerError::Tokenize(format!(
+                        "{symbol:?} isn't in the vocabulary"
+                    )))
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The bytes a token stands for, in the vocabulary's alphabet.
+    fn piece_bytes(&self, piece: &str, bytes: &mut Vec<u8>) {
+        match self.style {
+            Style::Metaspace => {
+                let byte = piece
+                    .strip_prefix("<0x")
+                    .and_then(|hex| hex.strip_suffix('>'))
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+                match byte {
+                    Some(byte) => bytes.push(byte),
+                    None => bytes.extend(piece.replace(METASPACE, " ").bytes()),
+                }
+            }
+            Style::ByteLevel => {
+                for c in piece.chars() {
+                    match self.byte_chars.iter().position(|mapped| *mapped == c) {
+                        Some(byte) => bytes.push(byte as u8),
+                        None => bytes.extend(c.to_string().bytes()),
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Tokenizer for HfTokenizer {
+    fn encode(&self, text: &str, add_bos: bool) -> Result<Vec<i32>, TokenizerError> {
+        let mut tokens = Vec::new();
+        if add_bos {
+            tokens.extend(self.bos);
+        }
+        for (i, part) in self.split_added(text).into_iter().enumerate() {
+            match part {
+                Ok(text) => {
+                    for word in self.words(text, i == 0) {
+                        self.bpe(&word, &mut tokens)?;
+                    }
+                }
+                Err(id) => tokens.push(id),
+            }
+        }
+
+        Ok(tokens)
+    }
+
+    fn decode(&self, tokens: &[i32]) -> Result<String, TokenizerError> {
+        let mut bytes = Vec::new();
+        for token in tokens {
+            let piece = self
+                .pieces
+                .get(token)
+                .ok_or(TokenizerError::InvalidToken(*token))?;
+            match self.added.iter().any(|(_, id)| id == token) {
+                true => bytes.extend(piece.bytes()),
+                false => self.piece_bytes(piece, &mut bytes),
+            }
+        }
+        let text = String::from_utf8_lossy(&bytes).into_owned();
+
+        Ok(match self.style {
+            // Undoes the space put before the first word.
+            Style::Metaspace => text.strip_prefix(' ').map(str::to_string).unwrap_or(text),
+            Style::ByteLevel => text,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_sentencepiece_and_byte_level_bpe() {
+        let sentencepiece = HfTokenizer::parse(
+            &serde_json::json!({
+                "added_tokens": [{ "id": 1, "content": "<s>" }],
+                "normalizer": { "type": "Replace", "pattern": { "String": " " }, "content": "▁" },
+                "post_processor": {
+                    "type": "TemplateProcessing",
+                    "single": [{ "SpecialToken": { "id": "<s>" } }, { "Sequence": { "id": "A" } }]
+                },
+                "model": {
+                    "type": "BPE",
+                    "byte_fallback": true,
+                    "vocab": {
+                        "<unk>": 0, "<s>": 1, "<0x21>": 2, "▁": 3, "h": 4, "i": 5,
+                        "▁h": 6, "▁hi": 7
+                    },
+                    "merges": ["▁ h", "▁h i"],
+                    "unk_token": "<unk>"
+                }
+            })
+            .to_string(),
+        )
+        .unwrap();
+        let tokens = sentencepiece.encode("hi hi!", true).unwrap();
+        assert_eq!(tokens, [1, 7, 7, 2]);
+        assert_eq!(sentencepiece.decode(&tokens[1..]).unwrap(), "hi hi!");
+        assert_eq!(count(&sentencepiece, "hi"), 2);
+        assert!(matches!(
+            sentencepiece.decode(&[99]),
+            Err(TokenizerError::InvalidToken(99))
+        ));
+
+        let byte_level = HfTokenizer::parse(
+            &serde_json::json!({
+                "added_tokens": [{ "id": 9, "content": "<|end|>" }],
+                "pre_tokenizer": { "type": "ByteLevel", "add_prefix_space": false },
+                "model": {
+                    "type": "BPE",
+                    "vocab": { "h": 0, "i": 1, "Ġ": 2, "hi": 3, "Ġhi": 4, "!": 5 },
+                    "merges": [["h", "i"], ["Ġ", "hi"]]
+                }
+            })
+            .to_string(),
+        )
+        .unwrap();
+        let tokens = byte_level.encode("hi hi!<|end|>", true).unwrap();
+        assert_eq!(tokens, [3, 4, 5, 9]);
+        assert_eq!(byte_level.decode(&tokens).unwrap(), "hi hi!<|end|>");
+    }
+}
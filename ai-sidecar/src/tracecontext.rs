@@ -0,0 +1,141 @@
+//! W3C trace context and request ids, so a request can be followed from the game through the
+//! sidecar to the backends it calls.
+//!
+//! Every v1 request has an `x-request-id`, the client's own if it sent one, which is echoed back in
+//! the response. Requests join the client's trace if they carry a valid `traceparent`, or start a
+//! new one. Calls to remote backends, a read-only replica's primary or a hosted model, carry the
+//! request id, a `traceparent` naming this hop as the parent, and the client's `baggage` untouched.
+
+use axum::http::{HeaderMap, HeaderValue};
+
+use crate::sessions::generate_id;
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+const TRACEPARENT_HEADER: &str = "traceparent";
+const BAGGAGE_HEADER: &str = "baggage";
+/// Longer request ids are replaced, so they can't bloat every log line.
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+/// Where a request sits in its trace.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceContext {
+    pub request_id: String,
+    /// 32 lowercase hex digits.
+    pub trace_id: String,
+    /// This hop's span, as the parent of any backend calls. 16 lowercase hex digits.
+    pub span_id: String,
+    pub sampled: bool,
+    pub baggage: Option<String>,
+}
+
+fn is_hex(value: &str, len: usize) -> bool {
+    value.len() == len
+        && value
+            .bytes()
+            .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+        && value.bytes().any(|b| b != b'0')
+}
+
+/// The trace id and sampled flag of a version 00 `traceparent`.
+fn parse_traceparent(value: &str) -> Option<(String, bool)> {
+    let mut parts = value.trim().split('-');
+    let (version, trace_id, parent_id, flags) =
+        (parts.next()?, parts.next()?, parts.next()?, parts.next()?);
+    let valid = version == "00"
+        && parts.next().is_none()
+        && is_hex(trace_id, 32)
+        && is_hex(parent_id, 16)
+        && flags.len() == 2;
+    if !valid {
+        return None;
+    }
+    let flags = u8::from_str_radix(flags, 16).ok()?;
+
+    Some((trace_id.to_string(), flags & 1 == 1))
+}
+
+impl TraceContext {
+    /// Continues the trace and request id of an incoming request, if it has them.
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        let header = |name| headers.get(name).and_then(|value| value.to_str().ok());
+        let request_id = header(REQUEST_ID_HEADER)
+            .filter(|id| !id.is_empty() && id.len() <= MAX_REQUEST_ID_LEN)
+            .map(str::to_string)
+            .unwrap_or_else(generate_id);
+        let (trace_id, sampled) = header(TRACEPARENT_HEADER)
+            .and_then(parse_traceparent)
+            .unwrap_or_else(|| (format!("{}{}", generate_id(), generate_id()), true));
+
+        Self {
+            request_id,
+            trace_id,
+            span_id: generate_id(),
+            sampled,
+            baggage: header(BAGGAGE_HEADER).map(str::to_string),
+        }
+    }
+
+    pub fn traceparent(&self) -> String {
+        format!(
+            "00-{}-{}-{:02x}",
+            self.trace_id,
+            self.span_id,
+            u8::from(self.sampled)
+        )
+    }
+
+    /// Sets the headers that carry the trace on to a backend.
+    pub fn inject(&self, headers: &mut HeaderMap) {
+        let mut set = |name, value: &str| {
+            if let Ok(value) = HeaderValue::from_str(value) {
+                headers.insert(name, value);
+            }
+        };
+        set(REQUEST_ID_HEADER, &self.request_id);
+        set(TRACEPARENT_HEADER, &self.traceparent());
+        if let Some(baggage) = &self.baggage {
+            set(BAGGAGE_HEADER, baggage);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn continues_the_callers_trace() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            TRACEPARENT_HEADER,
+            HeaderValue::from_static("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"),
+        );
+        headers.insert(REQUEST_ID_HEADER, HeaderValue::from_static("req-1"));
+        headers.insert(BAGGAGE_HEADER, HeaderValue::from_static("player=ayla"));
+        let context = TraceContext::from_headers(&headers);
+        assert_eq!(context.trace_id, "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(context.request_id, "req-1");
+        assert!(context.sampled);
+
+        let mut outgoing = HeaderMap::new();
+        context.inject(&mut outgoing);
+        assert_eq!(
+            outgoing[TRACEPARENT_HEADER],
+            format!("00-4bf92f3577b34da6a3ce929d0e0e4736-{}-01", context.span_id)
+        );
+        assert_eq!(outgoing[BAGGAGE_HEADER], "player=ayla");
+        assert_eq!(outgoing[REQUEST_ID_HEADER], "req-1");
+
+        for invalid in [
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            "01-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            "00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7",
+        ] {
+            assert_eq!(parse_traceparent(invalid), None, "{invalid}");
+        }
+        let fresh = TraceContext::from_headers(&HeaderMap::new());
+        assert!(is_hex(&fresh.trace_id, 32));
+        assert!(is_hex(&fresh.span_id, 16));
+    }
+}
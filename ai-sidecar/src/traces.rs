@@ -0,0 +1,139 @@
+//! A record of recent replies and how players rated them.
+//!
+//! Every reply `/generate` serves gets a generation id the game can send back with feedback.
+//! Only the latest traces are kept, so feedback on a long-gone reply is dropped.
+
+use std::{
+    collections::VecDeque,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+/// How many traces are kept before the oldest are dropped.
+const MAX_TRACES: usize = 1024;
+
+/// Where a reply came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Source {
+    Generated,
+    Authored,
+    Fallback,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Rating {
+    Up,
+    Down,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Feedback {
+    pub rating: Rating,
+    pub comment: Option<String>,
+    pub at: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Trace {
+    pub id: String,
+    pub session_id: Option<String>,
+    pub player_id: Option<String>,
+    pub prompt: String,
+    pub reply: String,
+    pub source: Source,
+    pub at: u64,
+    pub feedback: Option<Feedback>,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+#[derive(Debug, Default)]
+pub struct Traces {
+    traces: VecDeque<Trace>,
+}
+
+impl Traces {
+    /// Records a reply, returning its generation id.
+    pub fn record(
+        &mut self,
+        session_id: Option<String>,
+        player_id: Option<String>,
+        prompt: String,
+        reply: String,
+        source: Source,
+    ) -> String {
+        if self.traces.len() >= MAX_TRACES {
+            self.traces.pop_front();
+        }
+
+        let id = crate::sessions::generate_id();
+        self.traces.push_back(Trace {
+            id: id.clone(),
+            session_id,
+            player_id,
+            prompt,
+            reply,
+            source,
+            at: now(),
+            feedback: None,
+        });
+
+        id
+    }
+
+    /// Attaches feedback to a reply, replacing any given before. Returns `false` if the reply
+    /// isn't known, or no longer is.
+    pub fn feedback(&mut self, id: &str, rating: Rating, comment: Option<String>) -> bool {
+        let Some(trace) = self.traces.iter_mut().find(|trace| trace.id == id) else {
+            return false;
+        };
+        trace.feedback = Some(Feedback {
+            rating,
+            comment,
+            at: now(),
+        });
+
+        true
+    }
+
+    /// Oldest first.
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = &Trace> {
+        self.traces.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn attaches_feedback_to_recent_traces() {
+        let mut traces = Traces::default();
+        let first = traces.record(None, None, "Hi".into(), "Hello".into(), Source::Generated);
+        for _ in 1..MAX_TRACES {
+            traces.record(None, None, "Hi".into(), "Hello".into(), Source::Fallback);
+        }
+        let last = traces.record(
+            Some("s".into()),
+            None,
+            "Bye".into(),
+            "Farewell".into(),
+            Source::Authored,
+        );
+
+        assert!(!traces.feedback(&first, Rating::Up, None));
+        assert!(traces.feedback(&last, Rating::Down, Some("Too curt".into())));
+
+        let trace = traces.iter().last().unwrap();
+        assert_eq!(traces.iter().count(), MAX_TRACES);
+        assert_eq!(trace.feedback.as_ref().unwrap().rating, Rating::Down);
+    }
+}
@@ -0,0 +1,266 @@
+//! Turn-taking in party conversations, so one spammy player can't monopolize the NPC.
+//!
+//! Each player has at most one turn waiting for the conversation at a time. Messages they send
+//! while it waits are folded into it, so the NPC answers them together. This leaves the players'
+//! waiting turns queued in the order they arrived. On top of that, each player may send at most
+//! `AI_SIDECAR_PARTY_MESSAGES_PER_MINUTE` messages a minute.
+//!
+//! A folded message's request waits for the turn it joined. If that turn is refused, e.g. because
+//! the conversation was paused meanwhile, it gets the same refusal, since its message was dropped
+//! along with the turn.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use tokio::sync::oneshot;
+
+use crate::server::ServerError;
+
+const DEFAULT_MESSAGES_PER_MINUTE: usize = 10;
+const WINDOW: Duration = Duration::from_secs(60);
+
+/// A player in a session, by session id and player name.
+type Key = (String, String);
+
+/// A player's waiting turn.
+#[derive(Debug)]
+struct Pending<R> {
+    id: u64,
+    /// Every message folded into it so far.
+    text: String,
+    /// Where to send its refusal, for each request folded into it.
+    merged: Vec<oneshot::Sender<R>>,
+}
+
+#[derive(Debug)]
+struct Player<R> {
+    /// When their messages within the last minute were sent, oldest first.
+    recent: VecDeque<Instant>,
+    pending: Option<Pending<R>>,
+}
+
+impl<R> Default for Player<R> {
+    fn default() -> Self {
+        Self {
+            recent: VecDeque::new(),
+            pending: None,
+        }
+    }
+}
+
+/// Players' turns, refused with an `R`.
+#[derive(Debug)]
+pub struct Turns<R> {
+    limit: usize,
+    players: Mutex<HashMap<Key, Player<R>>>,
+    next: AtomicU64,
+}
+
+#[derive(Debug)]
+pub enum Turn<R> {
+    /// A new turn, waiting for the conversation.
+    Queued(PendingTurn<R>),
+    /// Folded into the player's waiting turn, which answers it too.
+    Merged(MergedTurn<R>),
+    RateLimited {
+        retry_after: Duration,
+    },
+}
+
+/// Keeps a player's turn open to more messages until it is taken, refused or dropped.
+#[derive(Debug)]
+pub struct PendingTurn<R> {
+    turns: Arc<Turns<R>>,
+    key: Key,
+    id: u64,
+}
+
+impl<R: Clone> PendingTurn<R> {
+    /// Closes the turn, returning every message sent in it.
+    pub fn take(self) -> Option<String> {
+        self.turns
+            .close(&self.key, self.id)
+            .map(|pending| pending.text)
+    }
+
+    /// Closes the turn without answering it, sending `refusal` to every request folded into it.
+    pub fn refuse(self, refusal: R) {
+        let merged = self
+            .turns
+            .close(&self.key, self.id)
+            .map(|pending| pending.merged)
+            .unwrap_or_default();
+        for request in merged {
+            // The request may have given up waiting.
+            let _ = request.send(refusal.clone());
+        }
+    }
+}
+
+impl<R> Drop for PendingTurn<R> {
+    fn drop(&mut self) {
+        self.turns.close(&self.key, self.id);
+    }
+}
+
+/// A message folded into a player's waiting turn.
+#[derive(Debug)]
+pub struct MergedTurn<R>(oneshot::Receiver<R>);
+
+impl<R> MergedTurn<R> {
+    /// Waits for the turn to close, returning its refusal if it was refused.
+    pub async fn refusal(self) -> Option<R> {
+        self.0.await.ok()
+    }
+}
+
+impl<R> Turns<R> {
+    pub fn from_env() -> Result<Self, ServerError> {
+        let limit = match std::env::var("AI_SIDECAR_PARTY_MESSAGES_PER_MINUTE") {
+            Ok(v) => {
+                v.parse()
+                    .ok()
+                    .filter(|limit| *limit > 0)
+                    .ok_or(ServerError::InvalidSetting(
+                        "AI_SIDECAR_PARTY_MESSAGES_PER_MINUTE",
+                        v,
+                    ))?
+            }
+            Err(_) => DEFAULT_MESSAGES_PER_MINUTE,
+        };
+
+        Ok(Self::new(limit))
+    }
+
+    pub fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            players: Default::default(),
+            next: AtomicU64::new(0),
+        }
+    }
+
+    /// Admits a player's message at `now`, as a new turn or into the one already waiting.
+    pub fn offer(
+        self: &Arc<Self>,
+        session_id: &str,
+        player: &str,
+        prompt: &str,
+        now: Instant,
+    ) -> Turn<R> {
+        let mut players = self.players.lock().unwrap();
+        players.retain(|_, player| {
+            while player
+                .recent
+                .front()
+                .is_some_and(|sent| now.duration_since(*sent) >= WINDOW)
+            {
+                player.recent.pop_front();
+            }
+            player.pending.is_some() || !player.recent.is_empty()
+        });
+
+        let key = (session_id.to_string(), player.to_string());
+        let player = players.entry(key.clone()).or_default();
+        if player.recent.len() >= self.limit {
+            let oldest = player.recent[0];
+            return Turn::RateLimited {
+                retry_after: WINDOW - now.duration_since(oldest),
+            };
+        }
+        player.recent.push_back(now);
+
+        if let Some(pending) = &mut player.pending {
+            pending.text.push('\n');
+            pending.text.push_str(prompt);
+            let (tx, rx) = oneshot::channel();
+            pending.merged.push(tx);
+            return Turn::Merged(MergedTurn(rx));
+        }
+        let id = self.next.fetch_add(1, Ordering::Relaxed);
+        player.pending = Some(Pending {
+            id,
+            text: prompt.to_string(),
+            merged: Vec::new(),
+        });
+
+        Turn::Queued(PendingTurn {
+            turns: self.clone(),
+            key,
+            id,
+        })
+    }
+
+    fn close(&self, key: &Key, id: u64) -> Option<Pending<R>> {
+        let mut players = self.players.lock().unwrap();
+        let player = players.get_mut(key)?;
+        match &player.pending {
+            Some(pending) if pending.id == id => player.pending.take(),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn folds_waiting_messages_and_limits_each_player() {
+        let turns = Arc::new(Turns::<()>::new(3));
+        let now = Instant::now();
+
+        let Turn::Queued(first) = turns.offer("s", "Ayla", "Hello", now) else {
+            panic!("expected a new turn");
+        };
+        let Turn::Merged(merged) = turns.offer("s", "Ayla", "Hello?", now) else {
+            panic!("expected a folded message");
+        };
+        assert!(matches!(
+            turns.offer("s", "Corwin", "Hi", now),
+            Turn::Queued(_)
+        ));
+        assert!(matches!(
+            turns.offer("s", "Ayla", "HELLO", now),
+            Turn::Merged(_)
+        ));
+        assert_eq!(first.take().as_deref(), Some("Hello\nHello?\nHELLO"));
+        assert_eq!(merged.refusal().await, None);
+
+        let second = Duration::from_secs(1);
+        let Turn::RateLimited { retry_after } = turns.offer("s", "Ayla", "Hey", now + second)
+        else {
+            panic!("expected a rate limit");
+        };
+        assert_eq!(retry_after, WINDOW - second);
+        assert!(matches!(
+            turns.offer("s", "Ayla", "Hey", now + WINDOW),
+            Turn::Queued(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn refuses_folded_messages_with_their_turn() {
+        let turns = Arc::new(Turns::new(3));
+        let now = Instant::now();
+
+        let Turn::Queued(first) = turns.offer("s", "Ayla", "Hello", now) else {
+            panic!("expected a new turn");
+        };
+        let Turn::Merged(merged) = turns.offer("s", "Ayla", "Hello?", now) else {
+            panic!("expected a folded message");
+        };
+        first.refuse("paused");
+        assert_eq!(merged.refusal().await, Some("paused"));
+        assert!(matches!(
+            turns.offer("s", "Ayla", "Hey", now),
+            Turn::Queued(_)
+        ));
+    }
+}
@@ -0,0 +1,80 @@
+//! Reassembles text from token pieces, which can end partway through a multi-byte character, so
+//! streamed chunks only ever hold whole characters.
+
+#[derive(Debug, Default)]
+pub struct Utf8Buffer {
+    /// The start of a character whose remaining bytes haven't arrived yet.
+    pending: Vec<u8>,
+}
+
+impl Utf8Buffer {
+    /// Adds `bytes`, returning the text they complete. Bytes that can't be part of any character
+    /// become U+FFFD.
+    pub fn push(&mut self, bytes: &[u8]) -> String {
+        self.pending.extend_from_slice(bytes);
+        let mut text = String::new();
+        let mut rest = self.pending.as_slice();
+        loop {
+            match std::str::from_utf8(rest) {
+                Ok(valid) => {
+                    text.push_str(valid);
+                    rest = &[];
+                    break;
+                }
+                Err(e) => {
+                    let (valid, after) = rest.split_at(e.valid_up_to());
+                    text.push_str(std::str::from_utf8(valid).expect("valid up to here"));
+                    match e.error_len() {
+                        Some(len) => {
+                            text.push(char::REPLACEMENT_CHARACTER);
+                            rest = &after[len..];
+                        }
+                        // The character may still be completed by the next piece.
+                        None => {
+                            rest = after;
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+        let done = self.pending.len() - rest.len();
+        self.pending.drain(..done);
+
+        text
+    }
+
+    /// Whatever is left once the last piece is in, with an unfinished character as U+FFFD.
+    pub fn finish(&mut self) -> String {
+        let text = String::from_utf8_lossy(&self.pending).into_owned();
+        self.pending.clear();
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reassembles_multilingual_text() {
+        let text = "Grüße, 世界! Привет, مرحبا 👋🏽 👨‍👩‍👧 नमस्ते";
+        let bytes = text.as_bytes();
+        for size in 1..=5 {
+            let mut buffer = Utf8Buffer::default();
+            let mut chunks = bytes
+                .chunks(size)
+                .map(|piece| buffer.push(piece))
+                .collect::<Vec<_>>();
+            chunks.push(buffer.finish());
+            assert_eq!(chunks.concat(), text, "pieces of {size} bytes");
+            assert!(chunks.iter().all(|chunk| !chunk.contains('\u{FFFD}')));
+        }
+
+        let mut buffer = Utf8Buffer::default();
+        assert_eq!(buffer.push(&[b'a', 0xff, 0xe4, 0xb8]), "a\u{FFFD}");
+        assert_eq!(buffer.push(&[0x96]), "世");
+        assert_eq!(buffer.push(&[0xf0, 0x9f]), "");
+        assert_eq!(buffer.finish(), "\u{FFFD}");
+    }
+}